@@ -0,0 +1,80 @@
+/*
+ * C API for banyan streams, see banyan-ffi/src/lib.rs for the documentation of each function.
+ *
+ * All objects are opaque handles. Fallible functions return a banyan_status, and on failure
+ * banyan_last_error returns a description of the error for the calling thread.
+ */
+#ifndef BANYAN_H
+#define BANYAN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum banyan_status {
+    BANYAN_OK = 0,
+    BANYAN_DONE = 1,
+    BANYAN_INVALID_ARGUMENT = 2,
+    BANYAN_STORE_ERROR = 3,
+    BANYAN_PANIC = 4,
+} banyan_status;
+
+/* settings for new leaves and branches of a stream, see banyan_config_default */
+typedef struct banyan_config {
+    size_t target_leaf_size;
+    size_t max_leaf_count;
+    size_t max_key_branches;
+    size_t max_summary_branches;
+    int32_t zstd_level;
+} banyan_config;
+
+typedef struct banyan_store banyan_store;
+typedef struct banyan_stream banyan_stream;
+typedef struct banyan_events banyan_events;
+
+/* an event of a query result, valid until the next banyan_events_next or banyan_events_free */
+typedef struct banyan_event {
+    uint64_t offset;
+    uint64_t time;
+    const char *const *tags;
+    size_t n_tags;
+    const char *value;
+} banyan_event;
+
+const char *banyan_last_error(void);
+banyan_config banyan_config_default(void);
+
+banyan_status banyan_store_open_memory(banyan_store **out);
+banyan_status banyan_store_open_sqlite(const char *path, banyan_store **out);
+void banyan_store_free(banyan_store *store);
+
+/* config may be null for the default, the 32 byte keys may both be null for no encryption */
+banyan_status banyan_stream_create(const banyan_store *store, const banyan_config *config,
+                                   const uint8_t *index_key, const uint8_t *value_key,
+                                   banyan_stream **out);
+banyan_status banyan_stream_load(const banyan_store *store, const char *root,
+                                 const banyan_config *config, const uint8_t *index_key,
+                                 const uint8_t *value_key, banyan_stream **out);
+void banyan_stream_free(banyan_stream *stream);
+banyan_status banyan_stream_count(const banyan_stream *stream, uint64_t *out);
+banyan_status banyan_stream_root(const banyan_stream *stream, char **out);
+void banyan_string_free(char *text);
+
+banyan_status banyan_stream_push(banyan_stream *stream, uint64_t time, const char *const *tags,
+                                 size_t n_tags, const char *value);
+banyan_status banyan_stream_commit(banyan_stream *stream);
+
+banyan_status banyan_stream_query(const banyan_stream *stream, const char *const *tags,
+                                  size_t n_tags, uint64_t start, uint64_t end,
+                                  banyan_events **out);
+banyan_status banyan_events_next(banyan_events *events, banyan_event *out);
+void banyan_events_free(banyan_events *events);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BANYAN_H */
@@ -0,0 +1,777 @@
+//! C API for banyan streams
+//!
+//! Streams use the tree types of [banyan_utils::tags] with string values, see
+//! `include/banyan.h` for the declarations. All objects are opaque handles that are created by a
+//! `banyan_*_open`, `banyan_*_create` or `banyan_*_load` function and must be released with the
+//! matching `banyan_*_free` function.
+//!
+//! Fallible functions return a [banyan_status]. On failure, a description of the error can be
+//! retrieved with [banyan_last_error] on the same thread. Panics never unwind into the caller,
+//! they are reported as BANYAN_PANIC.
+#![allow(non_camel_case_types)]
+use anyhow::{anyhow, Result};
+use banyan::{
+    chacha20,
+    query::{AllQuery, AndQuery, OffsetRangeQuery, Query, QueryExt},
+    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::{
+    sqlite::SqliteStore,
+    tag_index::{Tag, TagSet},
+    tags::{Digest, DnfQuery, Key, Sha256Digest, TT},
+};
+use ipfs_sqlite_block_store::BlockStore;
+use libipld::DefaultParams;
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    os::raw::c_char,
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+    sync::Arc,
+};
+
+type Link = Sha256Digest;
+
+/// Result of a fallible function
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum banyan_status {
+    /// the function succeeded
+    BANYAN_OK = 0,
+    /// an iterator has no more elements
+    BANYAN_DONE = 1,
+    /// an argument was null or not valid UTF-8
+    BANYAN_INVALID_ARGUMENT = 2,
+    /// reading from or writing to the store failed
+    BANYAN_STORE_ERROR = 3,
+    /// the library panicked, which is a bug. The objects passed to the function may be in an
+    /// inconsistent state and should only be released.
+    BANYAN_PANIC = 4,
+}
+
+use banyan_status::*;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(status: banyan_status, cause: anyhow::Error) -> banyan_status {
+    let text = CString::new(format!("{:#}", cause).replace('\0', ""))
+        .expect("nul bytes have been removed");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(text));
+    status
+}
+
+/// Runs `f`, catching panics so they do not unwind into C, and returns `fallback` on a panic
+fn guard_or<R>(fallback: R, f: impl FnOnce() -> R) -> R {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|text| text.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        set_error(BANYAN_PANIC, anyhow!("panic: {}", message));
+        fallback
+    })
+}
+
+/// Runs `f`, turning a panic into BANYAN_PANIC
+fn guard(f: impl FnOnce() -> banyan_status) -> banyan_status {
+    guard_or(BANYAN_PANIC, f)
+}
+
+/// Checks the pointer arguments of a function, returning BANYAN_INVALID_ARGUMENT if one is null
+macro_rules! non_null {
+    ($($arg:ident),+) => {
+        $(
+            if $arg.is_null() {
+                return set_error(
+                    BANYAN_INVALID_ARGUMENT,
+                    anyhow!(concat!(stringify!($arg), " must not be null")),
+                );
+            }
+        )+
+    };
+}
+
+unsafe fn to_str<'a>(text: *const c_char) -> Result<&'a str> {
+    Ok(CStr::from_ptr(text).to_str()?)
+}
+
+unsafe fn to_tags(tags: *const *const c_char, n_tags: usize) -> Result<TagSet> {
+    if n_tags == 0 {
+        return Ok(TagSet::empty());
+    }
+    anyhow::ensure!(!tags.is_null(), "tags must not be null");
+    std::slice::from_raw_parts(tags, n_tags)
+        .iter()
+        .map(|tag| {
+            anyhow::ensure!(!tag.is_null(), "tags must not contain null");
+            Ok(Tag::from(to_str(*tag)?))
+        })
+        .collect()
+}
+
+/// Settings for new leaves and branches of a stream, see [banyan_config_default]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct banyan_config {
+    /// rough maximum compressed size of a leaf in bytes, at most 1 MiB
+    pub target_leaf_size: usize,
+    /// maximum number of events in a leaf
+    pub max_leaf_count: usize,
+    /// maximum number of children of a branch above the leaves
+    pub max_key_branches: usize,
+    /// maximum number of children of the other branches, at least 2
+    pub max_summary_branches: usize,
+    /// zstd level from 1 to 22
+    pub zstd_level: i32,
+}
+
+impl banyan_config {
+    fn to_config(self) -> Result<Config> {
+        let config = Config {
+            target_leaf_size: self.target_leaf_size,
+            max_leaf_count: self.max_leaf_count,
+            max_key_branches: self.max_key_branches,
+            max_summary_branches: self.max_summary_branches,
+            zstd_level: self.zstd_level,
+            max_uncompressed_leaf_size: 16 * 1024 * 1024,
+            skip_redundant_compression: false,
+            summary_truncation_level: None,
+            adaptive_leaf_size: false,
+            ordered_keys: false,
+            pack_values: false,
+            dedup_window: 0,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// The config and secrets of a stream
+///
+/// Without a config, [banyan_config_default] is used. The keys must either both be null, for
+/// unencrypted blocks, or both point to 32 bytes.
+unsafe fn to_options(
+    config: *const banyan_config,
+    index_key: *const u8,
+    value_key: *const u8,
+) -> Result<(Config, Secrets)> {
+    let config = if config.is_null() {
+        banyan_config_default()
+    } else {
+        *config
+    };
+    let secrets = match (index_key.is_null(), value_key.is_null()) {
+        (true, true) => Secrets::none(),
+        (false, false) => Secrets::new(
+            *chacha20::Key::from_slice(slice::from_raw_parts(index_key, 32)),
+            *chacha20::Key::from_slice(slice::from_raw_parts(value_key, 32)),
+        ),
+        _ => return Err(anyhow!("either both keys or none must be given")),
+    };
+    Ok((config.to_config()?, secrets))
+}
+
+#[derive(Clone)]
+enum Storage {
+    Memory(MemStore<Link>),
+    Sqlite(SqliteStore<DefaultParams>),
+}
+
+impl ReadOnlyStore<Link> for Storage {
+    fn get(&self, link: &Link) -> Result<Box<[u8]>> {
+        match self {
+            Self::Memory(m) => m.get(link),
+            Self::Sqlite(s) => s.get(link),
+        }
+    }
+}
+
+impl BlockWriter<Link> for Storage {
+    fn put(&mut self, data: Vec<u8>) -> Result<Link> {
+        match self {
+            Self::Memory(m) => m.put(data),
+            Self::Sqlite(s) => s.put(data),
+        }
+    }
+}
+
+/// A block store, either in memory or in an sqlite database
+pub struct banyan_store {
+    storage: Storage,
+    forest: Forest<TT<Link>, Storage>,
+}
+
+impl banyan_store {
+    fn new(storage: Storage) -> Self {
+        let forest = Forest::new(storage.clone(), BranchCache::default());
+        Self { storage, forest }
+    }
+
+    fn stream(&self, builder: StreamBuilder<TT<Link>, String>) -> banyan_stream {
+        banyan_stream {
+            storage: self.storage.clone(),
+            forest: self.forest.clone(),
+            builder,
+            pending: Vec::new(),
+        }
+    }
+}
+
+/// A stream of events with string values
+pub struct banyan_stream {
+    storage: Storage,
+    forest: Forest<TT<Link>, Storage>,
+    builder: StreamBuilder<TT<Link>, String>,
+    /// events added with banyan_stream_push that are not yet committed
+    pending: Vec<(Key, String)>,
+}
+
+/// A single event of a query result
+///
+/// The pointers are owned by the iterator and are valid until the next call to
+/// [banyan_events_next] or [banyan_events_free].
+#[repr(C)]
+pub struct banyan_event {
+    pub offset: u64,
+    pub time: u64,
+    pub tags: *const *const c_char,
+    pub n_tags: usize,
+    pub value: *const c_char,
+}
+
+/// Iterator over the result of a query
+pub struct banyan_events {
+    iter: Box<dyn Iterator<Item = Result<(u64, Key, String)>>>,
+    tags: Vec<CString>,
+    tag_ptrs: Vec<*const c_char>,
+    value: CString,
+}
+
+/// The description of the last error on this thread, or null
+///
+/// The string is valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn banyan_last_error() -> *const c_char {
+    guard_or(ptr::null(), || {
+        LAST_ERROR.with(|last| {
+            last.borrow()
+                .as_ref()
+                .map(|text| text.as_ptr())
+                .unwrap_or_else(ptr::null)
+        })
+    })
+}
+
+/// The default config, which produces trees with large, well compressed leaves
+#[no_mangle]
+pub extern "C" fn banyan_config_default() -> banyan_config {
+    banyan_config {
+        target_leaf_size: 1 << 16,
+        max_leaf_count: 1 << 16,
+        max_key_branches: 32,
+        max_summary_branches: 32,
+        zstd_level: 10,
+    }
+}
+
+/// Opens a store that keeps all blocks in memory
+///
+/// # Safety
+///
+/// `out` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_store_open_memory(out: *mut *mut banyan_store) -> banyan_status {
+    guard(|| {
+        non_null!(out);
+        let storage = Storage::Memory(MemStore::new(usize::max_value(), <Link as Digest>::digest));
+        *out = Box::into_raw(Box::new(banyan_store::new(storage)));
+        BANYAN_OK
+    })
+}
+
+/// Opens a store backed by the sqlite database at `path`, which is created if it does not exist
+///
+/// # Safety
+///
+/// `path` must be a nul terminated string and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_store_open_sqlite(
+    path: *const c_char,
+    out: *mut *mut banyan_store,
+) -> banyan_status {
+    guard(|| {
+        non_null!(path, out);
+        let path = match to_str(path) {
+            Ok(path) => path,
+            Err(cause) => return set_error(BANYAN_INVALID_ARGUMENT, cause),
+        };
+        let store = BlockStore::open(path, ipfs_sqlite_block_store::Config::default())
+            .map_err(anyhow::Error::from)
+            .and_then(SqliteStore::new);
+        match store {
+            Ok(store) => {
+                *out = Box::into_raw(Box::new(banyan_store::new(Storage::Sqlite(store))));
+                BANYAN_OK
+            }
+            Err(cause) => set_error(BANYAN_STORE_ERROR, cause),
+        }
+    })
+}
+
+/// Releases a store. Streams opened from the store remain usable.
+///
+/// # Safety
+///
+/// `store` must be null or a store that has not been released yet.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_store_free(store: *mut banyan_store) {
+    guard_or((), || {
+        if !store.is_null() {
+            drop(Box::from_raw(store));
+        }
+    })
+}
+
+/// Creates a new, empty stream
+///
+/// `config` may be null for [banyan_config_default]. `index_key` and `value_key` must either
+/// both be null, for a stream that is not encrypted, or both point to 32 byte keys.
+///
+/// # Safety
+///
+/// `store` must be a valid store, `config` null or a valid config, the keys null or valid for 32
+/// bytes, and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_stream_create(
+    store: *const banyan_store,
+    config: *const banyan_config,
+    index_key: *const u8,
+    value_key: *const u8,
+    out: *mut *mut banyan_stream,
+) -> banyan_status {
+    guard(|| {
+        non_null!(store, out);
+        let (config, secrets) = match to_options(config, index_key, value_key) {
+            Ok(options) => options,
+            Err(cause) => return set_error(BANYAN_INVALID_ARGUMENT, cause),
+        };
+        let builder = StreamBuilder::new(config, secrets);
+        *out = Box::into_raw(Box::new((*store).stream(builder)));
+        BANYAN_OK
+    })
+}
+
+/// Loads the stream with the given root, as returned by [banyan_stream_root]
+///
+/// The keys must be the ones the stream was created with, see [banyan_stream_create].
+///
+/// # Safety
+///
+/// `store` must be a valid store, `root` a nul terminated string, `config` null or a valid
+/// config, the keys null or valid for 32 bytes, and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_stream_load(
+    store: *const banyan_store,
+    root: *const c_char,
+    config: *const banyan_config,
+    index_key: *const u8,
+    value_key: *const u8,
+    out: *mut *mut banyan_stream,
+) -> banyan_status {
+    guard(|| {
+        non_null!(store, root, out);
+        let link = match to_str(root).and_then(|root| root.parse::<Link>()) {
+            Ok(link) => link,
+            Err(cause) => return set_error(BANYAN_INVALID_ARGUMENT, cause),
+        };
+        let (config, secrets) = match to_options(config, index_key, value_key) {
+            Ok(options) => options,
+            Err(cause) => return set_error(BANYAN_INVALID_ARGUMENT, cause),
+        };
+        let store = &*store;
+        match store.forest.load_stream_builder(secrets, config, link) {
+            Ok(builder) => {
+                *out = Box::into_raw(Box::new(store.stream(builder)));
+                BANYAN_OK
+            }
+            Err(cause) => set_error(BANYAN_STORE_ERROR, cause),
+        }
+    })
+}
+
+/// Releases a stream, discarding events that have not been committed
+///
+/// # Safety
+///
+/// `stream` must be null or a stream that has not been released yet.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_stream_free(stream: *mut banyan_stream) {
+    guard_or((), || {
+        if !stream.is_null() {
+            drop(Box::from_raw(stream));
+        }
+    })
+}
+
+/// Writes the number of committed events in the stream to `out`
+///
+/// # Safety
+///
+/// `stream` must be a valid stream and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_stream_count(
+    stream: *const banyan_stream,
+    out: *mut u64,
+) -> banyan_status {
+    guard(|| {
+        non_null!(stream, out);
+        *out = (*stream).builder.count();
+        BANYAN_OK
+    })
+}
+
+/// Writes the root of the stream to `out`, or null if the stream is empty
+///
+/// The string must be released with [banyan_string_free].
+///
+/// # Safety
+///
+/// `stream` must be a valid stream and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_stream_root(
+    stream: *const banyan_stream,
+    out: *mut *mut c_char,
+) -> banyan_status {
+    guard(|| {
+        non_null!(stream, out);
+        *out = match (*stream).builder.link() {
+            Some(link) => CString::new(link.to_string())
+                .expect("cids do not contain nul")
+                .into_raw(),
+            None => ptr::null_mut(),
+        };
+        BANYAN_OK
+    })
+}
+
+/// Releases a string returned by this library
+///
+/// # Safety
+///
+/// `text` must be null or a string returned by this library that has not been released yet.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_string_free(text: *mut c_char) {
+    guard_or((), || {
+        if !text.is_null() {
+            drop(CString::from_raw(text));
+        }
+    })
+}
+
+/// Adds an event to the stream. It is written to the store by [banyan_stream_commit].
+///
+/// # Safety
+///
+/// `stream` must be a valid stream, `tags` must point to `n_tags` nul terminated strings, and
+/// `value` must be a nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_stream_push(
+    stream: *mut banyan_stream,
+    time: u64,
+    tags: *const *const c_char,
+    n_tags: usize,
+    value: *const c_char,
+) -> banyan_status {
+    guard(|| {
+        non_null!(stream, value);
+        let stream = &mut *stream;
+        let event = to_tags(tags, n_tags).and_then(|tags| Ok((tags, to_str(value)?)));
+        match event {
+            Ok((tags, value)) => {
+                let lamport = stream.builder.count() + stream.pending.len() as u64;
+                let key = Key::single(lamport, time, tags);
+                stream.pending.push((key, value.to_owned()));
+                BANYAN_OK
+            }
+            Err(cause) => set_error(BANYAN_INVALID_ARGUMENT, cause),
+        }
+    })
+}
+
+/// Writes all events added with [banyan_stream_push] to the store
+///
+/// The pending events are cleared even if writing fails.
+///
+/// # Safety
+///
+/// `stream` must be a valid stream.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_stream_commit(stream: *mut banyan_stream) -> banyan_status {
+    guard(|| {
+        non_null!(stream);
+        let stream = &mut *stream;
+        let events = std::mem::take(&mut stream.pending);
+        let mut txn = Transaction::new(stream.forest.clone(), stream.storage.clone());
+        match txn.extend(&mut stream.builder, events) {
+            Ok(()) => BANYAN_OK,
+            Err(cause) => set_error(BANYAN_STORE_ERROR, cause),
+        }
+    })
+}
+
+/// Queries the committed events of a stream
+///
+/// Only events that have all of the `n_tags` tags and whose offset is in `start..end` are
+/// returned. Pass `n_tags = 0` for no tag filter and `end = UINT64_MAX` for no upper bound.
+///
+/// # Safety
+///
+/// `stream` must be a valid stream, `tags` must point to `n_tags` nul terminated strings, and
+/// `out` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_stream_query(
+    stream: *const banyan_stream,
+    tags: *const *const c_char,
+    n_tags: usize,
+    start: u64,
+    end: u64,
+    out: *mut *mut banyan_events,
+) -> banyan_status {
+    guard(|| {
+        non_null!(stream, out);
+        let stream = &*stream;
+        let tags = match to_tags(tags, n_tags) {
+            Ok(tags) => tags,
+            Err(cause) => return set_error(BANYAN_INVALID_ARGUMENT, cause),
+        };
+        let mut query: Arc<dyn Query<TT<Link>>> = AllQuery.boxed();
+        if n_tags > 0 {
+            query = AndQuery(query, DnfQuery(vec![Key::filter_tags(tags)])).boxed();
+        }
+        query = AndQuery(query, OffsetRangeQuery::from(start..end)).boxed();
+        let iter = stream
+            .forest
+            .iter_filtered(&stream.builder.snapshot(), query);
+        *out = Box::into_raw(Box::new(banyan_events {
+            iter: Box::new(iter),
+            tags: Vec::new(),
+            tag_ptrs: Vec::new(),
+            value: CString::default(),
+        }));
+        BANYAN_OK
+    })
+}
+
+/// Advances the iterator and writes the next event to `out`
+///
+/// Returns BANYAN_DONE when there are no more events.
+///
+/// # Safety
+///
+/// `events` must be a valid iterator and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_events_next(
+    events: *mut banyan_events,
+    out: *mut banyan_event,
+) -> banyan_status {
+    guard(|| {
+        non_null!(events, out);
+        let events = &mut *events;
+        let (offset, key, value) = match events.iter.next() {
+            Some(Ok(event)) => event,
+            Some(Err(cause)) => return set_error(BANYAN_STORE_ERROR, cause),
+            None => return BANYAN_DONE,
+        };
+        // tags and values are nul free, since they were created from C strings
+        events.tags = key
+            .tags()
+            .iter()
+            .map(|tag| CString::new(tag.as_str()).unwrap_or_default())
+            .collect();
+        events.tag_ptrs = events.tags.iter().map(|tag| tag.as_ptr()).collect();
+        events.value = CString::new(value).unwrap_or_default();
+        *out = banyan_event {
+            offset,
+            time: key.min_time(),
+            tags: events.tag_ptrs.as_ptr(),
+            n_tags: events.tag_ptrs.len(),
+            value: events.value.as_ptr(),
+        };
+        BANYAN_OK
+    })
+}
+
+/// Releases an iterator
+///
+/// # Safety
+///
+/// `events` must be null or an iterator that has not been released yet.
+#[no_mangle]
+pub unsafe extern "C" fn banyan_events_free(events: *mut banyan_events) {
+    guard_or((), || {
+        if !events.is_null() {
+            drop(Box::from_raw(events));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_commit_query() {
+        unsafe {
+            let mut store = ptr::null_mut();
+            assert_eq!(banyan_store_open_memory(&mut store), BANYAN_OK);
+            let mut stream = ptr::null_mut();
+            let status =
+                banyan_stream_create(store, ptr::null(), ptr::null(), ptr::null(), &mut stream);
+            assert_eq!(status, BANYAN_OK);
+            let even = CString::new("even").unwrap();
+            let odd = CString::new("odd").unwrap();
+            for i in 0..100u64 {
+                let tag = if i % 2 == 0 {
+                    even.as_ptr()
+                } else {
+                    odd.as_ptr()
+                };
+                let value = CString::new(i.to_string()).unwrap();
+                let status = banyan_stream_push(stream, i * 10, &tag, 1, value.as_ptr());
+                assert_eq!(status, BANYAN_OK);
+            }
+            let mut count = u64::max_value();
+            assert_eq!(banyan_stream_count(stream, &mut count), BANYAN_OK);
+            assert_eq!(count, 0);
+            assert_eq!(banyan_stream_commit(stream), BANYAN_OK);
+            assert_eq!(banyan_stream_count(stream, &mut count), BANYAN_OK);
+            assert_eq!(count, 100);
+            let status = banyan_stream_count(ptr::null(), &mut count);
+            assert_eq!(status, BANYAN_INVALID_ARGUMENT);
+
+            let mut root = ptr::null_mut();
+            assert_eq!(banyan_stream_root(stream, &mut root), BANYAN_OK);
+            let mut loaded = ptr::null_mut();
+            let status = banyan_stream_load(
+                store,
+                root,
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                &mut loaded,
+            );
+            assert_eq!(status, BANYAN_OK);
+            banyan_string_free(root);
+
+            let tag = even.as_ptr();
+            let mut events = ptr::null_mut();
+            let status = banyan_stream_query(loaded, &tag, 1, 10, 20, &mut events);
+            assert_eq!(status, BANYAN_OK);
+            let mut event = std::mem::zeroed::<banyan_event>();
+            let mut offsets = Vec::new();
+            while banyan_events_next(events, &mut event) == BANYAN_OK {
+                assert_eq!(event.n_tags, 1);
+                assert_eq!(CStr::from_ptr(*event.tags).to_str().unwrap(), "even");
+                let value = CStr::from_ptr(event.value).to_str().unwrap();
+                assert_eq!(value, event.offset.to_string());
+                assert_eq!(event.time, event.offset * 10);
+                offsets.push(event.offset);
+            }
+            assert_eq!(offsets, vec![10, 12, 14, 16, 18]);
+            banyan_events_free(events);
+
+            let invalid = CString::new("not a cid").unwrap();
+            let status = banyan_stream_load(
+                store,
+                invalid.as_ptr(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                &mut loaded,
+            );
+            assert_eq!(status, BANYAN_INVALID_ARGUMENT);
+            assert!(!banyan_last_error().is_null());
+
+            banyan_stream_free(loaded);
+            banyan_stream_free(stream);
+            banyan_store_free(store);
+        }
+    }
+
+    #[test]
+    fn encrypted_stream() {
+        unsafe {
+            let mut store = ptr::null_mut();
+            assert_eq!(banyan_store_open_memory(&mut store), BANYAN_OK);
+            let (index_key, value_key) = ([1u8; 32], [2u8; 32]);
+            let config = banyan_config {
+                zstd_level: 0,
+                ..banyan_config_default()
+            };
+            let mut stream = ptr::null_mut();
+            let status =
+                banyan_stream_create(store, &config, ptr::null(), ptr::null(), &mut stream);
+            assert_eq!(status, BANYAN_INVALID_ARGUMENT);
+            let status = banyan_stream_create(
+                store,
+                ptr::null(),
+                index_key.as_ptr(),
+                ptr::null(),
+                &mut stream,
+            );
+            assert_eq!(status, BANYAN_INVALID_ARGUMENT);
+            let status = banyan_stream_create(
+                store,
+                ptr::null(),
+                index_key.as_ptr(),
+                value_key.as_ptr(),
+                &mut stream,
+            );
+            assert_eq!(status, BANYAN_OK);
+            let value = CString::new("secret").unwrap();
+            let status = banyan_stream_push(stream, 0, ptr::null(), 0, value.as_ptr());
+            assert_eq!(status, BANYAN_OK);
+            assert_eq!(banyan_stream_commit(stream), BANYAN_OK);
+            let mut root = ptr::null_mut();
+            assert_eq!(banyan_stream_root(stream, &mut root), BANYAN_OK);
+
+            // loading with the wrong keys fails
+            let mut loaded = ptr::null_mut();
+            let status = banyan_stream_load(
+                store,
+                root,
+                ptr::null(),
+                value_key.as_ptr(),
+                index_key.as_ptr(),
+                &mut loaded,
+            );
+            assert_eq!(status, BANYAN_STORE_ERROR);
+            let status = banyan_stream_load(
+                store,
+                root,
+                ptr::null(),
+                index_key.as_ptr(),
+                value_key.as_ptr(),
+                &mut loaded,
+            );
+            assert_eq!(status, BANYAN_OK);
+            banyan_string_free(root);
+            banyan_stream_free(loaded);
+            banyan_stream_free(stream);
+            banyan_store_free(store);
+        }
+    }
+
+    #[test]
+    fn panics_are_caught() {
+        assert_eq!(guard(|| panic!("boom")), BANYAN_PANIC);
+        let text = unsafe { CStr::from_ptr(banyan_last_error()) };
+        assert_eq!(text.to_str().unwrap(), "panic: boom");
+        assert_eq!(guard_or(7, || panic!("boom")), 7);
+    }
+}
@@ -0,0 +1,298 @@
+//! Python bindings for banyan streams
+//!
+//! The module `banyan_py` exposes streams with the tree types of [banyan_utils::tags], with
+//! string values. Each event is a tuple of time, tags and value, and is returned from queries
+//! together with its offset:
+//!
+//! ```python
+//! import banyan_py
+//!
+//! store = banyan_py.Store.sqlite("events.sqlite")
+//! stream = store.create_stream(banyan_py.Config(zstd_level=19))
+//! stream.extend([(1000, ["a", "b"], "first"), (2000, ["b"], "second")])
+//! store.alias("events", stream.root)
+//!
+//! stream = store.load_stream(store.resolve("events"))
+//! for offset, time, tags, value in stream.query(tags=["b"], offsets=(1, 10)):
+//!     print(offset, time, tags, value)
+//! ```
+use anyhow::Result;
+use banyan::{
+    query::{AllQuery, AndQuery, OffsetRangeQuery, Query, QueryExt},
+    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::{
+    sqlite::SqliteStore,
+    tag_index::{Tag, TagSet},
+    tags::{Digest, DnfQuery, Key, Sha256Digest, TT},
+};
+use ipfs_sqlite_block_store::BlockStore;
+use libipld::DefaultParams;
+use pyo3::{exceptions::PyRuntimeError, prelude::*};
+use std::sync::Arc;
+
+type Link = Sha256Digest;
+
+/// an event as seen from python: offset, time, tags and value
+type Event = (u64, u64, Vec<String>, String);
+
+fn to_py(cause: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(format!("{:#}", cause))
+}
+
+#[derive(Clone)]
+enum Storage {
+    Memory(MemStore<Link>),
+    Sqlite(SqliteStore<DefaultParams>),
+}
+
+impl ReadOnlyStore<Link> for Storage {
+    fn get(&self, link: &Link) -> Result<Box<[u8]>> {
+        match self {
+            Self::Memory(m) => m.get(link),
+            Self::Sqlite(s) => s.get(link),
+        }
+    }
+}
+
+impl BlockWriter<Link> for Storage {
+    fn put(&mut self, data: Vec<u8>) -> Result<Link> {
+        match self {
+            Self::Memory(m) => m.put(data),
+            Self::Sqlite(s) => s.put(data),
+        }
+    }
+}
+
+/// Settings for new leaves and branches of a stream
+///
+/// All arguments are optional, the defaults produce trees with large, well compressed leaves.
+#[pyclass(name = "Config")]
+#[derive(Clone)]
+struct PyConfig(Config);
+
+#[pymethods]
+impl PyConfig {
+    #[new]
+    #[args(
+        target_leaf_size = "1 << 16",
+        max_leaf_count = "1 << 16",
+        max_key_branches = "32",
+        max_summary_branches = "32",
+        zstd_level = "10"
+    )]
+    fn new(
+        target_leaf_size: usize,
+        max_leaf_count: usize,
+        max_key_branches: usize,
+        max_summary_branches: usize,
+        zstd_level: i32,
+    ) -> PyResult<Self> {
+        let config = Config {
+            target_leaf_size,
+            max_leaf_count,
+            max_key_branches,
+            max_summary_branches,
+            zstd_level,
+            max_uncompressed_leaf_size: 16 * 1024 * 1024,
+            skip_redundant_compression: false,
+            summary_truncation_level: None,
+            adaptive_leaf_size: false,
+            ordered_keys: false,
+            pack_values: false,
+            dedup_window: 0,
+        };
+        config.validate().map_err(to_py)?;
+        Ok(Self(config))
+    }
+}
+
+impl Default for PyConfig {
+    fn default() -> Self {
+        Self::new(1 << 16, 1 << 16, 32, 32, 10).expect("default config is valid")
+    }
+}
+
+/// A block store, either in memory or in an sqlite database
+#[pyclass(name = "Store")]
+struct PyStore {
+    storage: Storage,
+    forest: Forest<TT<Link>, Storage>,
+}
+
+impl PyStore {
+    fn new(storage: Storage) -> Self {
+        let forest = Forest::new(storage.clone(), BranchCache::default());
+        Self { storage, forest }
+    }
+
+    fn sqlite_store(&self) -> Result<&SqliteStore<DefaultParams>> {
+        match &self.storage {
+            Storage::Sqlite(s) => Ok(s),
+            Storage::Memory(_) => Err(anyhow::anyhow!("only sqlite stores support aliases")),
+        }
+    }
+
+    fn stream(&self, builder: StreamBuilder<TT<Link>, String>) -> PyStream {
+        PyStream {
+            storage: self.storage.clone(),
+            forest: self.forest.clone(),
+            builder,
+        }
+    }
+}
+
+#[pymethods]
+impl PyStore {
+    /// a store that keeps all blocks in memory
+    #[staticmethod]
+    fn memory() -> Self {
+        Self::new(Storage::Memory(MemStore::new(
+            usize::max_value(),
+            <Link as Digest>::digest,
+        )))
+    }
+
+    /// a store backed by the sqlite database at `path`, which is created if it does not exist
+    #[staticmethod]
+    fn sqlite(path: &str) -> PyResult<Self> {
+        let store = BlockStore::open(path, ipfs_sqlite_block_store::Config::default())
+            .map_err(|cause| to_py(cause.into()))?;
+        let store = SqliteStore::new(store).map_err(to_py)?;
+        Ok(Self::new(Storage::Sqlite(store)))
+    }
+
+    /// a new, empty stream, using `config` or the default `Config()` for extending it
+    #[args(config = "None")]
+    fn create_stream(&self, config: Option<PyConfig>) -> PyStream {
+        let config = config.unwrap_or_default().0;
+        self.stream(StreamBuilder::new(config, Secrets::default()))
+    }
+
+    /// the stream with the given root, using `config` or the default `Config()` for extending it
+    #[args(config = "None")]
+    fn load_stream(&self, root: &str, config: Option<PyConfig>) -> PyResult<PyStream> {
+        let link = root.parse().map_err(to_py)?;
+        let config = config.unwrap_or_default().0;
+        let builder = self
+            .forest
+            .load_stream_builder(Secrets::default(), config, link)
+            .map_err(to_py)?;
+        Ok(self.stream(builder))
+    }
+
+    /// sets or removes a named root, which keeps the stream from being garbage collected
+    #[args(root = "None")]
+    fn alias(&self, name: &str, root: Option<&str>) -> PyResult<()> {
+        let link = root
+            .map(|root| root.parse::<Link>())
+            .transpose()
+            .map_err(to_py)?;
+        self.sqlite_store()
+            .and_then(|store| store.alias(name, link))
+            .map_err(to_py)
+    }
+
+    /// the root with the given name, if any
+    fn resolve(&self, name: &str) -> PyResult<Option<String>> {
+        let link = self
+            .sqlite_store()
+            .and_then(|store| store.resolve::<Link>(name))
+            .map_err(to_py)?;
+        Ok(link.map(|link| link.to_string()))
+    }
+}
+
+/// A stream of events with string values
+#[pyclass(name = "Stream")]
+struct PyStream {
+    storage: Storage,
+    forest: Forest<TT<Link>, Storage>,
+    builder: StreamBuilder<TT<Link>, String>,
+}
+
+#[pymethods]
+impl PyStream {
+    /// appends events, given as tuples of time, tags and value
+    fn extend(&mut self, events: Vec<(u64, Vec<String>, String)>) -> PyResult<()> {
+        let start = self.builder.count();
+        let events = events
+            .into_iter()
+            .enumerate()
+            .map(|(i, (time, tags, value))| {
+                let tags = tags.into_iter().map(Tag::from).collect::<TagSet>();
+                (Key::single(start + i as u64, time, tags), value)
+            })
+            .collect::<Vec<_>>();
+        let mut txn = Transaction::new(self.forest.clone(), self.storage.clone());
+        txn.extend(&mut self.builder, events).map_err(to_py)
+    }
+
+    /// number of events in the stream
+    #[getter]
+    fn count(&self) -> u64 {
+        self.builder.count()
+    }
+
+    /// the root of the stream, or None if it is empty
+    #[getter]
+    fn root(&self) -> Option<String> {
+        self.builder.link().map(|link| link.to_string())
+    }
+
+    /// the events that have all of `tags` and are within the offset range `offsets`
+    #[args(tags = "None", offsets = "None")]
+    fn query(&self, tags: Option<Vec<String>>, offsets: Option<(u64, u64)>) -> Events {
+        let mut query: Arc<dyn Query<TT<Link>>> = AllQuery.boxed();
+        if let Some(tags) = tags {
+            let tags = tags.into_iter().map(Tag::from).collect::<TagSet>();
+            query = AndQuery(query, DnfQuery(vec![Key::filter_tags(tags)])).boxed();
+        }
+        if let Some((start, end)) = offsets {
+            query = AndQuery(query, OffsetRangeQuery::from(start..end)).boxed();
+        }
+        let iter = self
+            .forest
+            .iter_filtered(&self.builder.snapshot(), query)
+            .map(|item| {
+                item.map(|(offset, key, value)| {
+                    let tags = key.tags().iter().map(|tag| tag.to_string()).collect();
+                    (offset, key.min_time(), tags, value)
+                })
+            });
+        Events(Box::new(iter))
+    }
+
+    fn __iter__(&self) -> Events {
+        self.query(None, None)
+    }
+
+    fn __len__(&self) -> usize {
+        self.builder.count() as usize
+    }
+}
+
+/// Iterator over the result of a query
+#[pyclass(unsendable)]
+struct Events(Box<dyn Iterator<Item = Result<Event>>>);
+
+#[pymethods]
+impl Events {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<Option<Event>> {
+        slf.0.next().transpose().map_err(to_py)
+    }
+}
+
+#[pymodule]
+fn banyan_py(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyConfig>()?;
+    m.add_class::<PyStore>()?;
+    m.add_class::<PyStream>()?;
+    m.add_class::<Events>()?;
+    Ok(())
+}
@@ -0,0 +1,213 @@
+//! A small event logger, as a reference for wiring banyan into an application
+//!
+//! `run` ingests lines from stdin and optionally UDP, and periodically drops old events and
+//! garbage collects the store. `query` prints events from the same database.
+//!
+//! ```text
+//! echo "#sensor/1 #alarm too hot" | cargo run --example event_logger -- --db log.sqlite run
+//! cargo run --example event_logger -- --db log.sqlite query --tag sensor/
+//! ```
+use std::{
+    io::BufRead,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use banyan::{Config, Secrets};
+use banyan_utils::{create_chacha_key, event_log::EventLog, sqlite::SqliteStore};
+use ipfs_sqlite_block_store::BlockStore;
+use libipld::DefaultParams;
+use structopt::StructOpt;
+use tokio::{net::UdpSocket, sync::mpsc};
+
+/// name of the alias that holds the root of the log
+const ROOT_ALIAS: &str = "event_logger";
+/// maximum number of lines to append in one go
+const MAX_BATCH: usize = 1000;
+
+type Store = SqliteStore<DefaultParams>;
+
+#[derive(StructOpt)]
+#[structopt(about = "Event logger backed by a banyan tree")]
+struct Opts {
+    #[structopt(long)]
+    /// Path to the sqlite database, will be created if it doesn't exist
+    db: String,
+    #[structopt(long)]
+    /// An index password to use
+    index_pass: Option<String>,
+    #[structopt(long)]
+    /// A value password to use
+    value_pass: Option<String>,
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Ingest events from stdin, one per line. Leading `#words` are tags
+    Run {
+        #[structopt(long)]
+        /// Also ingest events from UDP datagrams received on this address
+        udp: Option<String>,
+        #[structopt(long, default_value = "86400")]
+        /// Drop events older than this many seconds
+        retain_secs: u64,
+        #[structopt(long, default_value = "60")]
+        /// Interval in seconds for retention and garbage collection
+        gc_secs: u64,
+    },
+    /// Print events
+    Query {
+        #[structopt(long)]
+        /// Only print events with a tag starting with this prefix
+        tag: Option<String>,
+        #[structopt(long)]
+        /// Only print events from the last n seconds
+        since_secs: Option<u64>,
+    },
+    /// Garbage collect the database
+    Gc,
+}
+
+/// current time in microseconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time before epoch")
+        .as_micros() as u64
+}
+
+/// persists the current root, so it survives a restart and is protected from gc
+fn commit(store: &Store, log: &EventLog<Store>) -> Result<()> {
+    store.alias(ROOT_ALIAS, log.root())
+}
+
+fn spawn_stdin(tx: mpsc::Sender<String>) {
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    if tx.blocking_send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(cause) => {
+                    tracing::error!("error reading stdin: {}", cause);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+async fn udp_loop(socket: UdpSocket, tx: mpsc::Sender<String>) -> Result<()> {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, _) = socket.recv_from(&mut buf).await?;
+        for line in String::from_utf8_lossy(&buf[..n]).lines() {
+            tx.send(line.to_string()).await?;
+        }
+    }
+}
+
+async fn run(
+    store: Store,
+    mut log: EventLog<Store>,
+    udp: Option<String>,
+    retain: Duration,
+    gc_interval: Duration,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::channel::<String>(MAX_BATCH);
+    spawn_stdin(tx.clone());
+    if let Some(addr) = udp {
+        let socket = UdpSocket::bind(&addr).await?;
+        tracing::info!("listening on udp {}", addr);
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(cause) = udp_loop(socket, tx).await {
+                tracing::error!("udp ingestion failed: {}", cause);
+            }
+        });
+    }
+    // only the ingestion tasks should keep the channel open
+    drop(tx);
+    let mut ticks = tokio::time::interval(gc_interval);
+    loop {
+        tokio::select! {
+            line = rx.recv() => {
+                let line = match line {
+                    Some(line) => line,
+                    // all inputs are closed
+                    None => break,
+                };
+                let mut batch = vec![line];
+                while batch.len() < MAX_BATCH {
+                    match rx.try_recv() {
+                        Ok(line) => batch.push(line),
+                        Err(_) => break,
+                    }
+                }
+                let n = log.append(now(), batch)?;
+                commit(&store, &log)?;
+                tracing::debug!("appended {} events, total {}", n, log.len());
+            }
+            _ = ticks.tick() => {
+                let cutoff = now().saturating_sub(retain.as_micros() as u64);
+                log.retain_since(cutoff)?;
+                commit(&store, &log)?;
+                store.gc()?;
+                tracing::info!("retention and gc done, root {:?}", log.root());
+            }
+        }
+    }
+    commit(&store, &log)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let opts = Opts::from_args();
+    let store = Store::new(BlockStore::open(
+        &opts.db,
+        ipfs_sqlite_block_store::Config::default(),
+    )?)?;
+    let index_key: chacha20::Key = opts.index_pass.map(create_chacha_key).unwrap_or_default();
+    let value_key: chacha20::Key = opts.value_pass.map(create_chacha_key).unwrap_or_default();
+    let secrets = Secrets::new(index_key, value_key);
+    let root = store.resolve(ROOT_ALIAS)?;
+    let log: EventLog<Store> = EventLog::new(store.clone(), Config::debug_fast(), secrets, root)?;
+    match opts.cmd {
+        Command::Run {
+            udp,
+            retain_secs,
+            gc_secs,
+        } => {
+            run(
+                store,
+                log,
+                udp,
+                Duration::from_secs(retain_secs),
+                Duration::from_secs(gc_secs),
+            )
+            .await?
+        }
+        Command::Query { tag, since_secs } => {
+            let start = since_secs
+                .map(|secs| now().saturating_sub(secs * 1_000_000))
+                .unwrap_or_default();
+            for event in log.query(tag.as_deref(), start..u64::max_value())? {
+                let tags = event.tags.iter().map(|t| t.as_str()).collect::<Vec<_>>();
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    event.offset,
+                    event.time,
+                    tags.join(","),
+                    event.message
+                );
+            }
+        }
+        Command::Gc => store.gc()?,
+    }
+    Ok(())
+}
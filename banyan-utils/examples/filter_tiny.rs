@@ -1,16 +1,10 @@
 //! Example for filtering a small number of events out of a rather large banyan tree
 //! Finding the needle in the haystack. Mostly for using cargo flamegraph.
-use std::{
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
-    },
-    time::{Duration, Instant},
-};
+use std::time::{Duration, Instant};
 
 use banyan::{
     query::{OffsetRangeQuery, Query},
-    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    store::{BranchCache, InstrumentedStore, MemStore},
     Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
 };
 use banyan_utils::{
@@ -18,45 +12,10 @@ use banyan_utils::{
     tags::{Key, Sha256Digest, TT},
 };
 
-#[derive(Clone)]
-struct OpsCountingStore<S> {
-    inner: S,
-    reads: Arc<AtomicU64>,
-    writes: Arc<AtomicU64>,
-}
-
-impl<S> OpsCountingStore<S> {
-    fn new(inner: S) -> Self {
-        Self {
-            inner,
-            reads: Arc::new(AtomicU64::default()),
-            writes: Arc::new(AtomicU64::default()),
-        }
-    }
-
-    fn reads(&self) -> u64 {
-        self.reads.load(Ordering::SeqCst)
-    }
-}
-
-impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for OpsCountingStore<S> {
-    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
-        self.reads.fetch_add(1, Ordering::SeqCst);
-        self.inner.get(link)
-    }
-}
-
-impl<L, S: BlockWriter<L> + Send + Sync> BlockWriter<L> for OpsCountingStore<S> {
-    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
-        self.writes.fetch_add(1, Ordering::SeqCst);
-        self.inner.put(data)
-    }
-}
-
 #[allow(clippy::type_complexity)]
 fn test_ops_count(
     name: &str,
-    forest: &Forest<TT, OpsCountingStore<MemStore<Sha256Digest>>>,
+    forest: &Forest<TT, InstrumentedStore<MemStore<Sha256Digest>>>,
     tree: &Tree<TT, u64>,
     query: impl Query<TT> + Clone + 'static,
 ) -> (Vec<anyhow::Result<(u64, Key, u64)>>, Duration, u64) {
@@ -76,7 +35,7 @@ fn main() -> anyhow::Result<()> {
         .map(|i| (Key::single(i, i, TagSet::empty()), i))
         .collect::<Vec<_>>();
     let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
-    let store = OpsCountingStore::new(store);
+    let store = InstrumentedStore::new(store);
     let branch_cache = BranchCache::<TT>::new(capacity);
     let mut txn = Transaction::new(Forest::new(store.clone(), branch_cache), store);
     let config = Config {
@@ -86,6 +45,12 @@ fn main() -> anyhow::Result<()> {
         max_summary_branches: 8,
         zstd_level: 10,
         max_uncompressed_leaf_size: 16 * 1024 * 1024,
+        skip_redundant_compression: false,
+        summary_truncation_level: None,
+        adaptive_leaf_size: false,
+        ordered_keys: false,
+        pack_values: false,
+        dedup_window: 0,
     };
     let mut builder = StreamBuilder::new(config, Secrets::default());
     txn.extend(&mut builder, xs)?;
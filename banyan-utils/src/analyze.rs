@@ -0,0 +1,150 @@
+//! statistics about the tags in a tree, computed from the keys of the leaves in their parents
+//!
+//! This never loads any leaves. The unit of the statistics is a leaf summary, i.e. the union of
+//! the tags of all elements of a leaf, since that is the granularity at which a tag query can
+//! prune the tree.
+use std::{collections::BTreeMap, io};
+
+use anyhow::Result;
+use banyan::{index::Index, query::AllQuery, store::ReadOnlyStore, Forest, Tree};
+
+use crate::{
+    tag_index::{Tag, TagSet},
+    tags::{Digest, TT},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TagStats {
+    /// number of leaf summaries that were looked at
+    pub leaves: u64,
+    /// for each tag, the number of leaves containing the tag
+    pub tags: BTreeMap<Tag, u64>,
+    /// for each pair of tags, the number of leaves containing both
+    pub pairs: BTreeMap<(Tag, Tag), u64>,
+}
+
+impl TagStats {
+    /// add a single leaf summary
+    pub fn add(&mut self, tags: &TagSet) {
+        self.leaves += 1;
+        for (i, a) in tags.iter().enumerate() {
+            *self.tags.entry(a.clone()).or_default() += 1;
+            for b in tags.iter().skip(i + 1) {
+                *self.pairs.entry((a.clone(), b.clone())).or_default() += 1;
+            }
+        }
+    }
+
+    /// fraction of the leaves that a query for this tag has to look at
+    pub fn selectivity(&self, tag: &Tag) -> f64 {
+        let count = self.tags.get(tag).copied().unwrap_or_default();
+        if self.leaves == 0 {
+            0.0
+        } else {
+            count as f64 / self.leaves as f64
+        }
+    }
+
+    /// tags that are present in more than `threshold` of all leaves, so a query for them can
+    /// hardly prune anything
+    pub fn useless(&self, threshold: f64) -> Vec<Tag> {
+        self.tags
+            .keys()
+            .filter(|tag| self.selectivity(tag) > threshold)
+            .cloned()
+            .collect()
+    }
+
+    /// writes a human readable report, listing the `top` most frequent pairs
+    pub fn report(&self, out: &mut impl io::Write, top: usize) -> io::Result<()> {
+        writeln!(out, "leaves: {}", self.leaves)?;
+        writeln!(out, "distinct tags: {}", self.tags.len())?;
+        writeln!(out)?;
+        writeln!(out, "tag\tleaves\tselectivity")?;
+        let mut tags = self.tags.iter().collect::<Vec<_>>();
+        tags.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (tag, count) in tags {
+            writeln!(out, "{}\t{}\t{:.3}", tag, count, self.selectivity(tag))?;
+        }
+        writeln!(out)?;
+        writeln!(out, "co-occurring tags\tleaves")?;
+        let mut pairs = self.pairs.iter().collect::<Vec<_>>();
+        pairs.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for ((a, b), count) in pairs.into_iter().take(top) {
+            writeln!(out, "{} {}\t{}", a, b, count)?;
+        }
+        writeln!(out)?;
+        writeln!(out, "tags useless for pruning (in more than 90% of leaves)")?;
+        for tag in self.useless(0.9) {
+            writeln!(out, "{}", tag)?;
+        }
+        Ok(())
+    }
+}
+
+/// collects tag statistics from the summaries of all leaves
+///
+/// The summaries are computed from the leaf indices, so this also covers leaves below higher
+/// branches, as in trees built with `extend_unpacked`, and leaves whose parents have truncated
+/// summaries.
+pub fn analyze_tags<D, S, V>(forest: &Forest<TT<D>, S>, tree: &Tree<TT<D>, V>) -> Result<TagStats>
+where
+    D: Digest,
+    S: ReadOnlyStore<D> + Clone + Send + Sync + 'static,
+{
+    let mut stats = TagStats::default();
+    for index in forest.iter_index(tree, AllQuery) {
+        let index = index?;
+        if let Index::Leaf(_) = &index {
+            stats.add(index.summarize().tags());
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::{Key, Sha256Digest};
+    use banyan::{
+        store::{BranchCache, MemStore},
+        Config, Secrets, StreamBuilder, Transaction,
+    };
+
+    fn tags(names: &[&str]) -> TagSet {
+        names.iter().map(|name| Tag::from(*name)).collect()
+    }
+
+    #[test]
+    fn tag_stats() {
+        let mut stats = TagStats::default();
+        stats.add(&tags(&["a", "b"]));
+        stats.add(&tags(&["a", "c"]));
+        stats.add(&tags(&["a", "b", "c"]));
+        stats.add(&tags(&["a"]));
+        assert_eq!(stats.leaves, 4);
+        assert_eq!(stats.tags[&Tag::from("a")], 4);
+        assert_eq!(stats.tags[&Tag::from("b")], 2);
+        assert_eq!(stats.pairs[&(Tag::from("a"), Tag::from("b"))], 2);
+        assert_eq!(stats.pairs[&(Tag::from("b"), Tag::from("c"))], 1);
+        assert_eq!(stats.selectivity(&Tag::from("c")), 0.5);
+        assert_eq!(stats.useless(0.9), vec![Tag::from("a")]);
+    }
+
+    #[test]
+    fn unpacked_leaves() -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+        let mut builder = StreamBuilder::<TT, u64>::new(Config::debug(), Secrets::default());
+        for (i, names) in [&["a"][..], &["a", "b"], &["c"]].iter().enumerate() {
+            let i = i as u64;
+            txn.extend_unpacked(&mut builder, vec![(Key::single(i, i, tags(names)), i)])?;
+        }
+        // the last leaf is not below a level 1 branch
+        let stats = analyze_tags(&txn, &builder.snapshot())?;
+        assert_eq!(stats.leaves, 3);
+        assert_eq!(stats.tags[&Tag::from("a")], 2);
+        assert_eq!(stats.tags[&Tag::from("c")], 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+//! synthetic datasets and timing routines for benchmarks
+//!
+//! A [Dataset] describes a stream of events with string values, and generates it
+//! deterministically, so results for different stores, configs or versions can be compared.
+//! [run] measures extending, packing and querying such a stream on any store, and [report]
+//! prints the results of several runs side by side.
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use banyan::{
+    query::Query,
+    store::{BlockWriter, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    tag_index::{Tag, TagSet},
+    tags::{Digest, DnfQuery, Key, TT},
+};
+
+/// A synthetic stream of events, each with a single tag
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dataset {
+    /// number of events per batch
+    pub batch_size: u64,
+    /// number of batches
+    pub batches: u64,
+    /// size of each value in bytes
+    ///
+    /// Values are the offset padded with zeros, or its last digits if it has more.
+    pub value_size: usize,
+    /// number of distinct tags, at least 1
+    pub tags: u64,
+    /// skew of the tag distribution
+    ///
+    /// With 0, tags are assigned round robin. Larger values make low tags more common than high
+    /// ones, e.g. with a skew of 1 the first tenth of the tags is used for about a third of the
+    /// events.
+    pub skew: f64,
+    /// seed for assigning tags if the distribution is skewed
+    pub seed: u64,
+}
+
+impl Dataset {
+    pub fn new(batch_size: u64, batches: u64) -> Self {
+        Self {
+            batch_size,
+            batches,
+            value_size: 16,
+            tags: 15,
+            skew: 0.0,
+            seed: 0,
+        }
+    }
+
+    pub fn with_value_size(mut self, value_size: usize) -> Self {
+        self.value_size = value_size;
+        self
+    }
+
+    pub fn with_tags(mut self, tags: u64) -> Self {
+        self.tags = tags.max(1);
+        self
+    }
+
+    pub fn with_skew(mut self, skew: f64) -> Self {
+        self.skew = skew.max(0.0);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// the tag with the given rank, where rank 0 is the most common one
+    pub fn tag(&self, rank: u64) -> Tag {
+        Tag::from(format!("tag{}", rank).as_str())
+    }
+
+    /// total number of events
+    pub fn total(&self) -> u64 {
+        self.batch_size * self.batches
+    }
+
+    /// the events, batch by batch
+    pub fn batches(&self) -> impl Iterator<Item = Vec<(Key, String)>> + '_ {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        // the fields are public, so the constructors can not guarantee this
+        let tags = self.tags.max(1);
+        (0..self.batches).map(move |b| {
+            (b * self.batch_size..(b + 1) * self.batch_size)
+                .map(|offset| {
+                    let rank = if self.skew > 0.0 {
+                        let x = rng.gen::<f64>().powf(1.0 + self.skew);
+                        ((x * tags as f64) as u64).min(tags - 1)
+                    } else {
+                        offset % tags
+                    };
+                    let key = Key::single(offset, offset, TagSet::single(self.tag(rank)));
+                    let value = format!("{:0width$}", offset, width = self.value_size);
+                    let value = value[value.len() - self.value_size..].to_owned();
+                    (key, value)
+                })
+                .collect()
+        })
+    }
+}
+
+/// Average time of `runs` calls of `f`, to smooth out caching effects
+pub fn measure(runs: u32, mut f: impl FnMut() -> Result<()>) -> Result<Duration> {
+    let t0 = Instant::now();
+    for _ in 0..runs {
+        f()?;
+    }
+    Ok(t0.elapsed() / runs.max(1))
+}
+
+/// Time to add all events of the dataset to the builder
+///
+/// The batches are generated before starting the clock.
+pub fn measure_extend<L, R, W>(
+    txn: &mut Transaction<TT<L>, R, W>,
+    builder: &mut StreamBuilder<TT<L>, String>,
+    dataset: &Dataset,
+    unpacked: bool,
+) -> Result<Duration>
+where
+    L: Digest,
+    R: ReadOnlyStore<L>,
+    W: BlockWriter<L>,
+{
+    let data = dataset.batches().collect::<Vec<_>>();
+    let t0 = Instant::now();
+    for batch in data {
+        if unpacked {
+            txn.extend_unpacked(builder, batch)?;
+        } else {
+            txn.extend(builder, batch)?;
+        }
+    }
+    Ok(t0.elapsed())
+}
+
+/// Time to pack the builder
+pub fn measure_pack<L, R, W>(
+    txn: &mut Transaction<TT<L>, R, W>,
+    builder: &mut StreamBuilder<TT<L>, String>,
+) -> Result<Duration>
+where
+    L: Digest,
+    R: ReadOnlyStore<L>,
+    W: BlockWriter<L>,
+{
+    let t0 = Instant::now();
+    txn.pack(builder)?;
+    Ok(t0.elapsed())
+}
+
+/// Average time to iterate over all elements matching the query
+pub fn measure_query<L, R, Q>(
+    forest: &Forest<TT<L>, R>,
+    tree: &Tree<TT<L>, String>,
+    query: Q,
+    runs: u32,
+) -> Result<Duration>
+where
+    L: Digest,
+    R: ReadOnlyStore<L>,
+    Q: Query<TT<L>> + Clone + 'static,
+{
+    measure(runs, || {
+        for item in forest.iter_filtered(tree, query.clone()) {
+            item?;
+        }
+        Ok(())
+    })
+}
+
+/// Timings of one benchmark run, see [run]
+#[derive(Debug, Clone, Default)]
+pub struct BenchResult {
+    /// time to add all events
+    pub extend: Duration,
+    /// time to pack the tree afterwards
+    pub pack: Duration,
+    /// number of nodes of the packed tree
+    pub nodes: u64,
+    /// average time to collect all events
+    pub collect: Duration,
+    /// average time to query the most common tag
+    pub filter_tag: Duration,
+    /// average time to query the most common tag in the first tenth of the stream
+    pub filter_range: Duration,
+}
+
+/// Builds a tree from the dataset, packs it and queries it `runs` times
+///
+/// With `unpacked`, the batches are added with [Transaction::extend_unpacked].
+pub fn run<L, R, W>(
+    txn: &mut Transaction<TT<L>, R, W>,
+    dataset: &Dataset,
+    runs: u32,
+    unpacked: bool,
+    secrets: Secrets,
+    config: Config,
+) -> Result<BenchResult>
+where
+    L: Digest,
+    R: ReadOnlyStore<L>,
+    W: BlockWriter<L>,
+{
+    let mut res = BenchResult::default();
+    let mut builder = StreamBuilder::new(config, secrets);
+    res.extend = measure_extend(txn, &mut builder, dataset, unpacked)?;
+    res.pack = measure_pack(txn, &mut builder)?;
+    let tree = builder.snapshot();
+    res.nodes = txn.tree_stats(&tree)?.nodes();
+    res.collect = measure(runs, || {
+        txn.collect(&tree)?;
+        Ok(())
+    })?;
+    let tag = TagSet::single(dataset.tag(0));
+    let query = DnfQuery(vec![Key::filter_tags(tag.clone())]);
+    res.filter_tag = measure_query(txn, &tree, query, runs)?;
+    let query = DnfQuery(vec![Key::range(0, dataset.total() / 10, tag)]);
+    res.filter_range = measure_query(txn, &tree, query, runs)?;
+    Ok(res)
+}
+
+/// Writes a table with one column per named result
+pub fn report(
+    dataset: &Dataset,
+    results: &[(&str, BenchResult)],
+    out: &mut impl Write,
+) -> Result<()> {
+    let ms = |d: Duration| format!("{:.3}", d.as_secs_f64() * 1000.0);
+    let per_sec = |d: Duration| format!("{:.0}", dataset.total() as f64 / d.as_secs_f64());
+    let rows: [(&str, &dyn Fn(&BenchResult) -> String); 7] = [
+        ("extend [ms]", &|r: &BenchResult| ms(r.extend)),
+        ("extend [events/s]", &|r: &BenchResult| per_sec(r.extend)),
+        ("pack [ms]", &|r: &BenchResult| ms(r.pack)),
+        ("nodes", &|r: &BenchResult| r.nodes.to_string()),
+        ("collect [ms]", &|r: &BenchResult| ms(r.collect)),
+        ("filter tag [ms]", &|r: &BenchResult| ms(r.filter_tag)),
+        ("filter range [ms]", &|r: &BenchResult| ms(r.filter_range)),
+    ];
+    write!(out, "{:<20}", "")?;
+    for (name, _) in results {
+        write!(out, "{:>14}", name)?;
+    }
+    writeln!(out)?;
+    for (label, f) in rows.iter() {
+        write!(out, "{:<20}", label)?;
+        for (_, result) in results {
+            write!(out, "{:>14}", f(result))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::Sha256Digest;
+    use banyan::store::{BranchCache, MemStore};
+
+    fn tag_counts(dataset: &Dataset) -> Vec<usize> {
+        let mut counts = vec![0; dataset.tags as usize];
+        for (key, _) in dataset.batches().flatten() {
+            for (rank, count) in counts.iter_mut().enumerate() {
+                if key.tags().contains(&dataset.tag(rank as u64)) {
+                    *count += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn datasets() {
+        let uniform = Dataset::new(100, 10).with_tags(10);
+        assert_eq!(tag_counts(&uniform), vec![100; 10]);
+        let (key, value) = &uniform.batches().nth(3).unwrap()[5];
+        assert_eq!(key.min_time(), 305);
+        assert_eq!(value.len(), 16);
+
+        // values are truncated to the value size, and tags are never 0
+        let tiny = Dataset {
+            tags: 0,
+            ..Dataset::new(100, 2).with_value_size(1)
+        };
+        let (key, value) = &tiny.batches().nth(1).unwrap()[23];
+        assert_eq!(value, "3");
+        assert!(key.tags().contains(&tiny.tag(0)));
+
+        let skewed = uniform.with_skew(1.0).with_seed(42);
+        let counts = tag_counts(&skewed);
+        assert_eq!(counts.iter().sum::<usize>(), 1000);
+        assert!(counts[0] > 250);
+        assert!(counts[9] < 100);
+        // generation is deterministic
+        assert_eq!(
+            skewed.batches().collect::<Vec<_>>(),
+            skewed.batches().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn bench_run() -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+        let dataset = Dataset::new(100, 5).with_value_size(4);
+        let result = run(
+            &mut txn,
+            &dataset,
+            2,
+            true,
+            Secrets::default(),
+            Config::debug(),
+        )?;
+        assert!(result.nodes > 1);
+        let mut out = Vec::new();
+        report(&dataset, &[("unpacked", result)], &mut out)?;
+        assert_eq!(String::from_utf8(out)?.lines().count(), 8);
+        Ok(())
+    }
+}
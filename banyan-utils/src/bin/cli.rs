@@ -3,7 +3,7 @@ use futures::future::poll_fn;
 use futures::prelude::*;
 use ipfs_sqlite_block_store::BlockStore;
 
-use std::{collections::BTreeMap, convert::TryFrom, str::FromStr, time::Duration};
+use std::{collections::BTreeMap, convert::TryFrom, io::Write, str::FromStr, time::Duration};
 use structopt::StructOpt;
 use tracing::Level;
 
@@ -13,11 +13,12 @@ use banyan::{
     Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
 };
 use banyan_utils::{
-    create_chacha_key, dump,
+    analyze, bench, create_chacha_key, dump, filter,
     ipfs::{pubsub_pub, pubsub_sub, IpfsStore},
+    migrate,
     sqlite::SqliteStore,
     tag_index::{Tag, TagSet},
-    tags::{DnfQuery, Key, Sha256Digest, TT},
+    tags::{Digest, DnfQuery, Key, Sha256Digest, TT},
 };
 use libipld::DefaultParams;
 
@@ -28,16 +29,18 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 pub type Error = anyhow::Error;
 pub type Result<T> = anyhow::Result<T>;
 
-type Txn = Transaction<TT, Storage, Storage>;
+/// The link type of all trees, replace this to use a different [Digest]
+type Link = Sha256Digest;
+type Txn = Transaction<TT<Link>, Storage, Storage>;
 
 #[derive(Clone)]
 enum Storage {
-    Memory(MemStore<Sha256Digest>),
+    Memory(MemStore<Link>),
     Ipfs(IpfsStore),
     Sqlite(SqliteStore<DefaultParams>),
 }
-impl ReadOnlyStore<Sha256Digest> for Storage {
-    fn get(&self, link: &Sha256Digest) -> Result<Box<[u8]>> {
+impl ReadOnlyStore<Link> for Storage {
+    fn get(&self, link: &Link) -> Result<Box<[u8]>> {
         match self {
             Self::Memory(m) => m.get(link),
             Storage::Ipfs(i) => i.get(link),
@@ -46,20 +49,36 @@ impl ReadOnlyStore<Sha256Digest> for Storage {
     }
 }
 
-impl BlockWriter<Sha256Digest> for Storage {
-    fn put(&mut self, data: Vec<u8>) -> Result<Sha256Digest> {
+impl BlockWriter<Link> for Storage {
+    fn put(&mut self, data: Vec<u8>) -> Result<Link> {
         match self {
             Self::Memory(m) => m.put(data),
             Storage::Ipfs(i) => i.put(data),
             Storage::Sqlite(s) => s.put(data),
         }
     }
+
+    fn put_many(&mut self, blocks: Vec<Vec<u8>>) -> Result<Vec<Link>> {
+        match self {
+            Self::Memory(m) => m.put_many(blocks),
+            Storage::Ipfs(i) => i.put_many(blocks),
+            Storage::Sqlite(s) => s.put_many(blocks),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Self::Memory(m) => m.flush(),
+            Storage::Ipfs(i) => BlockWriter::<Link>::flush(i),
+            Storage::Sqlite(s) => BlockWriter::<Link>::flush(s),
+        }
+    }
 }
 impl FromStr for Storage {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self> {
         let s = match s {
-            "memory" => Self::Memory(MemStore::new(usize::max_value(), Sha256Digest::new)),
+            "memory" => Self::Memory(MemStore::new(usize::max_value(), <Link as Digest>::digest)),
             "ipfs" => Self::Ipfs(IpfsStore::new()?),
             x => Self::Sqlite(SqliteStore::new(BlockStore::open(
                 x,
@@ -103,11 +122,35 @@ fn set_log_level(verbosity: u64) -> u64 {
 
 #[derive(StructOpt)]
 enum Command {
-    /// Benchmark
+    /// Report tag cardinalities and co-occurrence, using only the branch summaries
+    AnalyzeTags {
+        #[structopt(long)]
+        /// The root hash to use
+        root: Link,
+        #[structopt(long, default_value = "20")]
+        /// The number of co-occurring tag pairs to list
+        top: usize,
+    },
+    /// Benchmark extending, packing and querying a synthetic stream on the chosen storage
     Bench {
         #[structopt(long)]
         /// The number of values per batch
         count: u64,
+        #[structopt(long, default_value = "1")]
+        /// The number of batches
+        batches: u64,
+        #[structopt(long, default_value = "16")]
+        /// The size of each value in bytes
+        value_size: usize,
+        #[structopt(long, default_value = "15")]
+        /// The number of distinct tags. Every event has one of them.
+        tags: u64,
+        #[structopt(long, default_value = "0")]
+        /// The skew of the tag distribution, 0 for round robin
+        skew: f64,
+        #[structopt(long, default_value = "3")]
+        /// How often each query is run
+        runs: u32,
     },
     /// Build a tree
     Build {
@@ -122,7 +165,7 @@ enum Command {
         unbalanced: bool,
         #[structopt(long)]
         /// Base on which to build
-        base: Option<Sha256Digest>,
+        base: Option<Link>,
     },
     /// Traverse a tree and dump its output as dot. Can be piped directly:
     /// `banyan-cli graph --root <..> | dot -Tpng output.png`.
@@ -131,19 +174,19 @@ enum Command {
     Graph {
         #[structopt(long)]
         /// The root hash to use
-        root: Sha256Digest,
+        root: Link,
     },
     /// Dump a tree
     Dump {
         #[structopt(long)]
         /// The root hash to use
-        root: Sha256Digest,
+        root: Link,
     },
     /// Dump a block as json to stdout
     DumpBlock {
         #[structopt(long)]
         /// The root hash to use
-        hash: Sha256Digest,
+        hash: Link,
         #[structopt(long)]
         cbor: bool,
     },
@@ -151,13 +194,13 @@ enum Command {
     DumpValues {
         #[structopt(long)]
         /// The root hash to use
-        root: Sha256Digest,
+        root: Link,
     },
     /// Stream a tree, filtered
     Filter {
         #[structopt(long)]
         /// The root hash to use
-        root: Sha256Digest,
+        root: Link,
         #[structopt(long)]
         /// Tags to filter
         tag: Vec<String>,
@@ -166,16 +209,33 @@ enum Command {
     Forget {
         #[structopt(long)]
         /// The root hash to use
-        root: Sha256Digest,
+        root: Link,
         #[structopt(long)]
         /// The offset before which to forget data
         before: u64,
     },
+    /// Print the structure, statistics and per-level sizes of a tree
+    Inspect {
+        /// The root hash to use
+        root: Link,
+        #[structopt(long)]
+        /// Also print every node of the tree
+        nodes: bool,
+    },
+    /// Copy trees from the storage to another storage, verifying all blocks
+    MigrateStore {
+        #[structopt(long)]
+        /// The storage to copy to, same options as for --storage
+        to: Storage,
+        #[structopt(long, required = true)]
+        /// The root hashes of the trees to copy
+        root: Vec<Link>,
+    },
     /// Pack a tree
     Pack {
         #[structopt(long)]
         /// The root hash to use
-        root: Sha256Digest,
+        root: Link,
     },
     /// Receive a stream
     RecvStream {
@@ -183,11 +243,20 @@ enum Command {
         /// Topic to receive data over
         topic: String,
     },
+    /// Stream the events of a tree matching a filter as json to stdout, newline separated
+    Query {
+        /// The root hash to use
+        root: Link,
+        #[structopt(long, default_value = "all")]
+        /// The filter, e.g. `tag:fizz time:0..1000 or offset:10..20`. See the
+        /// filter module of banyan-utils for the syntax.
+        filter: String,
+    },
     /// Repair a tree
     Repair {
         #[structopt(long)]
         /// The root hash to use
-        root: Sha256Digest,
+        root: Link,
     },
     /// Send a stream
     SendStream {
@@ -199,7 +268,7 @@ enum Command {
     Stream {
         #[structopt(long)]
         /// The root hash to use
-        root: Sha256Digest,
+        root: Link,
     },
 }
 
@@ -221,12 +290,12 @@ impl Tagger {
 
 async fn build_tree(
     forest: &mut Txn,
-    base: Option<Sha256Digest>,
+    base: Option<Link>,
     batches: u64,
     count: u64,
     unbalanced: bool,
     print_every: u64,
-) -> anyhow::Result<StreamBuilder<TT, String>> {
+) -> anyhow::Result<StreamBuilder<TT<Link>, String>> {
     let secrets = Secrets::default();
     let config = Config::debug();
     let mut tagger = Tagger::new();
@@ -246,7 +315,7 @@ async fn build_tree(
     };
     let mut tree = match base {
         Some(root) => forest.load_stream_builder(secrets, config, root)?,
-        None => StreamBuilder::<TT, String>::new(config, secrets),
+        None => StreamBuilder::<TT<Link>, String>::new(config, secrets),
     };
     let mut offset: u64 = 0;
     for _ in 0..batches {
@@ -273,60 +342,46 @@ async fn build_tree(
     Ok(tree)
 }
 
-async fn bench_build(
-    forest: &mut Txn,
-    base: Option<Sha256Digest>,
-    batches: u64,
-    count: u64,
-    unbalanced: bool,
-    secrets: Secrets,
-    config: Config,
-) -> anyhow::Result<(Tree<TT, String>, std::time::Duration)> {
-    let mut tagger = Tagger::new();
-    // function to add some arbitrary tags to test out tag querying and compression
-    let mut tags_from_offset = |i: u64| -> TagSet {
-        let fizz = i % 3 == 0;
-        let buzz = i % 5 == 0;
-        if fizz && buzz {
-            tagger.tags(&["fizzbuzz"])
-        } else if fizz {
-            tagger.tags(&["fizz"])
-        } else if buzz {
-            tagger.tags(&["buzz"])
-        } else {
-            tagger.tags(&["we.like.long.identifiers.because.they.seem.professional"])
-        }
-    };
-    let mut tree = match base {
-        Some(link) => forest.load_stream_builder(secrets, config, link)?,
-        None => StreamBuilder::<TT, String>::new(config, secrets),
-    };
-    let mut offset: u64 = 0;
-    let data = (0..batches)
-        .map(|_b| {
-            (0..count)
-                .map(|_| {
-                    let result = (
-                        Key::single(offset, offset, tags_from_offset(offset)),
-                        offset.to_string(),
-                    );
-                    offset += 1;
-                    result
-                })
-                .collect::<Vec<_>>()
-        })
-        .collect::<Vec<_>>();
-    let t0 = std::time::Instant::now();
-    for v in data {
-        if unbalanced {
-            forest.extend_unpacked(&mut tree, v)?;
-        } else {
-            forest.extend(&mut tree, v)?;
-        }
-        forest.assert_invariants(&tree)?;
+fn inspect(
+    forest: &Txn,
+    tree: &Tree<TT<Link>, String>,
+    config: &Config,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let stats = forest.tree_stats(tree)?;
+    writeln!(out, "count       {}", tree.count())?;
+    if let Some(index) = tree.index() {
+        writeln!(out, "level       {}", index.level())?;
+        writeln!(out, "key bytes   {}", index.key_bytes())?;
+        writeln!(out, "value bytes {}", index.value_bytes())?;
     }
-    let t1 = std::time::Instant::now();
-    Ok((tree.snapshot(), t1 - t0))
+    writeln!(out, "depth       {}", stats.depth())?;
+    writeln!(out, "nodes       {}", stats.nodes())?;
+    writeln!(out, "fanout      {:.2}", stats.branch_fanout())?;
+    writeln!(out, "sealed      {:.2}", stats.sealed_ratio())?;
+    writeln!(out, "leaf fill   {:.2}", stats.leaf_fill(config))?;
+    writeln!(out, "leaf bytes  {:.2}", stats.leaf_bytes_fill(config))?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "{:>5} {:>10} {:>10} {:>10} {:>10} {:>14}",
+        "level", "nodes", "sealed", "purged", "children", "value bytes"
+    )?;
+    for (level, x) in stats.levels.iter().enumerate().rev() {
+        writeln!(
+            out,
+            "{:>5} {:>10} {:>10} {:>10} {:>10} {:>14}",
+            level, x.nodes, x.sealed, x.purged, x.children, x.value_bytes
+        )?;
+    }
+    let io = forest.stats();
+    writeln!(out)?;
+    writeln!(
+        out,
+        "read {} blocks, {} bytes",
+        io.block_reads, io.bytes_read
+    )?;
+    Ok(())
 }
 
 #[tokio::main]
@@ -361,6 +416,11 @@ async fn main() -> Result<()> {
     };
     let mut forest = txn();
     match opts.cmd {
+        Command::AnalyzeTags { root, top } => {
+            let tree = forest.load_tree::<String>(secrets, root)?;
+            let stats = analyze::analyze_tags(forest.read(), &tree)?;
+            stats.report(&mut std::io::stdout(), top)?;
+        }
         Command::Graph { root } => {
             let tree = forest.load_tree::<String>(secrets, root)?;
             let mut stdout = std::io::stdout();
@@ -379,7 +439,7 @@ async fn main() -> Result<()> {
             }
         }
         Command::DumpBlock { hash, cbor } => {
-            let nonce = <&chacha20::XNonce>::try_from(TT::NONCE).unwrap();
+            let nonce = <&chacha20::XNonce>::try_from(TT::<Link>::NONCE).unwrap();
             if cbor {
                 dump::dump_cbor(store, hash, &value_key, nonce, &mut std::io::stdout())?;
             } else {
@@ -408,61 +468,37 @@ async fn main() -> Result<()> {
             let tree = build_tree(&mut forest, base, batches, count, unbalanced, 1000).await?;
             forest.dump(&tree.snapshot())?;
         }
-        Command::Bench { count } => {
-            let config = Config::debug_fast();
-            let secrets = Secrets::new(index_key, value_key);
-            let branch_cache = BranchCache::default();
-            let mut forest = Txn::new(Forest::new(store.clone(), branch_cache), store);
-            let _t0 = std::time::Instant::now();
-            let base = None;
-            let batches = 1;
-            let unbalanced = false;
-            let (tree, tcreate) = bench_build(
-                &mut forest,
-                base,
-                batches,
-                count,
-                unbalanced,
-                secrets,
-                config,
-            )
-            .await?;
-            let t0 = std::time::Instant::now();
-            let _values: Vec<_> = forest.collect(&tree)?;
-            let t1 = std::time::Instant::now();
-            let tcollect = t1 - t0;
-            let t0 = std::time::Instant::now();
-            let tags = vec![Key::range(
-                0,
-                u64::max_value(),
-                TagSet::single(Tag::from("fizz")),
-            )];
-            let query = DnfQuery(tags).boxed();
-            let values = forest.iter_filtered(&tree, query).count();
-            println!("{}", values);
-            let t1 = std::time::Instant::now();
-            let tfilter_common = t1 - t0;
-            let t0 = std::time::Instant::now();
-            let tags = vec![Key::range(
-                0,
-                count / 10,
-                TagSet::single(Tag::from("fizzbuzz")),
-            )];
-            let query = DnfQuery(tags).boxed();
-            let values = forest.iter_filtered(&tree, query).count();
-            println!("{}", values);
-            let t1 = std::time::Instant::now();
-            let tfilter_rare = t1 - t0;
-            println!("create {}", (tcreate.as_micros() as f64) / 1000000.0);
-            println!("collect {}", (tcollect.as_micros() as f64) / 1000000.0);
-            println!(
-                "filter_common {}",
-                (tfilter_common.as_micros() as f64) / 1000000.0
-            );
+        Command::Bench {
+            count,
+            batches,
+            value_size,
+            tags,
+            skew,
+            runs,
+        } => {
+            let dataset = bench::Dataset::new(count, batches)
+                .with_value_size(value_size)
+                .with_tags(tags)
+                .with_skew(skew);
             println!(
-                "filter_rare {}",
-                (tfilter_rare.as_micros() as f64) / 1000000.0
+                "{} batches of {} events, {} byte values, {} tags with skew {}, {} runs per query",
+                batches, count, value_size, dataset.tags, dataset.skew, runs
             );
+            let mut results = Vec::new();
+            for &(name, unpacked) in &[("packed", false), ("unpacked", true)] {
+                // fresh caches for every run, so they do not benefit from each other
+                let mut forest = txn();
+                let result = bench::run(
+                    &mut forest,
+                    &dataset,
+                    runs,
+                    unpacked,
+                    secrets.clone(),
+                    config.clone(),
+                )?;
+                results.push((name, result));
+            }
+            bench::report(&dataset, &results, &mut std::io::stdout())?;
         }
         Command::Filter { tag, root } => {
             let tags = tag
@@ -489,6 +525,21 @@ async fn main() -> Result<()> {
             forest.dump(&tree.snapshot())?;
             println!("{:?}", tree);
         }
+        Command::Inspect { root, nodes } => {
+            let tree = forest.load_tree::<String>(secrets, root)?;
+            inspect(&forest, &tree, &config, &mut std::io::stdout())?;
+            if nodes {
+                println!();
+                forest.dump(&tree)?;
+            }
+        }
+        Command::MigrateStore { mut to, root } => {
+            let report = migrate::migrate(forest.read(), &mut to, secrets, &root)?;
+            println!(
+                "copied {} trees, {} blocks, {} bytes, {} purged nodes skipped",
+                report.roots, report.blocks, report.bytes, report.purged
+            );
+        }
         Command::Pack { root } => {
             let secrets = Secrets::default();
             let config = Config::debug();
@@ -505,7 +556,7 @@ async fn main() -> Result<()> {
             let links = pubsub_sub(&topic)?
                 .map_err(anyhow::Error::new)
                 .and_then(|data| future::ready(String::from_utf8(data).map_err(anyhow::Error::new)))
-                .and_then(|data| future::ready(Sha256Digest::from_str(&data)));
+                .and_then(|data| future::ready(Link::from_str(&data)));
             let forest2 = forest.clone();
             let trees = links.filter_map(move |link| {
                 let forest = forest2.clone();
@@ -520,6 +571,16 @@ async fn main() -> Result<()> {
                 println!("{:?}", ev);
             }
         }
+        Command::Query { root, filter } => {
+            let query = filter::parse_filter(&filter)?.compile::<TT<Link>>();
+            let tree = forest.load_tree::<String>(secrets, root)?;
+            let mut stream = forest.stream_filtered(&tree, query).boxed_local();
+            while let Some(item) = stream.next().await {
+                let (offset, key, value) = item?;
+                let json = serde_json::json!({ "offset": offset, "key": key, "value": value });
+                println!("{}", json);
+            }
+        }
         Command::Repair { root } => {
             let mut tree = forest.load_stream_builder::<String>(secrets, config, root)?;
             let _ = forest.repair(&mut tree)?;
@@ -528,7 +589,7 @@ async fn main() -> Result<()> {
         }
         Command::SendStream { topic } => {
             let mut ticks = tokio::time::interval(Duration::from_secs(1));
-            let mut tree = StreamBuilder::<TT, String>::new(config, secrets);
+            let mut tree = StreamBuilder::<TT<Link>, String>::new(config, secrets);
             let mut offset = 0;
             loop {
                 poll_fn(|cx| ticks.poll_tick(cx)).await;
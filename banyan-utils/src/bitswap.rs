@@ -0,0 +1,186 @@
+//! store adapter that fetches missing blocks from the network
+//!
+//! [BitswapStore] reads from a local store and asks a [BlockExchange] for blocks the local store
+//! does not have, such as a libp2p bitswap client. Fetched blocks are verified and written to the
+//! local store, so a remote tree is materialized lazily as it is queried.
+//!
+//! This crate does not depend on libp2p. To use a bitswap client, implement [BlockExchange] for
+//! it, blocking on its request future. Requires the `bitswap` feature.
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{ensure, Result};
+use banyan::{
+    error::BlockNotFound,
+    store::{BlockWriter, ReadOnlyStore},
+};
+use libipld::Cid;
+
+use crate::{ipfs::CidFormat, tags::Digest};
+
+/// A source of blocks on the network, such as a bitswap client
+pub trait BlockExchange: Clone + Send + Sync + 'static {
+    /// gets a block from the network, giving up after `timeout`
+    fn fetch(&self, cid: &Cid, timeout: Duration) -> Result<Vec<u8>>;
+}
+
+/// A store that falls back to a [BlockExchange] for blocks that are missing locally
+///
+/// Only blocks for which the local store returns [BlockNotFound] are fetched, other errors of the
+/// local store are returned as they are. Fetched blocks are written to a clone of the local
+/// store, so it has to share its blocks between clones, like all stores in this crate do. Clones share the counter of fetched blocks.
+#[derive(Clone)]
+pub struct BitswapStore<S, B> {
+    local: S,
+    exchange: B,
+    format: CidFormat,
+    timeout: Duration,
+    fetched: Arc<AtomicU64>,
+}
+
+impl<S, B> BitswapStore<S, B> {
+    pub fn new(local: S, exchange: B) -> Self {
+        Self {
+            local,
+            exchange,
+            format: CidFormat::default(),
+            timeout: Duration::from_secs(30),
+            fetched: Default::default(),
+        }
+    }
+
+    /// uses `format` for the cids that are requested from the network
+    pub fn with_cid_format(mut self, format: CidFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// how long to wait for a block from the network before failing, 30 seconds by default
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// the local store
+    pub fn local(&self) -> &S {
+        &self.local
+    }
+
+    /// number of blocks that were fetched from the network
+    pub fn fetched(&self) -> u64 {
+        self.fetched.load(Ordering::Relaxed)
+    }
+}
+
+impl<D, S, B> ReadOnlyStore<D> for BitswapStore<S, B>
+where
+    D: Digest,
+    S: ReadOnlyStore<D> + BlockWriter<D>,
+    B: BlockExchange,
+{
+    fn get(&self, link: &D) -> Result<Box<[u8]>> {
+        match self.local.get(link) {
+            Err(cause) if cause.downcast_ref::<BlockNotFound>().is_some() => {}
+            res => return res,
+        }
+        let cid = self.format.to_cid(*link)?;
+        let block = self.exchange.fetch(&cid, self.timeout)?;
+        ensure!(D::digest(&block) == *link, "block {} is corrupted", link);
+        self.local.clone().put(block.clone())?;
+        self.fetched.fetch_add(1, Ordering::Relaxed);
+        Ok(block.into())
+    }
+}
+
+impl<D, S, B> BlockWriter<D> for BitswapStore<S, B>
+where
+    D: Digest,
+    S: BlockWriter<D>,
+    B: BlockExchange,
+{
+    fn put(&mut self, data: Vec<u8>) -> Result<D> {
+        self.local.put(data)
+    }
+
+    fn put_many(&mut self, blocks: Vec<Vec<u8>>) -> Result<Vec<D>> {
+        self.local.put_many(blocks)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.local.flush()
+    }
+
+    fn compresses_at_rest(&self) -> bool {
+        self.local.compresses_at_rest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::Sha256Digest;
+    use anyhow::anyhow;
+    use banyan::store::MemStore;
+
+    /// an exchange that serves the blocks of a memory store
+    #[derive(Clone)]
+    struct MemExchange(MemStore<Sha256Digest>);
+
+    impl BlockExchange for MemExchange {
+        fn fetch(&self, cid: &Cid, _: Duration) -> Result<Vec<u8>> {
+            let link = CidFormat::default().from_cid(*cid)?;
+            self.0
+                .get(&link)
+                .map(|block| block.to_vec())
+                .map_err(|_| anyhow!("not found"))
+        }
+    }
+
+    #[test]
+    fn fetch_missing_blocks() -> Result<()> {
+        let mut remote = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let local = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let link = remote.put(b"remote".to_vec())?;
+        let store = BitswapStore::new(local.clone(), MemExchange(remote));
+        assert_eq!(store.get(&link)?.as_ref(), b"remote");
+        assert_eq!(store.fetched(), 1);
+        // the second time the block comes from the local store
+        assert!(local.get(&link).is_ok());
+        store.get(&link)?;
+        assert_eq!(store.fetched(), 1);
+        assert!(store.get(&Sha256Digest::new(b"missing")).is_err());
+        Ok(())
+    }
+
+    /// a local store whose reads fail
+    #[derive(Clone)]
+    struct BrokenStore;
+
+    impl ReadOnlyStore<Sha256Digest> for BrokenStore {
+        fn get(&self, _: &Sha256Digest) -> Result<Box<[u8]>> {
+            Err(anyhow!("disk on fire"))
+        }
+    }
+
+    impl BlockWriter<Sha256Digest> for BrokenStore {
+        fn put(&mut self, _: Vec<u8>) -> Result<Sha256Digest> {
+            Err(anyhow!("disk on fire"))
+        }
+    }
+
+    #[test]
+    fn local_errors_are_not_fetched() -> Result<()> {
+        let mut remote = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let link = remote.put(b"remote".to_vec())?;
+        let store = BitswapStore::new(BrokenStore, MemExchange(remote));
+        let err = store.get(&link).unwrap_err();
+        assert_eq!(err.to_string(), "disk on fire");
+        assert_eq!(store.fetched(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+//! a minimal event log on top of banyan, used by the `event_logger` example
+//!
+//! Events are single lines of text. Words at the start of a line that begin with `#` are
+//! used as tags, the rest of the line is the message. E.g. `#sensor/1 #alarm too hot`.
+use std::ops::Range;
+
+use anyhow::Result;
+use banyan::{
+    query::{AndQuery, QueryExt},
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+
+use crate::{
+    tag_index::{Tag, TagSet},
+    tags::{Digest, Key, Sha256Digest, TagPattern, TagPatternQuery, TimeRangeQuery, TT},
+};
+
+/// Level of the unpacked tree above which it will be packed after appending
+const MAX_UNPACKED_LEVEL: i32 = 32;
+
+/// A single event as stored in the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub offset: u64,
+    pub time: u64,
+    pub tags: TagSet,
+    pub message: String,
+}
+
+/// Splits a line into tags and message. Returns None for blank lines.
+pub fn parse_line(line: &str) -> Option<(TagSet, String)> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    let mut tags = Vec::new();
+    let mut rest = line;
+    while let Some(word) = rest
+        .split_whitespace()
+        .next()
+        .filter(|w| w.starts_with('#'))
+    {
+        if word.len() > 1 {
+            tags.push(Tag::from(&word[1..]));
+        }
+        rest = rest[word.len()..].trim_start();
+    }
+    let tags = tags.into_iter().collect::<TagSet>();
+    Some((tags, rest.to_string()))
+}
+
+pub struct EventLog<S, D: Digest = Sha256Digest> {
+    txn: Transaction<TT<D>, S, S>,
+    builder: StreamBuilder<TT<D>, String>,
+}
+
+impl<S, D> EventLog<S, D>
+where
+    D: Digest,
+    S: ReadOnlyStore<D> + BlockWriter<D> + Clone + Send + Sync + 'static,
+{
+    /// Opens the log at `root`, or creates an empty log if there is no root yet
+    pub fn new(store: S, config: Config, secrets: Secrets, root: Option<D>) -> Result<Self> {
+        let txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+        let builder = match root {
+            Some(root) => txn.load_stream_builder(secrets, config, root)?,
+            None => StreamBuilder::new(config, secrets),
+        };
+        Ok(Self { txn, builder })
+    }
+
+    /// The current root of the log, to be persisted somewhere
+    pub fn root(&self) -> Option<D> {
+        self.builder.link()
+    }
+
+    /// The number of events ever appended, including ones dropped by retention
+    pub fn len(&self) -> u64 {
+        self.builder.count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Appends all non-blank lines with the given timestamp, returning the number of new events
+    pub fn append(&mut self, time: u64, lines: impl IntoIterator<Item = String>) -> Result<usize> {
+        let offset = self.builder.count();
+        let events = lines
+            .into_iter()
+            .filter_map(|line| parse_line(&line))
+            .enumerate()
+            .map(|(i, (tags, message))| (Key::single(offset + i as u64, time, tags), message))
+            .collect::<Vec<_>>();
+        let n = events.len();
+        if n > 0 {
+            self.txn.extend_unpacked(&mut self.builder, events)?;
+            if self.builder.level() > MAX_UNPACKED_LEVEL {
+                self.txn.pack(&mut self.builder)?;
+            }
+        }
+        Ok(n)
+    }
+
+    /// Returns all events within the time range, optionally restricted to events with a tag
+    /// starting with `tag`
+    pub fn query(&self, tag: Option<&str>, time: Range<u64>) -> Result<Vec<Event>> {
+        let query = match tag {
+            Some(tag) => AndQuery(
+                TimeRangeQuery(time),
+                TagPatternQuery(TagPattern::prefix(tag)),
+            )
+            .boxed(),
+            None => TimeRangeQuery(time).boxed(),
+        };
+        self.txn
+            .iter_filtered(&self.builder.snapshot(), query)
+            .map(|res| {
+                let (offset, key, message) = res?;
+                Ok(Event {
+                    offset,
+                    time: key.min_time(),
+                    tags: key.tags().clone(),
+                    message,
+                })
+            })
+            .collect()
+    }
+
+    /// Drops events older than `time`
+    ///
+    /// This is best effort, see [Transaction::retain]. The dropped blocks can be reclaimed by
+    /// garbage collecting the store once the new root has been persisted.
+    pub fn retain_since(&mut self, time: u64) -> Result<()> {
+        // only sealed nodes can be dropped, so pack first
+        self.txn.pack(&mut self.builder)?;
+        self.txn
+            .retain(&mut self.builder, &TimeRangeQuery(time..u64::max_value()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use banyan::store::MemStore;
+
+    fn tags(names: &[&str]) -> TagSet {
+        names.iter().map(|name| Tag::from(*name)).collect()
+    }
+
+    #[test]
+    fn parse_line_tags() {
+        assert_eq!(parse_line("   "), None);
+        assert_eq!(
+            parse_line("#a #b/c hello #d world"),
+            Some((tags(&["a", "b/c"]), "hello #d world".into()))
+        );
+        assert_eq!(parse_line("no tags"), Some((tags(&[]), "no tags".into())));
+    }
+
+    #[test]
+    fn append_query_retain() -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut log = EventLog::new(store.clone(), Config::debug(), Secrets::default(), None)?;
+        for time in 0..100u64 {
+            let lines = vec![
+                format!("#sensor/{} #temp {}", time % 3, time),
+                "".to_string(),
+                format!("#alarm {}", time),
+            ];
+            assert_eq!(log.append(time, lines)?, 2);
+        }
+        assert_eq!(log.len(), 200);
+        assert_eq!(log.query(None, 0..u64::max_value())?.len(), 200);
+        assert_eq!(log.query(Some("sensor/"), 0..u64::max_value())?.len(), 100);
+        assert_eq!(log.query(Some("sensor/1"), 0..u64::max_value())?.len(), 33);
+        let alarms = log.query(Some("alarm"), 10..20)?;
+        assert_eq!(alarms.len(), 10);
+        assert!(alarms
+            .iter()
+            .all(|e| e.tags == tags(&["alarm"]) && e.message == e.time.to_string()));
+
+        // reopen from the root
+        let mut log = EventLog::new(store, Config::debug(), Secrets::default(), log.root())?;
+        log.retain_since(50)?;
+        assert_eq!(log.len(), 200);
+        let remaining = log.query(None, 0..u64::max_value())?;
+        // retention is best effort, but never drops anything that should be kept
+        assert!(remaining.iter().filter(|e| e.time >= 50).count() == 100);
+        assert!(remaining.len() < 200);
+        Ok(())
+    }
+}
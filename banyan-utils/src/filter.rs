@@ -0,0 +1,204 @@
+//! textual filter expressions for trees of [TT](../tags/struct.TT.html)
+//!
+//! A filter is a sequence of terms. Adjacent terms must all match, `or` combines alternatives,
+//! `not` negates a term, and parentheses group terms. The terms are
+//!
+//! - `all`: matches everything
+//! - `tag:<tag>`: events with the given tag
+//! - `prefix:<prefix>`: events with a tag starting with the prefix
+//! - `regex:<regex>`: events with a tag matching the regular expression
+//! - `time:<start>..<end>`: events with a time in the given range
+//! - `offset:<start>..<end>`: events with an offset in the given range
+//!
+//! Either end of a range can be omitted. Terms are separated by whitespace, so tags and regular
+//! expressions can not contain whitespace or parentheses. Example:
+//!
+//! `offset:1000.. (tag:fizz or prefix:machine/) not tag:buzz`
+use std::{iter::Peekable, ops::Range};
+
+use anyhow::{anyhow, bail, Context, Result};
+use banyan::{
+    index::{BranchIndex, LeafIndex},
+    query::{Query, QueryExpr},
+};
+
+use crate::{
+    tag_index::{Tag, TagSet},
+    tags::{Digest, DnfQuery, Key, TagPattern, TagPatternQuery, TimeRangeQuery, TT},
+};
+
+/// The queries that can be used in a filter expression
+#[derive(Debug, Clone)]
+pub enum FilterAtom {
+    Tags(DnfQuery),
+    TagPattern(TagPatternQuery),
+    TimeRange(TimeRangeQuery),
+}
+
+impl<D: Digest> Query<TT<D>> for FilterAtom {
+    fn containing(&self, offset: u64, index: &LeafIndex<TT<D>>, res: &mut [bool]) {
+        match self {
+            Self::Tags(q) => q.containing(offset, index, res),
+            Self::TagPattern(q) => q.containing(offset, index, res),
+            Self::TimeRange(q) => q.containing(offset, index, res),
+        }
+    }
+
+    fn intersecting(&self, offset: u64, index: &BranchIndex<TT<D>>, res: &mut [bool]) {
+        match self {
+            Self::Tags(q) => q.intersecting(offset, index, res),
+            Self::TagPattern(q) => q.intersecting(offset, index, res),
+            Self::TimeRange(q) => q.intersecting(offset, index, res),
+        }
+    }
+}
+
+/// parses a filter expression, see the [module docs](index.html) for the syntax
+pub fn parse_filter(text: &str) -> Result<QueryExpr<FilterAtom>> {
+    let spaced = text.replace('(', " ( ").replace(')', " ) ");
+    let mut tokens = spaced.split_whitespace().peekable();
+    let expr = parse_or(&mut tokens)?;
+    if let Some(token) = tokens.next() {
+        bail!("unexpected '{}' in filter", token);
+    }
+    Ok(expr)
+}
+
+type Tokens<'a> = Peekable<std::str::SplitWhitespace<'a>>;
+
+fn parse_or(tokens: &mut Tokens) -> Result<QueryExpr<FilterAtom>> {
+    let mut alternatives = vec![parse_and(tokens)?];
+    while tokens.peek() == Some(&"or") {
+        tokens.next();
+        alternatives.push(parse_and(tokens)?);
+    }
+    Ok(if alternatives.len() == 1 {
+        alternatives.pop().unwrap()
+    } else {
+        QueryExpr::Or(alternatives)
+    })
+}
+
+fn parse_and(tokens: &mut Tokens) -> Result<QueryExpr<FilterAtom>> {
+    let mut terms = vec![parse_term(tokens)?];
+    while !matches!(tokens.peek(), None | Some(&"or") | Some(&")")) {
+        terms.push(parse_term(tokens)?);
+    }
+    Ok(if terms.len() == 1 {
+        terms.pop().unwrap()
+    } else {
+        QueryExpr::And(terms)
+    })
+}
+
+fn parse_term(tokens: &mut Tokens) -> Result<QueryExpr<FilterAtom>> {
+    let token = tokens
+        .next()
+        .ok_or_else(|| anyhow!("unexpected end of filter"))?;
+    Ok(match token {
+        "(" => {
+            let expr = parse_or(tokens)?;
+            if tokens.next() != Some(")") {
+                bail!("missing ')' in filter");
+            }
+            expr
+        }
+        "not" => QueryExpr::Not(Box::new(parse_term(tokens)?)),
+        "all" => QueryExpr::All,
+        _ => {
+            let (kind, arg) = token
+                .split_once(':')
+                .ok_or_else(|| anyhow!("unknown term '{}' in filter", token))?;
+            let atom = match kind {
+                "tag" => FilterAtom::Tags(DnfQuery(vec![Key::filter_tags(TagSet::single(
+                    Tag::from(arg),
+                ))])),
+                "prefix" => FilterAtom::TagPattern(TagPatternQuery(TagPattern::prefix(arg))),
+                "regex" => FilterAtom::TagPattern(TagPatternQuery(TagPattern::regex(arg)?)),
+                "time" => FilterAtom::TimeRange(TimeRangeQuery(parse_range(arg)?)),
+                "offset" => return Ok(QueryExpr::OffsetRange(parse_range(arg)?)),
+                _ => bail!("unknown term '{}' in filter", token),
+            };
+            QueryExpr::Atom(atom)
+        }
+    })
+}
+
+fn parse_range(text: &str) -> Result<Range<u64>> {
+    let (start, end) = text
+        .split_once("..")
+        .ok_or_else(|| anyhow!("expected a range like 10..20, got '{}'", text))?;
+    let bound = |x: &str, default: u64| -> Result<u64> {
+        if x.is_empty() {
+            Ok(default)
+        } else {
+            x.parse()
+                .with_context(|| format!("invalid range '{}'", text))
+        }
+    };
+    Ok(bound(start, 0)?..bound(end, u64::max_value())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use banyan::{
+        store::{BranchCache, MemStore},
+        Config, Forest, Secrets, StreamBuilder, Transaction,
+    };
+
+    use crate::tags::Sha256Digest;
+
+    #[test]
+    fn filter_syntax() -> Result<()> {
+        assert!(matches!(parse_filter("all")?, QueryExpr::All));
+        assert!(matches!(
+            parse_filter("offset:10..")?,
+            QueryExpr::OffsetRange(Range { start: 10, end }) if end == u64::max_value()
+        ));
+        assert!(matches!(
+            parse_filter("tag:a (tag:b or not tag:c)")?,
+            QueryExpr::And(terms) if terms.len() == 2
+        ));
+        assert!(matches!(
+            parse_filter("tag:a or tag:b time:..10")?,
+            QueryExpr::Or(terms) if terms.len() == 2
+        ));
+        for invalid in &[
+            "",
+            "tag:a)",
+            "(tag:a",
+            "foo:bar",
+            "offset:x..1",
+            "time:10",
+            "or",
+        ] {
+            assert!(parse_filter(invalid).is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn filter_query() -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+        let mut builder = StreamBuilder::<TT, u64>::new(Config::debug(), Secrets::default());
+        let tag = |i: u64| Tag::from(if i.is_multiple_of(2) { "even" } else { "odd" });
+        let xs = (0..100u64).map(|i| (Key::single(i, i, TagSet::single(tag(i))), i));
+        txn.extend(&mut builder, xs)?;
+        let offsets = |filter: &str| -> Result<Vec<u64>> {
+            let query = parse_filter(filter)?.compile::<TT>();
+            txn.iter_filtered(&builder.snapshot(), query)
+                .map(|res| res.map(|(offset, _, _)| offset))
+                .collect()
+        };
+        assert_eq!(offsets("all")?.len(), 100);
+        assert_eq!(offsets("tag:even offset:..10")?, vec![0, 2, 4, 6, 8]);
+        assert_eq!(
+            offsets("time:95.. or prefix:ev time:..3")?,
+            vec![0, 2, 95, 96, 97, 98, 99]
+        );
+        assert_eq!(offsets("not tag:odd time:90..95")?, vec![90, 92, 94]);
+        Ok(())
+    }
+}
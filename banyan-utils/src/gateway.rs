@@ -0,0 +1,238 @@
+//! read only store that fetches blocks from an ipfs http gateway
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{ensure, Result};
+use banyan::store::ReadOnlyStore;
+use futures::{
+    channel::oneshot,
+    executor::{block_on, ThreadPool},
+};
+use reqwest::{blocking::Client, header::ACCEPT, Url};
+
+use crate::{ipfs::CidFormat, tags::Digest};
+
+/// A store that gets blocks by cid from an ipfs http gateway, e.g. `https://ipfs.io`
+///
+/// Blocks are requested in the raw block format of the trustless gateway api, and their digests
+/// are checked, so the gateway does not have to be trusted. Requests run on a pool of
+/// `parallelism` threads, so [get_many](#method.get_many) fetches up to that many blocks at once.
+/// Clones share the connection pool and the threads.
+#[derive(Clone)]
+pub struct GatewayStore {
+    base: Arc<Url>,
+    client: Client,
+    format: CidFormat,
+    pool: ThreadPool,
+}
+
+fn thread_pool(parallelism: usize) -> Result<ThreadPool> {
+    Ok(ThreadPool::builder()
+        .pool_size(parallelism.max(1))
+        .name_prefix("banyan-gateway-")
+        .create()?)
+}
+
+impl GatewayStore {
+    pub fn new(base: &str) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(60))
+            .pool_max_idle_per_host(16)
+            .build()?;
+        Ok(Self {
+            base: Arc::new(Url::parse(base)?),
+            client,
+            format: CidFormat::default(),
+            pool: thread_pool(8)?,
+        })
+    }
+
+    /// uses `format` for the cids in the urls
+    pub fn with_cid_format(mut self, format: CidFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// number of requests to have in flight at once, 8 by default
+    ///
+    /// This replaces the thread pool, so clones made before keep the old one.
+    pub fn with_parallelism(mut self, parallelism: usize) -> Result<Self> {
+        self.pool = thread_pool(parallelism)?;
+        Ok(self)
+    }
+
+    fn url<D: Digest>(&self, link: &D) -> Result<Url> {
+        let cid = self.format.to_cid(*link)?;
+        Ok(self.base.join(&format!("ipfs/{}?format=raw", cid))?)
+    }
+
+    /// blocking request, must not be called on an async runtime thread
+    fn fetch<D: Digest>(&self, link: &D) -> Result<Box<[u8]>> {
+        let response = self
+            .client
+            .get(self.url(link)?)
+            .header(ACCEPT, "application/vnd.ipld.raw")
+            .send()?
+            .error_for_status()?;
+        let block = response.bytes()?;
+        ensure!(D::digest(&block) == *link, "block {} is corrupted", link);
+        Ok(block.to_vec().into())
+    }
+
+    /// starts fetching the block on the thread pool
+    fn spawn_fetch<D: Digest>(&self, link: D) -> oneshot::Receiver<Result<Box<[u8]>>> {
+        let this = self.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn_ok(async move {
+            let _ = tx.send(this.fetch(&link));
+        });
+        rx
+    }
+}
+
+impl<D: Digest> ReadOnlyStore<D> for GatewayStore {
+    fn get(&self, link: &D) -> Result<Box<[u8]>> {
+        block_on(self.spawn_fetch(*link))?
+    }
+
+    fn get_many(&self, links: &[D]) -> Result<Vec<Box<[u8]>>> {
+        // the pool limits the number of requests in flight
+        let pending = links
+            .iter()
+            .map(|link| self.spawn_fetch(*link))
+            .collect::<Vec<_>>();
+        pending.into_iter().map(|rx| block_on(rx)?).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::Sha256Digest;
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Write},
+        net::{TcpListener, TcpStream},
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    /// a minimal gateway that serves the given bodies by path, one connection per request
+    #[derive(Default)]
+    struct Gateway {
+        blocks: HashMap<String, Vec<u8>>,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl Gateway {
+        fn serve(self) -> Result<(String, Arc<Self>)> {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let base = format!("http://{}/", listener.local_addr()?);
+            let this = Arc::new(self);
+            let gateway = this.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let gateway = gateway.clone();
+                    thread::spawn(move || gateway.respond(stream));
+                }
+            });
+            Ok((base, this))
+        }
+
+        fn respond(&self, mut stream: TcpStream) -> Result<()> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            let mut lines = BufReader::new(stream.try_clone()?).lines();
+            let request = lines.next().transpose()?.unwrap_or_default();
+            for line in lines {
+                if line?.is_empty() {
+                    break;
+                }
+            }
+            let path = request.split(' ').nth(1).unwrap_or_default();
+            // give other requests a chance to overlap
+            thread::sleep(Duration::from_millis(20));
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            match self.blocks.get(path) {
+                Some(body) => {
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )?;
+                    stream.write_all(body)?;
+                }
+                None => write!(
+                    stream,
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )?,
+            }
+            Ok(())
+        }
+    }
+
+    fn path(store: &GatewayStore, link: &Sha256Digest) -> Result<String> {
+        let url = store.url(link)?;
+        Ok(format!(
+            "{}?{}",
+            url.path(),
+            url.query().unwrap_or_default()
+        ))
+    }
+
+    #[test]
+    fn gateway_get() -> Result<()> {
+        let paths = GatewayStore::new("http://localhost/")?;
+        let good = Sha256Digest::new(b"good");
+        let corrupted = Sha256Digest::new(b"corrupted");
+        let missing = Sha256Digest::new(b"missing");
+        let mut gateway = Gateway::default();
+        gateway
+            .blocks
+            .insert(path(&paths, &good)?, b"good".to_vec());
+        gateway
+            .blocks
+            .insert(path(&paths, &corrupted)?, b"tampered".to_vec());
+        let (base, _) = gateway.serve()?;
+        let store = GatewayStore::new(&base)?;
+
+        assert_eq!(&store.get(&good)?[..], b"good");
+        let error = store.get(&corrupted).unwrap_err();
+        assert!(error.to_string().contains("corrupted"));
+        assert!(store.get(&missing).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn gateway_get_many() -> Result<()> {
+        let paths = GatewayStore::new("http://localhost/")?;
+        let data = (0..20u8).map(|i| vec![i; 100]).collect::<Vec<_>>();
+        let links = data
+            .iter()
+            .map(|block| Sha256Digest::new(block))
+            .collect::<Vec<_>>();
+        let mut gateway = Gateway::default();
+        for (link, block) in links.iter().zip(&data) {
+            gateway.blocks.insert(path(&paths, link)?, block.clone());
+        }
+        let (base, gateway) = gateway.serve()?;
+        let store = GatewayStore::new(&base)?.with_parallelism(3)?;
+
+        let blocks = store.get_many(&links)?;
+        assert_eq!(
+            blocks
+                .iter()
+                .map(|block| block.to_vec())
+                .collect::<Vec<_>>(),
+            data
+        );
+        let max_in_flight = gateway.max_in_flight.load(Ordering::SeqCst);
+        assert!(max_in_flight > 1 && max_in_flight <= 3);
+
+        // a missing block fails the whole batch
+        let mut links = links;
+        links.push(Sha256Digest::new(b"missing"));
+        assert!(store.get_many(&links).is_err());
+        Ok(())
+    }
+}
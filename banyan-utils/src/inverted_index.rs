@@ -0,0 +1,393 @@
+//! inverted index from tags to the offsets of the events that have them
+//!
+//! The index is a banyan tree of its own that is kept next to the data tree. Each element is a
+//! posting list, the offsets of all events with a given tag within a range of the data tree.
+//! Looking up a tag only reads the few index leaves for that tag, instead of every data branch
+//! whose summary happens to contain it, which is most of them for high-cardinality tags.
+//!
+//! Call [InvertedIndex::update] after appending to the data tree, and [InvertedIndex::rewrite]
+//! to turn a tag query into one that uses the index. Events that are not indexed yet are still
+//! found, by checking them against the original query.
+use crate::{
+    tag_index::Tag,
+    tags::{Digest, DnfQuery, Sha256Digest, TT},
+};
+use anyhow::Result;
+use banyan::{
+    index::{BranchIndex, CompactSeq, Index, LeafIndex, Summarizable, VecSeq},
+    query::{AllQuery, OffsetRangeQuery, Query},
+    store::{BlockWriter, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use libipld::DagCbor;
+use std::{collections::BTreeMap, marker::PhantomData, ops::Range, sync::Arc};
+
+/// Tree types for the index tree, with links of type `D`
+#[derive(Debug, Clone)]
+pub struct IndexTT<D = Sha256Digest>(PhantomData<D>);
+
+impl<D: Digest> TreeTypes for IndexTT<D> {
+    type Key = PostingKey;
+    type KeySeq = VecSeq<PostingKey>;
+    type Summary = PostingSummary;
+    type SummarySeq = VecSeq<PostingSummary>;
+    type Link = D;
+}
+
+/// Key of a posting list, the tag and the range of data offsets that was indexed
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct PostingKey {
+    pub tag: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Summary of a number of posting lists, the sorted tags and the range of data offsets
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct PostingSummary {
+    pub tags: Vec<String>,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl PostingSummary {
+    fn combine(mut self, other: PostingSummary) -> PostingSummary {
+        self.tags.extend(other.tags);
+        self.tags.sort();
+        self.tags.dedup();
+        self.start = self.start.min(other.start);
+        self.end = self.end.max(other.end);
+        self
+    }
+}
+
+impl From<&PostingKey> for PostingSummary {
+    fn from(key: &PostingKey) -> Self {
+        Self {
+            tags: vec![key.tag.clone()],
+            start: key.start,
+            end: key.end,
+        }
+    }
+}
+
+impl Summarizable<PostingSummary> for VecSeq<PostingKey> {
+    fn summarize(&self) -> PostingSummary {
+        self.as_ref()
+            .iter()
+            .map(PostingSummary::from)
+            .reduce(PostingSummary::combine)
+            .expect("summarize of empty sequence")
+    }
+}
+
+impl Summarizable<PostingSummary> for VecSeq<PostingSummary> {
+    fn summarize(&self) -> PostingSummary {
+        self.as_ref()
+            .iter()
+            .cloned()
+            .reduce(PostingSummary::combine)
+            .expect("summarize of empty sequence")
+    }
+}
+
+/// A query for the posting lists of a single tag in the index tree
+#[derive(Debug, Clone)]
+pub struct PostingQuery(pub Tag);
+
+impl<D: Digest> Query<IndexTT<D>> for PostingQuery {
+    fn intersecting(&self, _: u64, x: &BranchIndex<IndexTT<D>>, matching: &mut [bool]) {
+        let seq = x.summaries.as_ref();
+        for i in 0..seq.len().min(matching.len()) {
+            if matching[i] {
+                matching[i] = seq[i]
+                    .tags
+                    .binary_search_by(|tag| tag.as_str().cmp(self.0.as_str()))
+                    .is_ok();
+            }
+        }
+    }
+
+    fn containing(&self, _: u64, x: &LeafIndex<IndexTT<D>>, matching: &mut [bool]) {
+        let seq = x.keys.as_ref();
+        for i in 0..seq.len().min(matching.len()) {
+            if matching[i] {
+                matching[i] = seq[i].tag == self.0.as_str();
+            }
+        }
+    }
+}
+
+/// An inverted index for a data tree of [TT]
+pub struct InvertedIndex<D: Digest = Sha256Digest> {
+    builder: StreamBuilder<IndexTT<D>, Vec<u64>>,
+    /// number of events of the data tree that have been indexed
+    indexed: u64,
+}
+
+impl<D: Digest> InvertedIndex<D> {
+    /// An empty index
+    pub fn new(config: Config, secrets: Secrets) -> Self {
+        Self {
+            builder: StreamBuilder::new(config, secrets),
+            indexed: 0,
+        }
+    }
+
+    /// Opens the index at `root`
+    pub fn load<R: ReadOnlyStore<D>>(
+        forest: &Forest<IndexTT<D>, R>,
+        config: Config,
+        secrets: Secrets,
+        root: D,
+    ) -> Result<Self> {
+        let builder = forest.load_stream_builder(secrets, config, root)?;
+        // all posting lists of an update cover the same range, so the last one tells how far
+        // the index goes
+        let last = forest
+            .iter_filtered_reverse(&builder.snapshot(), AllQuery)
+            .next()
+            .transpose()?;
+        let indexed = last.map(|(_, key, _)| key.end).unwrap_or_default();
+        Ok(Self { builder, indexed })
+    }
+
+    /// The current root of the index, to be persisted next to the root of the data tree
+    pub fn root(&self) -> Option<D> {
+        self.builder.link()
+    }
+
+    /// The number of events of the data tree that have been indexed
+    pub fn indexed(&self) -> u64 {
+        self.indexed
+    }
+
+    /// Indexes the events of the data tree that have been added since the last update, and
+    /// returns their number.
+    ///
+    /// This only reads the keys of the new events from the branches of the data tree, so none
+    /// of its leaves are loaded.
+    pub fn update<R, W, V>(
+        &mut self,
+        txn: &mut Transaction<IndexTT<D>, R, W>,
+        data: &Forest<TT<D>, R>,
+        tree: &Tree<TT<D>, V>,
+    ) -> Result<u64>
+    where
+        R: ReadOnlyStore<D>,
+        W: BlockWriter<D>,
+    {
+        let start = self.indexed;
+        let count = tree.count();
+        anyhow::ensure!(count >= start, "the data tree is shorter than the index");
+        let mut postings = BTreeMap::<Tag, Vec<u64>>::new();
+        // leaves come from the back, so their offsets are known from the count of the tree
+        let mut end = count;
+        for index in data.iter_index_reverse(tree, OffsetRangeQuery::from(start..)) {
+            if end <= start {
+                break;
+            }
+            match index? {
+                Index::Leaf(leaf) => {
+                    let offset = end - leaf.keys.count();
+                    for (i, key) in leaf.keys.to_vec().into_iter().enumerate() {
+                        let offset = offset + i as u64;
+                        if offset >= start {
+                            for tag in key.tags() {
+                                postings.entry(tag.clone()).or_default().push(offset);
+                            }
+                        }
+                    }
+                    end = offset;
+                }
+                // purged branches have nothing to index
+                Index::Branch(branch) if branch.link.is_none() => end -= branch.count,
+                Index::Branch(_) => {}
+            }
+        }
+        let postings = postings.into_iter().map(|(tag, mut offsets)| {
+            offsets.sort_unstable();
+            let key = PostingKey {
+                tag: tag.to_string(),
+                start,
+                end: count,
+            };
+            (key, offsets)
+        });
+        txn.extend(&mut self.builder, postings.collect::<Vec<_>>())?;
+        self.indexed = count;
+        Ok(count - start)
+    }
+
+    /// The sorted offsets of all indexed events that have a tag
+    pub fn offsets<R: ReadOnlyStore<D>>(
+        &self,
+        forest: &Forest<IndexTT<D>, R>,
+        tag: &Tag,
+    ) -> Result<Vec<u64>> {
+        let mut result = Vec::new();
+        for item in forest.iter_filtered(&self.builder.snapshot(), PostingQuery(tag.clone())) {
+            let (_, _, offsets) = item?;
+            result.extend(offsets);
+        }
+        Ok(result)
+    }
+
+    /// Rewrites a tag query for the data tree so that it only looks at the indexed events that
+    /// the index lists for the tags of the query.
+    pub fn rewrite<R: ReadOnlyStore<D>>(
+        &self,
+        forest: &Forest<IndexTT<D>, R>,
+        query: DnfQuery,
+    ) -> Result<IndexedQuery> {
+        let mut candidates = Vec::new();
+        for key in &query.0 {
+            let mut tags = key.tags().iter();
+            let first = match tags.next() {
+                Some(tag) => self.offsets(forest, tag)?,
+                // a conjunction without tags matches every event
+                None => return Ok(query.into()),
+            };
+            let mut offsets = first;
+            for tag in tags {
+                let other = self.offsets(forest, tag)?;
+                offsets.retain(|offset| other.binary_search(offset).is_ok());
+            }
+            candidates.extend(offsets);
+        }
+        candidates.sort_unstable();
+        candidates.dedup();
+        Ok(IndexedQuery {
+            query,
+            candidates: Some(Arc::new(candidates)),
+            indexed: self.indexed,
+        })
+    }
+}
+
+/// A tag query for the data tree that is restricted to the candidates from an
+/// [InvertedIndex] for events that have been indexed
+///
+/// Without an index, this is just the original query.
+#[derive(Debug, Clone)]
+pub struct IndexedQuery {
+    query: DnfQuery,
+    /// sorted offsets of the indexed events that can match
+    candidates: Option<Arc<Vec<u64>>>,
+    /// number of indexed events
+    indexed: u64,
+}
+
+impl IndexedQuery {
+    /// true if there is no candidate in the indexed part of the range
+    fn excludes(&self, range: Range<u64>) -> bool {
+        match &self.candidates {
+            Some(candidates) if range.end <= self.indexed => {
+                let i = candidates.partition_point(|offset| *offset < range.start);
+                candidates.get(i).is_none_or(|offset| *offset >= range.end)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<DnfQuery> for IndexedQuery {
+    fn from(query: DnfQuery) -> Self {
+        Self {
+            query,
+            candidates: None,
+            indexed: 0,
+        }
+    }
+}
+
+impl<D: Digest> Query<TT<D>> for IndexedQuery {
+    fn intersecting(&self, offset: u64, x: &BranchIndex<TT<D>>, matching: &mut [bool]) {
+        if self.excludes(offset..offset + x.count) {
+            matching.iter_mut().for_each(|m| *m = false);
+        } else {
+            self.query.intersecting(offset, x, matching);
+        }
+    }
+
+    fn containing(&self, offset: u64, x: &LeafIndex<TT<D>>, matching: &mut [bool]) {
+        self.query.containing(offset, x, matching);
+        for i in 0..x.keys.len().min(matching.len()) {
+            let offset = offset + i as u64;
+            if matching[i] && self.excludes(offset..offset + 1) {
+                matching[i] = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::Key;
+    use banyan::store::{BranchCache, MemStore};
+    use vec_collections::vecset;
+
+    #[test]
+    fn inverted_index() -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut data = Transaction::<TT, _, _>::new(
+            Forest::new(store.clone(), BranchCache::default()),
+            store.clone(),
+        );
+        let mut txn = Transaction::<IndexTT, _, _>::new(
+            Forest::new(store.clone(), BranchCache::default()),
+            store,
+        );
+        let event = |i: u64| {
+            let tags = vecset! {"all".into(), format!("tag/{}", i % 50).into()};
+            (Key::single(i, i, tags), i)
+        };
+        let mut builder = StreamBuilder::<TT, u64>::new(Config::debug(), Secrets::default());
+        let mut index = InvertedIndex::new(Config::debug(), Secrets::default());
+        let query = DnfQuery(vec![
+            Key::filter_tags(vecset! {"all".into(), "tag/7".into()}),
+            Key::filter_tags(vecset! {"tag/13".into()}),
+        ]);
+        let expected = |count: u64| {
+            (0..count)
+                .filter(|i| i % 50 == 7 || i % 50 == 13)
+                .collect::<Vec<_>>()
+        };
+        for (n, count) in [(1000, 1000), (234, 1234)] {
+            let start = builder.count();
+            data.extend(&mut builder, (start..count).map(event))?;
+            let tree = builder.snapshot();
+            assert_eq!(index.update(&mut txn, &data, &tree)?, n);
+            assert_eq!(index.indexed(), count);
+            assert_eq!(
+                index.offsets(&txn, &"tag/7".into())?,
+                (7..count).step_by(50).collect::<Vec<_>>()
+            );
+            let rewritten = index.rewrite(&txn, query.clone())?;
+            let found = data
+                .iter_filtered(&tree, rewritten)
+                .map(|res| res.map(|(offset, _, _)| offset))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(found, expected(count));
+        }
+
+        // events that are not indexed yet are still found
+        data.extend(&mut builder, (1234..1300).map(event))?;
+        let rewritten = index.rewrite(&txn, query)?;
+        let found = data
+            .iter_filtered(&builder.snapshot(), rewritten)
+            .map(|res| res.map(|(offset, _, _)| offset))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(found, expected(1300));
+
+        let loaded = InvertedIndex::load(
+            &txn,
+            Config::debug(),
+            Secrets::default(),
+            index.root().unwrap(),
+        )?;
+        assert_eq!(loaded.indexed(), 1234);
+        Ok(())
+    }
+}
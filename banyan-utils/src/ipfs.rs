@@ -2,11 +2,11 @@
 use anyhow::{anyhow, Result};
 use banyan::store::{BlockWriter, ReadOnlyStore};
 use futures::prelude::*;
-use libipld::Cid;
+use libipld::{cid::Version, Cid};
 use serde::{de::IgnoredAny, de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
-use std::{convert::TryInto, fmt, str::FromStr};
+use std::{fmt, str::FromStr};
 
-use crate::tags::Sha256Digest;
+use crate::tags::Digest;
 
 pub fn block_get(key: &Cid) -> Result<Box<[u8]>> {
     let url = reqwest::Url::parse_with_params(
@@ -131,32 +131,101 @@ pub fn block_put(data: &[u8], codec: u64, pin: bool) -> Result<Cid> {
     Ok(cid)
 }
 
-#[derive(Clone)]
-pub struct IpfsStore;
+/// multicodec code of dag-cbor
+pub const DAG_CBOR: u64 = 0x71;
+
+/// multicodec code of raw binary
+pub const RAW: u64 = 0x55;
+
+/// The codec and cid version used when links are handed to a block store as [Cid]s
+///
+/// Links always have a dag-cbor cid version 1 as canonical form. Some pinning services and
+/// gateways expect a different codec, so the store adapters can convert links on the way in and
+/// normalize the cids they get back. Only the hash is used for the conversion, so both forms
+/// refer to the same block.
+///
+/// Note that stores that follow links for gc, such as the sqlite store, only see the links of
+/// dag-cbor blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CidFormat {
+    /// cid version. Version 0 is only valid for dag-pb, so it can not be used for banyan blocks
+    pub version: Version,
+    /// multicodec code of the codec, e.g. [DAG_CBOR] or [RAW]
+    pub codec: u64,
+}
+
+impl Default for CidFormat {
+    fn default() -> Self {
+        Self::dag_cbor()
+    }
+}
+
+impl CidFormat {
+    /// dag-cbor cids of version 1, the canonical form of links
+    pub fn dag_cbor() -> Self {
+        Self {
+            version: Version::V1,
+            codec: DAG_CBOR,
+        }
+    }
+
+    /// raw cids of version 1
+    pub fn raw() -> Self {
+        Self {
+            version: Version::V1,
+            codec: RAW,
+        }
+    }
+
+    /// the cid for a link in this format
+    pub fn to_cid<D: Digest>(&self, link: D) -> Result<Cid> {
+        let cid: Cid = link.into();
+        Ok(Cid::new(self.version, self.codec, *cid.hash())?)
+    }
+
+    /// the link for a cid, which can be in any format as long as the hash matches `D`
+    pub fn from_cid<D: Digest>(&self, cid: Cid) -> Result<D> {
+        D::try_from(Cid::new_v1(DAG_CBOR, *cid.hash()))
+    }
+}
+
+/// A store that uses the block api of the local ipfs node
+///
+/// The node hashes blocks with its default hash, sha2-256, so writing only works for a [Digest]
+/// that uses that hash.
+#[derive(Clone, Default)]
+pub struct IpfsStore {
+    format: CidFormat,
+}
 
 impl IpfsStore {
     pub fn new() -> anyhow::Result<Self> {
-        Ok(Self)
+        Ok(Self::default())
+    }
+
+    /// uses `format` for the cids of the blocks that are read and written
+    pub fn with_cid_format(mut self, format: CidFormat) -> Self {
+        self.format = format;
+        self
     }
 }
 
-impl ReadOnlyStore<Sha256Digest> for IpfsStore {
-    fn get(&self, link: &Sha256Digest) -> Result<Box<[u8]>> {
-        let cid: Cid = (*link).into();
+impl<D: Digest> ReadOnlyStore<D> for IpfsStore {
+    fn get(&self, link: &D) -> Result<Box<[u8]>> {
+        let cid = self.format.to_cid(*link)?;
         std::thread::spawn(move || crate::ipfs::block_get(&cid))
             .join()
             .map_err(|_| anyhow!("join error!"))?
     }
 }
 
-impl BlockWriter<Sha256Digest> for IpfsStore {
-    fn put(&mut self, data: Vec<u8>) -> Result<Sha256Digest> {
-        let cid = std::thread::spawn(move || crate::ipfs::block_put(&data, 0x71, false))
+impl<D: Digest> BlockWriter<D> for IpfsStore {
+    fn put(&mut self, data: Vec<u8>) -> Result<D> {
+        let codec = self.format.codec;
+        let cid = std::thread::spawn(move || crate::ipfs::block_put(&data, codec, false))
             .join()
             .map_err(|_| anyhow!("join error!"))??;
-        assert!(cid.hash().code() == 0x12);
-        assert!(cid.hash().digest().len() == 32);
-        cid.try_into()
+        self.format.from_cid(cid)
     }
 }
 
@@ -165,3 +234,25 @@ struct IpfsBlockPutResponseIo {
     #[serde(rename = "Key")]
     key: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::Sha256Digest;
+
+    #[test]
+    fn cid_format_roundtrip() -> Result<()> {
+        let link = Sha256Digest::new(b"block");
+        let raw = CidFormat::raw().to_cid(link)?;
+        assert_eq!(raw.codec(), RAW);
+        assert_eq!(CidFormat::raw().from_cid::<Sha256Digest>(raw)?, link);
+        assert_eq!(CidFormat::default().to_cid(link)?, Cid::from(link));
+        // version 0 is only valid for dag-pb
+        let v0 = CidFormat {
+            version: Version::V0,
+            codec: DAG_CBOR,
+        };
+        assert!(v0.to_cid(link).is_err());
+        Ok(())
+    }
+}
@@ -1,14 +1,33 @@
 #![allow(clippy::upper_case_acronyms)]
+pub mod analyze;
+pub mod bench;
+#[cfg(feature = "bitswap")]
+pub mod bitswap;
 pub mod dump;
+pub mod event_log;
+pub mod filter;
+pub mod gateway;
+pub mod inverted_index;
 pub mod ipfs;
+pub mod metrics;
+pub mod migrate;
+#[cfg(feature = "arrow")]
+pub mod record_batch;
+#[cfg(feature = "rocksdb")]
+pub mod rocks;
+#[cfg(feature = "sled")]
+pub mod sled_store;
 pub mod sqlite;
+pub mod sync;
 pub mod tag_index;
 pub mod tags;
 
-pub fn create_chacha_key(text: String) -> chacha20::Key {
-    let mut key = [0u8; 32];
+pub fn create_chacha_key(mut text: String) -> chacha20::Key {
+    use banyan::zeroize::Zeroize;
+    let mut key = chacha20::Key::default();
     for (i, v) in text.as_bytes().iter().take(32).enumerate() {
         key[i] = *v;
     }
-    key.into()
+    text.zeroize();
+    key
 }
@@ -0,0 +1,223 @@
+//! prometheus metrics for a forest
+//!
+//! [ForestMetrics] is a prometheus collector. Counters for block reads and cache hits are taken
+//! from [Forest::stats] whenever the metrics are scraped, so they are always up to date. Block
+//! writes are only known if the store is wrapped in an [InstrumentedStore], and the shape of a
+//! tree has to be reported explicitly with [ForestMetrics::observe_stream].
+use std::sync::Arc;
+
+use anyhow::Result;
+use banyan::{
+    store::{InstrumentedStore, ReadOnlyStore},
+    Forest, ForestStats, StreamBuilder, TreeTypes,
+};
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    Gauge, IntCounter, IntGauge, Opts, Registry,
+};
+
+type StatsFn = Arc<dyn Fn() -> ForestStats + Send + Sync>;
+type WritesFn = Arc<dyn Fn() -> (u64, u64) + Send + Sync>;
+
+/// Prometheus metrics for a [Forest]
+#[derive(Clone)]
+pub struct ForestMetrics {
+    stats: StatsFn,
+    writes: Option<WritesFn>,
+    blocks_read: IntCounter,
+    bytes_read: IntCounter,
+    bytes_decompressed: IntCounter,
+    blocks_written: IntCounter,
+    bytes_written: IntCounter,
+    branch_cache_hit_ratio: Gauge,
+    leaf_cache_hit_ratio: Gauge,
+    tree_level: IntGauge,
+    tree_count: IntGauge,
+    unsealed_bytes: IntGauge,
+}
+
+impl ForestMetrics {
+    /// creates metrics for a forest, using `namespace` as prefix for the metric names
+    pub fn new<T, R>(forest: &Forest<T, R>, namespace: &str) -> Result<Self>
+    where
+        T: TreeTypes,
+        R: ReadOnlyStore<T::Link>,
+    {
+        let forest = forest.clone();
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(namespace);
+        Ok(Self {
+            stats: Arc::new(move || forest.stats()),
+            writes: None,
+            blocks_read: IntCounter::with_opts(opts("blocks_read", "Blocks read from the store"))?,
+            bytes_read: IntCounter::with_opts(opts("bytes_read", "Bytes read from the store"))?,
+            bytes_decompressed: IntCounter::with_opts(opts(
+                "bytes_decompressed",
+                "Decompressed size of decoded branches and leaves",
+            ))?,
+            blocks_written: IntCounter::with_opts(opts(
+                "blocks_written",
+                "Blocks written to the store",
+            ))?,
+            bytes_written: IntCounter::with_opts(opts(
+                "bytes_written",
+                "Bytes written to the store",
+            ))?,
+            branch_cache_hit_ratio: Gauge::with_opts(opts(
+                "branch_cache_hit_ratio",
+                "Fraction of branch loads served from the cache",
+            ))?,
+            leaf_cache_hit_ratio: Gauge::with_opts(opts(
+                "leaf_cache_hit_ratio",
+                "Fraction of leaf loads served from the cache",
+            ))?,
+            tree_level: IntGauge::with_opts(opts("tree_level", "Level of the root of the tree"))?,
+            tree_count: IntGauge::with_opts(opts("tree_count", "Number of elements in the tree"))?,
+            unsealed_bytes: IntGauge::with_opts(opts(
+                "unsealed_bytes",
+                "Value bytes that will be rewritten when the tree is packed",
+            ))?,
+        })
+    }
+
+    /// also report the writes of the store that the forest writes to
+    pub fn with_store<S: Clone + Send + Sync + 'static>(
+        mut self,
+        store: &InstrumentedStore<S>,
+    ) -> Self {
+        let store = store.clone();
+        self.writes = Some(Arc::new(move || {
+            let stats = store.stats();
+            (stats.writes, stats.bytes_written)
+        }));
+        self
+    }
+
+    /// registers the metrics with a registry
+    pub fn register(&self, registry: &Registry) -> Result<()> {
+        registry.register(Box::new(self.clone()))?;
+        Ok(())
+    }
+
+    /// updates the metrics for the shape of a tree, typically after every transaction
+    pub fn observe_stream<T, R, V>(
+        &self,
+        forest: &Forest<T, R>,
+        builder: &StreamBuilder<T, V>,
+    ) -> Result<()>
+    where
+        T: TreeTypes,
+        R: ReadOnlyStore<T::Link>,
+    {
+        let total = builder.index().map(|x| x.value_bytes()).unwrap_or_default();
+        let sealed: u64 = forest.roots(builder)?.iter().map(|x| x.value_bytes()).sum();
+        self.tree_level.set(builder.level() as i64);
+        self.tree_count.set(builder.count() as i64);
+        self.unsealed_bytes.set(total.saturating_sub(sealed) as i64);
+        Ok(())
+    }
+
+    fn update(&self) {
+        let stats = (self.stats)();
+        advance(&self.blocks_read, stats.block_reads);
+        advance(&self.bytes_read, stats.bytes_read);
+        advance(&self.bytes_decompressed, stats.bytes_decompressed);
+        if let Some(writes) = &self.writes {
+            let (blocks, bytes) = writes();
+            advance(&self.blocks_written, blocks);
+            advance(&self.bytes_written, bytes);
+        }
+        self.branch_cache_hit_ratio
+            .set(ratio(stats.branch_cache_hits, stats.branch_cache_misses));
+        self.leaf_cache_hit_ratio
+            .set(ratio(stats.leaf_cache_hits, stats.leaf_cache_misses));
+    }
+
+    fn collectors(&self) -> [&dyn Collector; 10] {
+        [
+            &self.blocks_read,
+            &self.bytes_read,
+            &self.bytes_decompressed,
+            &self.blocks_written,
+            &self.bytes_written,
+            &self.branch_cache_hit_ratio,
+            &self.leaf_cache_hit_ratio,
+            &self.tree_level,
+            &self.tree_count,
+            &self.unsealed_bytes,
+        ]
+    }
+}
+
+impl Collector for ForestMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        self.collectors().iter().flat_map(|x| x.desc()).collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.update();
+        self.collectors().iter().flat_map(|x| x.collect()).collect()
+    }
+}
+
+/// counters can only go up, so add the difference to the last value
+fn advance(counter: &IntCounter, value: u64) {
+    let current = counter.get();
+    if value > current {
+        counter.inc_by(value - current);
+    }
+}
+
+fn ratio(hits: u64, misses: u64) -> f64 {
+    if hits + misses == 0 {
+        0.0
+    } else {
+        hits as f64 / (hits + misses) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tag_index::TagSet,
+        tags::{Key, Sha256Digest, TT},
+    };
+    use banyan::{
+        store::{BranchCache, MemStore},
+        Config, Secrets, Transaction,
+    };
+
+    #[test]
+    fn forest_metrics() -> Result<()> {
+        let store = InstrumentedStore::new(MemStore::new(usize::max_value(), Sha256Digest::digest));
+        let forest = Forest::new(store.clone(), BranchCache::default());
+        let mut txn = Transaction::new(forest.clone(), store.clone());
+        let metrics = ForestMetrics::new(&forest, "test")?.with_store(&store);
+        let registry = Registry::new();
+        metrics.register(&registry)?;
+
+        let mut builder = StreamBuilder::<TT, u64>::new(Config::debug(), Secrets::default());
+        // not a multiple of the leaf size, so the last leaf is not sealed
+        let xs = (0..1005u64).map(|i| (Key::single(i, i, TagSet::empty()), i));
+        txn.extend_unpacked(&mut builder, xs)?;
+        metrics.observe_stream(&forest, &builder)?;
+        forest.collect(&builder.snapshot())?;
+
+        let families = registry.gather();
+        let value = |name: &str| {
+            let family = families.iter().find(|x| x.get_name() == name).unwrap();
+            let metric = &family.get_metric()[0];
+            if metric.has_counter() {
+                metric.get_counter().get_value()
+            } else {
+                metric.get_gauge().get_value()
+            }
+        };
+        assert!(value("test_blocks_written") > 0.0);
+        assert!(value("test_blocks_read") > 0.0);
+        assert_eq!(value("test_tree_count"), 1005.0);
+        assert!(value("test_unsealed_bytes") > 0.0);
+        Ok(())
+    }
+}
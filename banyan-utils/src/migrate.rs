@@ -0,0 +1,112 @@
+//! copying trees from one store to another
+//!
+//! All blocks of the selected trees are copied and read back from the target to verify their
+//! digests, so a migration either succeeds completely or reports which block failed.
+//! Links embedded in values are not followed, only the blocks of the trees themselves are copied.
+use std::collections::BTreeSet;
+
+use anyhow::{ensure, Result};
+use banyan::{
+    query::AllQuery,
+    store::{BlockWriter, ReadOnlyStore},
+    Forest, Secrets,
+};
+
+use crate::tags::{Digest, TT};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// number of trees that were copied
+    pub roots: usize,
+    /// number of distinct blocks that were copied and verified
+    pub blocks: u64,
+    /// total size of the copied blocks
+    pub bytes: u64,
+    /// number of purged nodes that had no block to copy
+    pub purged: u64,
+}
+
+/// copies all blocks of the trees with the given roots from `forest` to `target`
+///
+/// Every block is read back from the target after writing, and its digest is checked.
+pub fn migrate<D, R, W>(
+    forest: &Forest<TT<D>, R>,
+    target: &mut W,
+    secrets: Secrets,
+    roots: &[D],
+) -> Result<MigrationReport>
+where
+    D: Digest,
+    R: ReadOnlyStore<D> + Clone + Send + Sync + 'static,
+    W: BlockWriter<D> + ReadOnlyStore<D>,
+{
+    let mut report = MigrationReport::default();
+    let mut copied = BTreeSet::new();
+    for root in roots {
+        let tree = forest.load_tree::<()>(secrets.clone(), *root)?;
+        for index in forest.iter_index(&tree, AllQuery) {
+            let link = match *index?.link() {
+                Some(link) => link,
+                None => {
+                    report.purged += 1;
+                    continue;
+                }
+            };
+            if !copied.insert(link) {
+                continue;
+            }
+            let data = forest.store().get(&link)?;
+            report.bytes += data.len() as u64;
+            let written = target.put(data.to_vec())?;
+            ensure!(
+                written == link,
+                "block {} was stored as {} in the target",
+                link,
+                written
+            );
+            let verify = target.get(&link)?;
+            ensure!(
+                D::digest(&verify) == link,
+                "block {} is corrupted in the target",
+                link
+            );
+            report.blocks += 1;
+        }
+        report.roots += 1;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tag_index::TagSet,
+        tags::{Key, Sha256Digest},
+    };
+    use banyan::{
+        store::{BranchCache, MemStore},
+        Config, StreamBuilder, Transaction,
+    };
+
+    #[test]
+    fn migrate_memstore() -> Result<()> {
+        let source = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut txn = Transaction::new(Forest::new(source.clone(), BranchCache::default()), source);
+        let mut builder = StreamBuilder::<TT, u64>::new(Config::debug(), Secrets::default());
+        let xs = (0..1000u64).map(|i| (Key::single(i, i, TagSet::empty()), i));
+        txn.extend(&mut builder, xs)?;
+        let root = builder.link().unwrap();
+
+        let mut target = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let report = migrate(txn.read(), &mut target, Secrets::default(), &[root, root])?;
+        assert_eq!(report.roots, 2);
+        assert!(report.blocks > 1);
+
+        // the tree can be read completely from the target
+        let forest = Forest::<TT, _>::new(target, BranchCache::default());
+        let tree = forest.load_tree::<u64>(Secrets::default(), root)?;
+        assert_eq!(forest.collect(&tree)?.len(), 1000);
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+//! conversion of query results to arrow record batches
+//!
+//! The elements of each leaf that match the query become one [RecordBatch], so the batch size
+//! follows the leaf size of the tree. Leaves without matching elements produce no batch.
+//!
+//! How keys and values are mapped to columns is up to the caller, see [ArrowMapping].
+//!
+//! With the `parquet` feature, [export_parquet] writes the batches to a parquet file.
+use anyhow::Result;
+use arrow::{
+    array::{ArrayRef, UInt64Array},
+    datatypes::SchemaRef,
+    record_batch::RecordBatch,
+};
+use banyan::{
+    query::Query,
+    store::{BanyanValue, ReadOnlyStore},
+    Forest, Tree, TreeTypes,
+};
+use futures::prelude::*;
+#[cfg(feature = "parquet")]
+use parquet::arrow::ArrowWriter;
+#[cfg(feature = "parquet")]
+use std::io::Write;
+use std::sync::Arc;
+
+/// Maps elements with keys of type `K` and values of type `V` to the columns of a record batch
+pub trait ArrowMapping<K, V>: Send + Sync + 'static {
+    /// the schema of all produced batches
+    fn schema(&self) -> SchemaRef;
+
+    /// the columns for the given elements, in the order of the schema
+    ///
+    /// Each element is a tuple of offset, key and value.
+    fn columns(&self, rows: &[(u64, K, V)]) -> Result<Vec<ArrayRef>>;
+}
+
+/// A column containing the offsets of the given elements
+pub fn offset_column<K, V>(rows: &[(u64, K, V)]) -> ArrayRef {
+    let offsets = rows
+        .iter()
+        .map(|(offset, _, _)| *offset)
+        .collect::<Vec<_>>();
+    Arc::new(UInt64Array::from(offsets))
+}
+
+fn to_record_batch<K, V, M: ArrowMapping<K, V>>(
+    mapping: &M,
+    rows: &[(u64, K, V)],
+) -> Result<RecordBatch> {
+    Ok(RecordBatch::try_new(
+        mapping.schema(),
+        mapping.columns(rows)?,
+    )?)
+}
+
+/// Record batches for the elements of a tree matching a query, one per leaf
+pub fn record_batches<T, R, V, Q, M>(
+    forest: &Forest<T, R>,
+    tree: &Tree<T, V>,
+    query: Q,
+    mapping: M,
+) -> impl Iterator<Item = Result<RecordBatch>> + 'static
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+    V: BanyanValue,
+    Q: Query<T>,
+    M: ArrowMapping<T::Key, V>,
+{
+    forest
+        .iter_filtered_chunked(tree, query, &|_| ())
+        .filter_map(move |chunk| match chunk {
+            Ok(chunk) if chunk.data.is_empty() => None,
+            Ok(chunk) => Some(to_record_batch(&mapping, &chunk.data)),
+            Err(cause) => Some(Err(cause)),
+        })
+}
+
+/// Stream of record batches for the elements of a tree matching a query, one per leaf
+pub fn stream_record_batches<T, R, V, Q, M>(
+    forest: &Forest<T, R>,
+    tree: &Tree<T, V>,
+    query: Q,
+    mapping: M,
+) -> impl Stream<Item = Result<RecordBatch>> + 'static
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+    V: BanyanValue,
+    Q: Query<T>,
+    M: ArrowMapping<T::Key, V>,
+{
+    forest
+        .stream_filtered_chunked(tree, query, &|_| ())
+        .filter_map(move |chunk| {
+            future::ready(match chunk {
+                Ok(chunk) if chunk.data.is_empty() => None,
+                Ok(chunk) => Some(to_record_batch(&mapping, &chunk.data)),
+                Err(cause) => Some(Err(cause)),
+            })
+        })
+}
+
+/// Writes the elements of a tree matching a query to a parquet file, and returns their number
+///
+/// Each leaf becomes one row group, so row groups have the leaf size of the tree.
+#[cfg(feature = "parquet")]
+pub fn export_parquet<T, R, V, Q, M, W>(
+    forest: &Forest<T, R>,
+    tree: &Tree<T, V>,
+    query: Q,
+    mapping: M,
+    writer: W,
+) -> Result<u64>
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+    V: BanyanValue,
+    Q: Query<T>,
+    M: ArrowMapping<T::Key, V>,
+    W: Write,
+{
+    let mut writer = ArrowWriter::try_new(writer, mapping.schema(), None)?;
+    let mut rows = 0;
+    for batch in record_batches(forest, tree, query, mapping) {
+        let batch = batch?;
+        rows += batch.num_rows() as u64;
+        writer.write(&batch)?;
+        writer.flush()?;
+    }
+    writer.close()?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::{Key, Sha256Digest, Tag, TagSet, TT};
+    use arrow::{
+        array::Array,
+        datatypes::{DataType, Field, Schema},
+    };
+    use banyan::{
+        query::{AllQuery, OffsetRangeQuery},
+        store::{BranchCache, MemStore},
+        Config, Forest, Secrets, StreamBuilder, Transaction,
+    };
+
+    struct TimeAndValue(SchemaRef);
+
+    impl ArrowMapping<Key, u64> for TimeAndValue {
+        fn schema(&self) -> SchemaRef {
+            self.0.clone()
+        }
+
+        fn columns(&self, rows: &[(u64, Key, u64)]) -> Result<Vec<ArrayRef>> {
+            let times = rows
+                .iter()
+                .map(|(_, k, _)| k.min_time())
+                .collect::<Vec<_>>();
+            let values = rows.iter().map(|(_, _, v)| *v).collect::<Vec<_>>();
+            Ok(vec![
+                offset_column(rows),
+                Arc::new(UInt64Array::from(times)),
+                Arc::new(UInt64Array::from(values)),
+            ])
+        }
+    }
+
+    #[test]
+    fn batches_per_leaf() -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+        let mut builder = StreamBuilder::<TT, u64>::new(Config::debug(), Secrets::default());
+        let xs = (0..100u64).map(|i| (Key::single(i, i * 10, TagSet::single(Tag::from("a"))), i));
+        txn.extend(&mut builder, xs)?;
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::UInt64, false),
+            Field::new("time", DataType::UInt64, false),
+            Field::new("value", DataType::UInt64, false),
+        ]));
+        let mapping = TimeAndValue(schema.clone());
+        let batches = record_batches(&txn, &builder.snapshot(), AllQuery, mapping)
+            .collect::<Result<Vec<_>>>()?;
+        assert!(batches.len() > 1);
+        assert!(batches.iter().all(|batch| batch.schema() == schema));
+        let mut offsets = Vec::new();
+        for batch in &batches {
+            let column = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<UInt64Array>()
+                .unwrap();
+            offsets.extend(column.values().iter().copied());
+        }
+        assert_eq!(offsets, (0..100).collect::<Vec<_>>());
+
+        let mapping = TimeAndValue(schema);
+        let batches = futures::executor::block_on_stream(stream_record_batches(
+            &txn,
+            &builder.snapshot(),
+            OffsetRangeQuery::from(10..12),
+            mapping,
+        ))
+        .collect::<Result<Vec<_>>>()?;
+        let rows = batches.iter().map(|batch| batch.num_rows()).sum::<usize>();
+        assert_eq!(rows, 2);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "parquet")]
+    fn parquet_row_groups() -> Result<()> {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+        let mut builder = StreamBuilder::<TT, u64>::new(Config::debug(), Secrets::default());
+        let xs = (0..100u64).map(|i| (Key::single(i, i * 10, TagSet::single(Tag::from("a"))), i));
+        txn.extend(&mut builder, xs)?;
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::UInt64, false),
+            Field::new("time", DataType::UInt64, false),
+            Field::new("value", DataType::UInt64, false),
+        ]));
+        let tree = builder.snapshot();
+        let leaves = record_batches(&txn, &tree, AllQuery, TimeAndValue(schema.clone())).count();
+        let path =
+            std::env::temp_dir().join(format!("banyan-export-{}.parquet", std::process::id()));
+        let rows = export_parquet(
+            &txn,
+            &tree,
+            AllQuery,
+            TimeAndValue(schema),
+            std::fs::File::create(&path)?,
+        )?;
+        assert_eq!(rows, 100);
+        let reader = SerializedFileReader::new(std::fs::File::open(&path)?)?;
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 100);
+        assert_eq!(reader.metadata().num_row_groups(), leaves);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+//! block store backed by rocksdb
+use std::{convert::TryFrom, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use banyan::{
+    error::BlockNotFound,
+    store::{BlockWriter, ReadOnlyStore},
+};
+use libipld::Cid;
+use rocksdb::{ColumnFamily, Options, WriteBatch, WriteOptions, DB};
+
+use crate::tags::Digest;
+
+/// column family for the blocks, keyed by cid
+const BLOCKS: &str = "blocks";
+/// column family for named roots
+const ROOTS: &str = "roots";
+
+/// Write settings of a [RocksStore]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RocksWriteConfig {
+    /// fsync after every write. Without this, a machine crash can lose the latest writes.
+    pub sync: bool,
+    /// skip the write ahead log. Faster, but a process crash can lose the latest writes.
+    pub disable_wal: bool,
+}
+
+impl RocksWriteConfig {
+    fn options(&self) -> WriteOptions {
+        let mut options = WriteOptions::default();
+        options.set_sync(self.sync);
+        options.disable_wal(self.disable_wal);
+        options
+    }
+}
+
+/// A store backed by rocksdb, with column families for blocks and named roots
+///
+/// It can be used with any [Digest] as link type. Unlike the sqlite store, there is no garbage
+/// collection. Clones share the database.
+#[derive(Clone)]
+pub struct RocksStore {
+    db: Arc<DB>,
+    write: RocksWriteConfig,
+}
+
+impl RocksStore {
+    /// opens or creates a database at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        Self::open_with(path, options)
+    }
+
+    /// opens a database at `path` with custom options, e.g. for tuning compaction
+    ///
+    /// Blocks are compressed and encrypted already, so rocksdb compression does not help much.
+    pub fn open_with(path: impl AsRef<Path>, options: Options) -> Result<Self> {
+        let db = DB::open_cf(&options, path, [BLOCKS, ROOTS])?;
+        Ok(Self {
+            db: Arc::new(db),
+            write: RocksWriteConfig::default(),
+        })
+    }
+
+    pub fn with_write_config(mut self, write: RocksWriteConfig) -> Self {
+        self.write = write;
+        self
+    }
+
+    fn cf(&self, name: &str) -> Result<&ColumnFamily> {
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| anyhow!("missing column family {}", name))
+    }
+
+    /// sets or removes a named root
+    pub fn alias<D: Digest>(&self, name: &str, link: Option<D>) -> Result<()> {
+        let cf = self.cf(ROOTS)?;
+        match link {
+            Some(link) => {
+                let cid: Cid = link.into();
+                self.db
+                    .put_cf_opt(cf, name, cid.to_bytes(), &self.write.options())?
+            }
+            None => self.db.delete_cf_opt(cf, name, &self.write.options())?,
+        }
+        Ok(())
+    }
+
+    /// resolves a named root
+    pub fn resolve<D: Digest>(&self, name: &str) -> Result<Option<D>> {
+        self.db
+            .get_cf(self.cf(ROOTS)?, name)?
+            .map(|bytes| D::try_from(Cid::try_from(bytes.as_slice())?))
+            .transpose()
+    }
+}
+
+impl<D: Digest> ReadOnlyStore<D> for RocksStore {
+    fn get(&self, link: &D) -> Result<Box<[u8]>> {
+        let cid: Cid = (*link).into();
+        match self.db.get_pinned_cf(self.cf(BLOCKS)?, cid.to_bytes())? {
+            Some(block) => Ok(block.as_ref().into()),
+            None => Err(BlockNotFound.into()),
+        }
+    }
+
+    fn get_many(&self, links: &[D]) -> Result<Vec<Box<[u8]>>> {
+        let cf = self.cf(BLOCKS)?;
+        let keys = links.iter().map(|link| {
+            let cid: Cid = (*link).into();
+            (cf, cid.to_bytes())
+        });
+        self.db
+            .multi_get_cf(keys)
+            .into_iter()
+            .map(|res| match res? {
+                Some(block) => Ok(block.into()),
+                None => Err(BlockNotFound.into()),
+            })
+            .collect()
+    }
+}
+
+impl<D: Digest> BlockWriter<D> for RocksStore {
+    fn put(&mut self, data: Vec<u8>) -> Result<D> {
+        let digest = D::digest(&data);
+        let cid: Cid = digest.into();
+        self.db.put_cf_opt(
+            self.cf(BLOCKS)?,
+            cid.to_bytes(),
+            data,
+            &self.write.options(),
+        )?;
+        Ok(digest)
+    }
+
+    /// writes all blocks in one atomic write batch
+    fn put_many(&mut self, blocks: Vec<Vec<u8>>) -> Result<Vec<D>> {
+        let cf = self.cf(BLOCKS)?;
+        let mut batch = WriteBatch::default();
+        let mut links = Vec::with_capacity(blocks.len());
+        for data in blocks {
+            let digest = D::digest(&data);
+            let cid: Cid = digest.into();
+            batch.put_cf(cf, cid.to_bytes(), data);
+            links.push(digest);
+        }
+        self.db.write_opt(batch, &self.write.options())?;
+        Ok(links)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.write.disable_wal {
+            // without a wal, writes are only durable once the memtables are written to disk
+            self.db.flush_cf(self.cf(BLOCKS)?)?;
+            self.db.flush_cf(self.cf(ROOTS)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::Sha256Digest;
+
+    #[test]
+    fn rocks_store() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut store = RocksStore::open(dir.path())?;
+        let link: Sha256Digest = store.put(b"block".to_vec())?;
+        assert_eq!(store.get(&link)?.as_ref(), b"block");
+        let missing = Sha256Digest::new(b"missing");
+        assert!(store.get(&missing).is_err());
+        let links: Vec<Sha256Digest> = store.put_many(vec![b"a".to_vec(), b"b".to_vec()])?;
+        let blocks = store.get_many(&[links[1], link, links[0]])?;
+        assert_eq!(blocks[0].as_ref(), b"b");
+        assert_eq!(blocks[1].as_ref(), b"block");
+        assert_eq!(blocks[2].as_ref(), b"a");
+        assert!(store.get_many(&[link, missing]).is_err());
+        store.alias("root", Some(link))?;
+        assert_eq!(store.resolve::<Sha256Digest>("root")?, Some(link));
+        store.alias::<Sha256Digest>("root", None)?;
+        assert_eq!(store.resolve::<Sha256Digest>("root")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_store_flush() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut store = RocksStore::open(dir.path())?.with_write_config(RocksWriteConfig {
+            sync: false,
+            disable_wal: true,
+        });
+        let links: Vec<Sha256Digest> = store.put_many(vec![b"a".to_vec(), b"b".to_vec()])?;
+        store.alias("root", Some(links[1]))?;
+        BlockWriter::<Sha256Digest>::flush(&mut store)?;
+        drop(store);
+        let store = RocksStore::open(dir.path())?;
+        assert_eq!(store.get(&links[0])?.as_ref(), b"a");
+        assert_eq!(store.get(&links[1])?.as_ref(), b"b");
+        assert_eq!(store.resolve::<Sha256Digest>("root")?, Some(links[1]));
+        Ok(())
+    }
+}
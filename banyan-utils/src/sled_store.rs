@@ -0,0 +1,136 @@
+//! block store backed by sled, without any C dependencies
+use std::{convert::TryFrom, path::Path};
+
+use anyhow::Result;
+use banyan::{
+    error::BlockNotFound,
+    store::{BlockWriter, ReadOnlyStore},
+};
+use libipld::Cid;
+
+use crate::tags::Digest;
+
+/// A store backed by a sled database, with trees for blocks and named roots
+///
+/// It can be used with any [Digest] as link type. There is no garbage collection. Clones share
+/// the database.
+#[derive(Clone)]
+pub struct SledStore {
+    db: sled::Db,
+    blocks: sled::Tree,
+    roots: sled::Tree,
+}
+
+impl SledStore {
+    /// opens or creates a database at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(sled::open(path)?)
+    }
+
+    /// uses an already opened database, e.g. one opened with a custom `sled::Config`
+    pub fn new(db: sled::Db) -> Result<Self> {
+        Ok(Self {
+            blocks: db.open_tree("blocks")?,
+            roots: db.open_tree("roots")?,
+            db,
+        })
+    }
+
+    /// sets or removes a named root
+    pub fn alias<D: Digest>(&self, name: &str, link: Option<D>) -> Result<()> {
+        match link {
+            Some(link) => {
+                let cid: Cid = link.into();
+                self.roots.insert(name, cid.to_bytes())?;
+            }
+            None => {
+                self.roots.remove(name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// resolves a named root
+    pub fn resolve<D: Digest>(&self, name: &str) -> Result<Option<D>> {
+        self.roots
+            .get(name)?
+            .map(|bytes| D::try_from(Cid::try_from(bytes.as_ref())?))
+            .transpose()
+    }
+
+    /// waits until all writes are on disk. sled also does this periodically in the background.
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+impl<D: Digest> ReadOnlyStore<D> for SledStore {
+    fn get(&self, link: &D) -> Result<Box<[u8]>> {
+        let cid: Cid = (*link).into();
+        match self.blocks.get(cid.to_bytes())? {
+            Some(block) => Ok(block.as_ref().into()),
+            None => Err(BlockNotFound.into()),
+        }
+    }
+}
+
+impl<D: Digest> BlockWriter<D> for SledStore {
+    fn put(&mut self, data: Vec<u8>) -> Result<D> {
+        let digest = D::digest(&data);
+        let cid: Cid = digest.into();
+        self.blocks.insert(cid.to_bytes(), data)?;
+        Ok(digest)
+    }
+
+    /// writes all blocks in one atomic batch
+    fn put_many(&mut self, blocks: Vec<Vec<u8>>) -> Result<Vec<D>> {
+        let mut batch = sled::Batch::default();
+        let mut links = Vec::with_capacity(blocks.len());
+        for data in blocks {
+            let digest = D::digest(&data);
+            let cid: Cid = digest.into();
+            batch.insert(cid.to_bytes(), data);
+            links.push(digest);
+        }
+        self.blocks.apply_batch(batch)?;
+        Ok(links)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        SledStore::flush(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::Sha256Digest;
+
+    #[test]
+    fn sled_store() -> Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let mut store = SledStore::new(db)?;
+        let link: Sha256Digest = store.put(b"block".to_vec())?;
+        assert_eq!(store.get(&link)?.as_ref(), b"block");
+        assert!(store.get(&Sha256Digest::new(b"missing")).is_err());
+        store.alias("root", Some(link))?;
+        assert_eq!(store.resolve::<Sha256Digest>("root")?, Some(link));
+        store.alias::<Sha256Digest>("root", None)?;
+        assert_eq!(store.resolve::<Sha256Digest>("root")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn sled_store_flush() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut store = SledStore::open(dir.path())?;
+        let links: Vec<Sha256Digest> = store.put_many(vec![b"a".to_vec(), b"b".to_vec()])?;
+        BlockWriter::<Sha256Digest>::flush(&mut store)?;
+        drop(store);
+        let store = SledStore::open(dir.path())?;
+        assert_eq!(store.get(&links[0])?.as_ref(), b"a");
+        assert_eq!(store.get(&links[1])?.as_ref(), b"b");
+        Ok(())
+    }
+}
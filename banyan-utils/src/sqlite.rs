@@ -1,46 +1,101 @@
 //! helper methods to work with ipfs/ipld
-use anyhow::{anyhow, Result};
-use banyan::store::{BlockWriter, ReadOnlyStore};
+use anyhow::Result;
+use banyan::{
+    error::BlockNotFound,
+    store::{BlockWriter, ReadOnlyStore},
+};
 use ipfs_sqlite_block_store::BlockStore;
-use libipld::{codec::References, store::StoreParams, Block, Cid, Ipld};
+use libipld::{codec::References, store::StoreParams, Block, Ipld};
 use parking_lot::Mutex;
 use std::sync::Arc;
 
-use crate::tags::Sha256Digest;
+use crate::{ipfs::CidFormat, tags::Digest};
 
+/// A store backed by an sqlite block store
+///
+/// It can be used with any [Digest] as link type.
 #[derive(Clone)]
-pub struct SqliteStore<S: StoreParams>(Arc<Mutex<BlockStore<S>>>);
+pub struct SqliteStore<S: StoreParams>(Arc<Mutex<BlockStore<S>>>, CidFormat);
 
 impl<S: StoreParams> SqliteStore<S> {
     pub fn new(store: BlockStore<S>) -> anyhow::Result<Self> {
-        Ok(SqliteStore(Arc::new(Mutex::new(store))))
+        Ok(SqliteStore(
+            Arc::new(Mutex::new(store)),
+            CidFormat::default(),
+        ))
+    }
+
+    /// uses `format` for the cids under which blocks are stored
+    ///
+    /// Blocks are looked up by cid, so blocks written with one format can not be read with
+    /// another. The codec must be supported by `S`.
+    pub fn with_cid_format(mut self, format: CidFormat) -> Self {
+        self.1 = format;
+        self
     }
 }
 
-impl<S: StoreParams> ReadOnlyStore<Sha256Digest> for SqliteStore<S>
+impl<S: StoreParams> SqliteStore<S>
 where
     Ipld: References<S::Codecs>,
 {
-    fn get(&self, link: &Sha256Digest) -> Result<Box<[u8]>> {
-        let cid = Cid::from(*link);
+    /// sets or removes a named root. Everything reachable from an alias is kept by [gc](#method.gc)
+    pub fn alias<D: Digest>(&self, name: &str, link: Option<D>) -> Result<()> {
+        let cid = link.map(|link| self.1.to_cid(link)).transpose()?;
+        self.0.lock().alias(name.as_bytes(), cid.as_ref())?;
+        Ok(())
+    }
+
+    /// resolves a named root
+    pub fn resolve<D: Digest>(&self, name: &str) -> Result<Option<D>> {
+        let cid = self.0.lock().resolve(name.as_bytes())?;
+        cid.map(|cid| self.1.from_cid(cid)).transpose()
+    }
+
+    /// deletes all blocks that are not reachable from an alias
+    pub fn gc(&self) -> Result<()> {
+        self.0.lock().gc()?;
+        Ok(())
+    }
+}
+
+impl<S: StoreParams, D: Digest> ReadOnlyStore<D> for SqliteStore<S>
+where
+    Ipld: References<S::Codecs>,
+{
+    fn get(&self, link: &D) -> Result<Box<[u8]>> {
+        let cid = self.1.to_cid(*link)?;
         let block = self.0.lock().get_block(&cid)?;
         if let Some(block) = block {
             Ok(block.into())
         } else {
-            Err(anyhow!("block not found!"))
+            Err(BlockNotFound.into())
         }
     }
 }
 
-impl<S: StoreParams> BlockWriter<Sha256Digest> for SqliteStore<S>
+impl<S: StoreParams, D: Digest> BlockWriter<D> for SqliteStore<S>
 where
     Ipld: References<S::Codecs>,
 {
-    fn put(&mut self, data: Vec<u8>) -> Result<Sha256Digest> {
-        let digest = Sha256Digest::new(&data);
-        let cid = digest.into();
+    fn put(&mut self, data: Vec<u8>) -> Result<D> {
+        let digest = D::digest(&data);
+        let cid = self.1.to_cid(digest)?;
         let block = Block::new_unchecked(cid, data);
         self.0.lock().put_block(block, None)?;
         Ok(digest)
     }
+
+    /// writes all blocks in one sqlite transaction
+    fn put_many(&mut self, blocks: Vec<Vec<u8>>) -> Result<Vec<D>> {
+        let mut links = Vec::with_capacity(blocks.len());
+        let mut batch = Vec::with_capacity(blocks.len());
+        for data in blocks {
+            let digest = D::digest(&data);
+            batch.push(Block::new_unchecked(self.1.to_cid(digest)?, data));
+            links.push(digest);
+        }
+        self.0.lock().put_blocks(batch, None)?;
+        Ok(links)
+    }
 }
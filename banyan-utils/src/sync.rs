@@ -0,0 +1,273 @@
+//! replicating trees between block stores
+//!
+//! A [SyncRequest] describes the blocks to replicate: all blocks under a root, or just the blocks
+//! needed for a range of offsets. [sync] copies these blocks from any [ReadOnlyStore], such as a
+//! store that fetches from a peer, and only fetches the blocks that are not already present in
+//! the target.
+//!
+//! This module does not implement a network protocol. [SyncRequest::selector] only constructs the
+//! standard ipld [selector] for all blocks under the root, to be handed to a GraphSync client
+//! that is not part of this crate.
+//!
+//! Without an offset range, no keys are needed, since the links of each block are stored
+//! unencrypted. Blocks linked from values are copied as well, just like a selector would, as long
+//! as their cids have the codec and hash of the link type. Other links are counted in
+//! [SyncReport::skipped]. An offset range can only be resolved with the index key, and only covers
+//! the blocks of the tree.
+//!
+//! [selector]: https://github.com/ipld/specs/blob/master/selectors/selectors.md
+use std::{
+    collections::{BTreeSet, VecDeque},
+    ops::Range,
+};
+
+use anyhow::{ensure, Result};
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Forest, Secrets,
+};
+use libipld::{cbor::DagCborCodec, codec::Codec, ipld, Cid, Ipld};
+
+use crate::tags::{Digest, TT};
+
+/// number of blocks to get from the remote store in one go
+const BATCH_SIZE: usize = 64;
+
+/// The blocks of a tree to replicate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncRequest<D> {
+    /// root of the tree
+    pub root: D,
+    /// only the blocks needed to read these offsets, or all blocks if None
+    pub offsets: Option<Range<u64>>,
+}
+
+impl<D: Digest> SyncRequest<D> {
+    /// all blocks under `root`
+    pub fn all(root: D) -> Self {
+        Self {
+            root,
+            offsets: None,
+        }
+    }
+
+    /// the blocks under `root` that are needed to read `offsets`
+    pub fn range(root: D, offsets: Range<u64>) -> Self {
+        Self {
+            root,
+            offsets: Some(offsets),
+        }
+    }
+
+    /// the selector to send to a GraphSync peer, in the ipld representation
+    ///
+    /// Counts are encrypted, so the peer can not restrict the traversal to an offset range. For
+    /// a range, the selector matches all blocks, and the range has to be applied using [sync]
+    /// against a store that fetches from the peer on demand.
+    pub fn selector(&self) -> Ipld {
+        // ExploreRecursive(limit: none, sequence: ExploreAll(next: ExploreRecursiveEdge))
+        ipld!({
+            "R": {
+                "l": { "none": {} },
+                ":>": { "a": { ">": { "@": {} } } },
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// number of blocks that were fetched from the remote store
+    pub fetched: u64,
+    /// total size of the fetched blocks
+    pub bytes: u64,
+    /// number of blocks that were already present in the target
+    pub present: u64,
+    /// number of links with a different codec or hash than the link type, which were not followed
+    pub skipped: u64,
+}
+
+/// copies the blocks of `request` that are missing in `target` from `remote`
+///
+/// The secrets are only used for an offset range. Blocks are fetched from `remote` in batches
+/// using [ReadOnlyStore::get_many], and their digests are checked before they are written with
+/// [BlockWriter::put_many].
+pub fn sync<D, S, W>(
+    remote: &S,
+    target: &mut W,
+    secrets: Secrets,
+    request: &SyncRequest<D>,
+) -> Result<SyncReport>
+where
+    D: Digest,
+    S: ReadOnlyStore<D>,
+    W: BlockWriter<D> + ReadOnlyStore<D>,
+{
+    let mut report = SyncReport::default();
+    match &request.offsets {
+        None => sync_all(remote, target, request.root, &mut report)?,
+        Some(offsets) => {
+            // the branches are only read, so a cache does not help much here
+            let forest = Forest::<TT<D>, S>::new(remote.clone(), BranchCache::default());
+            let tree = forest.load_tree::<()>(secrets, request.root)?;
+            let mut missing = Vec::new();
+            for index in forest.iter_index(&tree, OffsetRangeQuery::from(offsets.clone())) {
+                if let Some(link) = *index?.link() {
+                    if target.get(&link).is_ok() {
+                        report.present += 1;
+                    } else {
+                        missing.push(link);
+                    }
+                }
+            }
+            for batch in missing.chunks(BATCH_SIZE) {
+                copy(remote, target, batch, &mut report)?;
+            }
+        }
+    }
+    target.flush()?;
+    Ok(report)
+}
+
+/// breadth first traversal of the link sections, starting at `root`
+fn sync_all<D, S, W>(remote: &S, target: &mut W, root: D, report: &mut SyncReport) -> Result<()>
+where
+    D: Digest,
+    S: ReadOnlyStore<D>,
+    W: BlockWriter<D> + ReadOnlyStore<D>,
+{
+    let mut seen = BTreeSet::new();
+    let mut queue = VecDeque::new();
+    seen.insert(root);
+    queue.push_back(root);
+    while !queue.is_empty() {
+        let n = queue.len().min(BATCH_SIZE);
+        let batch = queue.drain(..n).collect::<Vec<_>>();
+        let mut blocks = Vec::with_capacity(batch.len());
+        let mut missing = Vec::new();
+        for link in batch {
+            match target.get(&link) {
+                Ok(block) => {
+                    report.present += 1;
+                    blocks.push(block);
+                }
+                Err(_) => missing.push(link),
+            }
+        }
+        blocks.extend(copy(remote, target, &missing, report)?);
+        for block in blocks {
+            let ipld: Ipld = DagCborCodec.decode(&block)?;
+            let mut links = BTreeSet::<Cid>::new();
+            ipld.references(&mut links);
+            for cid in links {
+                match D::try_from(cid) {
+                    Ok(link) => {
+                        if seen.insert(link) {
+                            queue.push_back(link);
+                        }
+                    }
+                    // e.g. a raw block linked from a value, which the remote store can not serve
+                    Err(_) => report.skipped += 1,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// fetches `links` from `remote` and writes them to `target`, returning the blocks
+fn copy<D, S, W>(
+    remote: &S,
+    target: &mut W,
+    links: &[D],
+    report: &mut SyncReport,
+) -> Result<Vec<Box<[u8]>>>
+where
+    D: Digest,
+    S: ReadOnlyStore<D>,
+    W: BlockWriter<D>,
+{
+    if links.is_empty() {
+        return Ok(Vec::new());
+    }
+    let blocks = remote.get_many(links)?;
+    ensure!(
+        blocks.len() == links.len(),
+        "remote store returned wrong number of blocks"
+    );
+    for (link, block) in links.iter().zip(&blocks) {
+        ensure!(D::digest(block) == *link, "block {} is corrupted", link);
+        report.fetched += 1;
+        report.bytes += block.len() as u64;
+    }
+    target.put_many(blocks.iter().map(|block| block.to_vec()).collect())?;
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tag_index::TagSet,
+        tags::{Key, Sha256Digest},
+    };
+    use banyan::{store::MemStore, Config, StreamBuilder, Transaction};
+    use multihash::MultihashDigest;
+
+    #[test]
+    fn sync_memstore() -> Result<()> {
+        let remote = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut txn = Transaction::new(Forest::new(remote.clone(), BranchCache::default()), remote);
+        let mut builder = StreamBuilder::<TT, u64>::new(Config::debug(), Secrets::default());
+        let xs = (0..1000u64).map(|i| (Key::single(i, i, TagSet::empty()), i));
+        txn.extend(&mut builder, xs)?;
+        let root = builder.link().unwrap();
+        let remote = txn.into_writer();
+
+        // a range only needs part of the tree
+        let mut target = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let range = sync(
+            &remote,
+            &mut target,
+            Secrets::default(),
+            &SyncRequest::range(root, 0..10),
+        )?;
+        assert!(range.fetched > 0);
+
+        // the rest of the tree is fetched without keys, and the range is not fetched again
+        let all = sync(
+            &remote,
+            &mut target,
+            Secrets::default(),
+            &SyncRequest::all(root),
+        )?;
+        assert_eq!(all.present, range.fetched);
+        let forest = Forest::<TT, _>::new(target, BranchCache::default());
+        let tree = forest.load_tree::<u64>(Secrets::default(), root)?;
+        assert_eq!(forest.collect(&tree)?.len(), 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_skips_foreign_links() -> Result<()> {
+        let mut remote = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let child: Sha256Digest = remote.put(DagCborCodec.encode(&Ipld::Integer(1))?)?;
+        let raw = Cid::new_v1(0x55, multihash::Code::Sha2_256.digest(b"raw"));
+        let root: Sha256Digest = remote.put(
+            DagCborCodec.encode(&Ipld::List(vec![Ipld::Link(child.into()), Ipld::Link(raw)]))?,
+        )?;
+
+        let mut target = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let report = sync(
+            &remote,
+            &mut target,
+            Secrets::default(),
+            &SyncRequest::all(root),
+        )?;
+        assert_eq!(report.fetched, 2);
+        assert_eq!(report.skipped, 1);
+        assert!(target.get(&child).is_ok());
+        Ok(())
+    }
+}
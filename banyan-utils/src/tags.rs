@@ -1,4 +1,7 @@
-use crate::{tag_index::map_to_index_set, tag_index::TagIndex, tag_index::TagSet};
+use crate::{
+    tag_index::map_to_index_set, tag_index::IndexSet, tag_index::Tag, tag_index::TagIndex,
+    tag_index::TagSet,
+};
 use banyan::query::Query;
 use banyan::{index::*, TreeTypes};
 use libipld::{
@@ -7,18 +10,55 @@ use libipld::{
     Cid, DagCbor,
 };
 use multihash::{Code, MultihashDigest};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::{
     convert::{TryFrom, TryInto},
     fmt,
+    hash::Hash,
     io::{Read, Seek, Write},
     iter::FromIterator,
+    marker::PhantomData,
+    ops::Range,
     str::FromStr,
 };
-use vec_collections::VecSet;
 
+/// Tree types for tagged events, with links of type `D`
+///
+/// Before links were generic, this was `pub struct TT {}`. `TT` in type position still means
+/// the sha2-256 variant, and `TT::default()` replaces `TT {}`.
 #[derive(Debug, Clone)]
-pub struct TT {}
+pub struct TT<D = Sha256Digest>(PhantomData<D>);
+
+impl<D> Default for TT<D> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+/// A hash function for blocks, together with the conversion of its digests to and from [Cid]s
+///
+/// The stores in this crate and [TT] work with any implementation, so a different hash can be
+/// used by implementing this trait for a new link type. [Sha256Digest] is the default.
+pub trait Digest:
+    Copy
+    + Eq
+    + Ord
+    + Hash
+    + fmt::Debug
+    + fmt::Display
+    + FromStr<Err = anyhow::Error>
+    + Encode<DagCborCodec>
+    + Decode<DagCborCodec>
+    + Into<Cid>
+    + TryFrom<Cid, Error = anyhow::Error>
+    + Send
+    + Sync
+    + 'static
+{
+    /// hashes the data of a block
+    fn digest(data: &[u8]) -> Self;
+}
 
 #[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Sha256Digest([u8; 32]);
@@ -46,6 +86,12 @@ impl Sha256Digest {
     }
 }
 
+impl Digest for Sha256Digest {
+    fn digest(data: &[u8]) -> Self {
+        Sha256Digest::new(data)
+    }
+}
+
 impl From<Sha256Digest> for Cid {
     fn from(value: Sha256Digest) -> Self {
         // https://github.com/multiformats/multicodec/blob/master/table.csv
@@ -85,14 +131,31 @@ impl fmt::Debug for Sha256Digest {
     }
 }
 
-impl TreeTypes for TT {
+impl<D: Digest> TreeTypes for TT<D> {
     type Key = Key;
     type KeySeq = KeySeq;
     type Summary = Key;
     type SummarySeq = KeySeq;
-    type Link = Sha256Digest;
+    type Link = D;
+
+    /// Collapses the tags of summaries with more than [MAX_SUMMARY_TAGS] tags to a single tag
+    /// that stands for all tags. The tag queries of this module treat such a summary as matching.
+    fn truncate_summary(mut summary: Key, _level: u32) -> Key {
+        if summary.tags.len() > MAX_SUMMARY_TAGS {
+            summary.tags = std::iter::once(Tag::from(ANY_TAG)).collect();
+        }
+        summary
+    }
 }
 
+/// Maximum number of tags of a summary at or above [Config::summary_truncation_level]
+///
+/// [Config::summary_truncation_level]: banyan::Config::summary_truncation_level
+pub const MAX_SUMMARY_TAGS: usize = 16;
+
+/// Reserved tag that replaces the tags of a truncated summary
+const ANY_TAG: &str = "\0*";
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Key {
     time: TimeData,
@@ -169,6 +232,18 @@ impl Key {
         }
     }
 
+    pub fn tags(&self) -> &TagSet {
+        &self.tags
+    }
+
+    pub fn min_time(&self) -> u64 {
+        self.time.min_time
+    }
+
+    pub fn max_time(&self) -> u64 {
+        self.time.max_time
+    }
+
     fn intersects(&self, that: &Key) -> bool {
         self.time.intersects(&that.time) && !self.tags.is_disjoint(&that.tags)
     }
@@ -186,7 +261,7 @@ impl Key {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct DnfQuery(pub Vec<Key>);
 
 #[allow(dead_code)]
@@ -198,25 +273,37 @@ impl DnfQuery {
         self.0.iter().any(|x| x.contains(v))
     }
     fn map_into<'a>(&self, keyseq: &'a KeySeq) -> TranslatedDnfQuery<'a> {
+        let any = keyseq.any_tag();
         TranslatedDnfQuery {
             query: self
                 .0
                 .iter()
-                .map(|key| {
-                    map_to_index_set(&keyseq.tags.tags, &key.tags).map(|index_set| TranslatedKey {
+                // a conjunction with a tag missing from the table can not match
+                .filter_map(|key| {
+                    let index_set = if any.is_some() {
+                        // tags missing from the table may still be covered by the wildcard
+                        Some(
+                            key.tags
+                                .iter()
+                                .filter_map(|t| keyseq.tags.tags.as_ref().binary_search(t).ok())
+                                .map(|x| x as u32)
+                                .collect(),
+                        )
+                    } else {
+                        map_to_index_set(&keyseq.tags.tags, &key.tags)
+                    };
+                    index_set.map(|index_set| TranslatedKey {
                         index_set,
                         time: key.time,
                     })
                 })
-                .collect::<Option<Vec<_>>>()
-                .unwrap_or_default(),
+                .collect(),
             seq: keyseq,
+            any,
         }
     }
 }
 
-type IndexSet = VecSet<[u32; 4]>;
-
 struct TranslatedKey {
     index_set: IndexSet,
     time: TimeData,
@@ -225,20 +312,24 @@ struct TranslatedKey {
 struct TranslatedDnfQuery<'a> {
     query: Vec<TranslatedKey>,
     seq: &'a KeySeq,
+    /// index of [ANY_TAG] in the tag table, if present
+    any: Option<u32>,
 }
 
 impl<'a> TranslatedDnfQuery<'a> {
     fn intersects(&self, i: usize) -> bool {
+        let tags = &self.seq.tags.elements[i];
+        let collapsed = matches!(self.any, Some(any) if tags.contains(&any));
         self.query.iter().any(|q| {
             q.time.intersects(&self.seq.time(i).unwrap())
-                && !q.index_set.is_disjoint(&self.seq.tags.elements[i])
+                && (collapsed || !q.index_set.is_disjoint(tags))
         })
     }
 
     fn contains(&self, i: usize) -> bool {
         self.query.iter().any(|q| {
             q.time.contains(&self.seq.time(i).unwrap())
-                && q.index_set.is_superset(&self.seq.tags.elements[i])
+                && q.index_set.is_subset(&self.seq.tags.elements[i])
         })
     }
 
@@ -259,8 +350,8 @@ impl<'a> TranslatedDnfQuery<'a> {
     }
 }
 
-impl Query<TT> for DnfQuery {
-    fn intersecting(&self, _: u64, x: &BranchIndex<TT>, matching: &mut [bool]) {
+impl<D: Digest> Query<TT<D>> for DnfQuery {
+    fn intersecting(&self, _: u64, x: &BranchIndex<TT<D>>, matching: &mut [bool]) {
         self.map_into(&x.summaries).intersecting(matching);
         // for i in 0..x.summaries.len().min(matching.len()) {
         //     if matching[i] {
@@ -268,7 +359,7 @@ impl Query<TT> for DnfQuery {
         //     }
         // }
     }
-    fn containing(&self, _: u64, x: &LeafIndex<TT>, matching: &mut [bool]) {
+    fn containing(&self, _: u64, x: &LeafIndex<TT<D>>, matching: &mut [bool]) {
         self.map_into(&x.keys).containing(matching);
         // for i in 0..x.keys.len().min(matching.len()) {
         //     if matching[i] {
@@ -278,6 +369,107 @@ impl Query<TT> for DnfQuery {
     }
 }
 
+/// A query for all events within a time range
+///
+/// Uses the min and max time of the summaries for pruning, and matches exactly at the leaf level.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TimeRangeQuery(pub Range<u64>);
+
+impl From<Range<u64>> for TimeRangeQuery {
+    fn from(value: Range<u64>) -> Self {
+        Self(value)
+    }
+}
+
+impl TimeRangeQuery {
+    fn intersects(&self, min_time: u64, max_time: u64) -> bool {
+        min_time < self.0.end && max_time >= self.0.start
+    }
+
+    fn contains(&self, min_time: u64, max_time: u64) -> bool {
+        min_time >= self.0.start && max_time < self.0.end
+    }
+}
+
+impl<D: Digest> Query<TT<D>> for TimeRangeQuery {
+    fn intersecting(&self, _: u64, x: &BranchIndex<TT<D>>, matching: &mut [bool]) {
+        let seq = &x.summaries;
+        for i in 0..seq.len().min(matching.len()) {
+            if matching[i] {
+                matching[i] = self.intersects(seq.min_time[i], seq.max_time[i]);
+            }
+        }
+    }
+    fn containing(&self, _: u64, x: &LeafIndex<TT<D>>, matching: &mut [bool]) {
+        let seq = &x.keys;
+        for i in 0..seq.len().min(matching.len()) {
+            if matching[i] {
+                matching[i] = self.contains(seq.min_time[i], seq.max_time[i]);
+            }
+        }
+    }
+}
+
+/// A pattern that is matched against individual tags
+#[derive(Debug, Clone)]
+pub enum TagPattern {
+    /// matches all tags starting with the given prefix, e.g. `machine/1/`
+    Prefix(Tag),
+    /// matches all tags matching the regular expression
+    Regex(Regex),
+}
+
+impl TagPattern {
+    pub fn prefix(prefix: impl Into<Tag>) -> Self {
+        Self::Prefix(prefix.into())
+    }
+
+    pub fn regex(regex: &str) -> anyhow::Result<Self> {
+        Ok(Self::Regex(Regex::new(regex)?))
+    }
+
+    pub fn matches(&self, tag: &str) -> bool {
+        match self {
+            Self::Prefix(prefix) => tag.starts_with(prefix.as_str()),
+            Self::Regex(regex) => regex.is_match(tag),
+        }
+    }
+}
+
+/// A query for all events that have at least one tag matching a pattern
+///
+/// The pattern is evaluated once per tag table, so pruning using the summaries is cheap.
+#[derive(Debug, Clone)]
+pub struct TagPatternQuery(pub TagPattern);
+
+impl TagPatternQuery {
+    fn matching(&self, seq: &KeySeq, matching: &mut [bool]) {
+        // indices of all tags in the table that match the pattern
+        let hits = seq
+            .tags
+            .tags
+            .iter()
+            .enumerate()
+            .filter(|(_, tag)| tag.as_str() == ANY_TAG || self.0.matches(tag))
+            .map(|(i, _)| i as u32)
+            .collect::<IndexSet>();
+        for i in 0..seq.len().min(matching.len()) {
+            if matching[i] {
+                matching[i] = !hits.is_disjoint(&seq.tags.elements[i]);
+            }
+        }
+    }
+}
+
+impl<D: Digest> Query<TT<D>> for TagPatternQuery {
+    fn intersecting(&self, _: u64, x: &BranchIndex<TT<D>>, matching: &mut [bool]) {
+        self.matching(&x.summaries, matching);
+    }
+    fn containing(&self, _: u64, x: &LeafIndex<TT<D>>, matching: &mut [bool]) {
+        self.matching(&x.keys, matching);
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, DagCbor)]
 pub struct KeySeq {
     min_lamport: Vec<u64>,
@@ -287,6 +479,16 @@ pub struct KeySeq {
 }
 
 impl KeySeq {
+    /// index of [ANY_TAG] in the tag table, if some summaries were truncated
+    fn any_tag(&self) -> Option<u32> {
+        self.tags
+            .tags
+            .as_ref()
+            .binary_search_by(|tag| tag.as_str().cmp(ANY_TAG))
+            .ok()
+            .map(|x| x as u32)
+    }
+
     fn time(&self, i: usize) -> Option<TimeData> {
         if i < self.min_lamport.len() {
             Some(TimeData {
@@ -375,3 +577,179 @@ impl FromIterator<Key> for KeySeq {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use banyan::{
+        store::{BranchCache, MemStore},
+        Config, Forest, Secrets, StreamBuilder, Transaction,
+    };
+    use libipld::Ipld;
+    use vec_collections::vecset;
+
+    fn leaf(tags: Vec<TagSet>) -> LeafIndex<TT> {
+        LeafIndex {
+            sealed: false,
+            link: None,
+            keys: tags
+                .into_iter()
+                .map(|tags| Key::single(0, 0, tags))
+                .collect(),
+            value_bytes: 0,
+            value_summary: Ipld::Null,
+        }
+    }
+
+    #[test]
+    fn tag_pattern_query() -> anyhow::Result<()> {
+        let index = leaf(vec![
+            vecset! {"machine/1/sensor/temp".into()},
+            vecset! {"machine/2/sensor/temp".into(), "a".into()},
+            vecset! {"b".into()},
+            TagSet::empty(),
+        ]);
+        let check = |pattern: TagPattern| {
+            let mut matching = [true; 4];
+            TagPatternQuery(pattern).containing(0, &index, &mut matching);
+            matching
+        };
+        assert_eq!(
+            check(TagPattern::prefix("machine/")),
+            [true, true, false, false]
+        );
+        assert_eq!(
+            check(TagPattern::regex("^machine/[0-9]+/sensor/temp$")?),
+            [true, true, false, false]
+        );
+        assert_eq!(
+            check(TagPattern::regex("^[ab]$")?),
+            [false, true, true, false]
+        );
+        assert_eq!(check(TagPattern::prefix("x")), [false; 4]);
+        Ok(())
+    }
+
+    /// A digest that is not the default, to check that everything works with other hashes
+    #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    struct Sha512Digest([u8; 64]);
+
+    impl Digest for Sha512Digest {
+        fn digest(data: &[u8]) -> Self {
+            Self(Code::Sha2_512.digest(data).digest().try_into().unwrap())
+        }
+    }
+
+    impl From<Sha512Digest> for Cid {
+        fn from(value: Sha512Digest) -> Self {
+            Cid::new_v1(0x71, multihash::Multihash::wrap(0x13, &value.0).unwrap())
+        }
+    }
+
+    impl TryFrom<Cid> for Sha512Digest {
+        type Error = anyhow::Error;
+
+        fn try_from(value: Cid) -> anyhow::Result<Self> {
+            anyhow::ensure!(value.hash().code() == 0x13, "Unexpected hash algorithm");
+            Ok(Self(value.hash().digest().try_into()?))
+        }
+    }
+
+    impl FromStr for Sha512Digest {
+        type Err = anyhow::Error;
+        fn from_str(s: &str) -> anyhow::Result<Self> {
+            Cid::from_str(s)?.try_into()
+        }
+    }
+
+    impl Encode<DagCborCodec> for Sha512Digest {
+        fn encode<W: Write>(&self, c: DagCborCodec, w: &mut W) -> anyhow::Result<()> {
+            Cid::from(*self).encode(c, w)
+        }
+    }
+
+    impl Decode<DagCborCodec> for Sha512Digest {
+        fn decode<R: Read + Seek>(c: DagCborCodec, r: &mut R) -> anyhow::Result<Self> {
+            Self::try_from(Cid::decode(c, r)?)
+        }
+    }
+
+    impl fmt::Display for Sha512Digest {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", Cid::from(*self))
+        }
+    }
+
+    impl fmt::Debug for Sha512Digest {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", Cid::from(*self))
+        }
+    }
+
+    #[test]
+    fn custom_digest() -> anyhow::Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha512Digest::digest);
+        let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+        let mut builder =
+            StreamBuilder::<TT<Sha512Digest>, u64>::new(Config::debug(), Secrets::default());
+        let xs = (0..100u64).map(|i| (Key::single(i, i, vecset! {"a".into()}), i));
+        txn.extend(&mut builder, xs)?;
+        let root = builder.link().unwrap();
+        assert_eq!(Sha512Digest::from_str(&root.to_string())?, root);
+        assert!(Sha256Digest::try_from(Cid::from(root)).is_err());
+        let tree = txn.load_tree::<u64>(Secrets::default(), root)?;
+        let found = txn
+            .iter_filtered(&tree, TimeRangeQuery(10..20))
+            .map(|res| res.map(|(_, _, v)| v))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(found, (10..20).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_summaries() -> anyhow::Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+        let config = Config {
+            summary_truncation_level: Some(1),
+            ..Config::debug()
+        };
+        let mut builder = StreamBuilder::<TT, u64>::new(config, Secrets::default());
+        let xs = (0..1000u64).map(|i| {
+            (
+                Key::single(i, i, vecset! {format!("t{}", i % 100).into()}),
+                i,
+            )
+        });
+        txn.extend(&mut builder, xs)?;
+        let tree = builder.snapshot();
+        let mut collapsed = false;
+        for index in txn.iter_index(&tree, banyan::query::AllQuery) {
+            if let Index::Branch(branch) = index? {
+                collapsed |= branch.summaries.tags.tags
+                    == std::iter::once(Tag::from(ANY_TAG)).collect::<TagSet>();
+            }
+        }
+        assert!(collapsed);
+        let found = txn
+            .iter_filtered(
+                &tree,
+                DnfQuery(vec![Key::filter_tags(vecset! {"t7".into()})]),
+            )
+            .map(|res| res.map(|(_, _, v)| v))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(
+            found,
+            (0..1000).filter(|i| i % 100 == 7).collect::<Vec<_>>()
+        );
+        let found = txn
+            .iter_filtered(&tree, TagPatternQuery(TagPattern::prefix("t9")))
+            .map(|res| res.map(|(_, _, v)| v))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let expected = (0..1000)
+            .filter(|i| i % 100 == 9 || i % 100 / 10 == 9)
+            .collect::<Vec<_>>();
+        assert_eq!(found, expected);
+        Ok(())
+    }
+}
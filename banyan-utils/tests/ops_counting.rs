@@ -1,60 +1,19 @@
-use std::{
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
-    },
-    time::{Duration, Instant},
-};
+use std::time::{Duration, Instant};
 
 use banyan::{
     query::{AllQuery, OffsetRangeQuery, Query},
-    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    store::{BranchCache, InstrumentedStore, MemStore},
     Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
 };
 use banyan_utils::{
     tag_index::TagSet,
-    tags::{Key, Sha256Digest, TT},
+    tags::{Key, Sha256Digest, TimeRangeQuery, TT},
 };
 
-#[derive(Clone)]
-struct OpsCountingStore<S> {
-    inner: S,
-    reads: Arc<AtomicU64>,
-    writes: Arc<AtomicU64>,
-}
-
-impl<S> OpsCountingStore<S> {
-    fn new(inner: S) -> Self {
-        Self {
-            inner,
-            reads: Arc::new(AtomicU64::default()),
-            writes: Arc::new(AtomicU64::default()),
-        }
-    }
-
-    fn reads(&self) -> u64 {
-        self.reads.load(Ordering::SeqCst)
-    }
-}
-
-impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for OpsCountingStore<S> {
-    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
-        self.reads.fetch_add(1, Ordering::SeqCst);
-        self.inner.get(link)
-    }
-}
-
-impl<L, S: BlockWriter<L> + Send + Sync> BlockWriter<L> for OpsCountingStore<S> {
-    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
-        self.writes.fetch_add(1, Ordering::SeqCst);
-        self.inner.put(data)
-    }
-}
-
 #[allow(clippy::type_complexity)]
 fn test_ops_count(
     name: &str,
-    forest: &Forest<TT, OpsCountingStore<MemStore<Sha256Digest>>>,
+    forest: &Forest<TT, InstrumentedStore<MemStore<Sha256Digest>>>,
     tree: &Tree<TT, u64>,
     query: impl Query<TT> + Clone + 'static,
 ) -> (Vec<anyhow::Result<(u64, Key, u64)>>, Duration, u64) {
@@ -76,6 +35,12 @@ fn ops_count_1() -> anyhow::Result<()> {
         max_key_branches: 4,
         max_uncompressed_leaf_size: 16 * 1024 * 1024,
         zstd_level: 10,
+        skip_redundant_compression: false,
+        summary_truncation_level: None,
+        adaptive_leaf_size: false,
+        ordered_keys: false,
+        pack_values: false,
+        dedup_window: 0,
     };
     let n = 1000000;
     // test with a rather large cache, but a new one on every test
@@ -86,7 +51,7 @@ fn ops_count_1() -> anyhow::Result<()> {
         .map(|i| (Key::single(i, i, TagSet::empty()), i))
         .collect::<Vec<_>>();
     let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
-    let store = OpsCountingStore::new(store);
+    let store = InstrumentedStore::new(store);
     let branch_cache = BranchCache::<TT>::new(0);
     let mut txn = Transaction::new(Forest::new(store.clone(), branch_cache), store.clone());
     let mut builder = StreamBuilder::new(config, Secrets::default());
@@ -113,20 +78,32 @@ fn ops_count_1() -> anyhow::Result<()> {
     );
     let (xs4, _, r_iter_tiny) = test_ops_count(
         "tiny   ",
-        &Forest::new(store, BranchCache::new(capacity)),
+        &Forest::new(store.clone(), BranchCache::new(capacity)),
         &tree,
         OffsetRangeQuery::from(0..10),
     );
+    // time and offset are the same for this tree
+    let (xs5, _, r_time_small) = test_ops_count(
+        "time   ",
+        &Forest::new(store, BranchCache::new(capacity)),
+        &tree,
+        TimeRangeQuery::from(0..n / 10),
+    );
 
     assert!(xs1.len() as u64 == n);
     assert!(xs2.len() as u64 == n);
     assert!(xs3.len() as u64 == n / 10);
     assert!(xs4.len() as u64 == 10);
+    assert_eq!(
+        xs5.into_iter().collect::<anyhow::Result<Vec<_>>>()?,
+        xs3.into_iter().collect::<anyhow::Result<Vec<_>>>()?
+    );
 
     assert_eq!(r_collect, 79);
     assert_eq!(r_iter, 79);
     assert_eq!(r_iter_small, 10);
     assert_eq!(r_iter_tiny, 3);
+    assert!(r_time_small <= r_iter_small);
 
     Ok(())
 }
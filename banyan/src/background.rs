@@ -0,0 +1,111 @@
+//! extending a stream on a background thread
+use crate::{
+    forest::{Secrets, Transaction, TreeTypes},
+    index::Index,
+    store::{BanyanValue, BlockWriter, ReadOnlyStore},
+    StreamBuilder, Tree,
+};
+use anyhow::{anyhow, Result};
+use parking_lot::Mutex;
+use std::{
+    sync::{mpsc, Arc},
+    thread::JoinHandle,
+};
+
+type Snapshot<T> = Option<(Index<T>, Secrets, u64)>;
+
+/// A stream builder that builds, compresses and stores leaves on a background thread
+///
+/// Appending with [push](#method.push) or [extend](#method.extend) only queues the elements, so
+/// the caller does not have to wait when a leaf is sealed. Elements that are queued while the
+/// background thread is busy are added in one go, which also avoids recompressing the unsealed
+/// leaf for every element. At most `queue_size` batches are queued, after which appending blocks
+/// until the background thread catches up.
+///
+/// [commit](#method.commit) waits for all queued elements to be added and returns the transaction
+/// and the builder. If adding elements fails, the background thread stops, and the error is
+/// returned by commit.
+pub struct BackgroundBuilder<T: TreeTypes, V, R, W> {
+    sender: Option<mpsc::SyncSender<Vec<(T::Key, V)>>>,
+    worker: Option<JoinHandle<Result<(Transaction<T, R, W>, StreamBuilder<T, V>)>>>,
+    latest: Arc<Mutex<Snapshot<T>>>,
+}
+
+impl<T, V, R, W> BackgroundBuilder<T, V, R, W>
+where
+    T: TreeTypes,
+    V: BanyanValue,
+    R: ReadOnlyStore<T::Link>,
+    W: BlockWriter<T::Link>,
+{
+    pub fn new(
+        mut txn: Transaction<T, R, W>,
+        mut builder: StreamBuilder<T, V>,
+        queue_size: usize,
+    ) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<(T::Key, V)>>(queue_size);
+        let latest = Arc::new(Mutex::new(builder.snapshot().into_inner()));
+        let worker = {
+            let latest = latest.clone();
+            std::thread::spawn(move || {
+                while let Ok(mut batch) = receiver.recv() {
+                    // take everything that has been queued in the meantime
+                    batch.extend(receiver.try_iter().flatten());
+                    txn.extend(&mut builder, batch)?;
+                    *latest.lock() = builder.snapshot().into_inner();
+                }
+                Ok((txn, builder))
+            })
+        };
+        Self {
+            sender: Some(sender),
+            worker: Some(worker),
+            latest,
+        }
+    }
+
+    /// queues a single element
+    pub fn push(&self, key: T::Key, value: V) -> Result<()> {
+        self.extend(vec![(key, value)])
+    }
+
+    /// queues some elements, to be added in order
+    pub fn extend(&self, elements: impl IntoIterator<Item = (T::Key, V)>) -> Result<()> {
+        let batch = elements.into_iter().collect::<Vec<_>>();
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.sender
+            .as_ref()
+            .expect("only taken in commit")
+            .send(batch)
+            .map_err(|_| anyhow!("background builder stopped, commit to get the error"))
+    }
+
+    /// the tree with all elements that have been added so far
+    ///
+    /// Elements that are still queued are not included.
+    pub fn snapshot(&self) -> Tree<T, V> {
+        match self.latest.lock().clone() {
+            Some((index, secrets, offset)) => Tree::new(index, secrets, offset),
+            None => Tree::default(),
+        }
+    }
+
+    /// waits until all queued elements have been added
+    pub fn commit(mut self) -> Result<(Transaction<T, R, W>, StreamBuilder<T, V>)> {
+        drop(self.sender.take());
+        self.worker
+            .take()
+            .expect("only taken in commit")
+            .join()
+            .map_err(|_| anyhow!("background builder panicked"))?
+    }
+}
+
+impl<T: TreeTypes, V, R, W> Drop for BackgroundBuilder<T, V, R, W> {
+    fn drop(&mut self) {
+        // closing the channel stops the worker once it has added the queued elements
+        drop(self.sender.take());
+    }
+}
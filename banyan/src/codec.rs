@@ -0,0 +1,200 @@
+//! Value encodings other than CBOR
+//!
+//! Values are stored in leaves as a sequence of CBOR items, so by default each value type encodes
+//! itself as CBOR, see [BanyanValue](../store/trait.BanyanValue.html). For small values such as
+//! numeric events, the CBOR structure can be a significant part of the leaf. To use another
+//! format, implement [ValueCodec] and use [Encoded] as the value type of the tree, e.g.
+//! `StreamBuilder<TT, Encoded<MyCodec, Event>>`.
+//!
+//! An encoded value is written as a single CBOR byte string containing the output of the codec.
+//! By default leaves store these byte strings as they are, so they remain valid CBOR sequences
+//! and can be read by generic tooling. If the codec produces values of a fixed size, set
+//! [Config::pack_values](crate::Config::pack_values) to store the size once per leaf instead of a
+//! byte string header per value. The bytes are opaque to link scraping, so values that contain
+//! links that should be visible to sync mechanisms must be encoded as CBOR.
+use cbor_data::{
+    codec::{CodecError, ReadCbor, WriteCbor},
+    Cbor, ItemKind, Writer,
+};
+use std::{fmt, marker::PhantomData, ops::Deref};
+
+/// An encoding of values of type `V` into bytes
+pub trait ValueCodec<V>: Send + Sync + 'static {
+    /// name of the encoding, used in error messages
+    const NAME: &'static str;
+
+    /// appends the encoded value to `out`
+    fn encode(value: &V, out: &mut Vec<u8>);
+
+    /// decodes a value that was encoded with [encode](#tymethod.encode)
+    fn decode(bytes: &[u8]) -> anyhow::Result<V>;
+}
+
+/// A value that is stored using the codec `C` instead of CBOR
+pub struct Encoded<C, V>(pub V, PhantomData<fn() -> C>);
+
+impl<C, V> Encoded<C, V> {
+    pub fn new(value: V) -> Self {
+        Self(value, PhantomData)
+    }
+
+    pub fn into_inner(self) -> V {
+        self.0
+    }
+}
+
+impl<C: ValueCodec<V>, V> WriteCbor for Encoded<C, V> {
+    fn write_cbor<W: Writer>(&self, w: W) -> W::Output {
+        let mut bytes = Vec::new();
+        C::encode(&self.0, &mut bytes);
+        w.write_bytes(&bytes, None)
+    }
+}
+
+impl<C: ValueCodec<V>, V> ReadCbor for Encoded<C, V> {
+    fn fmt(f: &mut impl fmt::Write) -> fmt::Result {
+        write!(f, "{} encoded bytes", C::NAME)
+    }
+
+    fn read_cbor(cbor: &Cbor) -> cbor_data::codec::Result<Self>
+    where
+        Self: Sized,
+    {
+        let decoded = cbor.tagged_item();
+        match decoded.kind() {
+            ItemKind::Bytes(bytes) => C::decode(&bytes.as_cow())
+                .map(Self::new)
+                .map_err(|err| CodecError::Custom(err.into())),
+            _ => Err(CodecError::type_error(C::NAME, &decoded)),
+        }
+    }
+}
+
+impl<C, V> From<V> for Encoded<C, V> {
+    fn from(value: V) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<C, V> Deref for Encoded<C, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.0
+    }
+}
+
+impl<C, V: Clone> Clone for Encoded<C, V> {
+    fn clone(&self) -> Self {
+        Self::new(self.0.clone())
+    }
+}
+
+impl<C, V: PartialEq> PartialEq for Encoded<C, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<C, V: fmt::Debug> fmt::Debug for Encoded<C, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        query::AllQuery,
+        store::NO_COMPRESSION,
+        store::{BranchCache, MemStore},
+        testing::{Sha256Digest, SimpleTT},
+        Config, Forest, Secrets, StreamBuilder, Transaction,
+    };
+    use anyhow::Result;
+    use std::convert::TryInto;
+
+    /// little endian u16, 2 bytes per value
+    struct U16Le;
+
+    impl ValueCodec<u16> for U16Le {
+        const NAME: &'static str = "u16le";
+
+        fn encode(value: &u16, out: &mut Vec<u8>) {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+
+        fn decode(bytes: &[u8]) -> Result<u16> {
+            Ok(u16::from_le_bytes(bytes.try_into()?))
+        }
+    }
+
+    #[test]
+    fn encoded_values() -> Result<()> {
+        let xs = (0..100u64)
+            .map(|i| (i, Encoded::<U16Le, _>::new(i as u16 * 300)))
+            .collect::<Vec<_>>();
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let forest = Forest::<SimpleTT, _>::new(store.clone(), BranchCache::default());
+        let mut txn = Transaction::new(forest, store);
+        let mut builder = StreamBuilder::new(Config::debug(), Secrets::default());
+        txn.extend(&mut builder, xs.clone())?;
+        let actual = txn
+            .iter_filtered(&builder.snapshot(), AllQuery)
+            .map(|x| x.map(|(_, k, v)| (k, v)))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(actual, xs);
+
+        // values of the wrong size are rejected
+        let mut cbor = Vec::new();
+        Encoded::<U16Le, u16>::new(1).write_cbor(cbor_data::CborBuilder::append_to(&mut cbor));
+        assert_eq!(cbor.len(), 3);
+        cbor[0] += 1;
+        cbor.push(0);
+        assert!(Encoded::<U16Le, u16>::read_cbor(Cbor::checked(&cbor)?).is_err());
+        Ok(())
+    }
+    #[test]
+    fn packed_values() -> Result<()> {
+        let xs = (0..1000u64)
+            .map(|i| (i, Encoded::<U16Le, _>::new((i * 7919) as u16)))
+            .collect::<Vec<_>>();
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let forest = Forest::<SimpleTT, _>::new(store.clone(), BranchCache::default());
+        let mut txn = Transaction::new(forest, store);
+        let value_bytes = |txn: &mut Transaction<SimpleTT, _, _>, pack_values| -> Result<u64> {
+            let config = Config {
+                zstd_level: NO_COMPRESSION,
+                pack_values,
+                max_leaf_count: 100,
+                ..Config::debug()
+            };
+            let mut builder = StreamBuilder::new(config, Secrets::default());
+            txn.extend_unpacked(&mut builder, xs[..550].to_vec())?;
+            txn.extend(&mut builder, xs[550..].to_vec())?;
+            let tree = builder.snapshot();
+            assert_eq!(tree.count(), 1000);
+            assert_eq!(txn.get(&tree, 777)?, Some(xs[777].clone()));
+            let actual = txn
+                .iter_filtered(&tree, AllQuery)
+                .map(|x| x.map(|(_, k, v)| (k, v)))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(actual, xs);
+            Ok(builder.index().unwrap().value_bytes())
+        };
+        let plain = value_bytes(&mut txn, false)?;
+        let packed = value_bytes(&mut txn, true)?;
+        // 2 instead of 3 bytes per value
+        assert!(packed * 5 < plain * 4, "{} vs {}", packed, plain);
+
+        // only byte strings can be packed
+        let config = Config {
+            pack_values: true,
+            ..Config::debug()
+        };
+        let mut builder = StreamBuilder::<SimpleTT, u64>::new(config, Secrets::default());
+        assert!(txn.extend(&mut builder, vec![(0, 0)]).is_err());
+        Ok(())
+    }
+}
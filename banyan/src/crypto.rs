@@ -0,0 +1,49 @@
+//! Key types for the encryption of blocks
+//!
+//! With the `crypto` feature, which is enabled by default, the compressed content of all blocks
+//! is encrypted with the XChaCha20 stream cipher. Without it, blocks are compressed but stored
+//! in a plain envelope without any encryption, and there are no keys. This is meant for public
+//! data in trusted stores. Builds without the feature can not read encrypted blocks, while
+//! builds with the feature can read plain blocks.
+#[cfg(feature = "crypto")]
+pub(crate) use chacha20::{Key, XNonce};
+
+/// Placeholder for a key, there are no keys without the `crypto` feature
+#[cfg(not(feature = "crypto"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Key;
+
+/// Placeholder for a nonce, there are no nonces without the `crypto` feature
+#[cfg(not(feature = "crypto"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct XNonce;
+
+/// whether two keys are equal, in a time that does not depend on where they differ
+#[cfg(feature = "crypto")]
+pub(crate) fn keys_equal(a: &Key, b: &Key) -> bool {
+    use subtle::ConstantTimeEq;
+    a.as_slice().ct_eq(b.as_slice()).into()
+}
+
+/// whether two keys are equal. There are no keys without the `crypto` feature.
+#[cfg(not(feature = "crypto"))]
+pub(crate) fn keys_equal(a: &Key, b: &Key) -> bool {
+    a == b
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_keys() {
+        let a = Key::from([1u8; 32]);
+        let mut b = a;
+        assert!(keys_equal(&a, &b));
+        b[31] = 0;
+        assert!(!keys_equal(&a, &b));
+        b[31] = 1;
+        b[0] = 0;
+        assert!(!keys_equal(&a, &b));
+    }
+}
@@ -0,0 +1,137 @@
+//! Stable traversal cursors for pagination
+//!
+//! A [Cursor] records where a filtered traversal stopped, so it can be resumed later, possibly
+//! from another process. It is meant to be handed to clients of a web api as an opaque page token.
+//!
+//! # Serialization
+//!
+//! A cursor is serialized as a dag-cbor list `[version, root, offset, query]`:
+//!
+//! - `version` is the format version, currently [CURSOR_VERSION]
+//! - `root` is the root link of the tree the cursor was created for, or null for an empty tree
+//! - `offset` is the offset of the next element to look at
+//! - `query` is a hash of the query, see [query_hash]
+//!
+//! There is no path from the root to the leaf in the cursor. The offset determines the path, and
+//! unlike a path it stays valid when the tree is packed or old elements are dropped by retention,
+//! since offsets never change.
+//!
+//! Cursors are not authenticated. If clients must not be able to forge them, sign or encrypt the
+//! serialized bytes.
+use std::{fmt::Debug, hash::Hasher};
+
+use anyhow::{anyhow, ensure, Result};
+use libipld::{
+    cbor::{DagCbor, DagCborCodec},
+    codec::Codec,
+    DagCbor,
+};
+
+/// Current version of the cursor serialization format
+pub const CURSOR_VERSION: u64 = 1;
+
+/// Position of a filtered traversal, see the [module docs](index.html)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cursor<L> {
+    root: Option<L>,
+    offset: u64,
+    query: u64,
+}
+
+#[derive(DagCbor)]
+struct CursorIpld<L: DagCbor>(u64, Option<L>, u64, u64);
+
+impl<L> Cursor<L> {
+    /// creates a cursor that resumes a traversal with `query` at `offset`
+    pub fn new<Q: Debug>(root: Option<L>, offset: u64, query: &Q) -> Self {
+        Self {
+            root,
+            offset,
+            query: query_hash(query),
+        }
+    }
+
+    /// root of the tree the cursor was created for
+    pub fn root(&self) -> Option<&L> {
+        self.root.as_ref()
+    }
+
+    /// offset of the next element to look at
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// checks that the cursor was created for `query`
+    pub fn validate<Q: Debug>(&self, query: &Q) -> Result<()> {
+        ensure!(
+            self.query == query_hash(query),
+            "cursor was created for a different query"
+        );
+        Ok(())
+    }
+}
+
+impl<L: DagCbor + Clone> Cursor<L> {
+    /// serializes the cursor, see the [module docs](index.html) for the format
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let ipld = CursorIpld(CURSOR_VERSION, self.root.clone(), self.offset, self.query);
+        DagCborCodec.encode(&ipld)
+    }
+
+    /// deserializes a cursor, failing for unknown versions
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let CursorIpld(version, root, offset, query) = DagCborCodec
+            .decode(data)
+            .map_err(|cause| anyhow!("invalid cursor: {}", cause))?;
+        ensure!(
+            version == CURSOR_VERSION,
+            "unsupported cursor version {}",
+            version
+        );
+        Ok(Self {
+            root,
+            offset,
+            query,
+        })
+    }
+}
+
+/// hash of a query, to detect cursors that are used with a different query
+///
+/// This is a FNV-1a hash of the [Debug] representation of the query, so it is stable across
+/// processes as long as the query type and its debug output do not change.
+pub fn query_hash<Q: Debug>(query: &Q) -> u64 {
+    let mut hasher = fnv::FnvHasher::default();
+    hasher.write(format!("{:?}", query).as_bytes());
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{AllQuery, OffsetRangeQuery};
+    use libipld::Cid;
+    use std::str::FromStr;
+
+    #[test]
+    fn cursor_roundtrip() -> Result<()> {
+        let root = Cid::from_str("bafyreihtx752fmf3zafbys5dtr4jxohb53yi3qtzfzf6wd5274jwtn5agu")?;
+        let cursor = Cursor::new(Some(root), 1234, &AllQuery);
+        let bytes = cursor.to_bytes()?;
+        assert_eq!(Cursor::<Cid>::from_bytes(&bytes)?, cursor);
+        cursor.validate(&AllQuery)?;
+        assert!(cursor.validate(&OffsetRangeQuery::from(0..10)).is_err());
+
+        let empty = Cursor::<Cid>::new(None, 0, &AllQuery);
+        assert_eq!(Cursor::<Cid>::from_bytes(&empty.to_bytes()?)?, empty);
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_version() -> Result<()> {
+        let bytes = DagCborCodec.encode(&CursorIpld::<Cid>(CURSOR_VERSION + 1, None, 0, 0))?;
+        assert!(Cursor::<Cid>::from_bytes(&bytes).is_err());
+        assert!(Cursor::<Cid>::from_bytes(&[1, 2, 3]).is_err());
+        Ok(())
+    }
+}
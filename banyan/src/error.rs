@@ -0,0 +1,156 @@
+//! context for errors that happen while reading a block
+//!
+//! Errors are [anyhow] errors. When reading, decrypting or decoding a block fails, a
+//! [BlockContext] is attached to the error, so the offending block can be identified from the log
+//! message or programmatically:
+//!
+//! ```ignore
+//! if let Some(ctx) = error.downcast_ref::<BlockContext<Link>>() {
+//!     println!("bad block {}", ctx.link);
+//! }
+//! ```
+use std::{fmt, ops::Range};
+
+/// The kind of a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    Branch,
+    Leaf,
+}
+
+impl fmt::Display for BlockKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Branch => write!(f, "branch"),
+            Self::Leaf => write!(f, "leaf"),
+        }
+    }
+}
+
+/// Where a block that could not be read comes from
+///
+/// The link is always known. Root, level and offsets are filled in by tree traversals.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockContext<L> {
+    /// whether the block is a branch or a leaf
+    pub kind: BlockKind,
+    /// link of the block
+    pub link: L,
+    /// root of the tree that was being read
+    pub root: Option<L>,
+    /// level of the node in the tree, 0 for leaves
+    pub level: Option<u32>,
+    /// offsets of the elements of the node
+    pub offsets: Option<Range<u64>>,
+}
+
+impl<L> BlockContext<L> {
+    pub fn new(kind: BlockKind, link: L) -> Self {
+        Self {
+            kind,
+            link,
+            root: None,
+            level: None,
+            offsets: None,
+        }
+    }
+}
+
+impl<L: fmt::Display> fmt::Display for BlockContext<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error reading {} {}", self.kind, self.link)?;
+        if let Some(level) = self.level {
+            write!(f, " at level {}", level)?;
+        }
+        if let Some(offsets) = &self.offsets {
+            write!(f, " with offsets {}..{}", offsets.start, offsets.end)?;
+        }
+        if let Some(root) = &self.root {
+            write!(f, " in tree {}", root)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error when splitting a tree at an offset that falls within a purged node
+///
+/// The elements of a purged node are gone, so there is nothing to split. Callers that retain
+/// parts of a stream can check for it with `error.downcast_ref::<PurgedSplit>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PurgedSplit;
+
+impl fmt::Display for PurgedSplit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "can not split a tree within a purged node")
+    }
+}
+
+impl std::error::Error for PurgedSplit {}
+
+/// Error when a store does not have a block
+///
+/// Stores that can tell a missing block apart from a failed read return this, so wrappers that
+/// fetch missing blocks from elsewhere can check for it with
+/// `error.downcast_ref::<BlockNotFound>()` and propagate all other errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockNotFound;
+
+impl fmt::Display for BlockNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block not found")
+    }
+}
+
+impl std::error::Error for BlockNotFound {}
+
+/// adds a [BlockContext] for the given block to an error, unless it already has one
+pub(crate) fn with_block<L>(error: anyhow::Error, kind: BlockKind, link: L) -> anyhow::Error
+where
+    L: fmt::Display + fmt::Debug + Send + Sync + 'static,
+{
+    if error.downcast_ref::<BlockContext<L>>().is_some() {
+        error
+    } else {
+        error.context(BlockContext::new(kind, link))
+    }
+}
+
+/// fills in the position of the block in the tree, if the error has a [BlockContext]
+///
+/// Fields that are already set are left alone, since they come from closer to the failure.
+pub(crate) fn with_position<L>(
+    mut error: anyhow::Error,
+    root: Option<L>,
+    level: u32,
+    offsets: Option<Range<u64>>,
+) -> anyhow::Error
+where
+    L: fmt::Display + fmt::Debug + Send + Sync + 'static,
+{
+    if let Some(ctx) = error.downcast_mut::<BlockContext<L>>() {
+        ctx.root = ctx.root.take().or(root);
+        ctx.level = ctx.level.or(Some(level));
+        ctx.offsets = ctx.offsets.take().or(offsets);
+    }
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_context() {
+        let error = with_block(anyhow::anyhow!("bad cbor"), BlockKind::Leaf, 17u64);
+        let error = with_block(error, BlockKind::Branch, 18u64);
+        let error = with_position(error, Some(1u64), 0, Some(10..20));
+        let ctx = error.downcast_ref::<BlockContext<u64>>().unwrap();
+        assert_eq!(ctx.kind, BlockKind::Leaf);
+        assert_eq!(ctx.link, 17);
+        assert_eq!(
+            error.to_string(),
+            "error reading leaf 17 at level 0 with offsets 10..20 in tree 1"
+        );
+        assert_eq!(error.root_cause().to_string(), "bad cbor");
+    }
+}
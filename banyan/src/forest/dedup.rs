@@ -0,0 +1,209 @@
+//! window of recently written blocks, to avoid writing the same block twice in a transaction
+//!
+//! The blocks are identified by their sha2-256 digest, so this needs the `dedup` feature.
+#[cfg(any(test, feature = "dedup"))]
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+/// LRU map from the sha2-256 digest of a block to the link it was stored under
+///
+/// The digest is computed here instead of using the link, since the link is only known after
+/// the block has been handed to the store.
+#[derive(Debug)]
+pub(crate) struct WriteDedup<L> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<[u8; 32], (L, u64)>,
+    by_time: BTreeMap<u64, [u8; 32]>,
+    hits: u64,
+}
+
+impl<L> Default for WriteDedup<L> {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl<L> WriteDedup<L> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            by_time: BTreeMap::new(),
+            hits: 0,
+        }
+    }
+
+    /// changes the number of remembered blocks, forgetting the least recently used ones
+    ///
+    /// Without the `dedup` feature, nothing is remembered.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = if cfg!(any(test, feature = "dedup")) {
+            capacity
+        } else {
+            0
+        };
+        while self.entries.len() > self.capacity {
+            let t = match self.by_time.keys().next() {
+                Some(t) => *t,
+                None => break,
+            };
+            let oldest = self.by_time.remove(&t).unwrap();
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+impl<L: Copy> WriteDedup<L> {
+    /// number of puts that were skipped because the block had already been written
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// writes `data` using `put`, unless the same block has been written recently
+    pub fn put(
+        &mut self,
+        data: Vec<u8>,
+        put: impl FnOnce(Vec<u8>) -> anyhow::Result<L>,
+    ) -> anyhow::Result<L> {
+        if self.capacity == 0 {
+            return put(data);
+        }
+        let digest = digest(&data);
+        self.tick += 1;
+        if let Some((link, t)) = self.entries.get_mut(&digest) {
+            self.by_time.remove(t);
+            *t = self.tick;
+            self.by_time.insert(self.tick, digest);
+            self.hits += 1;
+            return Ok(*link);
+        }
+        let link = put(data)?;
+        self.insert(digest, link);
+        Ok(link)
+    }
+
+    /// writes the blocks that have not been written recently using one call to `put_many`
+    pub fn put_many(
+        &mut self,
+        blocks: Vec<Vec<u8>>,
+        put_many: impl FnOnce(Vec<Vec<u8>>) -> anyhow::Result<Vec<L>>,
+    ) -> anyhow::Result<Vec<L>> {
+        if self.capacity == 0 {
+            return put_many(blocks);
+        }
+        let mut links = Vec::with_capacity(blocks.len());
+        let mut missing = Vec::new();
+        let mut digests = Vec::new();
+        for data in blocks {
+            let digest = digest(&data);
+            self.tick += 1;
+            if let Some((link, t)) = self.entries.get_mut(&digest) {
+                self.by_time.remove(t);
+                *t = self.tick;
+                self.by_time.insert(self.tick, digest);
+                self.hits += 1;
+                links.push(Some(*link));
+            } else {
+                links.push(None);
+                missing.push(data);
+                digests.push(digest);
+            }
+        }
+        let mut written = Vec::new();
+        if !missing.is_empty() {
+            written = put_many(missing)?;
+            anyhow::ensure!(
+                written.len() == digests.len(),
+                "writer returned wrong number of links"
+            );
+        }
+        let mut written = digests.into_iter().zip(written);
+        Ok(links
+            .into_iter()
+            .map(|link| {
+                link.unwrap_or_else(|| {
+                    let (digest, link) = written.next().expect("one link per missing block");
+                    self.tick += 1;
+                    self.insert(digest, link);
+                    link
+                })
+            })
+            .collect())
+    }
+
+    fn insert(&mut self, digest: [u8; 32], link: L) {
+        // the same block can occur twice in one batch
+        if let Some((_, t)) = self.entries.remove(&digest) {
+            self.by_time.remove(&t);
+        }
+        if self.entries.len() >= self.capacity {
+            if let Some(t) = self.by_time.keys().next().copied() {
+                let oldest = self.by_time.remove(&t).unwrap();
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(digest, (link, self.tick));
+        self.by_time.insert(self.tick, digest);
+    }
+}
+
+#[cfg(any(test, feature = "dedup"))]
+fn digest(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// only called with a capacity above 0, which requires the `dedup` feature
+#[cfg(not(any(test, feature = "dedup")))]
+fn digest(_data: &[u8]) -> [u8; 32] {
+    unreachable!("write deduplication requires the dedup feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_dedup_lru() -> anyhow::Result<()> {
+        let mut dedup = WriteDedup::new(2);
+        let mut puts = 0;
+        let mut put = |data: &[u8]| {
+            dedup.put(data.to_vec(), |data| {
+                puts += 1;
+                Ok(data.len())
+            })
+        };
+        put(&[1])?;
+        put(&[2, 2])?;
+        assert_eq!(put(&[1])?, 1);
+        // evicts [2, 2], which was used least recently
+        put(&[3, 3, 3])?;
+        put(&[2, 2])?;
+        put(&[3, 3, 3])?;
+        assert_eq!(puts, 4);
+        assert_eq!(dedup.hits(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn write_dedup_batch() -> anyhow::Result<()> {
+        let mut dedup = WriteDedup::new(4);
+        let mut written = Vec::new();
+        let mut put_many = |blocks: Vec<Vec<u8>>| {
+            dedup.put_many(blocks, |blocks| {
+                written.extend(blocks.iter().map(|data| data.len()));
+                Ok(blocks.iter().map(|data| data.len()).collect())
+            })
+        };
+        assert_eq!(put_many(vec![vec![1], vec![2, 2]])?, vec![1, 2]);
+        assert_eq!(
+            put_many(vec![vec![3; 3], vec![1], vec![2, 2]])?,
+            vec![3, 1, 2]
+        );
+        // only the new block was written the second time
+        assert_eq!(written, vec![1, 2, 3]);
+        assert_eq!(dedup.hits(), 2);
+        Ok(())
+    }
+}
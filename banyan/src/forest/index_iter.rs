@@ -1,11 +1,13 @@
 use super::{Forest, Secrets, TreeTypes};
 use crate::{
+    error::with_position,
     index::{CompactSeq, Index, NodeInfo},
     query::Query,
     store::ReadOnlyStore,
 };
 use anyhow::Result;
 use smallvec::{smallvec, SmallVec};
+use std::ops::Range;
 
 #[derive(PartialEq)]
 enum Mode {
@@ -102,9 +104,11 @@ where
     R: ReadOnlyStore<T::Link>,
     Q: Query<T>,
 {
-    type Item = Result<Index<T>>;
+    /// each index together with the offsets of its elements
+    type Item = Result<(Index<T>, Range<u64>)>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let root = self.stack.first().and_then(|state| *state.index.link());
         let res = loop {
             let head = match self.stack.last_mut() {
                 Some(i) => i,
@@ -129,7 +133,17 @@ where
                 NodeInfo::Branch(index, branch) => {
                     let branch = match branch.load_cached() {
                         Ok(branch) => branch,
-                        Err(cause) => return Some(Err(cause)),
+                        Err(cause) => {
+                            // the offset only tells where the branch starts before its first child
+                            let offsets = match self.mode {
+                                Mode::Forward => self.offset..self.offset + index.count,
+                                Mode::Backward => {
+                                    self.offset.saturating_sub(index.count)..self.offset
+                                }
+                            };
+                            let offsets = Some(offsets).filter(|_| head.filter.is_empty());
+                            return Some(Err(with_position(cause, root, index.level, offsets)));
+                        }
                     };
                     if head.filter.is_empty() {
                         // we hit this branch node for the first time. Apply the
@@ -147,7 +161,7 @@ where
                             .intersecting(start_offset, &index, &mut head.filter);
                         debug_assert_eq!(branch.children.len(), head.filter.len());
 
-                        break head.index.clone();
+                        break (head.index.clone(), start_offset..start_offset + index.count);
                     }
 
                     let next_idx = head.position as usize;
@@ -171,20 +185,22 @@ where
                 }
 
                 NodeInfo::Leaf(index, _) => {
-                    match self.mode {
+                    let offsets = match self.mode {
                         Mode::Forward => {
                             self.offset += index.keys.count();
+                            self.offset - index.keys.count()..self.offset
                         }
                         Mode::Backward => {
                             self.offset -= index.keys.count();
+                            self.offset..self.offset + index.keys.count()
                         }
-                    }
+                    };
                     // Ascend to parent's node, if it exists
                     let this_index = self.stack.pop().expect("not empty").index;
                     if let Some(last) = self.stack.last_mut() {
                         last.next_pos(&self.mode);
                     }
-                    break this_index;
+                    break (this_index, offsets);
                 }
 
                 // even for purged leafs and branches or ignored chunks,
@@ -196,15 +212,17 @@ where
                     if let Some(last) = self.stack.last_mut() {
                         last.next_pos(&self.mode);
                     };
-                    match self.mode {
+                    let offsets = match self.mode {
                         Mode::Forward => {
                             self.offset += index.count();
+                            self.offset - index.count()..self.offset
                         }
                         Mode::Backward => {
                             self.offset -= index.count();
+                            self.offset..self.offset + index.count()
                         }
                     };
-                    break index;
+                    break (index, offsets);
                 }
             };
         };
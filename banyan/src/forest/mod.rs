@@ -1,19 +1,37 @@
 //! creation and traversal of banyan trees
 use super::index::*;
-use crate::store::{BlockWriter, BranchCache, ReadOnlyStore};
-use core::{fmt::Debug, hash::Hash, iter::FromIterator, ops::Range};
+use crate::{
+    crypto::{keys_equal, Key, XNonce},
+    store::{
+        BlockWriter, BranchCache, LeafCache, ReadOnlyStore, SelectionCache, SwappableStore,
+        ZstdDagCborSeq,
+    },
+    CipherOffset,
+};
+use core::{cmp, fmt::Debug, hash::Hash, iter::FromIterator, ops::Range};
 use libipld::cbor::DagCbor;
-use std::{fmt::Display, sync::Arc};
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+mod dedup;
 mod index_iter;
 #[cfg(feature = "metrics")]
 mod prom;
 mod read;
+mod stats;
 mod stream;
 mod write;
+use dedup::WriteDedup;
 pub(crate) use index_iter::IndexIter;
 #[cfg(feature = "metrics")]
 pub(crate) use prom::register_metrics;
 pub(crate) use read::{ChunkVisitor, TreeIter};
+use stats::Counters;
+pub use stats::{ForestStats, LevelStats, TreeStats};
 
 /// Trees can be parametrized with the key type and the sequence type. Also, to avoid a dependency
 /// on a link type with all its baggage, we parameterize the link type.
@@ -47,6 +65,25 @@ pub trait TreeTypes: Debug + Send + Sync + Clone + 'static {
     type Link: Display + Debug + Hash + Eq + Clone + Copy + Send + Sync + DagCbor;
 
     const NONCE: &'static [u8; 24] = &[0u8; 24];
+
+    /// Reduces the detail of a summary that is stored in a branch of the given level, to make
+    /// the branches near the root smaller. Only used for levels from
+    /// [Config::summary_truncation_level] upwards. The default keeps the summary as it is.
+    ///
+    /// The result must cover everything the original summary covers, e.g. a wider key range or
+    /// a superset of tags. Otherwise queries will skip matching elements. Aggregates over
+    /// summaries see the truncated summaries as well.
+    fn truncate_summary(summary: Self::Summary, _level: u32) -> Self::Summary {
+        summary
+    }
+
+    /// Compares two keys, for streams with [Config::ordered_keys]. `None` means that the keys
+    /// can not be compared, which is an error for such streams.
+    ///
+    /// The default can not compare any keys, so it has to be overridden to use ordered keys.
+    fn compare_keys(_a: &Self::Key, _b: &Self::Key) -> Option<cmp::Ordering> {
+        None
+    }
 }
 
 /// Everything that is needed to read trees
@@ -54,8 +91,16 @@ pub trait TreeTypes: Debug + Send + Sync + Clone + 'static {
 pub struct ForestInner<T: TreeTypes, R> {
     pub(crate) store: R,
     pub(crate) branch_cache: BranchCache<T>,
+    pub(crate) leaf_cache: LeafCache<T>,
+    pub(crate) selection_cache: SelectionCache<T>,
+    pub(crate) counters: Arc<Counters>,
 }
 
+/// Shared handle for reading trees from a store
+///
+/// Cloning is cheap, all clones share the store and the caches. A forest is `Send` and `Sync`
+/// when the store is, so it can be used from many threads at once. The caches are behind locks
+/// that are only held for the lookup or insertion itself, never while loading from the store.
 #[derive(Debug)]
 pub struct Forest<TT: TreeTypes, R>(Arc<ForestInner<TT, R>>);
 
@@ -70,21 +115,69 @@ impl<TT: TreeTypes, R: Clone> Forest<TT, R> {
         Self(Arc::new(ForestInner {
             store,
             branch_cache,
+            leaf_cache: LeafCache::default(),
+            selection_cache: SelectionCache::default(),
+            counters: Default::default(),
         }))
     }
 
+    /// Use a cache for decrypted leaves, in addition to the branch cache.
+    pub fn with_leaf_cache(self, leaf_cache: LeafCache<TT>) -> Self {
+        Self(Arc::new(ForestInner {
+            store: self.store.clone(),
+            branch_cache: self.branch_cache.clone(),
+            leaf_cache,
+            selection_cache: self.selection_cache.clone(),
+            counters: self.counters.clone(),
+        }))
+    }
+
+    /// Use a cache for the items that queries select from leaves.
+    pub fn with_selection_cache(self, selection_cache: SelectionCache<TT>) -> Self {
+        Self(Arc::new(ForestInner {
+            store: self.store.clone(),
+            branch_cache: self.branch_cache.clone(),
+            leaf_cache: self.leaf_cache.clone(),
+            selection_cache,
+            counters: self.counters.clone(),
+        }))
+    }
+
+    /// Counters for cache hits and misses, blocks read and bytes decompressed
+    pub fn stats(&self) -> ForestStats {
+        self.counters.snapshot()
+    }
+
     pub fn transaction<W: BlockWriter<TT::Link>>(
         &self,
         f: impl FnOnce(R) -> (R, W),
     ) -> Transaction<TT, R, W> {
         let (reader, writer) = f(self.0.as_ref().store.clone());
         Transaction {
-            read: Self::new(reader, self.branch_cache.clone()),
+            read: Self(Arc::new(ForestInner {
+                store: reader,
+                branch_cache: self.branch_cache.clone(),
+                leaf_cache: self.leaf_cache.clone(),
+                selection_cache: self.selection_cache.clone(),
+                counters: self.counters.clone(),
+            })),
             writer,
+            written: WriteDedup::default(),
+            blocks_written: 0,
+            bytes_written: Default::default(),
+            write_limit: None,
         }
     }
 }
 
+impl<TT: TreeTypes, S: Clone> Forest<TT, SwappableStore<S>> {
+    /// Replaces the store for this forest and everything else that shares the
+    /// [SwappableStore], returning the previous store. Caches and stream builders are kept.
+    pub fn swap_store(&self, store: S) -> S {
+        self.store.swap(store)
+    }
+}
+
 impl<T: TreeTypes, R> std::ops::Deref for Forest<T, R> {
     type Target = ForestInner<T, R>;
 
@@ -97,6 +190,19 @@ impl<T: TreeTypes, R> std::ops::Deref for Forest<T, R> {
 pub struct Transaction<T: TreeTypes, R, W> {
     read: Forest<T, R>,
     writer: W,
+    /// recently written blocks, to skip writing them again
+    written: WriteDedup<T::Link>,
+    /// number and total size of blocks handed to the writer
+    blocks_written: u64,
+    /// shared, so the input of [extend_with_budget] can stop once the budget is used up
+    ///
+    /// [extend_with_budget]: #method.extend_with_budget
+    bytes_written: Arc<AtomicU64>,
+    /// value of `bytes_written` at which batches of leaves are cut short, while running
+    /// [extend_with_budget]
+    ///
+    /// [extend_with_budget]: #method.extend_with_budget
+    write_limit: Option<u64>,
 }
 
 impl<T: TreeTypes, R, W> Transaction<T, R, W> {
@@ -114,6 +220,40 @@ impl<T: TreeTypes, R, W> Transaction<T, R, W> {
     pub fn writer_mut(&mut self) -> &mut W {
         &mut self.writer
     }
+
+    /// number of block writes that were skipped because the block was written recently, see
+    /// [Config::dedup_window]
+    pub fn deduplicated_puts(&self) -> u64 {
+        self.written.hits()
+    }
+
+    /// number of blocks handed to the writer, not counting deduplicated puts
+    pub fn blocks_written(&self) -> u64 {
+        self.blocks_written
+    }
+
+    /// total size of the blocks handed to the writer
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes_written_counter(&self) -> Arc<AtomicU64> {
+        self.bytes_written.clone()
+    }
+
+    pub(crate) fn set_write_limit(&mut self, limit: Option<u64>) {
+        self.write_limit = limit;
+    }
+}
+
+impl<T: TreeTypes, R, W: BlockWriter<T::Link>> Transaction<T, R, W> {
+    /// makes the blocks written so far durable, see [BlockWriter::flush]
+    ///
+    /// This is done automatically at the end of every operation that writes blocks, so it is
+    /// only needed after writing blocks directly to the [writer](#method.writer_mut).
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()
+    }
 }
 
 impl<T: TreeTypes, R, W> Transaction<T, R, W>
@@ -126,7 +266,14 @@ where
     /// It is up to the caller to ensure that the reader reads the writes of the writer,
     /// if complex operations that require that should be performed in the transaction.
     pub fn new(read: Forest<T, R>, writer: W) -> Self {
-        Self { read, writer }
+        Self {
+            read,
+            writer,
+            written: WriteDedup::default(),
+            blocks_written: 0,
+            bytes_written: Default::default(),
+            write_limit: None,
+        }
     }
 }
 
@@ -138,40 +285,316 @@ impl<T: TreeTypes, R, W> std::ops::Deref for Transaction<T, R, W> {
     }
 }
 
-#[derive(Debug, Clone)]
+/// The keys to encrypt and decrypt the blocks of a stream
+///
+/// The keys are either held in memory, or requested from a [KeyProvider] whenever a block is
+/// encrypted or decrypted, see [Secrets::from_provider].
+///
+/// Keys held in memory are shared between clones, so cloning does not copy them, and they are
+/// overwritten with zeros when the last clone is dropped. The [Debug] output does not contain
+/// the keys.
+///
+/// For public data, [Secrets::none] disables encryption, see there.
+///
+/// # Nonces
+///
+/// Blocks are encrypted with XChaCha20, using the cipher offset of the block as the position
+/// in the key stream. By default, the nonce is [TreeTypes::NONCE], so all streams that use the
+/// same keys and tree types share one key stream. With [Secrets::with_stream_id], the nonces are
+/// derived from the stream id and the role of the block instead, so different streams can safely
+/// share keys. Either way, encryption is deterministic: the same elements written to a stream
+/// with the same secrets and config produce the same blocks, which allows deduplication and
+/// lets replicas of a stream converge on the same root.
+///
+/// This requires that each key stream, i.e. each combination of keys and stream id, is written
+/// by only one builder at a time. Two builders that write different data with the same keys and
+/// stream id reuse cipher offsets, which reveals the XOR of the plaintexts.
+#[derive(Clone)]
 pub struct Secrets {
+    keys: KeySource,
+    /// input for deriving the nonces, see [Secrets::with_stream_id]
+    stream_id: Option<Arc<[u8]>>,
+}
+
+#[derive(Clone)]
+enum KeySource {
+    /// blocks are not encrypted
+    None,
+    Keys(Arc<SecretKeys>),
+    #[cfg(feature = "crypto")]
+    Provider(Arc<dyn KeyProvider>),
+}
+
+struct SecretKeys {
     /// chacha20 key to decrypt index nodes
-    index_key: chacha20::Key,
+    index_key: Key,
     /// chacha20 key to decrypt value nodes
-    value_key: chacha20::Key,
+    value_key: Key,
+}
+
+impl Drop for SecretKeys {
+    fn drop(&mut self) {
+        #[cfg(feature = "crypto")]
+        {
+            use zeroize::Zeroize;
+            self.index_key.as_mut_slice().zeroize();
+            self.value_key.as_mut_slice().zeroize();
+        }
+    }
+}
+
+/// Supplies the keys of a stream on demand, e.g. from an OS keyring, a KMS or an HSM
+///
+/// The keys are requested every time a block is encrypted or decrypted, and zeroed right after
+/// use, so they are only in memory while they are needed. Providers that fetch keys from a
+/// remote service should cache them for a short time. To use different keys for each stream,
+/// create secrets with a different provider for each stream.
+#[cfg(feature = "crypto")]
+pub trait KeyProvider: Send + Sync + 'static {
+    /// chacha20 key to encrypt and decrypt index nodes
+    fn index_key(&self) -> anyhow::Result<chacha20::Key>;
+
+    /// chacha20 key to encrypt and decrypt value nodes
+    fn value_key(&self) -> anyhow::Result<chacha20::Key>;
 }
 
+#[cfg(feature = "crypto")]
 impl Secrets {
-    pub fn new(index_key: chacha20::Key, value_key: chacha20::Key) -> Self {
-        Self {
+    /// Creates secrets from the two keys.
+    ///
+    /// The keys are moved into a buffer that is zeroed on drop. Since keys are `Copy`, the
+    /// caller is responsible for zeroing any other copies it holds.
+    pub fn new(mut index_key: chacha20::Key, mut value_key: chacha20::Key) -> Self {
+        use zeroize::Zeroize;
+        let secrets = Self::from_source(KeySource::Keys(Arc::new(SecretKeys {
             index_key,
             value_key,
+        })));
+        index_key.as_mut_slice().zeroize();
+        value_key.as_mut_slice().zeroize();
+        secrets
+    }
+
+    /// Creates secrets that request the keys from a provider whenever they are needed
+    pub fn from_provider(provider: impl KeyProvider) -> Self {
+        Self::from_source(KeySource::Provider(Arc::new(provider)))
+    }
+
+    /// the index key, if it is held in memory
+    pub fn index_key(&self) -> Option<&chacha20::Key> {
+        match &self.keys {
+            KeySource::Keys(keys) => Some(&keys.index_key),
+            _ => None,
         }
     }
 
-    pub fn index_key(&self) -> &chacha20::Key {
-        &self.index_key
+    /// the value key, if it is held in memory
+    pub fn value_key(&self) -> Option<&chacha20::Key> {
+        match &self.keys {
+            KeySource::Keys(keys) => Some(&keys.value_key),
+            _ => None,
+        }
+    }
+}
+
+/// What a block contains, which determines the key and nonce that are used for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BlockRole {
+    /// a branch, encrypted with the index key
+    Index,
+    /// a leaf, encrypted with the value key
+    Value,
+}
+
+impl Secrets {
+    fn from_source(keys: KeySource) -> Self {
+        Self {
+            keys,
+            stream_id: None,
+        }
+    }
+
+    /// Secrets that do not encrypt blocks at all.
+    ///
+    /// Blocks are still compressed, and links are still hashes of the blocks, but the data is
+    /// stored in the plain envelope, so standard tooling can look into the blocks and no time is
+    /// spent on encryption. This is meant for public datasets. Encrypted blocks can not be read
+    /// with these secrets.
+    pub fn none() -> Self {
+        Self::from_source(KeySource::None)
     }
 
-    pub fn value_key(&self) -> &chacha20::Key {
-        &self.value_key
+    /// true unless created with [Secrets::none]
+    pub fn encrypts(&self) -> bool {
+        !matches!(self.keys, KeySource::None)
     }
+
+    /// Derives the nonces from `stream_id` instead of using [TreeTypes::NONCE], see the
+    /// [type docs](#nonces).
+    ///
+    /// The stream id is not secret, but it has to be the same for writing and reading a stream.
+    pub fn with_stream_id(self, stream_id: impl AsRef<[u8]>) -> Self {
+        Self {
+            stream_id: Some(stream_id.as_ref().into()),
+            ..self
+        }
+    }
+
+    /// the stream id the nonces are derived from, if any
+    pub fn stream_id(&self) -> Option<&[u8]> {
+        self.stream_id.as_deref()
+    }
+
+    /// Encrypts a block, or puts it into the plain envelope for [Secrets::none]
+    pub(crate) fn encrypt<T: TreeTypes>(
+        &self,
+        role: BlockRole,
+        data: ZstdDagCborSeq,
+        state: &mut CipherOffset,
+    ) -> anyhow::Result<Vec<u8>> {
+        if !self.encrypts() {
+            return data.into_plain(state);
+        }
+        self.with_key(role, |key| {
+            data.into_encrypted(key, &self.nonce::<T>(role), state)
+        })
+    }
+
+    /// Decrypts a block, which may also be in the plain envelope
+    pub(crate) fn decrypt<T: TreeTypes>(
+        &self,
+        role: BlockRole,
+        data: &[u8],
+    ) -> anyhow::Result<(ZstdDagCborSeq, Range<u64>)> {
+        if !self.encrypts() {
+            return ZstdDagCborSeq::decode_plain(data);
+        }
+        self.with_key(role, |key| {
+            ZstdDagCborSeq::decrypt(data, key, &self.nonce::<T>(role))
+        })
+    }
+
+    /// the first 24 bytes of `sha256(NONCE || role || stream id)`, or just `NONCE`
+    #[cfg(feature = "crypto")]
+    fn nonce<T: TreeTypes>(&self, role: BlockRole) -> XNonce {
+        use sha2::{Digest, Sha256};
+        match &self.stream_id {
+            Some(stream_id) => {
+                let role = match role {
+                    BlockRole::Index => b'i',
+                    BlockRole::Value => b'v',
+                };
+                let mut hasher = Sha256::new();
+                hasher.update(T::NONCE);
+                hasher.update([role]);
+                hasher.update(stream_id);
+                XNonce::clone_from_slice(&hasher.finalize()[..24])
+            }
+            None => *crate::util::nonce::<T>(),
+        }
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    fn nonce<T: TreeTypes>(&self, _role: BlockRole) -> XNonce {
+        XNonce
+    }
+
+    /// calls `f` with the key for `role`, which is zeroed afterwards if it came from a provider
+    fn with_key<R>(
+        &self,
+        role: BlockRole,
+        f: impl FnOnce(&Key) -> anyhow::Result<R>,
+    ) -> anyhow::Result<R> {
+        match (&self.keys, role) {
+            (KeySource::None, _) => anyhow::bail!("no keys, encryption is disabled"),
+            (KeySource::Keys(keys), BlockRole::Index) => f(&keys.index_key),
+            (KeySource::Keys(keys), BlockRole::Value) => f(&keys.value_key),
+            #[cfg(feature = "crypto")]
+            (KeySource::Provider(provider), BlockRole::Index) => {
+                with_provided_key(provider.index_key()?, f)
+            }
+            #[cfg(feature = "crypto")]
+            (KeySource::Provider(provider), BlockRole::Value) => {
+                with_provided_key(provider.value_key()?, f)
+            }
+        }
+    }
+
+    /// true if leaves written with one of the secrets can be read with the other
+    pub(crate) fn same_value_key(&self, other: &Secrets) -> anyhow::Result<bool> {
+        if !self.encrypts() || !other.encrypts() {
+            return Ok(self.encrypts() == other.encrypts());
+        }
+        self.with_key(BlockRole::Value, |a| {
+            other.with_key(BlockRole::Value, |b| Ok(keys_equal(a, b)))
+        })
+    }
+}
+
+#[cfg(feature = "crypto")]
+fn with_provided_key<R>(
+    mut key: Key,
+    f: impl FnOnce(&Key) -> anyhow::Result<R>,
+) -> anyhow::Result<R> {
+    use zeroize::Zeroize;
+    let result = f(&key);
+    key.as_mut_slice().zeroize();
+    result
 }
 
 impl Default for Secrets {
     fn default() -> Self {
-        Self {
-            index_key: [0; 32].into(),
-            value_key: [0; 32].into(),
+        Self::from_source(KeySource::Keys(Arc::new(SecretKeys {
+            index_key: Key::default(),
+            value_key: Key::default(),
+        })))
+    }
+}
+
+/// Secrets are equal if they hold the same keys, or use the same provider, and have the same
+/// stream id. Keys are compared in constant time.
+impl PartialEq for Secrets {
+    fn eq(&self, other: &Self) -> bool {
+        if self.stream_id != other.stream_id {
+            return false;
+        }
+        match (&self.keys, &other.keys) {
+            (KeySource::None, KeySource::None) => true,
+            // both keys are always compared, so the time does not tell which of them differs
+            (KeySource::Keys(a), KeySource::Keys(b)) => {
+                Arc::ptr_eq(a, b)
+                    || (keys_equal(&a.index_key, &b.index_key)
+                        & keys_equal(&a.value_key, &b.value_key))
+            }
+            #[cfg(feature = "crypto")]
+            (KeySource::Provider(a), KeySource::Provider(b)) => {
+                // compare the data pointers only, vtables are not unique
+                Arc::as_ptr(a) as *const u8 == Arc::as_ptr(b) as *const u8
+            }
+            _ => false,
         }
     }
 }
 
+impl Eq for Secrets {}
+
+impl std::fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let source = match &self.keys {
+            KeySource::None => "none",
+            KeySource::Keys(_) => "keys",
+            #[cfg(feature = "crypto")]
+            KeySource::Provider(_) => "provider",
+        };
+        f.debug_struct("Secrets")
+            .field("source", &source)
+            .field("stream_id", &self.stream_id)
+            .field("index_key", &"<redacted>")
+            .field("value_key", &"<redacted>")
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone)]
 /// Configuration for a forest. Includes settings for when a node is considered full
 pub struct Config {
@@ -191,6 +614,41 @@ pub struct Config {
     pub max_uncompressed_leaf_size: usize,
     /// zstd level to use for compression
     pub zstd_level: i32,
+    /// do not compress leaves if the store reports that it compresses at rest.
+    ///
+    /// Leaves stored this way are still valid zstd frames, so they can be read by any reader.
+    pub skip_redundant_compression: bool,
+    /// lowest branch level at which summaries are passed through [TreeTypes::truncate_summary]
+    ///
+    /// Branches near the root are read on every query, so keeping their summaries small can be
+    /// worth the loss of pruning precision. `None` keeps all summaries as they are.
+    pub summary_truncation_level: Option<u32>,
+    /// stop filling a leaf once its uncompressed size reaches `target_leaf_size` divided by the
+    /// compression ratio of the previous leaves of the stream
+    ///
+    /// This keeps compressed leaves close to `target_leaf_size` for payloads whose compression
+    /// ratio varies, instead of alternating between tiny and oversized leaves.
+    pub adaptive_leaf_size: bool,
+    /// require the keys of the stream to be non-decreasing, according to
+    /// [TreeTypes::compare_keys]
+    ///
+    /// Extending the stream with a key that is smaller than the previous one fails. This allows
+    /// looking up elements by key with [Forest::find_by_key].
+    pub ordered_keys: bool,
+    /// store the values of new leaves without their CBOR headers
+    ///
+    /// This is for value types that are byte strings of a fixed size, such as
+    /// [Encoded](crate::codec::Encoded) values of a fixed size codec. The leaf then contains the
+    /// size once, followed by the contents of the values. Values that are not byte strings, or
+    /// that differ in size from the first value of the leaf, are an error. Leaves are decoded the
+    /// same way either way, so this only has to be set for writing.
+    pub pack_values: bool,
+    /// number of recently written blocks a [Transaction] remembers while writing the tree
+    ///
+    /// Writing one of them again is skipped, which is common when repacking reuses subtrees.
+    /// This costs a sha2-256 digest per block, so 0, which disables it, is the default. Only has
+    /// an effect with the `dedup` feature.
+    pub dedup_window: usize,
 }
 
 impl Config {
@@ -205,6 +663,12 @@ impl Config {
             max_summary_branches: 4,
             zstd_level: 0,
             max_uncompressed_leaf_size: 16 * 1024 * 1024,
+            skip_redundant_compression: false,
+            summary_truncation_level: None,
+            adaptive_leaf_size: false,
+            ordered_keys: false,
+            pack_values: false,
+            dedup_window: 0,
         }
     }
 
@@ -217,6 +681,12 @@ impl Config {
             max_key_branches: 32,
             zstd_level: 0,
             max_uncompressed_leaf_size: 16 * 1024 * 1024,
+            skip_redundant_compression: false,
+            summary_truncation_level: None,
+            adaptive_leaf_size: false,
+            ordered_keys: false,
+            pack_values: false,
+            dedup_window: 0,
         }
     }
 
@@ -242,6 +712,28 @@ impl Config {
         }
     }
 
+    /// predicate to determine if a sealed leaf is also full under this config
+    ///
+    /// Uses the same limits as filling a leaf, except for the uncompressed size, which is not
+    /// recorded in the index. So a leaf that was sealed because of its uncompressed size is not
+    /// considered full.
+    pub(crate) fn leaf_sealed<T: TreeTypes>(&self, leaf: &LeafIndex<T>) -> bool {
+        let keys = leaf.keys.count();
+        leaf.sealed
+            && keys <= self.max_leaf_count as u64
+            && (keys == self.max_leaf_count as u64
+                || leaf.value_bytes >= self.target_leaf_size as u64)
+    }
+
+    /// the summary of `child` to store in a branch of the given level
+    pub fn summary_at<T: TreeTypes>(&self, child: &Index<T>, level: u32) -> T::Summary {
+        let summary = child.summarize();
+        match self.summary_truncation_level {
+            Some(min_level) if level >= min_level => T::truncate_summary(summary, level),
+            _ => summary,
+        }
+    }
+
     pub fn validate(&self) -> anyhow::Result<()> {
         anyhow::ensure!(self.max_summary_branches > 1);
         anyhow::ensure!(self.max_key_branches > 0);
@@ -272,6 +764,124 @@ pub(crate) enum CreateMode {
     Unpacked,
 }
 
+/// What to do when the values of a leaf can not be decrypted or decoded during iteration,
+/// for example because only the index key is available.
+///
+/// Failures to get blocks from the store are always reported as errors.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub enum MissingValuePolicy {
+    /// fail with an error, like the other iteration methods
+    #[default]
+    Error,
+    /// leave out the elements of the leaf, so there is a gap in the offsets
+    SkipWithGap,
+    /// return the offsets and keys of the leaf, without values
+    KeysOnly,
+}
+
+/// How thoroughly to check a tree when loading it, see [Forest::load_tree_with]
+///
+/// [Forest::load_tree_with]: struct.Forest.html#method.load_tree_with
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub enum Validation {
+    /// trust the link, just like [Forest::load_tree]
+    ///
+    /// [Forest::load_tree]: struct.Forest.html#method.load_tree
+    #[default]
+    None,
+    /// check that the root branch decodes and that its children have sane counts and levels
+    Quick,
+    /// like quick, and check the invariants of every node below the root like
+    /// [Forest::check_invariants], except for the ones that need the config of the stream. This
+    /// reads the entire tree, so it needs the value key.
+    ///
+    /// [Forest::check_invariants]: struct.Forest.html#method.check_invariants
+    Full,
+}
+
+/// A failed invariant of a tree, see [Forest::check_invariants]
+///
+/// [Forest::check_invariants]: struct.Forest.html#method.check_invariants
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// child indices from the root to the node that violates the invariant
+    pub path: Vec<usize>,
+    pub kind: ViolationKind,
+}
+
+/// The kind of a [Violation]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// the number of children of a branch differs from the number of summaries in its index
+    BadChildCount { expected: u64, actual: u64 },
+    /// the number of elements of a node differs from the count in its index, e.g. the number of
+    /// values of a leaf differs from the number of keys
+    BadElementCount { expected: u64, actual: u64 },
+    /// a child is on a level that is not possible below its parent
+    WrongLevel { parent: u32, child: u32 },
+    /// the summary of the child with the given index, as stored in the parent, differs from the
+    /// summary computed from the child
+    SummaryMismatch { child: usize },
+    /// the sealed flag of a branch does not match its children, e.g. a sealed branch with an
+    /// unsealed child
+    NonMonotoneSealing { sealed: bool },
+    /// a sealed node at the given level comes after an unsealed node at the same or a lower
+    /// level, which packing never produces
+    SealedAfterUnsealed { level: u32 },
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            ViolationKind::BadChildCount { expected, actual } => write!(
+                f,
+                "number of children is {} instead of {}",
+                actual, expected
+            )?,
+            ViolationKind::BadElementCount { expected, actual } => write!(
+                f,
+                "number of elements is {} instead of {}",
+                actual, expected
+            )?,
+            ViolationKind::WrongLevel { parent, child } => {
+                write!(f, "child at level {} below level {}", child, parent)?
+            }
+            ViolationKind::SummaryMismatch { child } => {
+                write!(f, "summary of child {} does not match", child)?
+            }
+            ViolationKind::NonMonotoneSealing { sealed } => {
+                write!(f, "branch is {}sealed", if *sealed { "" } else { "not " })?
+            }
+            ViolationKind::SealedAfterUnsealed { level } => {
+                write!(f, "sealed node at level {} after an unsealed node", level)?
+            }
+        }
+        write!(f, " at /")?;
+        for (i, child) in self.path.iter().enumerate() {
+            if i > 0 {
+                write!(f, "/")?;
+            }
+            write!(f, "{}", child)?;
+        }
+        Ok(())
+    }
+}
+
+/// Progress of [Transaction::pack_with_progress]
+///
+/// [Transaction::pack_with_progress]: struct.Transaction.html#method.pack_with_progress
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PackProgress {
+    /// number of packed subtrees that are left to be joined. 1 or 0 when done.
+    pub roots: usize,
+    /// number of elements after the packed part of the tree that were added again
+    pub reinserted: u64,
+    /// number of blocks written so far
+    pub blocks_written: u64,
+    /// total size of the blocks written so far
+    pub bytes_written: u64,
+}
+
 /// A filtered chunk.
 /// Contains both data and information about the offsets the data resulted from.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -1,21 +1,25 @@
 #[cfg(feature = "metrics")]
 use super::prom;
-use super::{BranchCache, Config, FilteredChunk, Forest, Secrets, TreeTypes};
+use super::{
+    BlockRole, BranchCache, Config, Counters, FilteredChunk, Forest, MissingValuePolicy, Secrets,
+    TreeTypes, Violation, ViolationKind,
+};
 use crate::{
+    error::{with_block, with_position, BlockKind},
     index::{
-        deserialize_compressed, Branch, BranchIndex, BranchLoader, CompactSeq, Index, Leaf,
-        LeafIndex, LeafLoader, NodeInfo,
+        deserialize_compressed, zip_with_offset_ref, Aggregatable, Aggregate, Branch, BranchIndex,
+        BranchLoader, CompactSeq, Index, Leaf, LeafIndex, LeafLoader, NodeInfo,
     },
-    query::Query,
-    store::ZstdDagCborSeq,
-    store::{BanyanValue, ReadOnlyStore},
-    util::{nonce, BoolSliceExt, IterExt},
+    pipeline::Pipeline,
+    query::{MatchEstimate, OrderedSummary, Query},
+    store::{count_decompressed, BanyanValue, ReadOnlyStore},
+    util::{BoolSliceExt, Instant, IterExt},
 };
 use anyhow::{anyhow, Result};
-use cbor_data::codec::ReadCbor;
+use cbor_data::{codec::ReadCbor, Cbor};
 use futures::{prelude::*, stream::BoxStream};
 use smallvec::{smallvec, SmallVec};
-use std::{iter, marker::PhantomData, ops::Range, sync::Arc, time::Instant};
+use std::{io::Write, iter, marker::PhantomData, ops::AddAssign, ops::Range, sync::Arc};
 
 pub(crate) trait TreeVisitor<T: TreeTypes, R> {
     type Item;
@@ -75,20 +79,19 @@ where
         &self,
         range: Range<u64>,
         index: Arc<LeafIndex<T>>,
-        leaf: LeafLoader<T, R>,
+        loader: LeafLoader<T, R>,
         matching: &[bool],
     ) -> Result<Self::Item> {
         // materialize the actual (offset, key, value) triples for the matching bits
         let data = if matching.any() {
             tracing::trace!("loading leaf {:?}", range);
-            let leaf = leaf.load()?;
             let offsets = matching
                 .iter()
                 .enumerate()
                 .filter(|(_, m)| **m)
                 .map(|(i, _)| range.start + i as u64);
             let keys = index.select_keys(matching);
-            let elems: Vec<V> = leaf.as_ref().select(matching)?;
+            let elems: Vec<V> = loader.forest().select_values(&loader, matching)?;
             offsets
                 .zip(keys)
                 .zip(elems)
@@ -97,11 +100,109 @@ where
         } else {
             Vec::new()
         };
-        let extra = (self.mk_extra)(&NodeInfo::Leaf(index, leaf));
+        let extra = (self.mk_extra)(&NodeInfo::Leaf(index, loader));
         Ok(FilteredChunk { range, data, extra })
     }
 }
 
+/// A tree visitor that loads matching leaves, but leaves decoding the values to the caller.
+pub(crate) struct LeafVisitor;
+
+impl<T, R> TreeVisitor<T, R> for LeafVisitor
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+{
+    #[allow(clippy::type_complexity)]
+    type Item = Option<(u64, Arc<LeafIndex<T>>, Leaf, Vec<bool>)>;
+
+    fn skip(&self, _: Range<u64>, _: &NodeInfo<T, R>) -> Self::Item {
+        None
+    }
+
+    fn leaf(
+        &self,
+        range: Range<u64>,
+        index: Arc<LeafIndex<T>>,
+        leaf: LeafLoader<T, R>,
+        matching: &[bool],
+    ) -> Result<Self::Item> {
+        Ok(if matching.any() {
+            Some((range.start, index, leaf.load()?, matching.to_vec()))
+        } else {
+            None
+        })
+    }
+}
+
+/// A tree visitor that materializes matching elements, applying a [MissingValuePolicy] to leaves
+/// whose values can not be decrypted or decoded.
+pub(crate) struct PolicyVisitor<V> {
+    policy: MissingValuePolicy,
+    _p: PhantomData<V>,
+}
+
+impl<V> PolicyVisitor<V> {
+    pub fn new(policy: MissingValuePolicy) -> Self {
+        Self {
+            policy,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<T, R, V> TreeVisitor<T, R> for PolicyVisitor<V>
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+    V: BanyanValue,
+{
+    #[allow(clippy::type_complexity)]
+    type Item = Vec<(u64, T::Key, Option<V>)>;
+
+    fn skip(&self, _: Range<u64>, _: &NodeInfo<T, R>) -> Self::Item {
+        Vec::new()
+    }
+
+    fn leaf(
+        &self,
+        range: Range<u64>,
+        index: Arc<LeafIndex<T>>,
+        loader: LeafLoader<T, R>,
+        matching: &[bool],
+    ) -> Result<Self::Item> {
+        if !matching.any() {
+            return Ok(Vec::new());
+        }
+        let values: Vec<Option<V>> = match loader.forest().select_values(&loader, matching) {
+            Ok(values) => values.into_iter().map(Some).collect(),
+            Err(cause) if self.policy == MissingValuePolicy::Error => return Err(cause),
+            Err(cause) => {
+                // the policy is only about values, not about the store
+                loader.forest().store().get(loader.link())?;
+                tracing::debug!("values of leaf {:?} not available: {}", range, cause);
+                match self.policy {
+                    MissingValuePolicy::KeysOnly => {
+                        matching.iter().filter(|m| **m).map(|_| None).collect()
+                    }
+                    _ => return Ok(Vec::new()),
+                }
+            }
+        };
+        let offsets = matching
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| **m)
+            .map(|(i, _)| range.start + i as u64);
+        let keys = index.select_keys(matching);
+        Ok(offsets
+            .zip(keys)
+            .zip(values)
+            .map(|((o, k), v)| (o, k, v))
+            .collect())
+    }
+}
+
 #[derive(PartialEq)]
 enum Mode {
     Forward,
@@ -115,6 +216,10 @@ pub(crate) struct TreeIter<T: TreeTypes, R, Q, V> {
     mode: Mode,
     query: Q,
     visitor: V,
+    // number of leaf blocks to get from the store in one go
+    batch_size: usize,
+    // leaf blocks that have been fetched ahead of time
+    prefetched: Vec<(T::Link, Box<[u8]>)>,
 }
 
 struct TraverseState<T: TreeTypes> {
@@ -176,6 +281,8 @@ where
             mode,
             query,
             visitor,
+            batch_size: 1,
+            prefetched: Vec::new(),
         }
     }
     pub(crate) fn new_rev(
@@ -197,9 +304,55 @@ where
             mode,
             query,
             visitor,
+            batch_size: 1,
+            prefetched: Vec::new(),
         }
     }
 
+    /// get leaf blocks in batches of `batch_size` from the store
+    pub(crate) fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// get the block for the leaf at the top of the stack, together with the blocks of the
+    /// next leaves of the same parent that might match, in one store round.
+    fn prefetch(&mut self, link: &T::Link) -> Result<Box<[u8]>> {
+        if let Some(i) = self.prefetched.iter().position(|(l, _)| l == link) {
+            return Ok(self.prefetched.swap_remove(i).1);
+        }
+        let mut links = vec![*link];
+        if let Some(parent) = self.stack.iter().rev().nth(1) {
+            if let Some(parent_link) = parent.index.link() {
+                let branch = self
+                    .forest
+                    .load_branch_cached_from_link(&self.secrets, parent_link)?;
+                let position = parent.position as usize;
+                let siblings: Box<dyn Iterator<Item = usize>> = match self.mode {
+                    Mode::Forward => Box::new(position + 1..branch.children.len()),
+                    Mode::Backward => Box::new((0..position).rev()),
+                };
+                for i in siblings {
+                    if links.len() >= self.batch_size {
+                        break;
+                    }
+                    if let (true, Index::Leaf(child)) = (parent.filter[i], &branch.children[i]) {
+                        links.extend(child.link);
+                    }
+                }
+            }
+        }
+        let mut blocks = self.forest.store().get_many(&links)?;
+        blocks.iter().for_each(|x| self.forest.count_block_read(x));
+        anyhow::ensure!(
+            blocks.len() == links.len(),
+            "store returned wrong number of blocks"
+        );
+        let block = blocks.remove(0);
+        self.prefetched = links.into_iter().skip(1).zip(blocks).collect();
+        Ok(block)
+    }
+
     /// common code for early returns. Pop a state from the stack and completely skip the index.
     ///
     /// this can only be called before the index is partially processed.
@@ -220,7 +373,15 @@ where
 
     #[allow(clippy::type_complexity)]
     fn next_fallible(&mut self) -> Result<Option<V::Item>> {
+        let root = self.stack.first().and_then(|state| *state.index.link());
         Ok(Some(loop {
+            // link of the parent of the head, used for prefetching
+            let parent = self
+                .stack
+                .iter()
+                .rev()
+                .nth(1)
+                .and_then(|state| *state.index.link());
             let head = match self.stack.last_mut() {
                 Some(i) => i,
                 // Nothing to do ..
@@ -249,6 +410,8 @@ where
             let info = self.forest.node_info(&self.secrets, &head.index);
             match info {
                 NodeInfo::Branch(index, branch) => {
+                    // the offset only tells where the branch starts before its first child
+                    let offsets = Some(range.clone()).filter(|_| head.filter.is_empty());
                     if head.filter.is_empty() {
                         // we hit this branch node for the first time. Apply the
                         // query on its children and store it
@@ -267,7 +430,30 @@ where
                         }
                     }
 
-                    let branch = branch.load_cached()?;
+                    let first_visit = offsets.is_some();
+                    // only the first visit counts for prefetching. Coming back to a branch after
+                    // visiting a child is always a hit, and would reset the misses of the parent.
+                    let branch = if first_visit {
+                        branch.load_cached_with_parent(parent.as_ref())
+                    } else {
+                        branch.load_cached()
+                    };
+                    let branch =
+                        branch.map_err(|cause| with_position(cause, root, index.level, offsets))?;
+                    if first_visit {
+                        // tell the store which children we are going to visit, in order
+                        let mut links = branch
+                            .children
+                            .iter()
+                            .zip(head.filter.iter())
+                            .filter(|(_, visit)| **visit)
+                            .filter_map(|(child, _)| *child.link())
+                            .collect::<Vec<_>>();
+                        if self.mode == Mode::Backward {
+                            links.reverse();
+                        }
+                        self.forest.store().will_need(&links);
+                    }
 
                     let next_idx = head.position as usize;
                     if head.filter[next_idx] {
@@ -298,7 +484,21 @@ where
                 NodeInfo::Leaf(index, leaf) => {
                     let mut matching: SmallVec<[_; 32]> = smallvec![true; index.keys.len()];
                     self.query.containing(range.start, &index, &mut matching);
-                    let result = self.visitor.leaf(range, index.clone(), leaf, &matching)?;
+                    let link = *leaf.link();
+                    let in_tree = |cause| {
+                        let cause = with_block(cause, BlockKind::Leaf, link);
+                        with_position(cause, root, 0, Some(range.clone()))
+                    };
+                    let leaf = if self.batch_size > 1 && matching.any() {
+                        let block = self.prefetch(&link).map_err(in_tree)?;
+                        leaf.with_block(block)
+                    } else {
+                        leaf
+                    };
+                    let result = self
+                        .visitor
+                        .leaf(range.clone(), index.clone(), leaf, &matching)
+                        .map_err(in_tree)?;
                     match self.mode {
                         Mode::Backward => self.offset -= index.keys.count(),
                         Mode::Forward => self.offset += index.keys.count(),
@@ -371,33 +571,78 @@ where
 
     /// load a leaf given a leaf index
     pub(crate) fn load_leaf_from_link(&self, stream: &Secrets, link: &T::Link) -> Result<Leaf> {
+        if let Some(leaf) = self.leaf_cache.get(link) {
+            Counters::add(&self.counters.leaf_cache_hits, 1);
+            return Ok(leaf);
+        }
         #[cfg(feature = "metrics")]
         let _timer = prom::LEAF_LOAD_HIST.start_timer();
-        let data = &self.get_block(link)?;
-        let (items, range) = ZstdDagCborSeq::decrypt(data, stream.value_key(), nonce::<T>())?;
-        Ok(Leaf::new(items, range))
+        let data = &self
+            .get_block(link)
+            .map_err(|cause| with_block(cause, BlockKind::Leaf, *link))?;
+        self.decrypt_leaf(stream, link, data)
+    }
+
+    /// decrypt a leaf from a block that has already been fetched
+    pub(crate) fn leaf_from_block(
+        &self,
+        stream: &Secrets,
+        link: &T::Link,
+        data: &[u8],
+    ) -> Result<Leaf> {
+        match self.leaf_cache.get(link) {
+            Some(leaf) => {
+                Counters::add(&self.counters.leaf_cache_hits, 1);
+                Ok(leaf)
+            }
+            None => self.decrypt_leaf(stream, link, data),
+        }
+    }
+
+    fn decrypt_leaf(&self, stream: &Secrets, link: &T::Link, data: &[u8]) -> Result<Leaf> {
+        Counters::add(&self.counters.leaf_cache_misses, 1);
+        let (items, range) = stream
+            .decrypt::<T>(BlockRole::Value, data)
+            .map_err(|cause| with_block(cause, BlockKind::Leaf, *link))?;
+        let leaf = Leaf::new(items, range);
+        self.leaf_cache.put(*link, &leaf);
+        Ok(leaf)
     }
 
     pub(crate) fn create_index_from_link(
         &self,
         secrets: &Secrets,
-        sealed: impl Fn(&[Index<T>], u32) -> bool,
+        config: Option<&Config>,
         link: T::Link,
     ) -> Result<(Index<T>, Range<u64>)> {
-        let index_key = secrets.index_key();
-        let bytes = self.get_block(&link)?;
-        let (children, byte_range) = deserialize_compressed::<T>(index_key, nonce::<T>(), &bytes)?;
+        let (bytes, (children, byte_range)) = self
+            .get_block(&link)
+            .and_then(|bytes| {
+                let res = deserialize_compressed::<T>(secrets, &bytes)?;
+                Ok((bytes, res))
+            })
+            .map_err(|cause| with_block(cause, BlockKind::Branch, link))?;
         let level = children.iter().map(|x| x.level()).max().unwrap() + 1;
         let count = children.iter().map(|x| x.count()).sum();
         let value_bytes = children.iter().map(|x| x.value_bytes()).sum();
         let key_bytes = children.iter().map(|x| x.key_bytes()).sum::<u64>() + (bytes.len() as u64);
-        let summaries = children.iter().map(|x| x.summarize()).collect();
+        let (summaries, sealed) = match config {
+            Some(config) => (
+                children
+                    .iter()
+                    .map(|x| config.summary_at(x, level))
+                    .collect(),
+                config.branch_sealed(&children, level),
+            ),
+            // not going to be extended, so the node might as well be sealed
+            None => (children.iter().map(|x| x.summarize()).collect(), true),
+        };
         let result = BranchIndex {
             link: Some(link),
             level,
             count,
             summaries,
-            sealed: sealed(&children, level),
+            sealed,
             value_bytes,
             key_bytes,
         }
@@ -413,8 +658,12 @@ where
     ) -> Result<Branch<T>> {
         let res = self.branch_cache().get(link);
         match res {
-            Some(branch) => Ok(branch),
+            Some(branch) => {
+                Counters::add(&self.counters.branch_cache_hits, 1);
+                Ok(branch)
+            }
             None => {
+                Counters::add(&self.counters.branch_cache_misses, 1);
                 let branch = self.load_branch_from_link(secrets, link)?;
                 self.branch_cache().put(*link, branch.clone());
                 Ok(branch)
@@ -422,17 +671,126 @@ where
         }
     }
 
+    /// load a branch given a branch index, from the cache, keeping track of misses for the parent
+    ///
+    /// If there are too many consecutive misses for children of `parent`, all its children
+    /// are loaded into the cache.
+    pub(crate) fn load_branch_cached_from_link_with_parent(
+        &self,
+        secrets: &Secrets,
+        link: &T::Link,
+        parent: Option<&T::Link>,
+    ) -> Result<Branch<T>> {
+        let parent = match parent {
+            Some(parent) => parent,
+            None => return self.load_branch_cached_from_link(secrets, link),
+        };
+        if let Some(branch) = self.branch_cache().get(link) {
+            Counters::add(&self.counters.branch_cache_hits, 1);
+            self.branch_cache().record_hit(parent);
+            return Ok(branch);
+        }
+        Counters::add(&self.counters.branch_cache_misses, 1);
+        let branch = self.load_branch_from_link(secrets, link)?;
+        self.branch_cache().put(*link, branch.clone());
+        if self.branch_cache().record_miss(parent) {
+            self.prefetch_children(secrets, parent)?;
+        }
+        Ok(branch)
+    }
+
+    /// load all branch children of a branch into the cache
+    fn prefetch_children(&self, secrets: &Secrets, parent: &T::Link) -> Result<()> {
+        let parent = self.load_branch_cached_from_link(secrets, parent)?;
+        let links = parent.children.iter().filter_map(|child| match child {
+            Index::Branch(index) => index.link,
+            Index::Leaf(_) => None,
+        });
+        let mut count = 0;
+        for link in links {
+            if self.branch_cache().get(&link).is_some() {
+                continue;
+            }
+            // prefetching is just an optimization, so failing to load a sibling
+            // that might never be needed is not an error.
+            match self.load_branch_from_link(secrets, &link) {
+                Ok(branch) => {
+                    self.branch_cache().put(link, branch);
+                    count += 1;
+                }
+                Err(cause) => tracing::debug!("prefetching {} failed: {}", link, cause),
+            }
+        }
+        Counters::add(&self.counters.branches_prefetched, count);
+        tracing::trace!("prefetched {} branches", count);
+        Ok(())
+    }
+
     fn get_block(&self, link: &T::Link) -> anyhow::Result<Box<[u8]>> {
         #[cfg(feature = "metrics")]
         let _timer = prom::BLOCK_GET_HIST.start_timer();
         let res = self.store.get(link);
-        #[cfg(feature = "metrics")]
         if let Ok(x) = &res {
+            self.count_block_read(x);
+            #[cfg(feature = "metrics")]
             prom::BLOCK_GET_SIZE_HIST.observe(x.len() as f64);
         }
         res
     }
 
+    fn count_block_read(&self, block: &[u8]) {
+        Counters::add(&self.counters.block_reads, 1);
+        Counters::add(&self.counters.bytes_read, block.len() as u64);
+    }
+
+    /// decodes the values selected by `matching` from a leaf, using the selection cache
+    pub(crate) fn select_values<V: ReadCbor>(
+        &self,
+        loader: &LeafLoader<T, R>,
+        matching: &[bool],
+    ) -> Result<Vec<V>> {
+        if !self.selection_cache.is_enabled() {
+            let leaf = loader.load()?;
+            return self.count_decompressed(|| leaf.as_ref().select(matching));
+        }
+        let items = match self.selection_cache.get(loader.link(), matching) {
+            Some(items) => items,
+            None => {
+                let leaf = loader.load()?;
+                let mut items = Vec::new();
+                self.count_decompressed(|| {
+                    leaf.as_ref().visit_selected(matching, &mut |_, cbor| {
+                        items.extend_from_slice(cbor.as_slice());
+                        Ok(())
+                    })
+                })?;
+                let items: Arc<[u8]> = items.into();
+                self.selection_cache
+                    .put(*loader.link(), matching, items.clone());
+                items
+            }
+        };
+        let mut result = Vec::new();
+        let mut bytes = &items[..];
+        while !bytes.is_empty() {
+            let (cbor, rest) = Cbor::checked_prefix(bytes)?;
+            result.push(V::read_cbor(cbor)?);
+            bytes = rest;
+        }
+        Ok(result)
+    }
+
+    /// runs `f`, counting the bytes it decompresses on this thread
+    ///
+    /// Nested calls are only counted once, by the outermost call.
+    pub(crate) fn count_decompressed<X>(&self, f: impl FnOnce() -> X) -> X {
+        let (res, n) = count_decompressed(f);
+        if let Some(n) = n {
+            Counters::add(&self.counters.bytes_decompressed, n);
+        }
+        res
+    }
+
     /// load a branch given a branch index
     pub(crate) fn load_branch_from_link(
         &self,
@@ -441,12 +799,13 @@ where
     ) -> Result<Branch<T>> {
         #[cfg(feature = "metrics")]
         let _timer = prom::BRANCH_LOAD_HIST.start_timer();
-        Ok({
+        let load = || {
             let bytes = self.get_block(link)?;
             let (children, byte_range) =
-                deserialize_compressed(secrets.index_key(), nonce::<T>(), &bytes)?;
-            Branch::<T>::new(children, byte_range)
-        })
+                self.count_decompressed(|| deserialize_compressed(secrets, &bytes))?;
+            Ok(Branch::<T>::new(children, byte_range))
+        };
+        load().map_err(|cause| with_block(cause, BlockKind::Branch, *link))
     }
 
     pub(crate) fn node_info(&self, secrets: &Secrets, index: &Index<T>) -> NodeInfo<T, R> {
@@ -472,9 +831,10 @@ where
     ) -> Result<Option<Branch<T>>> {
         let t0 = Instant::now();
         let result = Ok(if let Some(link) = &index.link {
-            let bytes = self.get_block(link)?;
-            let (children, byte_range) =
-                deserialize_compressed(secrets.index_key(), nonce::<T>(), &bytes)?;
+            let (children, byte_range) = self
+                .get_block(link)
+                .and_then(|bytes| deserialize_compressed(secrets, &bytes))
+                .map_err(|cause| with_block(cause, BlockKind::Branch, *link))?;
             Some(Branch::<T>::new(children, byte_range))
         } else {
             None
@@ -483,6 +843,167 @@ where
         result
     }
 
+    /// aggregate the elements of a subtree matching a query.
+    ///
+    /// Subtrees that are completely covered by the query are answered from their summaries.
+    /// Values are never loaded.
+    pub(crate) fn aggregate0<S, Q>(
+        &self,
+        secrets: &Secrets,
+        query: &Q,
+        offset: u64,
+        index: &Index<T>,
+        result: &mut Aggregate<S>,
+    ) -> Result<()>
+    where
+        S: AddAssign,
+        Q: Query<T>,
+        T::Key: Aggregatable<S>,
+        T::Summary: Aggregatable<S>,
+    {
+        match index {
+            // purged parts of the tree are skipped, just like when iterating
+            Index::Leaf(index) if index.link.is_some() => {
+                let mut matching: SmallVec<[_; 32]> = smallvec![true; index.keys.len()];
+                query.containing(offset, index, &mut matching);
+                for key in index.select_keys(&matching) {
+                    result.count += 1;
+                    result.value += key.aggregate_value();
+                }
+            }
+            Index::Branch(index) if index.link.is_some() => {
+                // the index of a branch does not tell whether some of its children are purged,
+                // so even covered branches have to be loaded. Their leaves are never loaded.
+                let covered = query.covers(offset, index);
+                let mut matching: SmallVec<[_; 64]> = smallvec![true; index.summaries.len()];
+                if !covered {
+                    query.intersecting(offset, index, &mut matching);
+                    if !matching.any() {
+                        return Ok(());
+                    }
+                }
+                let branch =
+                    self.load_branch_cached_from_link(secrets, index.link.as_ref().unwrap())?;
+                let mut offset = offset;
+                for (child, matching) in branch.children.iter().zip(matching) {
+                    match child {
+                        Index::Leaf(leaf) if covered && leaf.link.is_some() => {
+                            let summary: T::Summary = child.summarize();
+                            result.count += child.count();
+                            result.value += summary.aggregate_value();
+                        }
+                        // purged leaves have nothing to aggregate
+                        Index::Leaf(_) if covered => {}
+                        _ if matching => {
+                            self.aggregate0(secrets, query, offset, child, result)?;
+                        }
+                        _ => {}
+                    }
+                    offset += child.count();
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// count the elements of a subtree matching a query, without loading any leaves.
+    pub(crate) fn count_matching0<Q: Query<T>>(
+        &self,
+        secrets: &Secrets,
+        query: &Q,
+        offset: u64,
+        index: &Index<T>,
+    ) -> Result<u64> {
+        Ok(match index {
+            // purged parts of the tree are skipped, just like when iterating
+            Index::Leaf(index) if index.link.is_some() => {
+                let mut matching: SmallVec<[_; 32]> = smallvec![true; index.keys.len()];
+                query.containing(offset, index, &mut matching);
+                matching.iter().filter(|x| **x).count() as u64
+            }
+            Index::Branch(index) if index.link.is_some() => {
+                // like in aggregate0, covered branches are loaded to skip purged children
+                let covered = query.covers(offset, index);
+                let mut matching: SmallVec<[_; 64]> = smallvec![true; index.summaries.len()];
+                if !covered {
+                    query.intersecting(offset, index, &mut matching);
+                    if !matching.any() {
+                        return Ok(0);
+                    }
+                }
+                let branch =
+                    self.load_branch_cached_from_link(secrets, index.link.as_ref().unwrap())?;
+                let mut offset = offset;
+                let mut count = 0;
+                for (child, matching) in branch.children.iter().zip(matching) {
+                    match child {
+                        Index::Leaf(leaf) if covered && leaf.link.is_some() => {
+                            count += child.count();
+                        }
+                        // purged leaves have nothing to count
+                        Index::Leaf(_) if covered => {}
+                        _ if matching => {
+                            count += self.count_matching0(secrets, query, offset, child)?;
+                        }
+                        _ => {}
+                    }
+                    offset += child.count();
+                }
+                count
+            }
+            _ => 0,
+        })
+    }
+
+    /// estimate the number of elements of a subtree matching a query.
+    ///
+    /// Branches whose children are leaves are never loaded, so this only reads the upper levels
+    /// of the tree.
+    pub(crate) fn estimate_matching0<Q: Query<T>>(
+        &self,
+        secrets: &Secrets,
+        query: &Q,
+        offset: u64,
+        index: &Index<T>,
+        result: &mut MatchEstimate,
+    ) -> Result<()> {
+        match index {
+            // the keys of a leaf are part of the index, so this is exact
+            Index::Leaf(index) if index.link.is_some() => {
+                let mut matching: SmallVec<[_; 32]> = smallvec![true; index.keys.len()];
+                query.containing(offset, index, &mut matching);
+                let count = matching.iter().filter(|x| **x).count() as u64;
+                result.lower += count;
+                result.upper += count;
+            }
+            Index::Branch(index) if index.link.is_some() => {
+                let mut matching: SmallVec<[_; 64]> = smallvec![true; index.summaries.len()];
+                query.intersecting(offset, index, &mut matching);
+                if !matching.any() {
+                    return Ok(());
+                }
+                if index.level <= 1 {
+                    // without loading the branch we know neither the counts of the children
+                    // nor whether some of them are purged, even if the query covers it
+                    result.upper += index.count;
+                    return Ok(());
+                }
+                let branch =
+                    self.load_branch_cached_from_link(secrets, index.link.as_ref().unwrap())?;
+                let mut offset = offset;
+                for (child, matching) in branch.children.iter().zip(matching) {
+                    if matching {
+                        self.estimate_matching0(secrets, query, offset, child, result)?;
+                    }
+                    offset += child.count();
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
     pub(crate) fn get0<V: ReadCbor>(
         &self,
         stream: &Secrets,
@@ -505,15 +1026,73 @@ where
                 Err(anyhow!("index out of bounds: {}", offset))
             }
             NodeInfo::Leaf(index, leaf) => {
-                let k = index.keys.get(offset as usize).unwrap();
+                let k = index
+                    .keys
+                    .get(offset as usize)
+                    .ok_or_else(|| anyhow!("index out of bounds: {}", offset))?;
                 let leaf = leaf.load()?;
-                let v = leaf.child_at::<V>(offset)?;
+                let v = self.count_decompressed(|| leaf.child_at::<V>(offset))?;
                 Ok(Some((k, v)))
             }
             NodeInfo::PurgedBranch(_) | NodeInfo::PurgedLeaf(_) => Ok(None),
         }
     }
 
+    /// fills the result slots for `requests`, which are pairs of an offset and a slot, sorted by
+    /// offset. `start` is the offset of the first element of `index`.
+    pub(crate) fn get_many0<V: ReadCbor>(
+        &self,
+        stream: &Secrets,
+        index: &Index<T>,
+        mut start: u64,
+        mut requests: &[(u64, usize)],
+        into: &mut [Option<(T::Key, V)>],
+    ) -> Result<()> {
+        if requests.is_empty() {
+            return Ok(());
+        }
+        match self.node_info(stream, index) {
+            NodeInfo::Branch(_, info) => {
+                let node = info.load_cached()?;
+                for child in node.children.iter() {
+                    let end = start + child.count();
+                    let n = requests
+                        .iter()
+                        .take_while(|(offset, _)| *offset < end)
+                        .count();
+                    let (inside, rest) = requests.split_at(n);
+                    self.get_many0(stream, child, start, inside, into)?;
+                    requests = rest;
+                    start = end;
+                }
+            }
+            NodeInfo::Leaf(index, leaf) => {
+                let mut take = vec![false; index.keys.count() as usize];
+                for (offset, _) in requests {
+                    take[(offset - start) as usize] = true;
+                }
+                let leaf = leaf.load()?;
+                let mut requests = requests.iter().peekable();
+                self.count_decompressed(|| {
+                    leaf.as_ref().visit_selected(&take, &mut |i, cbor| {
+                        let offset = start + i as u64;
+                        // decode once for every request, so duplicate offsets need no clone
+                        while let Some((_, slot)) = requests.next_if(|(o, _)| *o == offset) {
+                            let key = index
+                                .keys
+                                .get(i)
+                                .ok_or_else(|| anyhow!("index out of bounds: {}", offset))?;
+                            into[*slot] = Some((key, V::read_cbor(cbor)?));
+                        }
+                        Ok(())
+                    })
+                })?;
+            }
+            NodeInfo::PurgedBranch(_) | NodeInfo::PurgedLeaf(_) => {}
+        }
+        Ok(())
+    }
+
     pub(crate) fn collect0<V: ReadCbor>(
         &self,
         stream: &Secrets,
@@ -534,7 +1113,8 @@ where
                 }
             }
             NodeInfo::Leaf(index, node) => {
-                let vs = node.load()?.as_ref().items::<V>()?;
+                let leaf = node.load()?;
+                let vs = self.count_decompressed(|| leaf.as_ref().items::<V>())?;
                 let ks = index.keys.to_vec();
                 for (k, v) in ks.into_iter().zip(vs.into_iter()).skip(offset as usize) {
                     into.push(Some((k, v)));
@@ -596,11 +1176,122 @@ where
         query: Q,
         index: Index<T>,
     ) -> impl Iterator<Item = Result<(u64, T::Key, V)>> {
-        self.traverse0(secrets, query, index, &|_| {})
-            .flat_map(|res| match res {
-                Ok(chunk) => chunk.data.into_iter().map(Ok).left_iter(),
-                Err(cause) => iter::once(Err(cause)).right_iter(),
-            })
+        self.iter_filtered_batched0(secrets, query, index, 1)
+    }
+    pub(crate) fn iter_filtered_batched0<Q: Query<T>, V: BanyanValue>(
+        &self,
+        secrets: Secrets,
+        query: Q,
+        index: Index<T>,
+        batch_size: usize,
+    ) -> impl Iterator<Item = Result<(u64, T::Key, V)>> {
+        TreeIter::new(
+            self.clone(),
+            secrets,
+            query,
+            ChunkVisitor::<_, (V, ())>::new(&|_: &NodeInfo<T, R>| {}),
+            index,
+        )
+        .with_batch_size(batch_size)
+        .flat_map(|res| match res {
+            Ok(chunk) => chunk.data.into_iter().map(Ok).left_iter(),
+            Err(cause) => iter::once(Err(cause)).right_iter(),
+        })
+    }
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn iter_filtered_with_policy0<Q: Query<T>, V: BanyanValue>(
+        &self,
+        secrets: Secrets,
+        query: Q,
+        index: Index<T>,
+        policy: MissingValuePolicy,
+    ) -> impl Iterator<Item = Result<(u64, T::Key, Option<V>)>> {
+        TreeIter::new(
+            self.clone(),
+            secrets,
+            query,
+            PolicyVisitor::new(policy),
+            index,
+        )
+        .flat_map(|res| match res {
+            Ok(elems) => elems.into_iter().map(Ok).left_iter(),
+            Err(cause) => iter::once(Err(cause)).right_iter(),
+        })
+    }
+    pub(crate) fn for_each_filtered_cbor0<Q: Query<T>>(
+        &self,
+        secrets: Secrets,
+        query: Q,
+        index: Index<T>,
+        f: &mut impl FnMut(u64, T::Key, &Cbor) -> Result<()>,
+    ) -> Result<()> {
+        for res in TreeIter::new(self.clone(), secrets, query, LeafVisitor, index) {
+            if let Some((start, index, leaf, matching)) = res? {
+                let mut keys = index.select_keys(&matching);
+                // the decompression buffer is borrowed while visiting, so copy the values out
+                // and call `f` afterwards, in case it reads from the forest itself
+                let mut values = Vec::new();
+                self.count_decompressed(|| {
+                    leaf.as_ref().visit_selected(&matching, &mut |i, cbor| {
+                        values.push((i, cbor.to_owned()));
+                        Ok(())
+                    })
+                })?;
+                for (i, cbor) in values {
+                    let key = keys
+                        .next()
+                        .ok_or_else(|| anyhow!("fewer keys than values"))?;
+                    f(start + i as u64, key, &cbor)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    pub(crate) fn run_pipeline0<Q: Query<T>, V: BanyanValue, O: 'static>(
+        &self,
+        secrets: Secrets,
+        query: Q,
+        index: Index<T>,
+        pipeline: &mut Pipeline<(u64, T::Key, V), O>,
+    ) -> Result<Vec<O>> {
+        let mut result = Vec::new();
+        if pipeline.is_done(0) {
+            return Ok(result);
+        }
+        for res in TreeIter::new(self.clone(), secrets, query, LeafVisitor, index) {
+            if let Some((start, index, leaf, matching)) = res? {
+                let mut keys = index.select_keys(&matching);
+                let done = self.count_decompressed(|| -> Result<bool> {
+                    let mut items = leaf.as_ref().iter::<V>()?;
+                    for (i, take) in matching.iter().enumerate() {
+                        if !*take {
+                            items
+                                .skip_item()
+                                .ok_or_else(|| anyhow!("fewer values than keys"))??;
+                            continue;
+                        }
+                        let value = items
+                            .next()
+                            .ok_or_else(|| anyhow!("fewer values than keys"))??;
+                        let key = keys
+                            .next()
+                            .ok_or_else(|| anyhow!("fewer keys than values"))?;
+                        if let Some(item) = pipeline.push((start + i as u64, key, value)) {
+                            result.push(item);
+                            // stop decoding in the middle of the leaf
+                            if pipeline.is_done(result.len()) {
+                                return Ok(true);
+                            }
+                        }
+                    }
+                    Ok(false)
+                })?;
+                if done {
+                    break;
+                }
+            }
+        }
+        Ok(result)
     }
     pub(crate) fn iter_filtered_reverse0<Q: Query<T>, V: BanyanValue>(
         &self,
@@ -662,6 +1353,57 @@ where
         Ok(())
     }
 
+    /// writes the node for `index` and all its descendants as DOT statements, returns the id of
+    /// the node. `next_id` is the id for the next node to be written.
+    pub(crate) fn dump_dot0(
+        &self,
+        secrets: &Secrets,
+        index: &Index<T>,
+        next_id: &mut usize,
+        w: &mut impl Write,
+    ) -> Result<usize> {
+        let id = *next_id;
+        *next_id += 1;
+        let purged = index.link().is_none();
+        let (kind, shape) = match (index, purged) {
+            (Index::Leaf(_), false) => ("Leaf", "ellipse"),
+            (Index::Leaf(_), true) => ("PurgedLeaf", "ellipse"),
+            (Index::Branch(_), false) => ("Branch", "box"),
+            (Index::Branch(_), true) => ("PurgedBranch", "box"),
+        };
+        // purged nodes are dotted, unsealed nodes are dashed
+        let style = if purged {
+            "dotted"
+        } else if !index.sealed() {
+            "dashed"
+        } else {
+            "solid"
+        };
+        writeln!(
+            w,
+            "  n{} [shape={}, style={}, label=\"{}\\nlevel={} count={} sealed={}\\nkey_bytes={} value_bytes={}\"];",
+            id,
+            shape,
+            style,
+            kind,
+            index.level(),
+            index.count(),
+            index.sealed(),
+            index.key_bytes(),
+            index.value_bytes(),
+        )?;
+        if let Index::Branch(b) = index {
+            if let Some(link) = b.link {
+                let branch = self.load_branch_from_link(secrets, &link)?;
+                for child in branch.children.iter() {
+                    let child_id = self.dump_dot0(secrets, child, next_id, w)?;
+                    writeln!(w, "  n{} -> n{};", id, child_id)?;
+                }
+            }
+        }
+        Ok(id)
+    }
+
     pub(crate) fn roots_impl(&self, stream: &Secrets, index: &Index<T>) -> Result<Vec<Index<T>>> {
         let mut res = Vec::new();
         let mut level: i32 = i32::max_value();
@@ -693,50 +1435,114 @@ where
         Ok(())
     }
 
+    /// checks a node and its children, adding the failed invariants to `violations`
+    ///
+    /// Without a config, the summaries and the sealed flags of branches are not checked.
     pub(crate) fn check_invariants0(
         &self,
         secrets: &Secrets,
-        config: &Config,
+        config: Option<&Config>,
         index: &Index<T>,
         level: &mut i32,
-        msgs: &mut Vec<String>,
+        path: &mut Vec<usize>,
+        violations: &mut Vec<Violation>,
     ) -> Result<()> {
-        macro_rules! check {
-            ($expression:expr) => {
-                if !$expression {
-                    let text = stringify!($expression);
-                    msgs.push(format!("{}", text));
-                }
-            };
-        }
-        if !index.sealed() {
+        let mut check = |ok: bool, kind: ViolationKind| {
+            if !ok {
+                violations.push(Violation {
+                    path: path.clone(),
+                    kind,
+                });
+            }
+        };
+        // nodes only become sealed from left to right, so after an unsealed node there can only
+        // be sealed nodes below its level
+        if index.sealed() {
+            check(
+                index.level() as i32 <= *level,
+                ViolationKind::SealedAfterUnsealed {
+                    level: index.level(),
+                },
+            );
+        } else {
             *level = (*level).min((index.level() as i32) - 1);
         }
         match self.node_info(secrets, index) {
             NodeInfo::Leaf(index, leaf) => {
                 let leaf = leaf.load()?;
-                let value_count = leaf.as_ref().count()?;
+                let value_count = self.count_decompressed(|| leaf.as_ref().count())?;
                 let key_count = index.keys.count();
-                check!(value_count == key_count);
+                check(
+                    value_count == key_count,
+                    ViolationKind::BadElementCount {
+                        expected: key_count,
+                        actual: value_count,
+                    },
+                );
             }
             NodeInfo::Branch(index, branch) => {
                 let branch = branch.load_cached()?;
-                check!(branch.count() == index.summaries.count());
-                for child in &branch.children.to_vec() {
-                    if index.sealed {
-                        check!(child.level() == index.level - 1);
+                let children = branch.count();
+                check(
+                    children == index.summaries.count(),
+                    ViolationKind::BadChildCount {
+                        expected: index.summaries.count(),
+                        actual: children,
+                    },
+                );
+                let count = branch.children.iter().map(|x| x.count()).sum::<u64>();
+                check(
+                    count == index.count,
+                    ViolationKind::BadElementCount {
+                        expected: index.count,
+                        actual: count,
+                    },
+                );
+                for child in branch.children.iter() {
+                    let ok = if index.sealed {
+                        child.level() == index.level - 1
                     } else {
-                        check!(child.level() < index.level);
-                    }
+                        child.level() < index.level
+                    };
+                    let kind = ViolationKind::WrongLevel {
+                        parent: index.level,
+                        child: child.level(),
+                    };
+                    check(ok, kind);
                 }
-                for (child, summary) in branch.children.iter().zip(index.summaries()) {
-                    let child_summary = child.summarize();
-                    check!(child_summary == summary);
+                if let Some(config) = config {
+                    for (i, (child, summary)) in
+                        branch.children.iter().zip(index.summaries()).enumerate()
+                    {
+                        let child_summary = config.summary_at(child, index.level);
+                        check(
+                            child_summary == summary,
+                            ViolationKind::SummaryMismatch { child: i },
+                        );
+                    }
+                    let branch_sealed = config.branch_sealed(&branch.children, index.level);
+                    check(
+                        index.sealed == branch_sealed,
+                        ViolationKind::NonMonotoneSealing {
+                            sealed: index.sealed,
+                        },
+                    );
                 }
-                let branch_sealed = config.branch_sealed(&branch.children, index.level);
-                check!(index.sealed == branch_sealed);
-                for child in &branch.children.to_vec() {
-                    self.check_invariants0(secrets, config, child, level, msgs)?;
+                // the children of a branch created by extend_unpacked are separate trees, so
+                // the sealed nodes of one may come after the unsealed nodes of the one before
+                let unpacked = !index.sealed
+                    && branch.children.split_last().is_some_and(|(_, rest)| {
+                        rest.iter()
+                            .any(|child| !child.sealed() || child.level() != index.level - 1)
+                    });
+                let parent_level = *level;
+                for (i, child) in branch.children.iter().enumerate() {
+                    if unpacked {
+                        *level = parent_level;
+                    }
+                    path.push(i);
+                    self.check_invariants0(secrets, config, child, level, path, violations)?;
+                    path.pop();
                 }
             }
             NodeInfo::PurgedBranch(_) => {
@@ -749,6 +1555,74 @@ where
         Ok(())
     }
 
+    /// checks a node without a config, and everything below it with
+    /// [check_invariants0](#method.check_invariants0) if `full` is set
+    pub(crate) fn validate0(
+        &self,
+        secrets: &Secrets,
+        index: &Index<T>,
+        full: bool,
+        msgs: &mut Vec<String>,
+    ) -> Result<()> {
+        macro_rules! check {
+            ($link:expr, $expression:expr) => {
+                if !$expression {
+                    let text = stringify!($expression);
+                    msgs.push(format!("{} ({})", text, $link));
+                }
+            };
+        }
+        let mut violations = Vec::new();
+        match self.node_info(secrets, index) {
+            NodeInfo::Branch(index, branch) => {
+                let link = index.link.unwrap();
+                let branch = branch.load_cached()?;
+                let children = &branch.children;
+                check!(link, children.len() == index.summaries.len());
+                check!(
+                    link,
+                    children.iter().map(|x| x.count()).sum::<u64>() == index.count
+                );
+                check!(
+                    link,
+                    children.iter().map(|x| x.value_bytes()).sum::<u64>() == index.value_bytes
+                );
+                check!(link, children.iter().all(|x| x.level() < index.level));
+                check!(link, children.iter().all(|x| x.count() > 0));
+                if full {
+                    // a loaded root is always marked as sealed, so whether its children may be
+                    // unsealed is unknown, and each child is checked as a separate tree
+                    for (i, child) in children.iter().enumerate() {
+                        let mut level = i32::MAX;
+                        let mut path = vec![i];
+                        self.check_invariants0(
+                            secrets,
+                            None,
+                            child,
+                            &mut level,
+                            &mut path,
+                            &mut violations,
+                        )?;
+                    }
+                }
+            }
+            _ if full => {
+                let mut level = i32::MAX;
+                self.check_invariants0(
+                    secrets,
+                    None,
+                    index,
+                    &mut level,
+                    &mut Vec::new(),
+                    &mut violations,
+                )?;
+            }
+            _ => {}
+        }
+        msgs.extend(violations.iter().map(|violation| violation.to_string()));
+        Ok(())
+    }
+
     /// Checks if a node is packed to the left
     pub(crate) fn is_packed0(&self, secrets: &Secrets, index: &Index<T>) -> Result<bool> {
         Ok(
@@ -775,4 +1649,274 @@ where
             },
         )
     }
+
+    /// the key of the last element of a tree
+    pub(crate) fn last_key0(&self, secrets: &Secrets, index: &Index<T>) -> Result<T::Key> {
+        match self.node_info(secrets, index) {
+            NodeInfo::Leaf(index, _) | NodeInfo::PurgedLeaf(index) => Ok(index.keys.last()),
+            NodeInfo::Branch(_, branch) => {
+                let branch = branch.load_cached()?;
+                let last = branch.children.last().expect("branch has no children");
+                self.last_key0(secrets, last)
+            }
+            NodeInfo::PurgedBranch(_) => Err(anyhow!("the end of the tree has been purged")),
+        }
+    }
+
+    /// finds the first element with a key that is not smaller than `key`, in a tree with
+    /// ordered keys
+    pub(crate) fn find_by_key0<K: Ord, V: ReadCbor>(
+        &self,
+        secrets: &Secrets,
+        index: &Index<T>,
+        key: &K,
+        offset: u64,
+    ) -> Result<Option<(u64, T::Key, V)>>
+    where
+        T::Key: OrderedSummary<K>,
+        T::Summary: OrderedSummary<K>,
+    {
+        match self.node_info(secrets, index) {
+            NodeInfo::Branch(index, branch) => {
+                let first = partition_point(index.summaries.len(), |i| {
+                    index.summaries.get(i).unwrap().max_key() < *key
+                });
+                let branch = branch.load_cached()?;
+                let offsets = zip_with_offset_ref(branch.children.iter(), offset);
+                // truncated summaries can cover larger keys than the subtree contains
+                for (child, offset) in offsets.skip(first) {
+                    let found = self.find_by_key0(secrets, child, key, offset)?;
+                    if found.is_some() {
+                        return Ok(found);
+                    }
+                }
+                Ok(None)
+            }
+            NodeInfo::Leaf(index, leaf) => {
+                let i = partition_point(index.keys.len(), |i| {
+                    index.keys.get(i).unwrap().max_key() < *key
+                });
+                if i < index.keys.len() {
+                    let leaf = leaf.load()?;
+                    let v = leaf.child_at::<V>(i as u64)?;
+                    Ok(Some((offset + i as u64, index.keys.get(i).unwrap(), v)))
+                } else {
+                    Ok(None)
+                }
+            }
+            NodeInfo::PurgedBranch(_) | NodeInfo::PurgedLeaf(_) => Ok(None),
+        }
+    }
+
+    /// Collects the sealed subtrees at the start of a tree whose elements all match a query,
+    /// skipping subtrees without any matches.
+    ///
+    /// Stops at the first node with some, but not all, elements matching, or that is not
+    /// sealed. `resume` is set to the offset of the first element that has not been handled.
+    /// The roots are sorted by level in descending order, so they can be joined to a tree.
+    ///
+    /// Returns `None` if the walk was stopped, and otherwise whether the node was added as a whole.
+    pub(crate) fn materialize_prefix0<Q: Query<T>>(
+        &self,
+        secrets: &Secrets,
+        query: &Q,
+        offset: u64,
+        index: &Index<T>,
+        roots: &mut Vec<Index<T>>,
+        resume: &mut u64,
+    ) -> Result<Option<bool>> {
+        Ok(match self.node_info(secrets, index) {
+            NodeInfo::Leaf(leaf, _) => {
+                let mut matching = vec![true; leaf.keys.len()];
+                query.containing(offset, &leaf, &mut matching);
+                if !matching.as_slice().any() {
+                    *resume = offset + index.count();
+                    Some(false)
+                } else if leaf.sealed && matching.iter().all(|x| *x) {
+                    roots.push(index.clone());
+                    *resume = offset + index.count();
+                    Some(true)
+                } else {
+                    None
+                }
+            }
+            NodeInfo::Branch(branch_index, branch) => {
+                let mut matching = vec![true; branch_index.summaries.len()];
+                query.intersecting(offset, &branch_index, &mut matching);
+                if !matching.as_slice().any() {
+                    *resume = offset + index.count();
+                    return Ok(Some(false));
+                }
+                let branch = branch.load_cached()?;
+                let start = roots.len();
+                let mut whole = true;
+                let offsets = zip_with_offset_ref(branch.children.iter(), offset);
+                for (i, (child, offset)) in offsets.enumerate() {
+                    if !matching[i] {
+                        *resume = offset + child.count();
+                        whole = false;
+                        continue;
+                    }
+                    match self.materialize_prefix0(secrets, query, offset, child, roots, resume)? {
+                        Some(child_whole) => whole &= child_whole,
+                        None => return Ok(None),
+                    }
+                }
+                // the node replaces its children, unless it would be above the previous root
+                let fits = start == 0 || roots[start - 1].level() >= branch_index.level;
+                if whole && branch_index.sealed && fits {
+                    roots.truncate(start);
+                    roots.push(index.clone());
+                    Some(true)
+                } else {
+                    Some(false)
+                }
+            }
+            NodeInfo::PurgedLeaf(_) | NodeInfo::PurgedBranch(_) => None,
+        })
+    }
+}
+
+/// the number of leading elements of a sorted sequence of length `len` for which `pred` is true
+fn partition_point(len: usize, pred: impl Fn(usize) -> bool) -> usize {
+    let mut range = 0..len;
+    while range.start < range.end {
+        let mid = range.start + (range.end - range.start) / 2;
+        if pred(mid) {
+            range.start = mid + 1;
+        } else {
+            range.end = mid;
+        }
+    }
+    range.start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        index::{serialize_compressed, VecSeq},
+        query::AllQuery,
+        store::{BlockWriter, BranchCache, MemStore},
+        testing::{Sha256Digest, SimpleTT},
+        CipherOffset, StreamBuilder, StreamBuilderState, Transaction, Validation,
+    };
+
+    #[test]
+    fn sealed_after_unsealed() -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let forest = Forest::<SimpleTT, _>::new(store.clone(), BranchCache::default());
+        let mut txn = Transaction::new(forest, store);
+        let secrets = Secrets::default();
+        // 4 sealed level 1 branches of 4 leaves each below a sealed root
+        let mut builder = StreamBuilder::new(Config::debug(), secrets.clone());
+        txn.extend(&mut builder, (0..160u64).map(|i| (i, i)))?;
+        assert!(txn.check_invariants(&builder)?.is_empty());
+
+        // a copy of the root where the second level 1 branch claims to be unsealed
+        let root = match builder.index() {
+            Some(Index::Branch(root)) => (**root).clone(),
+            _ => panic!("root is not a branch"),
+        };
+        let mut children = txn
+            .load_branch_cached_from_link(&secrets, root.link.as_ref().unwrap())?
+            .children
+            .to_vec();
+        let mut child = match &children[1] {
+            Index::Branch(child) => (**child).clone(),
+            _ => panic!("child is not a branch"),
+        };
+        child.sealed = false;
+        children[1] = child.into();
+        let mut offset = CipherOffset::new(1 << 30);
+        let block = serialize_compressed(&secrets, &mut offset, &children, 0)?;
+        let root = BranchIndex {
+            link: Some(txn.writer_mut().put(block)?),
+            ..root
+        };
+        let state = StreamBuilderState::new(1 << 31, secrets, Config::debug());
+        let forged = StreamBuilder::<SimpleTT, u64>::new_from_index(Some(root.into()), state);
+
+        let violations = txn
+            .check_invariants(&forged)?
+            .into_iter()
+            .filter(|violation| violation.kind == ViolationKind::SealedAfterUnsealed { level: 1 })
+            .map(|violation| violation.path)
+            .collect::<Vec<_>>();
+        assert_eq!(violations, vec![vec![2], vec![3]]);
+        Ok(())
+    }
+
+    #[test]
+    fn pipeline_missing_values() -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let forest = Forest::<SimpleTT, _>::new(store.clone(), BranchCache::default());
+        let mut txn = Transaction::new(forest, store);
+        let secrets = Secrets::default();
+        let mut builder = StreamBuilder::new(Config::debug(), secrets.clone());
+        txn.extend_unpacked(&mut builder, vec![(0u64, 0u64), (1, 1)])?;
+
+        // a leaf that claims to have one more key than it has values
+        let root = match builder.index() {
+            Some(Index::Branch(root)) => (**root).clone(),
+            _ => panic!("root is not a branch"),
+        };
+        let children = txn
+            .load_branch_cached_from_link(&secrets, root.link.as_ref().unwrap())?
+            .children
+            .to_vec();
+        let mut leaf = match &children[0] {
+            Index::Leaf(leaf) => (**leaf).clone(),
+            _ => panic!("child is not a leaf"),
+        };
+        leaf.keys = vec![0u64, 1, 2].into_iter().collect::<VecSeq<_>>();
+        let state = StreamBuilderState::new(1 << 31, secrets, Config::debug());
+        let forged = StreamBuilder::<SimpleTT, u64>::new_from_index(Some(leaf.into()), state);
+
+        let error = txn
+            .run_pipeline(&forged.snapshot(), AllQuery, Pipeline::new())
+            .unwrap_err();
+        assert_eq!(error.root_cause().to_string(), "fewer values than keys");
+        Ok(())
+    }
+
+    #[test]
+    fn full_validation() -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let forest = Forest::<SimpleTT, _>::new(store.clone(), BranchCache::default());
+        let mut txn = Transaction::new(forest, store);
+        let secrets = Secrets::default();
+        let mut builder = StreamBuilder::new(Config::debug(), secrets.clone());
+        txn.extend(&mut builder, (0..30u64).map(|i| (i, i)))?;
+
+        // a copy of the root where the first leaf claims to have one more key than values
+        let root = match builder.index() {
+            Some(Index::Branch(root)) => (**root).clone(),
+            _ => panic!("root is not a branch"),
+        };
+        let mut children = txn
+            .load_branch_cached_from_link(&secrets, root.link.as_ref().unwrap())?
+            .children
+            .to_vec();
+        let mut leaf = match &children[0] {
+            Index::Leaf(leaf) => (**leaf).clone(),
+            _ => panic!("child is not a leaf"),
+        };
+        let mut keys = leaf.keys.to_vec();
+        keys.push(1000);
+        leaf.keys = keys.into_iter().collect::<VecSeq<_>>();
+        children[0] = leaf.into();
+        let mut offset = CipherOffset::new(1 << 30);
+        let block = serialize_compressed(&secrets, &mut offset, &children, 0)?;
+        let link = txn.writer_mut().put(block)?;
+
+        txn.load_tree_with::<u64>(secrets.clone(), link, Validation::Quick)?;
+        let error = txn
+            .load_tree_with::<u64>(secrets, link, Validation::Full)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("number of elements is"), "{}", error);
+        assert!(error.ends_with(" at /0"), "{}", error);
+        Ok(())
+    }
 }
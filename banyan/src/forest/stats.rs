@@ -0,0 +1,155 @@
+//! cheap counters for the work done by a forest, and statistics about the shape of trees
+use super::{Config, TreeTypes};
+use crate::index::{CompactSeq, Index};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters for the work done by a [Forest](struct.Forest.html) since it was created
+///
+/// Forests created by [Forest::transaction](struct.Forest.html#method.transaction) share the
+/// counters of the forest they were created from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ForestStats {
+    /// branches that were found in the branch cache
+    pub branch_cache_hits: u64,
+    /// branches that had to be loaded from the store
+    pub branch_cache_misses: u64,
+    /// leaves that were found in the leaf cache
+    pub leaf_cache_hits: u64,
+    /// leaves that had to be decrypted, including all leaves if the leaf cache is disabled
+    pub leaf_cache_misses: u64,
+    /// number of blocks read from the store
+    pub block_reads: u64,
+    /// total size of the blocks read from the store
+    pub bytes_read: u64,
+    /// decompressed size of all branches and leaves that were decoded
+    pub bytes_decompressed: u64,
+    /// branches that were loaded into the branch cache ahead of time, see
+    /// [BranchCache::with_prefetch](crate::store::BranchCache::with_prefetch)
+    pub branches_prefetched: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) branch_cache_hits: AtomicU64,
+    pub(crate) branch_cache_misses: AtomicU64,
+    pub(crate) leaf_cache_hits: AtomicU64,
+    pub(crate) leaf_cache_misses: AtomicU64,
+    pub(crate) block_reads: AtomicU64,
+    pub(crate) bytes_read: AtomicU64,
+    pub(crate) bytes_decompressed: AtomicU64,
+    pub(crate) branches_prefetched: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ForestStats {
+        let get = |x: &AtomicU64| x.load(Ordering::Relaxed);
+        ForestStats {
+            branch_cache_hits: get(&self.branch_cache_hits),
+            branch_cache_misses: get(&self.branch_cache_misses),
+            leaf_cache_hits: get(&self.leaf_cache_hits),
+            leaf_cache_misses: get(&self.leaf_cache_misses),
+            block_reads: get(&self.block_reads),
+            bytes_read: get(&self.bytes_read),
+            bytes_decompressed: get(&self.bytes_decompressed),
+            branches_prefetched: get(&self.branches_prefetched),
+        }
+    }
+}
+
+/// Structural statistics of a tree, see [Forest::tree_stats](struct.Forest.html#method.tree_stats)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// statistics per level, starting with the leaves at level 0
+    pub levels: Vec<LevelStats>,
+}
+
+/// Statistics for all nodes of one level of a tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LevelStats {
+    /// number of nodes
+    pub nodes: u64,
+    /// number of sealed nodes
+    pub sealed: u64,
+    /// number of purged nodes, which no longer have a block
+    pub purged: u64,
+    /// total number of children of branches, or of elements of leaves
+    pub children: u64,
+    /// total serialized size of the values
+    pub value_bytes: u64,
+}
+
+impl LevelStats {
+    /// average number of children of branches, or of elements of leaves
+    pub fn fanout(&self) -> f64 {
+        ratio(self.children, self.nodes)
+    }
+}
+
+impl TreeStats {
+    pub(crate) fn add<T: TreeTypes>(&mut self, index: &Index<T>) {
+        let level = index.level() as usize;
+        if self.levels.len() <= level {
+            self.levels.resize(level + 1, LevelStats::default());
+        }
+        let stats = &mut self.levels[level];
+        stats.nodes += 1;
+        stats.sealed += index.sealed() as u64;
+        stats.purged += index.link().is_none() as u64;
+        stats.children += match index {
+            Index::Leaf(leaf) => leaf.keys.count(),
+            Index::Branch(branch) => branch.summaries.count(),
+        };
+        if level == 0 {
+            stats.value_bytes += index.value_bytes();
+        }
+    }
+
+    /// number of levels, including the leaves
+    pub fn depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// total number of nodes
+    pub fn nodes(&self) -> u64 {
+        self.levels.iter().map(|x| x.nodes).sum()
+    }
+
+    /// average number of children of all branches
+    pub fn branch_fanout(&self) -> f64 {
+        let branches = self.levels.iter().skip(1);
+        let children = branches.clone().map(|x| x.children).sum();
+        ratio(children, branches.map(|x| x.nodes).sum())
+    }
+
+    /// fraction of nodes that are sealed
+    pub fn sealed_ratio(&self) -> f64 {
+        ratio(self.levels.iter().map(|x| x.sealed).sum(), self.nodes())
+    }
+
+    /// average number of elements per leaf, relative to [Config::max_leaf_count]
+    pub fn leaf_fill(&self, config: &Config) -> f64 {
+        self.leaves().fanout() / config.max_leaf_count as f64
+    }
+
+    /// average value bytes per leaf, relative to [Config::target_leaf_size]
+    pub fn leaf_bytes_fill(&self, config: &Config) -> f64 {
+        let leaves = self.leaves();
+        ratio(leaves.value_bytes, leaves.nodes) / config.target_leaf_size as f64
+    }
+
+    fn leaves(&self) -> LevelStats {
+        self.levels.first().copied().unwrap_or_default()
+    }
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 {
+        0.0
+    } else {
+        a as f64 / b as f64
+    }
+}
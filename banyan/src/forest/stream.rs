@@ -3,11 +3,12 @@ use crate::{
     index::NodeInfo,
     store::{BanyanValue, ReadOnlyStore},
     tree::Tree,
-    util::{take_until_condition, ToStreamExt},
+    util::take_until_condition,
 };
 
 use super::{FilteredChunk, Forest, TreeTypes};
 use crate::query::*;
+#[cfg(not(target_arch = "wasm32"))]
 use futures::executor::ThreadPool;
 use futures::prelude::*;
 use std::sync::atomic::Ordering;
@@ -91,6 +92,7 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
     /// - range: the range which to stream. It is up to the caller to ensure that we have events for this range.
     /// - mk_extra: a fn that allows to compute extra info from indices.
     ///     this can be useful to get progress info even if the query does not match any events
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn stream_trees_chunked_threaded<S, Q, V, E, F>(
         &self,
         query: Q,
@@ -106,6 +108,7 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
         E: Send + 'static,
         F: Send + Sync + 'static + Fn(&NodeInfo<T, R>) -> E,
     {
+        use crate::util::ToStreamExt;
         let offset = Arc::new(AtomicU64::new(*range.start()));
         let forest = self.clone();
         trees
@@ -1,10 +1,10 @@
 #[cfg(feature = "metrics")]
 use super::prom;
 use crate::{
-    forest::{BranchResult, Config, CreateMode, Forest, Transaction, TreeTypes},
+    error::PurgedSplit,
+    forest::{BlockRole, BranchResult, Config, CreateMode, Forest, Transaction, TreeTypes},
     index::{zip_with_offset_ref, NodeInfo},
-    store::{BlockWriter, ReadOnlyStore},
-    util::nonce,
+    store::{BanyanValue, BlockWriter, ReadOnlyStore},
     StreamBuilderState,
 };
 use crate::{
@@ -14,12 +14,18 @@ use crate::{
     index::Index,
     index::LeafIndex,
     query::Query,
-    store::ZstdDagCborSeq,
+    store::{ZstdDagCborSeq, NO_COMPRESSION},
     util::{is_sorted, BoolSliceExt},
 };
 use anyhow::{ensure, Result};
-use cbor_data::codec::WriteCbor;
-use std::iter;
+use cbor_data::codec::{ReadCbor, WriteCbor};
+use libipld::Ipld;
+use std::{iter, sync::atomic::Ordering};
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    crate::CipherOffset,
+    std::{ops::Range, sync::mpsc},
+};
 
 /// basic random access append only tree
 impl<T, R, W> Transaction<T, R, W>
@@ -42,12 +48,48 @@ where
         self.extend_leaf(&[], None, from, stream)
     }
 
-    fn put_block(&mut self, data: Vec<u8>) -> anyhow::Result<T::Link> {
+    /// stores a block, unless it is one of the last [Config::dedup_window] blocks written for a
+    /// stream with the config of `stream`
+    ///
+    /// [Config::dedup_window]: ../struct.Config.html#structfield.dedup_window
+    fn put_block(&mut self, data: Vec<u8>, stream: &StreamBuilderState) -> anyhow::Result<T::Link> {
         #[cfg(feature = "metrics")]
         let _timer = prom::BLOCK_PUT_HIST.start_timer();
         #[cfg(feature = "metrics")]
         prom::BLOCK_PUT_SIZE_HIST.observe(data.len() as f64);
-        self.writer.put(data)
+        self.written.set_capacity(stream.config().dedup_window);
+        let writer = &mut self.writer;
+        let blocks_written = &mut self.blocks_written;
+        let bytes_written = &self.bytes_written;
+        self.written.put(data, |data| {
+            *blocks_written += 1;
+            bytes_written.fetch_add(data.len() as u64, Ordering::Relaxed);
+            writer.put(data)
+        })
+    }
+
+    /// like [put_block](#method.put_block), but hands all blocks to the writer in one batch
+    fn put_blocks(
+        &mut self,
+        blocks: Vec<Vec<u8>>,
+        stream: &StreamBuilderState,
+    ) -> anyhow::Result<Vec<T::Link>> {
+        #[cfg(feature = "metrics")]
+        let _timer = prom::BLOCK_PUT_HIST.start_timer();
+        #[cfg(feature = "metrics")]
+        for data in &blocks {
+            prom::BLOCK_PUT_SIZE_HIST.observe(data.len() as f64);
+        }
+        self.written.set_capacity(stream.config().dedup_window);
+        let writer = &mut self.writer;
+        let blocks_written = &mut self.blocks_written;
+        let bytes_written = &self.bytes_written;
+        self.written.put_many(blocks, |blocks| {
+            *blocks_written += blocks.len() as u64;
+            let bytes = blocks.iter().map(|data| data.len() as u64).sum();
+            bytes_written.fetch_add(bytes, Ordering::Relaxed);
+            writer.put_many(blocks)
+        })
     }
 
     /// Creates a leaf from a sequence that either contains all items from the sequence, or is full
@@ -56,37 +98,172 @@ where
     fn extend_leaf<V: WriteCbor>(
         &mut self,
         compressed: &[u8],
-        keys: Option<T::KeySeq>,
+        index: Option<&LeafIndex<T>>,
         from: &mut iter::Peekable<impl Iterator<Item = (T::Key, V)>>,
         stream: &mut StreamBuilderState,
     ) -> Result<LeafIndex<T>> {
+        let (mut index, encrypted) = self.leaf_block(compressed, index, from, stream)?;
+        // store leaf
+        index.link = Some(self.put_block(encrypted, stream)?);
+        Ok(index)
+    }
+
+    /// whether a batch of leaves with `pending` bytes that are not written yet uses up the budget
+    /// of [extend_with_budget](#method.extend_with_budget), so it has to end
+    fn budget_used(&self, pending: u64) -> bool {
+        self.write_limit.is_some_and(|limit| {
+            self.bytes_written
+                .load(Ordering::Relaxed)
+                .saturating_add(pending)
+                >= limit
+        })
+    }
+
+    /// Like [extend_leaf](#method.extend_leaf), but returns the encrypted block instead of
+    /// storing it, so the link of the result is not set.
+    fn leaf_block<V: WriteCbor>(
+        &mut self,
+        compressed: &[u8],
+        index: Option<&LeafIndex<T>>,
+        from: &mut iter::Peekable<impl Iterator<Item = (T::Key, V)>>,
+        stream: &mut StreamBuilderState,
+    ) -> Result<(LeafIndex<T>, Vec<u8>)> {
         #[cfg(feature = "metrics")]
         let _timer = prom::LEAF_STORE_HIST.start_timer();
+        let (index, data) = self.leaf_data(compressed, index, from, stream)?;
+        // cloning the secrets only clones a reference to the keys
+        let secrets = stream.secrets().clone();
+        let encrypted = secrets.encrypt::<T>(BlockRole::Value, data, &mut stream.offset)?;
+        Ok((index, encrypted))
+    }
+
+    /// Creates new leaves from `from` until it is empty or there are `n` leaves, and returns
+    /// them together with their encrypted blocks, without storing them
+    ///
+    /// While extending with a budget, the batch also ends once its leaves use up the budget.
+    ///
+    /// Where a leaf ends depends on the compressed size of its values, so the leaves are filled
+    /// and compressed one after the other. The cipher offsets of each leaf are reserved as soon
+    /// as it is compressed, so it can be encrypted on another thread while the next leaf is
+    /// being compressed.
+    fn leaf_blocks<V: WriteCbor>(
+        &mut self,
+        n: usize,
+        from: &mut iter::Peekable<impl Iterator<Item = (T::Key, V)>>,
+        stream: &mut StreamBuilderState,
+    ) -> Result<(Vec<LeafIndex<T>>, Vec<Vec<u8>>)> {
+        #[cfg(not(target_arch = "wasm32"))]
+        if stream.secrets().encrypts() {
+            let secrets = stream.secrets().clone();
+            return std::thread::scope(|scope| {
+                let (tx, rx) = mpsc::channel::<(ZstdDagCborSeq, Range<u64>)>();
+                let encrypt = scope.spawn(move || {
+                    rx.into_iter()
+                        .map(|(data, range)| {
+                            let mut offset = CipherOffset::limited(range);
+                            secrets.encrypt::<T>(BlockRole::Value, data, &mut offset)
+                        })
+                        .collect::<Result<Vec<_>>>()
+                });
+                // the sender is dropped when filling is done, which stops the encryption thread
+                let leaves = self.fill_leaves(n, from, stream, tx);
+                let blocks = encrypt
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+                Ok((leaves?, blocks))
+            });
+        }
+        let mut leaves = Vec::new();
+        let mut blocks = Vec::new();
+        let mut pending = 0;
+        while from.peek().is_some() && leaves.len() < n && !self.budget_used(pending) {
+            let (leaf, block) = self.leaf_block(&[], None, from, stream)?;
+            pending += block.len() as u64;
+            leaves.push(leaf);
+            blocks.push(block);
+        }
+        Ok((leaves, blocks))
+    }
+
+    /// Fills up to `n` leaves and sends their data together with the reserved cipher offsets to
+    /// `tx`, see [leaf_blocks](#method.leaf_blocks)
+    #[cfg(not(target_arch = "wasm32"))]
+    fn fill_leaves<V: WriteCbor>(
+        &mut self,
+        n: usize,
+        from: &mut iter::Peekable<impl Iterator<Item = (T::Key, V)>>,
+        stream: &mut StreamBuilderState,
+        tx: mpsc::Sender<(ZstdDagCborSeq, Range<u64>)>,
+    ) -> Result<Vec<LeafIndex<T>>> {
+        let mut leaves = Vec::new();
+        let mut pending = 0;
+        while from.peek().is_some() && leaves.len() < n && !self.budget_used(pending) {
+            #[cfg(feature = "metrics")]
+            let _timer = prom::LEAF_STORE_HIST.start_timer();
+            let (leaf, data) = self.leaf_data(&[], None, from, stream)?;
+            let len = data.compressed().len();
+            pending += len as u64;
+            let start = stream.offset.reserve(len)?;
+            // only fails if the encryption thread has stopped with an error, which is reported
+            // when joining it
+            if tx.send((data, start..start + len as u64)).is_err() {
+                break;
+            }
+            leaves.push(leaf);
+        }
+        Ok(leaves)
+    }
+
+    /// Fills and compresses a leaf like [leaf_block](#method.leaf_block), but does not encrypt
+    /// it, so no cipher offsets are reserved for it yet.
+    fn leaf_data<V: WriteCbor>(
+        &mut self,
+        compressed: &[u8],
+        index: Option<&LeafIndex<T>>,
+        from: &mut iter::Peekable<impl Iterator<Item = (T::Key, V)>>,
+        stream: &mut StreamBuilderState,
+    ) -> Result<(LeafIndex<T>, ZstdDagCborSeq)> {
         assert!(from.peek().is_some());
-        let mut keys = keys.map(|keys| keys.to_vec()).unwrap_or_default();
-        let (data, sealed) = ZstdDagCborSeq::fill(
+        let mut keys = index.map(|index| index.keys.to_vec()).unwrap_or_default();
+        let summarizer = stream.value_summarizer().cloned();
+        // the new values, if they have to be summarized
+        let mut values = Vec::new();
+        let zstd_level = self.leaf_zstd_level(stream);
+        let (data, sealed, uncompressed) = ZstdDagCborSeq::fill_inspect(
             compressed,
             from,
             &mut keys,
-            stream.config().zstd_level,
+            &mut |value| {
+                if summarizer.is_some() {
+                    values.extend_from_slice(value)
+                }
+            },
+            zstd_level,
             stream.config().target_leaf_size,
             stream.config().max_uncompressed_leaf_size,
+            stream.leaf_fill_target(),
             stream.config().max_leaf_count,
+            stream.config().pack_values,
         )?;
+        // without a summarizer, a previous summary would no longer cover all values
+        let value_summary = match &summarizer {
+            Some(summarizer) => summarizer.add_values(
+                index.map_or(&Ipld::Null, |index| &index.value_summary),
+                &values,
+            )?,
+            None => Ipld::Null,
+        };
         let value_bytes = data.compressed().len() as u64;
-        let encrypted = data.into_encrypted(
-            &stream.value_key().clone(),
-            nonce::<T>(),
-            &mut stream.offset,
-        )?;
+        if sealed {
+            stream.record_leaf(data.compressed().len(), uncompressed);
+        }
         let keys = keys.into_iter().collect::<T::KeySeq>();
-        // store leaf
-        let link = self.put_block(encrypted)?;
         let index: LeafIndex<T> = LeafIndex {
-            link: Some(link),
+            link: None,
             value_bytes,
             sealed,
             keys,
+            value_summary,
         };
         tracing::trace!(
             "leaf created count={} bytes={} sealed={}",
@@ -94,7 +271,16 @@ where
             index.value_bytes,
             index.sealed
         );
-        Ok(index)
+        Ok((index, data))
+    }
+
+    /// zstd level for new leaves, see [Config::skip_redundant_compression]
+    fn leaf_zstd_level(&self, stream: &StreamBuilderState) -> i32 {
+        if stream.config().skip_redundant_compression && self.writer.compresses_at_rest() {
+            NO_COMPRESSION
+        } else {
+            stream.config().zstd_level
+        }
     }
 
     /// given some children and some additional elements, creates a node with the given
@@ -135,6 +321,19 @@ where
             .iter()
             .map(|child| child.summarize())
             .collect::<Vec<_>>();
+        if level == 1 {
+            // build all new leaves before storing them, so the writer gets them in one batch and
+            // can store them in parallel, see ParallelWriter
+            let n = max_branch_count.saturating_sub(children.len());
+            let (leaves, blocks) = self.leaf_blocks(n, from, stream)?;
+            let links = self.put_blocks(blocks, stream)?;
+            for (mut leaf, link) in leaves.into_iter().zip(links) {
+                leaf.link = Some(link);
+                let child = Index::from(leaf);
+                summaries.push(child.summarize());
+                children.push(child);
+            }
+        }
         while from.peek().is_some() && (children.len() < max_branch_count) {
             let child = self.fill_node(level - 1, from, stream)?;
             let summary = child.summarize();
@@ -195,7 +394,7 @@ where
         let count = children.iter().map(|x| x.count()).sum();
         let summaries = children
             .iter()
-            .map(|child| child.summarize())
+            .map(|child| stream.config().summary_at(child, level))
             .collect::<T::SummarySeq>();
         let value_bytes = children.iter().map(|x| x.value_bytes()).sum();
         let sealed = stream.config().branch_sealed(children, level);
@@ -247,6 +446,70 @@ where
         Ok(node)
     }
 
+    /// builds a packed tree from scratch, bottom-up
+    ///
+    /// Unlike [extend_above](#method.extend_above), nodes are not created top-down. A branch is
+    /// created as soon as its last child is written, so every node is written exactly once, and
+    /// only the nodes on the right edge of the tree are created at the end.
+    pub(crate) fn bulk_import0<V: WriteCbor>(
+        &mut self,
+        from: &mut iter::Peekable<impl Iterator<Item = (T::Key, V)>>,
+        stream: &mut StreamBuilderState,
+    ) -> Result<Option<Index<T>>> {
+        // nodes of each level that do not have a parent yet, all of them sealed except for the
+        // very last leaf
+        let mut levels: Vec<Vec<Index<T>>> = Vec::new();
+        while from.peek().is_some() {
+            // the leaves of one level 1 branch are written in one batch
+            let mut leaves = Vec::new();
+            let mut blocks = Vec::new();
+            while from.peek().is_some() && leaves.len() < stream.config().max_key_branches {
+                let (leaf, block) = self.leaf_block(&[], None, from, stream)?;
+                leaves.push(leaf);
+                blocks.push(block);
+            }
+            let links = self.put_blocks(blocks, stream)?;
+            for (mut leaf, link) in leaves.into_iter().zip(links) {
+                leaf.link = Some(link);
+                let mut node = Index::from(leaf);
+                let mut level = 0;
+                loop {
+                    if levels.len() == level {
+                        levels.push(Vec::new());
+                    }
+                    levels[level].push(node);
+                    let max_children = if level == 0 {
+                        stream.config().max_key_branches
+                    } else {
+                        stream.config().max_summary_branches
+                    };
+                    if levels[level].len() < max_children {
+                        break;
+                    }
+                    let children = std::mem::take(&mut levels[level]);
+                    node = self
+                        .new_branch(&children, stream, CreateMode::Packed)?
+                        .into();
+                    level += 1;
+                }
+            }
+        }
+        // join the incomplete levels, from the bottom up
+        let mut carry: Option<Index<T>> = None;
+        for mut children in levels {
+            children.extend(carry.take());
+            carry = match children.len() {
+                0 => None,
+                1 => children.pop(),
+                _ => Some(
+                    self.new_branch(&children, stream, CreateMode::Packed)?
+                        .into(),
+                ),
+            };
+        }
+        Ok(carry)
+    }
+
     pub(crate) fn extend_unpacked0<I, V>(
         &mut self,
         index: Option<&Index<T>>,
@@ -280,6 +543,22 @@ where
         }))
     }
 
+    /// joins two trees with an unpacked branch above them, or returns the second tree if the
+    /// first one is empty
+    pub(crate) fn concat0(
+        &mut self,
+        a: Option<&Index<T>>,
+        b: &Index<T>,
+        stream: &mut StreamBuilderState,
+    ) -> Result<Index<T>> {
+        Ok(match a {
+            Some(a) => self
+                .new_branch(&[a.clone(), b.clone()], stream, CreateMode::Unpacked)?
+                .into(),
+            None => b.clone(),
+        })
+    }
+
     /// extends an existing node with some values
     ///
     /// The result will have the same level as the input. `from` will contain all elements that did not fit.
@@ -304,9 +583,13 @@ where
             NodeInfo::Leaf(index, leaf) => {
                 tracing::trace!("extending existing leaf");
                 let leaf = leaf.load()?;
-                let keys = index.keys.clone();
-                self.extend_leaf(leaf.as_ref().compressed(), Some(keys), from, stream)?
-                    .into()
+                self.extend_leaf(
+                    leaf.as_ref().compressed(),
+                    Some(index.as_ref()),
+                    from,
+                    stream,
+                )?
+                .into()
             }
             NodeInfo::Branch(index, branch) => {
                 tracing::trace!("extending existing branch");
@@ -326,6 +609,58 @@ where
         })
     }
 
+    /// packs the rightmost subtree `depth` levels below `index`, and rebuilds the path to it.
+    pub(crate) fn pack_tail0<V: BanyanValue>(
+        &mut self,
+        index: &Index<T>,
+        depth: usize,
+        stream: &mut StreamBuilderState,
+    ) -> Result<Index<T>> {
+        // sealed nodes are already packed
+        if index.sealed() {
+            return Ok(index.clone());
+        }
+        let secrets = stream.secrets().clone();
+        if let (Index::Branch(branch_index), true) = (index, depth > 0) {
+            let link = match branch_index.link {
+                Some(link) => link,
+                // purged nodes can not be packed
+                None => return Ok(index.clone()),
+            };
+            let branch = self.load_branch_cached_from_link(&secrets, &link)?;
+            let mut children = branch.children.to_vec();
+            let last_child = children
+                .last_mut()
+                .expect("branch can never have 0 children");
+            *last_child = self.pack_tail0::<V>(last_child, depth - 1, stream)?;
+            return Ok(self
+                .new_branch(&children, stream, CreateMode::Unpacked)?
+                .into());
+        }
+        // pack this subtree, just like pack does for the entire tree
+        let mut roots = self.roots_impl(&secrets, index)?;
+        let packed_count: u64 = roots.iter().map(|x| x.count()).sum();
+        while roots.len() > 1 {
+            self.simplify_roots(&mut roots, 0, stream)?;
+        }
+        let mut remainder = Vec::<Option<(T::Key, V)>>::new();
+        self.collect0(&secrets, index, packed_count, &mut remainder)?;
+        let mut remainder = remainder
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow::anyhow!("found purged data"))?
+            .into_iter()
+            .peekable();
+        let packed = roots.pop();
+        Ok(match (packed, remainder.peek().is_some()) {
+            (packed, true) => {
+                self.extend_above(packed.as_ref(), u32::MAX, &mut remainder, stream)?
+            }
+            (Some(packed), false) => packed,
+            (None, false) => index.clone(),
+        })
+    }
+
     /// Performs a single step of simplification on a sequence of sealed roots of descending level
     pub(crate) fn simplify_roots(
         &mut self,
@@ -356,10 +691,10 @@ where
         #[cfg(feature = "metrics")]
         let _timer = prom::BRANCH_STORE_HIST.start_timer();
         let level = stream.config().zstd_level;
-        let key = *stream.index_key();
-        let cbor = serialize_compressed(&key, nonce::<T>(), &mut stream.offset, items, level)?;
+        let secrets = stream.secrets().clone();
+        let cbor = serialize_compressed(&secrets, &mut stream.offset, items, level)?;
         let len = cbor.len() as u64;
-        Ok((self.put_block(cbor)?, len))
+        Ok((self.put_block(cbor, stream)?, len))
     }
 
     pub(crate) fn retain0<Q: Query<T> + Send + Sync>(
@@ -429,6 +764,150 @@ where
         }
     }
 
+    /// rewrites all leaves below a node with values converted by `f`, and all branches above
+    /// them, keeping the keys and the shape of the tree
+    pub(crate) fn migrate0<V1: ReadCbor, V2: WriteCbor>(
+        &mut self,
+        node: &Index<T>,
+        f: &impl Fn(V1) -> V2,
+        stream: &mut StreamBuilderState,
+    ) -> Result<Index<T>> {
+        let secrets = stream.secrets().clone();
+        Ok(match self.node_info(&secrets, node) {
+            NodeInfo::Leaf(index, leaf) => {
+                let leaf = leaf.load()?;
+                let values = self.count_decompressed(|| leaf.as_ref().items::<V1>())?;
+                ensure!(
+                    values.len() as u64 == index.keys.count(),
+                    "leaf has {} keys but {} values",
+                    index.keys.count(),
+                    values.len()
+                );
+                let values = values.into_iter().map(f).collect::<Vec<_>>();
+                let data = ZstdDagCborSeq::from_iter(&values, self.leaf_zstd_level(stream))?;
+                let value_bytes = data.compressed().len() as u64;
+                let encrypted = secrets.encrypt::<T>(BlockRole::Value, data, &mut stream.offset)?;
+                LeafIndex {
+                    // keep the sealed state, so the branches above stay the same
+                    sealed: index.sealed,
+                    link: Some(self.put_block(encrypted, stream)?),
+                    keys: index.keys.clone(),
+                    value_bytes,
+                    // computed for the old value type
+                    value_summary: Ipld::Null,
+                }
+                .into()
+            }
+            NodeInfo::Branch(_, branch) => {
+                let branch = branch.load_cached()?;
+                let children = branch
+                    .children
+                    .iter()
+                    .map(|child| self.migrate0(child, f, stream))
+                    .collect::<Result<Vec<_>>>()?;
+                self.new_branch(&children, stream, CreateMode::Unpacked)?
+                    .into()
+            }
+            // purged nodes have no values to convert
+            NodeInfo::PurgedBranch(_) | NodeInfo::PurgedLeaf(_) => node.clone(),
+        })
+    }
+
+    /// splits a node into the elements before `at` and the elements from `at` on
+    ///
+    /// Only the nodes on the path to the split point are rewritten, all other nodes are shared
+    /// with the original. Since the new branches can have fewer children than the original ones,
+    /// they are created as unpacked branches.
+    pub(crate) fn split0<V: BanyanValue>(
+        &mut self,
+        index: &Index<T>,
+        at: u64,
+        stream: &mut StreamBuilderState,
+    ) -> Result<(Option<Index<T>>, Option<Index<T>>)> {
+        if at == 0 {
+            return Ok((None, Some(index.clone())));
+        }
+        if at >= index.count() {
+            return Ok((Some(index.clone()), None));
+        }
+        let secrets = stream.secrets().clone();
+        Ok(match self.node_info(&secrets, index) {
+            NodeInfo::Leaf(index, leaf) => {
+                let leaf = leaf.load()?;
+                let values = self.count_decompressed(|| leaf.as_ref().items::<V>())?;
+                ensure!(
+                    values.len() as u64 == index.keys.count(),
+                    "leaf has {} keys but {} values",
+                    index.keys.count(),
+                    values.len()
+                );
+                let mut left = index
+                    .keys
+                    .to_vec()
+                    .into_iter()
+                    .zip(values)
+                    .collect::<Vec<_>>();
+                let right = left.split_off(at as usize);
+                (
+                    Some(self.leaf_from_vec(left, stream)?.into()),
+                    Some(self.leaf_from_vec(right, stream)?.into()),
+                )
+            }
+            NodeInfo::Branch(_, branch) => {
+                let children = branch.load_cached()?.children.to_vec();
+                // find the child containing the split point
+                let mut i = 0;
+                let mut offset = 0;
+                while offset + children[i].count() <= at {
+                    offset += children[i].count();
+                    i += 1;
+                }
+                let (left, right) = self.split0::<V>(&children[i], at - offset, stream)?;
+                let left = children[..i]
+                    .iter()
+                    .cloned()
+                    .chain(left)
+                    .collect::<Vec<_>>();
+                let right = right.into_iter().chain(children[i + 1..].iter().cloned());
+                let right = right.collect::<Vec<_>>();
+                (
+                    self.join_children(left, stream)?,
+                    self.join_children(right, stream)?,
+                )
+            }
+            NodeInfo::PurgedLeaf(_) | NodeInfo::PurgedBranch(_) => return Err(PurgedSplit.into()),
+        })
+    }
+
+    /// creates a leaf with exactly the given elements
+    fn leaf_from_vec<V: WriteCbor>(
+        &mut self,
+        elements: Vec<(T::Key, V)>,
+        stream: &mut StreamBuilderState,
+    ) -> Result<LeafIndex<T>> {
+        let mut from = elements.into_iter().peekable();
+        let leaf = self.leaf_from_iter(&mut from, stream)?;
+        ensure!(
+            from.peek().is_none(),
+            "elements do not fit into a single leaf"
+        );
+        Ok(leaf)
+    }
+
+    /// creates an unpacked branch for some children, unless there is at most one child
+    fn join_children(
+        &mut self,
+        mut children: Vec<Index<T>>,
+        stream: &mut StreamBuilderState,
+    ) -> Result<Option<Index<T>>> {
+        Ok(if children.len() > 1 {
+            let branch = self.new_branch(&children, stream, CreateMode::Unpacked)?;
+            Some(branch.into())
+        } else {
+            children.pop()
+        })
+    }
+
     pub(crate) fn repair0(
         &mut self,
         index: &Index<T>,
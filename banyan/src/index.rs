@@ -39,22 +39,23 @@
 //! [Semigroup]: trait.Semigroup.html
 //! [SimpleCompactSeq]: struct.SimpleCompactSeq.html
 use crate::{
-    forest::TreeTypes,
+    forest::{BlockRole, TreeTypes},
     store::{ReadOnlyStore, ZstdDagCborSeq},
     CipherOffset, Forest, Secrets,
 };
 use anyhow::{anyhow, Result};
-use cbor_data::codec::ReadCbor;
+use cbor_data::{codec::ReadCbor, Cbor};
 use libipld::{
     cbor::{DagCbor, DagCborCodec},
-    codec::{Decode, Encode},
-    DagCbor,
+    codec::{Codec, Decode, Encode},
+    DagCbor, Ipld,
 };
 use std::{
     convert::TryFrom,
     fmt::{self, Debug, Display},
     io,
     iter::FromIterator,
+    marker::PhantomData,
     ops::Range,
     sync::Arc,
 };
@@ -64,6 +65,85 @@ pub trait Summarizable<T> {
     fn summarize(&self) -> T;
 }
 
+/// Incrementally computed summary of the values of a leaf
+///
+/// Values are added in order as they are appended to a leaf, starting from `Default::default()`
+/// for a new leaf, or from the previous summary when an existing leaf is extended. To compute it
+/// for a stream, see [StreamBuilder::with_value_summary].
+///
+/// [StreamBuilder::with_value_summary]: ../struct.StreamBuilder.html#method.with_value_summary
+pub trait ValueSummarizable<V> {
+    fn add_value(&mut self, value: &V);
+}
+
+/// Computes the value summaries of new leaves, see [LeafIndex::value_summary]
+///
+/// The values are passed as a CBOR sequence, so this does not depend on the value type of the
+/// stream. [TypedValueSummarizer] implements it for a [ValueSummarizable].
+pub trait ValueSummarizer: Debug + Send + Sync {
+    /// adds the CBOR sequence `values` to `summary`, which is [Ipld::Null] for a new leaf
+    fn add_values(&self, summary: &Ipld, values: &[u8]) -> Result<Ipld>;
+}
+
+/// A [ValueSummarizer] that decodes the values as `V` and adds them to a summary of type `S`
+pub struct TypedValueSummarizer<S, V>(PhantomData<fn() -> (S, V)>);
+
+impl<S, V> TypedValueSummarizer<S, V> {
+    pub fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<S, V> Default for TypedValueSummarizer<S, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, V> Debug for TypedValueSummarizer<S, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TypedValueSummarizer<{}>", std::any::type_name::<S>())
+    }
+}
+
+impl<S, V> ValueSummarizer for TypedValueSummarizer<S, V>
+where
+    S: ValueSummarizable<V> + Default + Encode<DagCborCodec> + Decode<DagCborCodec>,
+    V: ReadCbor,
+{
+    fn add_values(&self, summary: &Ipld, values: &[u8]) -> Result<Ipld> {
+        let mut summary: S = match summary {
+            Ipld::Null => S::default(),
+            ipld => DagCborCodec.decode(&DagCborCodec.encode(ipld)?)?,
+        };
+        let mut bytes = values;
+        while !bytes.is_empty() {
+            let (cbor, rest) = Cbor::checked_prefix(bytes)?;
+            summary.add_value(&V::read_cbor(cbor)?);
+            bytes = rest;
+        }
+        DagCborCodec.decode(&DagCborCodec.encode(&summary)?)
+    }
+}
+
+/// A key or summary with a numeric component that can be aggregated
+///
+/// Values are opaque to banyan, so the numeric component has to be part of the key. For summaries,
+/// `aggregate_value` must return the combination of the values of all summarized keys, e.g. the
+/// sum.
+pub trait Aggregatable<S> {
+    fn aggregate_value(&self) -> S;
+}
+
+/// Result of an aggregate query
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Aggregate<S> {
+    /// number of matching elements
+    pub count: u64,
+    /// combined numeric component of all matching elements
+    pub value: S,
+}
+
 /// a compact representation of a sequence of 1 or more items
 ///
 /// in general, this will have a different internal representation than just a bunch of values that is more compact and
@@ -120,6 +200,11 @@ pub struct LeafIndex<T: TreeTypes> {
     pub keys: T::KeySeq,
     // serialized size of the data
     pub value_bytes: u64,
+    /// Summary of the values, computed by the [ValueSummarizer] of the stream. Null if the
+    /// stream has none, in which case it is omitted, so trees without value summaries keep
+    /// their wire format.
+    #[ipld(default = Ipld::Null)]
+    pub value_summary: Ipld,
 }
 
 impl<T: TreeTypes> Clone for LeafIndex<T> {
@@ -129,6 +214,7 @@ impl<T: TreeTypes> Clone for LeafIndex<T> {
             value_bytes: self.value_bytes,
             link: self.link,
             keys: self.keys.clone(),
+            value_summary: self.value_summary.clone(),
         }
     }
 }
@@ -140,6 +226,17 @@ impl<T: TreeTypes> LeafIndex<T> {
     pub fn select_keys(&self, bits: &[bool]) -> impl Iterator<Item = T::Key> {
         self.keys.select(bits).into_iter()
     }
+
+    /// the value summary decoded as `S`, or None if the leaf has no summary of this type
+    ///
+    /// Leaves without a summary can contain anything, so queries must not skip them.
+    pub fn value_summary_as<S: Decode<DagCborCodec>>(&self) -> Option<S> {
+        if self.value_summary == Ipld::Null {
+            return None;
+        }
+        let bytes = DagCborCodec.encode(&self.value_summary).ok()?;
+        DagCborCodec.decode(&bytes).ok()
+    }
 }
 
 /// index for a branch node, containing summary data for its children
@@ -297,7 +394,7 @@ impl<T: TreeTypes> Branch<T> {
 /// fully in memory representation of a leaf node
 ///
 /// This is a wrapper around a cbor encoded and zstd compressed sequence of values
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Leaf {
     pub items: ZstdDagCborSeq,
     pub byte_range: Range<u64>,
@@ -342,6 +439,14 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> BranchLoader<T, R> {
             .load_branch_cached_from_link(&self.secrets, &self.link)
     }
 
+    pub(crate) fn load_cached_with_parent(
+        &self,
+        parent: Option<&T::Link>,
+    ) -> anyhow::Result<Branch<T>> {
+        self.forest
+            .load_branch_cached_from_link_with_parent(&self.secrets, &self.link, parent)
+    }
+
     pub fn load(&self) -> anyhow::Result<Branch<T>> {
         self.forest.load_branch_from_link(&self.secrets, &self.link)
     }
@@ -352,6 +457,8 @@ pub struct LeafLoader<T: TreeTypes, R> {
     forest: Forest<T, R>,
     secrets: Secrets,
     link: T::Link,
+    // block that has already been fetched from the store
+    block: Option<Box<[u8]>>,
 }
 
 impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> LeafLoader<T, R> {
@@ -360,11 +467,31 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> LeafLoader<T, R> {
             forest: forest.clone(),
             secrets: secrets.clone(),
             link,
+            block: None,
         }
     }
 
+    /// use an already fetched block instead of getting it from the store
+    pub(crate) fn with_block(mut self, block: Box<[u8]>) -> Self {
+        self.block = Some(block);
+        self
+    }
+
+    pub fn link(&self) -> &T::Link {
+        &self.link
+    }
+
+    pub(crate) fn forest(&self) -> &Forest<T, R> {
+        &self.forest
+    }
+
     pub fn load(&self) -> anyhow::Result<Leaf> {
-        self.forest.load_leaf_from_link(&self.secrets, &self.link)
+        match &self.block {
+            Some(block) => self
+                .forest
+                .leaf_from_block(&self.secrets, &self.link, block),
+            None => self.forest.load_leaf_from_link(&self.secrets, &self.link),
+        }
     }
 }
 
@@ -429,22 +556,20 @@ impl<T: TreeTypes, R> Display for NodeInfo<T, R> {
 }
 
 pub(crate) fn serialize_compressed<T: TreeTypes>(
-    key: &chacha20::Key,
-    nonce: &chacha20::XNonce,
+    secrets: &Secrets,
     state: &mut CipherOffset,
     items: &[Index<T>],
     level: i32,
 ) -> Result<Vec<u8>> {
     let zs = ZstdDagCborSeq::from_iter_ipld(items, level)?;
-    zs.into_encrypted(key, nonce, state)
+    secrets.encrypt::<T>(BlockRole::Index, zs, state)
 }
 
 pub(crate) fn deserialize_compressed<T: TreeTypes>(
-    key: &chacha20::Key,
-    nonce: &chacha20::XNonce,
+    secrets: &Secrets,
     ipld: &[u8],
 ) -> Result<(Vec<Index<T>>, Range<u64>)> {
-    let (seq, byte_range) = ZstdDagCborSeq::decrypt(ipld, key, nonce)?;
+    let (seq, byte_range) = secrets.decrypt::<T>(BlockRole::Index, ipld)?;
     let seq = seq.items_ipld::<Index<T>>()?;
     Ok((seq, byte_range))
 }
@@ -537,3 +662,49 @@ impl<T: DagCbor> FromIterator<T> for VecSeq<T> {
         Self(iter.into_iter().collect())
     }
 }
+
+/// A compact sequence made up of two independent channels
+///
+/// This allows combining e.g. a time range and a bloom filter into one summary without having to
+/// define a combined type. Each channel is stored as its own sequence, so queries can consult a single
+/// channel directly via the public fields, and channels can be evolved independently.
+///
+/// Channels can be nested, e.g. `ChannelSeq<A, ChannelSeq<B, C>>`, to get more than two of them.
+/// Both channels must always have the same length.
+#[derive(Debug, Clone, DagCbor)]
+pub struct ChannelSeq<A: DagCbor, B: DagCbor>(pub A, pub B);
+
+impl<A: CompactSeq, B: CompactSeq> CompactSeq for ChannelSeq<A, B> {
+    type Item = (A::Item, B::Item);
+    fn get(&self, index: usize) -> Option<Self::Item> {
+        Some((self.0.get(index)?, self.1.get(index)?))
+    }
+    fn len(&self) -> usize {
+        debug_assert_eq!(self.0.len(), self.1.len());
+        self.0.len()
+    }
+    fn estimated_size(&self) -> usize {
+        self.0.estimated_size() + self.1.estimated_size()
+    }
+}
+
+impl<A, B, SA, SB> Summarizable<(SA, SB)> for ChannelSeq<A, B>
+where
+    A: DagCbor + Summarizable<SA>,
+    B: DagCbor + Summarizable<SB>,
+{
+    fn summarize(&self) -> (SA, SB) {
+        (self.0.summarize(), self.1.summarize())
+    }
+}
+
+impl<A, B> FromIterator<(A::Item, B::Item)> for ChannelSeq<A, B>
+where
+    A: CompactSeq + FromIterator<A::Item>,
+    B: CompactSeq + FromIterator<B::Item>,
+{
+    fn from_iter<I: IntoIterator<Item = (A::Item, B::Item)>>(iter: I) -> Self {
+        let (a, b): (Vec<_>, Vec<_>) = iter.into_iter().unzip();
+        Self(a.into_iter().collect(), b.into_iter().collect())
+    }
+}
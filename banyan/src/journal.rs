@@ -0,0 +1,371 @@
+//! write-ahead journal for elements that are not yet part of a persisted root
+//!
+//! Extending a stream writes the new leaves and branches to the store right away, but the new
+//! root only becomes durable when the application persists it, e.g. with
+//! [Transaction::publish_root] or [StreamBuilder::to_bytes]. Elements added after that are lost in
+//! a crash. To avoid this, extend with [Transaction::extend_journaled], which appends the elements
+//! to a [Journal] before adding them to the tree, and [clear](Journal::clear) the journal whenever
+//! the root has been persisted. After a crash, load the last persisted builder, or create a fresh
+//! one if there is none, and call [Transaction::replay_journal].
+//!
+//! The builder may have written blocks after it was persisted, so replaying first skips the
+//! cipher offsets up to [CHECKPOINT_HEADROOM] after the last one recorded in the journal. This
+//! way no offset is used twice, as long as less than that many bytes were written after the last
+//! entry was appended.
+//!
+//! # Entry format
+//!
+//! Each entry holds the elements of one call to [Transaction::extend_journaled]:
+//!
+//! - the length of the header, as a little endian u32
+//! - the header, a dag-cbor list `[version, offset, cipher offset, keys]`, where `offset` is the
+//!   offset of the first element, `cipher offset` is the next cipher offset of the builder before
+//!   adding the elements and `keys` is the key sequence of the elements
+//! - the values, as concatenated cbor items
+//!
+//! [Transaction::publish_root]: ../struct.Transaction.html#method.publish_root
+//! [Transaction::extend_journaled]: ../struct.Transaction.html#method.extend_journaled
+//! [Transaction::replay_journal]: ../struct.Transaction.html#method.replay_journal
+//! [StreamBuilder::to_bytes]: ../struct.StreamBuilder.html#method.to_bytes
+//! [CHECKPOINT_HEADROOM]: ../constant.CHECKPOINT_HEADROOM.html
+use crate::{
+    forest::{Transaction, TreeTypes},
+    index::CompactSeq,
+    store::{BanyanValue, BlockWriter, ReadOnlyStore},
+    util::tuple_version,
+    StreamBuilder, CHECKPOINT_HEADROOM,
+};
+use anyhow::{anyhow, ensure, Context, Result};
+use cbor_data::{Cbor, CborBuilder};
+use libipld::{cbor::DagCborCodec, codec::Codec, DagCbor};
+use std::{
+    convert::TryInto,
+    fs::{File, OpenOptions},
+    hash::Hasher,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Current version of the entry format
+const ENTRY_VERSION: u64 = 1;
+
+#[derive(DagCbor)]
+struct HeaderIpld<T: TreeTypes>(u64, u64, u64, T::KeySeq);
+
+/// A decoded journal entry
+struct Entry<K, V> {
+    offset: u64,
+    cipher_offset: u64,
+    elements: Vec<(K, V)>,
+}
+
+/// A sink for journal entries, see the [module docs](index.html)
+pub trait Journal: Send {
+    /// appends an entry, which must be durable when this returns successfully
+    fn append(&mut self, entry: &[u8]) -> Result<()>;
+
+    /// all entries, in the order they were appended
+    fn entries(&self) -> Result<Vec<Vec<u8>>>;
+
+    /// removes all entries, once the elements they contain are part of a persisted root
+    fn clear(&mut self) -> Result<()>;
+}
+
+/// A journal in memory, which does not survive a crash. Mostly useful for testing.
+#[derive(Debug, Clone, Default)]
+pub struct MemJournal(Vec<Vec<u8>>);
+
+impl MemJournal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Journal for MemJournal {
+    fn append(&mut self, entry: &[u8]) -> Result<()> {
+        self.0.push(entry.to_vec());
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.0.clone())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.0.clear();
+        Ok(())
+    }
+}
+
+/// A journal in a file
+///
+/// Every record is the length of the entry as a little endian u32, a FNV-1a hash of the entry
+/// as a little endian u64 and the entry itself. Appending syncs the file before returning. A
+/// record that was only partially written when the process crashed is dropped on open.
+#[derive(Debug)]
+pub struct FileJournal {
+    file: File,
+}
+
+impl FileJournal {
+    /// opens or creates the journal at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // existing entries are replayed
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("opening journal {}", path.display()))?;
+        let journal = Self { file };
+        let (entries, valid) = journal.read_entries()?;
+        if valid < journal.file.metadata()?.len() {
+            tracing::warn!(
+                "dropping incomplete record at the end of journal {} after {} entries",
+                path.display(),
+                entries.len()
+            );
+            journal.file.set_len(valid)?;
+            journal.file.sync_data()?;
+        }
+        Ok(journal)
+    }
+
+    /// reads all complete records, and returns them with the length of the valid prefix
+    fn read_entries(&self) -> Result<(Vec<Vec<u8>>, u64)> {
+        let mut data = Vec::new();
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut data)?;
+        let mut entries = Vec::new();
+        let mut rest = data.as_slice();
+        let mut valid = 0;
+        while rest.len() >= 12 {
+            let len = u32::from_le_bytes(rest[0..4].try_into()?) as usize;
+            let hash = u64::from_le_bytes(rest[4..12].try_into()?);
+            let entry = match rest.get(12..12 + len) {
+                Some(entry) if fnv_hash(entry) == hash => entry,
+                _ => break,
+            };
+            entries.push(entry.to_vec());
+            rest = &rest[12 + len..];
+            valid += 12 + len as u64;
+        }
+        Ok((entries, valid))
+    }
+}
+
+impl Journal for FileJournal {
+    fn append(&mut self, entry: &[u8]) -> Result<()> {
+        let len: u32 = entry
+            .len()
+            .try_into()
+            .map_err(|_| anyhow!("journal entry of {} bytes is too large", entry.len()))?;
+        let mut record = Vec::with_capacity(12 + entry.len());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&fnv_hash(entry).to_le_bytes());
+        record.extend_from_slice(entry);
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.read_entries()?.0)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+fn fnv_hash(data: &[u8]) -> u64 {
+    let mut hasher = fnv::FnvHasher::default();
+    hasher.write(data);
+    hasher.finish()
+}
+
+fn encode_entry<T: TreeTypes, V: BanyanValue>(
+    offset: u64,
+    cipher_offset: u64,
+    keys: T::KeySeq,
+    values: &[V],
+) -> Result<Vec<u8>> {
+    let header =
+        DagCborCodec.encode(&HeaderIpld::<T>(ENTRY_VERSION, offset, cipher_offset, keys))?;
+    let len: u32 = header.len().try_into()?;
+    let mut entry = len.to_le_bytes().to_vec();
+    entry.extend_from_slice(&header);
+    for value in values {
+        value.write_cbor(CborBuilder::append_to(&mut entry));
+    }
+    Ok(entry)
+}
+
+fn decode_entry<T: TreeTypes, V: BanyanValue>(entry: &[u8]) -> Result<Entry<T::Key, V>> {
+    ensure!(entry.len() >= 4, "journal entry too short");
+    let len = u32::from_le_bytes(entry[0..4].try_into()?) as usize;
+    let header = entry
+        .get(4..4 + len)
+        .ok_or_else(|| anyhow!("journal entry too short"))?;
+    let version =
+        tuple_version(header).map_err(|cause| anyhow!("invalid journal entry: {}", cause))?;
+    ensure!(
+        version == ENTRY_VERSION,
+        "unsupported journal entry version {}",
+        version
+    );
+    let HeaderIpld::<T>(_, offset, cipher_offset, keys) = DagCborCodec
+        .decode(header)
+        .map_err(|cause| anyhow!("invalid journal entry: {}", cause))?;
+    let mut bytes = &entry[4 + len..];
+    let mut values = Vec::with_capacity(keys.len());
+    while !bytes.is_empty() {
+        let (cbor, rest) = Cbor::checked_prefix(bytes)?;
+        values.push(V::read_cbor(cbor)?);
+        bytes = rest;
+    }
+    ensure!(
+        values.len() == keys.len(),
+        "journal entry has {} keys but {} values",
+        keys.len(),
+        values.len()
+    );
+    Ok(Entry {
+        offset,
+        cipher_offset,
+        elements: keys.to_vec().into_iter().zip(values).collect(),
+    })
+}
+
+impl<T: TreeTypes, R: ReadOnlyStore<T::Link>, W: BlockWriter<T::Link>> Transaction<T, R, W> {
+    /// Like [extend](#method.extend), but appends the elements to a journal first, so they can
+    /// be recovered with [replay_journal](#method.replay_journal) after a crash.
+    ///
+    /// If extending fails, the entry stays in the journal, so the builder should be recovered
+    /// from the journal before it is used again.
+    pub fn extend_journaled<I, V>(
+        &mut self,
+        journal: &mut impl Journal,
+        tree: &mut StreamBuilder<T, V>,
+        from: I,
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = (T::Key, V)>,
+        V: BanyanValue,
+    {
+        let (keys, values): (Vec<_>, Vec<_>) = from.into_iter().unzip();
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let keys: T::KeySeq = keys.into_iter().collect();
+        let cipher_offset = tree.state().offset.current();
+        let entry = encode_entry::<T, V>(tree.count(), cipher_offset, keys.clone(), &values)?;
+        journal.append(&entry)?;
+        self.extend(tree, keys.to_vec().into_iter().zip(values))
+    }
+
+    /// Adds the elements of the journal that are not yet in the builder, and returns their number.
+    ///
+    /// The builder has to be the last one that was persisted before the journal was cleared,
+    /// or a fresh builder if the journal was never cleared. Elements before the end of the
+    /// builder are skipped, so replaying twice does no harm. Cipher offsets that the builder may
+    /// have used after it was persisted are skipped, see the [module docs](journal/index.html).
+    pub fn replay_journal<V>(
+        &mut self,
+        journal: &impl Journal,
+        tree: &mut StreamBuilder<T, V>,
+    ) -> Result<u64>
+    where
+        V: BanyanValue,
+    {
+        let start = tree.count();
+        let entries = journal
+            .entries()?
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                decode_entry::<T, V>(entry).with_context(|| format!("journal entry {}", i))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(last) = entries.iter().map(|entry| entry.cipher_offset).max() {
+            let safe = last
+                .checked_add(CHECKPOINT_HEADROOM)
+                .ok_or_else(|| anyhow!("ran out of offsets"))?;
+            tree.state_mut().offset.advance_to(safe)?;
+        }
+        for (i, entry) in entries.into_iter().enumerate() {
+            let count = tree.count();
+            ensure!(
+                entry.offset <= count,
+                "journal entry {} starts at offset {}, but the builder ends at {}",
+                i,
+                entry.offset,
+                count
+            );
+            let skip = (count - entry.offset) as usize;
+            self.extend(tree, entry.elements.into_iter().skip(skip))?;
+        }
+        Ok(tree.count() - start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        forest::{Config, Forest, Secrets},
+        query::AllQuery,
+        store::{BranchCache, MemStore},
+        testing::{Sha256Digest, SimpleTT},
+    };
+
+    #[test]
+    fn journal_replay() -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let forest = Forest::<SimpleTT, _>::new(store.clone(), BranchCache::default());
+        let mut txn = Transaction::new(forest, store);
+        let path = std::env::temp_dir().join(format!("banyan-journal-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut journal = FileJournal::open(&path)?;
+        let mut builder = StreamBuilder::new(Config::debug(), Secrets::default());
+        let xs = (0..30u64).map(|i| (i, i * 2)).collect::<Vec<_>>();
+        txn.extend_journaled(&mut journal, &mut builder, xs[..10].to_vec())?;
+        let checkpoint = builder.to_bytes()?;
+        journal.clear()?;
+        txn.extend_journaled(&mut journal, &mut builder, xs[10..20].to_vec())?;
+        txn.extend_journaled(&mut journal, &mut builder, xs[20..].to_vec())?;
+        // simulate a crash in the middle of writing a record
+        drop(journal);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(&[1, 2, 3])?;
+
+        let journal = FileJournal::open(&path)?;
+        assert_eq!(journal.entries()?.len(), 2);
+        let mut recovered =
+            StreamBuilder::<SimpleTT, u64>::from_bytes(&checkpoint, Secrets::default())?;
+        assert_eq!(txn.replay_journal(&journal, &mut recovered)?, 20);
+        // the cipher offsets the builder used after the checkpoint are not reused
+        assert!(recovered.state().offset.current() > builder.state().offset.current());
+        // already replayed elements are skipped
+        assert_eq!(txn.replay_journal(&journal, &mut recovered)?, 0);
+        let actual = txn
+            .iter_filtered(&recovered.snapshot(), AllQuery)
+            .map(|x| x.map(|(_, k, v)| (k, v)))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(actual, xs);
+
+        // without the checkpoint, entries are missing
+        let mut fresh = StreamBuilder::<SimpleTT, u64>::new(Config::debug(), Secrets::default());
+        assert!(txn.replay_journal(&journal, &mut fresh).is_err());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+//! export and import of trees as JSON lines, for inspecting and reconstructing datasets
+use crate::{
+    forest::{Forest, Transaction, TreeTypes},
+    query::AllQuery,
+    store::{BanyanValue, BlockWriter, ReadOnlyStore},
+    tree::EXTEND_CHUNK_SIZE,
+    StreamBuilder, Tree,
+};
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+/// one line of the export
+#[derive(Serialize, Deserialize)]
+struct Line<K, V> {
+    offset: u64,
+    key: K,
+    value: V,
+}
+
+impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
+    /// Writes all elements of a tree as JSON lines of the form
+    /// `{"offset":0,"key":...,"value":...}`, and returns the number of lines.
+    ///
+    /// Purged elements are skipped, so the offsets have gaps. Importing closes them, see
+    /// [import_jsonl](struct.Transaction.html#method.import_jsonl).
+    pub fn export_jsonl<V>(&self, tree: &Tree<T, V>, mut writer: impl Write) -> Result<u64>
+    where
+        T::Key: Serialize,
+        V: BanyanValue + Serialize,
+    {
+        let mut lines = 0;
+        for item in self.iter_filtered(tree, AllQuery) {
+            let (offset, key, value) = item?;
+            serde_json::to_writer(&mut writer, &Line { offset, key, value })?;
+            writeln!(writer)?;
+            lines += 1;
+        }
+        writer.flush()?;
+        Ok(lines)
+    }
+}
+
+impl<T: TreeTypes, R: ReadOnlyStore<T::Link>, W: BlockWriter<T::Link>> Transaction<T, R, W> {
+    /// Adds the elements of a [export_jsonl](struct.Forest.html#method.export_jsonl) export to
+    /// a builder, and returns the number of elements.
+    ///
+    /// The offsets have to increase and start at or after the end of the builder. Gaps, e.g. from
+    /// purged elements, are closed, so the elements after them get lower offsets than in the
+    /// export. Without gaps, importing into an empty builder reconstructs the exported tree.
+    /// Empty lines are ignored.
+    ///
+    /// If any line can not be imported, the builder is left as it was.
+    pub fn import_jsonl<V>(
+        &mut self,
+        tree: &mut StreamBuilder<T, V>,
+        reader: impl BufRead,
+    ) -> Result<u64>
+    where
+        T::Key: DeserializeOwned,
+        V: BanyanValue + DeserializeOwned,
+    {
+        let mut tree = tree.transaction();
+        let start = tree.count();
+        // offset of the next line, at least
+        let mut next = start;
+        let mut chunk = Vec::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let line: Line<T::Key, V> =
+                serde_json::from_str(&line).with_context(|| format!("line {}", i + 1))?;
+            anyhow::ensure!(
+                line.offset >= next,
+                "line {}: expected offset {} or later, found {}",
+                i + 1,
+                next,
+                line.offset
+            );
+            next = line.offset + 1;
+            chunk.push((line.key, line.value));
+            if chunk.len() >= EXTEND_CHUNK_SIZE {
+                self.extend(&mut tree, std::mem::take(&mut chunk))?;
+            }
+        }
+        self.extend(&mut tree, chunk)?;
+        let count = tree.count() - start;
+        tree.commit();
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        query::OffsetRangeQuery,
+        store::{BranchCache, MemStore},
+        testing::{Sha256Digest, SimpleTT},
+        Config, Secrets,
+    };
+
+    #[test]
+    fn jsonl_roundtrip() -> Result<()> {
+        let xs = (0..100u64).map(|i| (i, i * 2)).collect::<Vec<_>>();
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let forest = Forest::<SimpleTT, _>::new(store.clone(), BranchCache::default());
+        let mut txn = Transaction::new(forest, store);
+        let mut builder = StreamBuilder::new(Config::debug(), Secrets::default());
+        txn.extend(&mut builder, xs.clone())?;
+        let mut jsonl = Vec::new();
+        assert_eq!(txn.export_jsonl(&builder.snapshot(), &mut jsonl)?, 100);
+        let text = std::str::from_utf8(&jsonl)?;
+        assert!(text.starts_with("{\"offset\":0,\"key\":0,\"value\":0}\n"));
+
+        let mut imported = StreamBuilder::<SimpleTT, u64>::new(Config::debug(), Secrets::default());
+        assert_eq!(txn.import_jsonl(&mut imported, jsonl.as_slice())?, 100);
+        let actual = txn
+            .iter_filtered(&imported.snapshot(), AllQuery)
+            .map(|x| x.map(|(_, k, v)| (k, v)))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(actual, xs);
+        // offsets have to continue the builder
+        assert!(txn.import_jsonl(&mut imported, jsonl.as_slice()).is_err());
+        assert_eq!(imported.count(), 100);
+        // a failing line leaves the builder as it was, even after a full chunk
+        let mut invalid = Vec::new();
+        for offset in 100..100 + EXTEND_CHUNK_SIZE as u64 {
+            writeln!(invalid, "{{\"offset\":{},\"key\":0,\"value\":0}}", offset)?;
+        }
+        writeln!(invalid, "not json")?;
+        assert!(txn.import_jsonl(&mut imported, invalid.as_slice()).is_err());
+        assert_eq!(imported.count(), 100);
+
+        // the gaps of purged elements are closed on import
+        txn.retain(&mut builder, &OffsetRangeQuery::from(50..))?;
+        let mut jsonl = Vec::new();
+        let exported = txn.export_jsonl(&builder.snapshot(), &mut jsonl)?;
+        assert!(exported < 100);
+        let mut imported = StreamBuilder::<SimpleTT, u64>::new(Config::debug(), Secrets::default());
+        assert_eq!(txn.import_jsonl(&mut imported, jsonl.as_slice())?, exported);
+        let expected = txn
+            .iter_filtered(&builder.snapshot(), AllQuery)
+            .map(|x| x.map(|(_, k, v)| (k, v)))
+            .collect::<Result<Vec<_>>>()?;
+        let actual = txn
+            .iter_filtered(&imported.snapshot(), AllQuery)
+            .map(|x| x.map(|(_, k, v)| (k, v)))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(actual, expected);
+        Ok(())
+    }
+}
@@ -15,6 +15,14 @@
 //!
 //! Banyan trees are persistent, using a content-addressed storage system such as [ipfs] or a key value store.
 //! Data is [CBOR] encoded and [zstd] compressed for space efficient persistent storage and replication. It is also encrypted using the [chacha20] stream cipher.
+//! The encryption can be disabled by building without the default `crypto` feature, for public data in trusted stores.
+//!
+//! ## WebAssembly
+//!
+//! The crate compiles for `wasm32-unknown-unknown`. There, leaves are decompressed with a pure rust zstd decoder
+//! and written without compression, and the types that need threads or block the calling thread, such as the
+//! throttling and retrying stores, are not available. With the `js` feature, `store::JsStore` reads and writes
+//! blocks through a javascript object, e.g. backed by IndexedDB or an HTTP gateway.
 //!
 //! # Indexing
 //!
@@ -56,11 +64,24 @@
 //! [chacha20]: https://en.wikipedia.org/wiki/Salsa20#ChaCha_variant
 //! [ipfs]: https://ipfs.io/
 //! [B-Trees]: https://en.wikipedia.org/wiki/B-tree
+#[cfg(not(target_arch = "wasm32"))]
+mod background;
+pub mod codec;
+mod crypto;
+pub mod cursor;
+pub mod error;
 mod forest;
 pub mod index;
+pub mod journal;
+#[cfg(feature = "jsonl")]
+mod jsonl;
+pub mod link;
+pub mod pipeline;
 pub mod query;
 pub mod store;
 mod stream_builder;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod tree;
 mod util;
 use stream_builder::{CipherOffset, StreamBuilderState};
@@ -68,10 +89,22 @@ use stream_builder::{CipherOffset, StreamBuilderState};
 #[cfg(feature = "metrics")]
 use prometheus::Registry;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub use background::BackgroundBuilder;
+#[cfg(feature = "crypto")]
 pub use chacha20;
-pub use forest::{Config, FilteredChunk, Forest, Secrets, Transaction, TreeTypes};
-pub use stream_builder::{StreamBuilder, StreamTransaction};
-pub use tree::Tree;
+#[cfg(feature = "crypto")]
+pub use forest::KeyProvider;
+pub use forest::{
+    Config, FilteredChunk, Forest, ForestStats, LevelStats, MissingValuePolicy, PackProgress,
+    Secrets, Transaction, TreeStats, TreeTypes, Validation, Violation, ViolationKind,
+};
+pub use stream_builder::{
+    PackJob, PackedSnapshot, StreamBuilder, StreamTransaction, CHECKPOINT_HEADROOM,
+};
+pub use tree::{MappedTree, Tree, TreeReader};
+#[cfg(feature = "crypto")]
+pub use zeroize;
 
 #[cfg(test)]
 extern crate quickcheck;
@@ -0,0 +1,113 @@
+//! compact fixed size links
+//!
+//! Links are usually encoded as [CIDs](https://github.com/multiformats/cid), which carry a
+//! version, codec and hash algorithm in addition to the digest. For wide branches these prefixes
+//! are a measurable fraction of the branch size. [FixedLink] is a link that is just a digest of a
+//! size that is known at compile time, encoded as a plain cbor byte string.
+//!
+//! Since a fixed link is not a CID, it is not visible to block stores that scrape links from the
+//! blocks to decide which blocks are still reachable. Only use it with stores that keep blocks
+//! alive by other means.
+use std::{
+    convert::TryInto,
+    fmt,
+    io::{Read, Seek, Write},
+};
+
+use anyhow::anyhow;
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Decode, Encode},
+};
+
+/// Largest supported link size, in bytes
+pub const MAX_LINK_SIZE: usize = 64;
+
+/// A link consisting of exactly `N` bytes, usually a digest
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FixedLink<const N: usize>([u8; N]);
+
+impl<const N: usize> FixedLink<N> {
+    /// Fails to compile for link sizes that are 0 or larger than [MAX_LINK_SIZE]
+    const SIZE_CHECK: () = assert!(N > 0 && N <= MAX_LINK_SIZE, "unsupported link size");
+
+    /// size of the link in bytes
+    pub const SIZE: usize = N;
+
+    pub fn new(bytes: [u8; N]) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::SIZE_CHECK;
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; N] {
+        &self.0
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for FixedLink<N> {
+    fn from(bytes: [u8; N]) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl<const N: usize> Encode<DagCborCodec> for FixedLink<N> {
+    fn encode<W: Write>(&self, c: DagCborCodec, w: &mut W) -> anyhow::Result<()> {
+        self.0[..].encode(c, w)
+    }
+}
+
+impl<const N: usize> Decode<DagCborCodec> for FixedLink<N> {
+    fn decode<R: Read + Seek>(c: DagCborCodec, r: &mut R) -> anyhow::Result<Self> {
+        let bytes = Box::<[u8]>::decode(c, r)?;
+        let bytes: [u8; N] = bytes[..]
+            .try_into()
+            .map_err(|_| anyhow!("expected a link of {} bytes, got {}", N, bytes.len()))?;
+        Ok(Self::new(bytes))
+    }
+}
+
+impl<const N: usize> fmt::Display for FixedLink<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> fmt::Debug for FixedLink<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FixedLink({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::{codec::Codec, Cid};
+    use multihash::{Code, MultihashDigest};
+
+    #[test]
+    fn fixed_link_roundtrip() -> anyhow::Result<()> {
+        let link = FixedLink::new([7u8; 32]);
+        let bytes = DagCborCodec.encode(&link)?;
+        assert_eq!(DagCborCodec.decode::<FixedLink<32>>(&bytes)?, link);
+        // wrong size
+        assert!(DagCborCodec.decode::<FixedLink<20>>(&bytes).is_err());
+        assert_eq!(link.to_string(), "07".repeat(32));
+        Ok(())
+    }
+
+    #[test]
+    fn fixed_link_is_smaller_than_cid() -> anyhow::Result<()> {
+        let cid = Cid::new_v1(0x71, Code::Sha2_256.digest(b"banyan"));
+        let link = FixedLink::<32>::new(cid.hash().digest().try_into()?);
+        let cid_size = DagCborCodec.encode(&cid)?.len();
+        let link_size = DagCborCodec.encode(&link)?.len();
+        // 2 bytes for the cbor header, instead of a tag and the cid prefix
+        assert_eq!(link_size, 34);
+        assert!(link_size + 7 <= cid_size);
+        Ok(())
+    }
+}
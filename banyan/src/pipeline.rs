@@ -0,0 +1,101 @@
+//! Filter, map and take stages that run while leaves are decoded
+//!
+//! A [Pipeline] is a chain of stages that is applied to each `(offset, key, value)` triple as
+//! soon as the value is decoded, see [Forest::run_pipeline]. Items rejected by a filter are
+//! dropped right away, and once the limit set with [take](struct.Pipeline.html#method.take) is
+//! reached, decoding stops in the middle of the leaf and no further leaves are loaded.
+//!
+//! The query is still the place for conditions on offsets and keys, since it can skip entire
+//! subtrees. Pipeline stages are for conditions on values and for transforming the results.
+//!
+//! [Pipeline]: struct.Pipeline.html
+//! [Forest::run_pipeline]: ../struct.Forest.html#method.run_pipeline
+use std::fmt;
+
+/// A chain of filter and map stages with an optional limit, see the [module docs](index.html)
+pub struct Pipeline<I, O> {
+    stages: Box<dyn FnMut(I) -> Option<O> + Send>,
+    limit: Option<usize>,
+}
+
+impl<I: 'static> Pipeline<I, I> {
+    /// a pipeline that passes all items through unchanged
+    pub fn new() -> Self {
+        Self {
+            stages: Box::new(Some),
+            limit: None,
+        }
+    }
+}
+
+impl<I: 'static> Default for Pipeline<I, I> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I: 'static, O: 'static> Pipeline<I, O> {
+    /// keep only items for which `f` returns true
+    pub fn filter(self, mut f: impl FnMut(&O) -> bool + Send + 'static) -> Self {
+        let mut stages = self.stages;
+        Self {
+            stages: Box::new(move |item| stages(item).filter(|x| f(x))),
+            limit: self.limit,
+        }
+    }
+
+    /// transform items using `f`
+    pub fn map<P: 'static>(self, mut f: impl FnMut(O) -> P + Send + 'static) -> Pipeline<I, P> {
+        let mut stages = self.stages;
+        Pipeline {
+            stages: Box::new(move |item| stages(item).map(&mut f)),
+            limit: self.limit,
+        }
+    }
+
+    /// transform items using `f`, dropping items for which it returns None
+    pub fn filter_map<P: 'static>(
+        self,
+        mut f: impl FnMut(O) -> Option<P> + Send + 'static,
+    ) -> Pipeline<I, P> {
+        let mut stages = self.stages;
+        Pipeline {
+            stages: Box::new(move |item| stages(item).and_then(&mut f)),
+            limit: self.limit,
+        }
+    }
+
+    /// stop after `n` items have made it through the pipeline
+    ///
+    /// The limit always applies to the output of the entire pipeline, no matter where in the
+    /// chain it is set. Setting it more than once keeps the smallest limit.
+    pub fn take(self, n: usize) -> Self {
+        Self {
+            stages: self.stages,
+            limit: Some(self.limit.map_or(n, |limit| limit.min(n))),
+        }
+    }
+
+    /// the limit set with [take](#method.take), if any
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// runs all stages for a single item
+    pub(crate) fn push(&mut self, item: I) -> Option<O> {
+        (self.stages)(item)
+    }
+
+    /// true if `produced` items satisfy the limit, so there is no need to decode any more
+    pub(crate) fn is_done(&self, produced: usize) -> bool {
+        self.limit.is_some_and(|limit| produced >= limit)
+    }
+}
+
+impl<I, O> fmt::Debug for Pipeline<I, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
@@ -3,13 +3,44 @@
 //! To implement querying for a new key type, implement the [Query] trait. Queries can be combined
 //! using boolean combinators.
 //!
+//! Queries can also be described as data using [QueryExpr], which can be sent to a remote
+//! party and compiled back into a query there. With the `serde` feature, the built-in query
+//! types and [QueryExpr] implement `Serialize` and `Deserialize`.
+//!
 //! [Query]: trait.Query.html
+//! [QueryExpr]: enum.QueryExpr.html
 use crate::{
     forest::TreeTypes,
-    index::{BranchIndex, CompactSeq, LeafIndex},
+    index::{BranchIndex, CompactSeq, LeafIndex, Summarizable},
     util::{MutBoolSliceExt, RangeBoundsExt},
 };
-use std::{fmt::Debug, ops::RangeBounds, sync::Arc};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Debug,
+    ops::{
+        Bound, Range, RangeBounds, RangeFrom, RangeFull, RangeInclusive, RangeTo, RangeToInclusive,
+    },
+    sync::Arc,
+};
+
+/// Bounds for the number of elements matching a query, see [Forest::estimate_matching]
+///
+/// [Forest::estimate_matching]: ../struct.Forest.html#method.estimate_matching
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MatchEstimate {
+    /// at least this many elements match
+    pub lower: u64,
+    /// at most this many elements match
+    pub upper: u64,
+}
+
+impl MatchEstimate {
+    /// true if the estimate is the exact number of matching elements
+    pub fn is_exact(&self) -> bool {
+        self.lower == self.upper
+    }
+}
 
 /// A query
 ///
@@ -19,6 +50,13 @@ pub trait Query<T: TreeTypes>: Debug + Send + Sync + 'static {
     fn containing(&self, offset: u64, _index: &LeafIndex<T>, res: &mut [bool]);
     /// a bitvec with `x.data.count()` elements, where each value is a bool indicating if the query *can* match
     fn intersecting(&self, offset: u64, _index: &BranchIndex<T>, res: &mut [bool]);
+    /// true if the query is known to match *all* elements of the branch
+    ///
+    /// This is used to answer aggregate queries from summaries without looking at the individual
+    /// elements. Returning false is always correct, so this is what the default implementation does.
+    fn covers(&self, _offset: u64, _index: &BranchIndex<T>) -> bool {
+        false
+    }
 }
 
 pub trait QueryExt<TT> {
@@ -40,10 +78,15 @@ impl<T: TreeTypes> Query<T> for Arc<dyn Query<T>> {
     fn intersecting(&self, offset: u64, x: &BranchIndex<T>, res: &mut [bool]) {
         self.as_ref().intersecting(offset, x, res);
     }
+
+    fn covers(&self, offset: u64, x: &BranchIndex<T>) -> bool {
+        self.as_ref().covers(offset, x)
+    }
 }
 
 /// The only query that does not require looking at indices
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct OffsetRangeQuery<R>(R);
 
 impl<R: RangeBounds<u64>> From<R> for OffsetRangeQuery<R> {
@@ -78,10 +121,264 @@ impl<T: TreeTypes, R: RangeBounds<u64> + Debug + Sync + Send + 'static> Query<T>
             res.clear();
         }
     }
+
+    fn covers(&self, offset: u64, index: &BranchIndex<T>) -> bool {
+        index.count == 0
+            || (self.0.contains(&offset) && self.0.contains(&(offset + index.count - 1)))
+    }
+}
+
+/// A query that matches every `step`th offset, starting at `phase`
+///
+/// Leaves and branches whose offset range does not contain a sampled offset are skipped entirely.
+/// This is useful to get a downsampled view of a very large tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(try_from = "SamplingQueryFields")
+)]
+pub struct SamplingQuery {
+    step: u64,
+    phase: u64,
+}
+
+/// the serialized form of a [SamplingQuery], which is checked like in [SamplingQuery::new]
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct SamplingQueryFields {
+    step: u64,
+    phase: u64,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<SamplingQueryFields> for SamplingQuery {
+    type Error = &'static str;
+
+    fn try_from(fields: SamplingQueryFields) -> Result<Self, Self::Error> {
+        if fields.step == 0 {
+            Err("step must not be 0")
+        } else {
+            Ok(Self::new(fields.step, fields.phase))
+        }
+    }
+}
+
+impl SamplingQuery {
+    /// Create a new sampling query. `step` must not be 0. `phase` is taken modulo `step`.
+    pub fn new(step: u64, phase: u64) -> Self {
+        assert!(step > 0, "step must not be 0");
+        Self {
+            step,
+            phase: phase % step,
+        }
+    }
+
+    pub fn step(&self) -> u64 {
+        self.step
+    }
+
+    pub fn phase(&self) -> u64 {
+        self.phase
+    }
+
+    /// true if the given offset is sampled by this query
+    pub fn is_sampled(&self, offset: u64) -> bool {
+        offset % self.step == self.phase
+    }
+
+    /// the first sampled offset that is `>= offset`, if it exists
+    fn next_sampled(&self, offset: u64) -> Option<u64> {
+        let rem = offset % self.step;
+        let delta = if rem <= self.phase {
+            self.phase - rem
+        } else {
+            self.step - rem + self.phase
+        };
+        offset.checked_add(delta)
+    }
+
+    /// true if the given offset range contains a sampled offset
+    fn intersects(&self, range: &Range<u64>) -> bool {
+        self.next_sampled(range.start)
+            .map(|x| x < range.end)
+            .unwrap_or_default()
+    }
+}
+
+impl<T: TreeTypes> Query<T> for SamplingQuery {
+    fn containing(&self, offset: u64, index: &LeafIndex<T>, res: &mut [bool]) {
+        let range = offset..offset + index.keys.count();
+        if !self.intersects(&range) {
+            res.clear();
+        } else {
+            for (res, offset) in res.iter_mut().zip(range) {
+                *res = *res && self.is_sampled(offset);
+            }
+        }
+    }
+
+    fn intersecting(&self, offset: u64, index: &BranchIndex<T>, res: &mut [bool]) {
+        // we don't know the counts of the individual children here, so we can only prune
+        // the branch as a whole.
+        let range = offset..offset + index.count;
+        if !self.intersects(&range) {
+            res.clear();
+        }
+    }
+
+    fn covers(&self, _offset: u64, _index: &BranchIndex<T>) -> bool {
+        self.step == 1
+    }
+}
+
+/// A key or summary that covers a range of values of an ordered type `K`
+///
+/// Implementing this for both the key and the summary type of a tree allows using [KeyRangeQuery]
+/// without writing a custom query.
+///
+/// [KeyRangeQuery]: struct.KeyRangeQuery.html
+pub trait OrderedSummary<K: Ord> {
+    /// the smallest value covered by this key or summary
+    fn min_key(&self) -> K;
+    /// the largest value covered by this key or summary
+    fn max_key(&self) -> K;
+}
+
+/// Every ordered value trivially covers just itself
+impl<K: Ord + Clone> OrderedSummary<K> for K {
+    fn min_key(&self) -> K {
+        self.clone()
+    }
+
+    fn max_key(&self) -> K {
+        self.clone()
+    }
+}
+
+/// A query for a range of ordered keys, such as timestamps or sequence numbers
+///
+/// Branches are pruned using the min and max values of their summaries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct KeyRangeQuery<K> {
+    start: Bound<K>,
+    end: Bound<K>,
+}
+
+impl<K: Clone> KeyRangeQuery<K> {
+    /// a query for the keys within `range`
+    pub fn new(range: impl RangeBounds<K>) -> Self {
+        Self {
+            start: cloned_bound(range.start_bound()),
+            end: cloned_bound(range.end_bound()),
+        }
+    }
+}
+
+// a blanket impl for all RangeBounds would overlap with From<T> for T
+macro_rules! key_range_from {
+    ($($range:ty),*) => {
+        $(
+            impl<K: Clone> From<$range> for KeyRangeQuery<K> {
+                fn from(value: $range) -> Self {
+                    Self::new(value)
+                }
+            }
+        )*
+    };
+}
+
+key_range_from!(
+    Range<K>,
+    RangeInclusive<K>,
+    RangeFrom<K>,
+    RangeTo<K>,
+    RangeToInclusive<K>,
+    (Bound<K>, Bound<K>)
+);
+
+impl<K> From<RangeFull> for KeyRangeQuery<K> {
+    fn from(_: RangeFull) -> Self {
+        Self {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        }
+    }
+}
+
+fn cloned_bound<K: Clone>(bound: Bound<&K>) -> Bound<K> {
+    match bound {
+        Bound::Included(x) => Bound::Included(x.clone()),
+        Bound::Excluded(x) => Bound::Excluded(x.clone()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+impl<K: Ord> KeyRangeQuery<K> {
+    /// true if the range `min..=max` has a non-empty intersection with the query range
+    fn intersects(&self, min: &K, max: &K) -> bool {
+        let above_start = match &self.start {
+            Bound::Included(start) => max >= start,
+            Bound::Excluded(start) => max > start,
+            Bound::Unbounded => true,
+        };
+        let below_end = match &self.end {
+            Bound::Included(end) => min <= end,
+            Bound::Excluded(end) => min < end,
+            Bound::Unbounded => true,
+        };
+        let non_empty = match (&self.start, &self.end) {
+            (Bound::Included(start), Bound::Included(end)) => start <= end,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end)) => start < end,
+            _ => true,
+        };
+        non_empty && above_start && below_end
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.intersects(key, key)
+    }
+
+    fn matches<S: OrderedSummary<K>>(&self, summary: &S) -> bool {
+        self.intersects(&summary.min_key(), &summary.max_key())
+    }
+}
+
+impl<T, K> Query<T> for KeyRangeQuery<K>
+where
+    T: TreeTypes,
+    T::Key: OrderedSummary<K>,
+    T::Summary: OrderedSummary<K>,
+    K: Ord + Debug + Send + Sync + 'static,
+{
+    fn containing(&self, _offset: u64, index: &LeafIndex<T>, res: &mut [bool]) {
+        for i in 0..index.keys.len().min(res.len()) {
+            if res[i] {
+                res[i] = self.matches(&index.keys.get(i).unwrap());
+            }
+        }
+    }
+
+    fn intersecting(&self, _offset: u64, index: &BranchIndex<T>, res: &mut [bool]) {
+        for i in 0..index.summaries.len().min(res.len()) {
+            if res[i] {
+                res[i] = self.matches(&index.summaries.get(i).unwrap());
+            }
+        }
+    }
+
+    fn covers(&self, _offset: u64, index: &BranchIndex<T>) -> bool {
+        let summary: T::Summary = index.summaries.summarize();
+        self.contains(&summary.min_key()) && self.contains(&summary.max_key())
+    }
 }
 
 /// A query that matches nothing
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct EmptyQuery;
 
 impl<T: TreeTypes> Query<T> for EmptyQuery {
@@ -96,6 +393,7 @@ impl<T: TreeTypes> Query<T> for EmptyQuery {
 
 /// A query that matches everything
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct AllQuery;
 
 impl<T: TreeTypes> Query<T> for AllQuery {
@@ -106,12 +404,17 @@ impl<T: TreeTypes> Query<T> for AllQuery {
     fn intersecting(&self, _offset: u64, _index: &BranchIndex<T>, _res: &mut [bool]) {
         // this query does not add any additional constraints, so we don't have to do anything
     }
+
+    fn covers(&self, _offset: u64, _index: &BranchIndex<T>) -> bool {
+        true
+    }
 }
 
 /// An intersection of two queries.
 ///
 /// This is equivalent to performing the two sub-queries and performing a boolean and on the results.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct AndQuery<A, B>(pub A, pub B);
 
 impl<T: TreeTypes, A: Query<T>, B: Query<T>> Query<T> for AndQuery<A, B> {
@@ -124,12 +427,17 @@ impl<T: TreeTypes, A: Query<T>, B: Query<T>> Query<T> for AndQuery<A, B> {
         self.0.intersecting(offset, index, res);
         self.1.intersecting(offset, index, res);
     }
+
+    fn covers(&self, offset: u64, index: &BranchIndex<T>) -> bool {
+        self.0.covers(offset, index) && self.1.covers(offset, index)
+    }
 }
 
 /// Union of two subqueries
 ///
 /// This is equivalent to performing the two sub-queries and performing a boolean or on the results.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct OrQuery<A, B>(pub A, pub B);
 
 impl<T: TreeTypes, A: Query<T>, B: Query<T>> Query<T> for OrQuery<A, B> {
@@ -146,7 +454,124 @@ impl<T: TreeTypes, A: Query<T>, B: Query<T>> Query<T> for OrQuery<A, B> {
         self.1.intersecting(offset, index, &mut tmp);
         res.or_with(&tmp);
     }
+
+    fn covers(&self, offset: u64, index: &BranchIndex<T>) -> bool {
+        self.0.covers(offset, index) || self.1.covers(offset, index)
+    }
+}
+
+/// A query described as data
+///
+/// `Q` is the type of the leaf queries, typically a key specific query such as a tag query.
+/// A `QueryExpr` can be sent over the wire and then turned back into a [Query] using [compile].
+///
+/// [Query]: trait.Query.html
+/// [compile]: #method.compile
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum QueryExpr<Q> {
+    /// matches everything, see [AllQuery](struct.AllQuery.html)
+    All,
+    /// matches nothing, see [EmptyQuery](struct.EmptyQuery.html)
+    Empty,
+    /// matches a range of offsets, see [OffsetRangeQuery](struct.OffsetRangeQuery.html)
+    OffsetRange(Range<u64>),
+    /// a leaf query
+    Atom(Q),
+    /// intersection of all sub expressions. An empty intersection matches everything.
+    And(Vec<QueryExpr<Q>>),
+    /// union of all sub expressions. An empty union matches nothing.
+    Or(Vec<QueryExpr<Q>>),
+    /// complement of the sub expression, see [NotQuery](struct.NotQuery.html)
+    Not(Box<QueryExpr<Q>>),
+}
+
+impl<Q> QueryExpr<Q> {
+    /// compile the expression into a query that can be used to traverse a tree
+    pub fn compile<T: TreeTypes>(self) -> Arc<dyn Query<T>>
+    where
+        Q: Query<T>,
+    {
+        match self {
+            Self::All => AllQuery.boxed(),
+            Self::Empty => EmptyQuery.boxed(),
+            Self::OffsetRange(range) => OffsetRangeQuery::from(range).boxed(),
+            Self::Atom(q) => q.boxed(),
+            Self::And(exprs) => exprs
+                .into_iter()
+                .map(Self::compile)
+                .reduce(|a, b| AndQuery(a, b).boxed())
+                .unwrap_or_else(|| AllQuery.boxed()),
+            Self::Or(exprs) => exprs
+                .into_iter()
+                .map(Self::compile)
+                .reduce(|a, b| OrQuery(a, b).boxed())
+                .unwrap_or_else(|| EmptyQuery.boxed()),
+            Self::Not(expr) => NotQuery(expr.compile()).boxed(),
+        }
+    }
+}
+
+impl<Q> From<Q> for QueryExpr<Q> {
+    fn from(value: Q) -> Self {
+        Self::Atom(value)
+    }
+}
+
+/// Complement of a subquery
+///
+/// At the leaf level, this matches exactly the elements that are not matched by the subquery.
+/// Branch level information only tells whether a subquery *can* match, which is not enough to
+/// tell that it matches *all* elements of a child. So this query never prunes branches.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NotQuery<A>(pub A);
+
+impl<T: TreeTypes, A: Query<T>> Query<T> for NotQuery<A> {
+    fn containing(&self, offset: u64, index: &LeafIndex<T>, res: &mut [bool]) {
+        let mut tmp = vec![true; res.len()];
+        self.0.containing(offset, index, &mut tmp);
+        for (res, inner) in res.iter_mut().zip(tmp) {
+            *res = *res && !inner;
+        }
+    }
+
+    fn intersecting(&self, _offset: u64, _index: &BranchIndex<T>, _res: &mut [bool]) {
+        // we can not exclude anything, see above
+    }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[quickcheck]
+    fn sampling_query_intersects(step: u8, phase: u8, start: u16, len: u8) -> bool {
+        let query = SamplingQuery::new(u64::from(step).max(1), phase.into());
+        let range = u64::from(start)..u64::from(start) + u64::from(len);
+        query.intersects(&range) == range.clone().any(|o| query.is_sampled(o))
+    }
+
+    #[quickcheck]
+    fn key_range_query_intersects(start: u8, end: u8, min: u8, max: u8) -> bool {
+        let (min, max) = (min.min(max), min.max(max));
+        let query = KeyRangeQuery::from(start..end);
+        query.intersects(&min, &max) == (min..=max).any(|x| (start..end).contains(&x))
+    }
+
+    #[test]
+    #[cfg(feature = "jsonl")]
+    fn sampling_query_deserialize() {
+        let query: SamplingQuery = serde_json::from_str(r#"{"step":10,"phase":13}"#).unwrap();
+        assert_eq!(query, SamplingQuery::new(10, 3));
+        assert!(serde_json::from_str::<SamplingQuery>(r#"{"step":0,"phase":0}"#).is_err());
+    }
+
+    #[test]
+    fn sampling_query_no_overflow() {
+        let query = SamplingQuery::new(10, 3);
+        assert!(!query.intersects(&(u64::MAX - 1..u64::MAX)));
+        assert_eq!(query.next_sampled(0), Some(3));
+        assert_eq!(query.next_sampled(4), Some(13));
+    }
+}
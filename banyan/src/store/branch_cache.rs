@@ -1,11 +1,18 @@
 use crate::{
-    index::{Branch, CompactSeq, Index},
+    index::{Branch, BranchIndex, CompactSeq, Index, LeafIndex},
     TreeTypes,
 };
+use fnv::FnvHashMap;
 use parking_lot::Mutex;
 use std::{num::NonZeroUsize, sync::Arc};
-use weight_cache::{Weighable, WeightCache};
+use weight_cache::Weighable;
 
+use super::{CachePolicy, Lru};
+
+/// Estimated size of a decoded branch in memory, in bytes
+///
+/// This includes the child indices behind their `Arc`s, so the weight of a branch grows with
+/// both the number of children and the size of their keys and summaries.
 impl<T: TreeTypes> Weighable for Branch<T> {
     fn measure(value: &Self) -> usize {
         let mut bytes = std::mem::size_of::<Branch<T>>();
@@ -13,9 +20,11 @@ impl<T: TreeTypes> Weighable for Branch<T> {
             bytes += std::mem::size_of::<Index<T>>();
             match child {
                 Index::Leaf(leaf) => {
+                    bytes += std::mem::size_of::<LeafIndex<T>>();
                     bytes += leaf.keys.estimated_size();
                 }
                 Index::Branch(branch) => {
+                    bytes += std::mem::size_of::<BranchIndex<T>>();
                     bytes += branch.summaries.estimated_size();
                 }
             }
@@ -24,10 +33,124 @@ impl<T: TreeTypes> Weighable for Branch<T> {
     }
 }
 
-type CacheOrBypass<T> = Option<Arc<Mutex<WeightCache<<T as TreeTypes>::Link, Branch<T>>>>>;
+/// The branches in the cache, with eviction decided by a [CachePolicy]
+#[derive(Debug)]
+struct Entries<T: TreeTypes> {
+    capacity: usize,
+    /// total weight of all entries
+    used: usize,
+    entries: FnvHashMap<T::Link, (Branch<T>, usize)>,
+    policy: Box<dyn CachePolicy<T::Link>>,
+}
+
+impl<T: TreeTypes> Entries<T> {
+    fn new(capacity: usize, policy: Box<dyn CachePolicy<T::Link>>) -> Self {
+        Self {
+            capacity,
+            used: 0,
+            entries: Default::default(),
+            policy,
+        }
+    }
+
+    fn get(&mut self, link: &T::Link) -> Option<Branch<T>> {
+        let result = self.entries.get(link).map(|(branch, _)| branch.clone());
+        self.policy.on_access(link, result.is_some());
+        result
+    }
+
+    fn remove(&mut self, link: &T::Link) {
+        if let Some((_, weight)) = self.entries.remove(link) {
+            self.used -= weight;
+            self.policy.on_remove(link);
+        }
+    }
+
+    fn put(&mut self, link: T::Link, branch: Branch<T>) -> anyhow::Result<()> {
+        let weight = Branch::measure(&branch);
+        anyhow::ensure!(
+            weight <= self.capacity,
+            "branch of {} bytes exceeds the capacity of {} bytes",
+            weight,
+            self.capacity
+        );
+        // an old entry for the same link is replaced
+        let mut used = self.used - self.entries.get(&link).map_or(0, |(_, weight)| *weight);
+        // decide on all victims before evicting any, so nothing is evicted in vain
+        let mut victims = Vec::new();
+        for victim in self.policy.victims() {
+            if used + weight <= self.capacity {
+                break;
+            }
+            if victim == link {
+                continue;
+            }
+            if !self.policy.admit(&link, &victim) {
+                // not worth caching
+                return Ok(());
+            }
+            used -= self.entries.get(&victim).map_or(0, |(_, weight)| *weight);
+            victims.push(victim);
+        }
+        if used + weight > self.capacity {
+            // only pinned entries left
+            return Ok(());
+        }
+        self.remove(&link);
+        for victim in victims {
+            self.remove(&victim);
+        }
+        let level = branch
+            .children
+            .iter()
+            .map(|x| x.level())
+            .max()
+            .unwrap_or_default()
+            + 1;
+        self.used += weight;
+        self.entries.insert(link, (branch, weight));
+        self.policy.on_insert(&link, level);
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.used = 0;
+        self.policy.clear();
+    }
+}
+
+type CacheOrBypass<T> = Option<Arc<Mutex<Entries<T>>>>;
 
+/// Cache for decoded branches, bounded by their estimated size in memory
+///
+/// Branches vary in size by orders of magnitude, depending on the number of children and the
+/// key and summary types, so the capacity is given in bytes and not as a number of branches.
+/// Which branches are evicted when the cache is full is decided by a [CachePolicy], by default
+/// [Lru].
+///
+/// Clones share the same entries, and the cache can be shared between threads.
 #[derive(Debug, Clone)]
-pub struct BranchCache<T: TreeTypes>(CacheOrBypass<T>);
+pub struct BranchCache<T: TreeTypes> {
+    cache: CacheOrBypass<T>,
+    prefetch: Option<Arc<MissTracker<T::Link>>>,
+}
+
+/// Keeps track of consecutive cache misses for the children of each parent
+///
+/// A traversal descends into a child before it visits the next sibling, so misses for the
+/// children of several parents on the current path are interleaved. They have to be counted
+/// separately, or the misses of the grandchildren would reset the count for the siblings.
+#[derive(Debug)]
+struct MissTracker<L> {
+    threshold: usize,
+    /// number of consecutive misses for the children of each parent
+    misses: Mutex<FnvHashMap<L, usize>>,
+}
+
+/// maximum number of parents to track misses for. This only needs to cover the parents on the
+/// paths of a few concurrent traversals, so the map is just cleared when it gets bigger.
+const MAX_TRACKED_PARENTS: usize = 256;
 
 impl<T: TreeTypes> Default for BranchCache<T> {
     fn default() -> Self {
@@ -36,30 +159,138 @@ impl<T: TreeTypes> Default for BranchCache<T> {
 }
 
 impl<T: TreeTypes> BranchCache<T> {
-    /// Passing a capacity of 0 disables the cache.
+    /// Creates a cache holding branches with a total estimated size of up to `capacity` bytes.
+    ///
+    /// Passing a capacity of 0 disables the cache. Branches larger than the capacity are never
+    /// cached.
     pub fn new(capacity: usize) -> Self {
-        let cache = NonZeroUsize::new(capacity)
-            .map(WeightCache::new)
-            .map(Mutex::new)
-            .map(Arc::new);
+        Self::with_policy(capacity, Lru::default())
+    }
+
+    /// Like [new](#method.new), but with a custom eviction policy.
+    pub fn with_policy(capacity: usize, policy: impl CachePolicy<T::Link>) -> Self {
+        let cache = if capacity > 0 {
+            Some(Arc::new(Mutex::new(Entries::new(
+                capacity,
+                Box::new(policy),
+            ))))
+        } else {
+            None
+        };
+        Self {
+            cache,
+            prefetch: None,
+        }
+    }
 
-        Self(cache)
+    /// Enable prefetching of siblings.
+    ///
+    /// After `threshold` consecutive cache misses for children of the same parent, all children
+    /// of that parent will be loaded into the cache at once. This speeds up scans over trees that
+    /// are not yet in the cache. Passing a threshold of 0 disables prefetching.
+    pub fn with_prefetch(mut self, threshold: usize) -> Self {
+        self.prefetch = if threshold > 0 && self.cache.is_some() {
+            Some(Arc::new(MissTracker {
+                threshold,
+                misses: Mutex::new(FnvHashMap::default()),
+            }))
+        } else {
+            None
+        };
+        self
     }
 
     pub fn get<'a>(&'a self, link: &'a T::Link) -> Option<Branch<T>> {
-        self.0.as_ref().and_then(|x| x.lock().get(link).cloned())
+        self.cache.as_ref().and_then(|x| x.lock().get(link))
     }
 
     pub fn put(&self, link: T::Link, branch: Branch<T>) {
-        if let Some(Err(e)) = self.0.as_ref().map(|x| x.lock().put(link, branch)) {
+        if let Some(Err(e)) = self.cache.as_ref().map(|x| x.lock().put(link, branch)) {
             tracing::warn!("Adding {} to cache failed: {}", link, e);
         }
     }
 
+    /// Clears the cache and sets a new capacity in bytes.
     pub fn reset(&self, capacity: NonZeroUsize) {
-        if let Some(cache) = self.0.as_ref() {
+        if let Some(cache) = self.cache.as_ref() {
             let mut cache = cache.lock();
-            *cache = WeightCache::new(capacity);
+            cache.clear();
+            cache.capacity = capacity.get();
+        }
+    }
+
+    /// record a cache hit for a child of `parent`
+    pub(crate) fn record_hit(&self, parent: &T::Link) {
+        if let Some(tracker) = self.prefetch.as_ref() {
+            tracker.misses.lock().remove(parent);
+        }
+    }
+
+    /// record a cache miss for a child of `parent`.
+    ///
+    /// Returns true if the siblings should be prefetched.
+    pub(crate) fn record_miss(&self, parent: &T::Link) -> bool {
+        if let Some(tracker) = self.prefetch.as_ref() {
+            let mut misses = tracker.misses.lock();
+            if misses.len() >= MAX_TRACKED_PARENTS && !misses.contains_key(parent) {
+                misses.clear();
+            }
+            let count = misses.entry(*parent).or_default();
+            *count += 1;
+            if *count >= tracker.threshold {
+                misses.remove(parent);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        index::VecSeq,
+        store::TinyLfu,
+        testing::{Sha256Digest, SimpleTT},
+    };
+    use libipld::Ipld;
+
+    fn branch(children: usize) -> Branch<SimpleTT> {
+        let leaf = LeafIndex::<SimpleTT> {
+            sealed: true,
+            link: None,
+            keys: vec![0u64].into_iter().collect::<VecSeq<_>>(),
+            value_bytes: 0,
+            value_summary: Ipld::Null,
+        };
+        Branch::new(vec![leaf.into(); children], 0..0)
+    }
+
+    #[test]
+    fn rejected_put_evicts_nothing() {
+        let [a, b, c] = [b"a", b"b", b"c"].map(|x| Sha256Digest::digest(&x[..]));
+        let small = Branch::measure(&branch(1));
+        let cache = BranchCache::<SimpleTT>::with_policy(2 * small, TinyLfu::default());
+        cache.put(b, branch(1));
+        cache.put(a, branch(1));
+        for _ in 0..3 {
+            assert!(cache.get(&a).is_some());
+        }
+        // c needs the space of both, and is used more often than b but less often than a
+        cache.get(&c);
+        cache.get(&c);
+        cache.put(c, branch(2));
+        assert!(cache.get(&c).is_none());
+        assert!(cache.get(&b).is_some());
+        assert!(cache.get(&a).is_some());
+
+        for _ in 0..3 {
+            cache.get(&c);
         }
+        cache.put(c, branch(2));
+        assert!(cache.get(&c).is_some());
+        assert!(cache.get(&a).is_none());
+        assert!(cache.get(&b).is_none());
     }
 }
@@ -0,0 +1,381 @@
+//! eviction policies for the [BranchCache](struct.BranchCache.html)
+//!
+//! A policy only keeps track of links. The cache itself stores the branches and their weights,
+//! and asks the policy which entry to evict when it is full.
+use fnv::FnvHasher;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Debug,
+    hash::{Hash, Hasher},
+};
+
+/// Decides which branches to keep in a [BranchCache](struct.BranchCache.html)
+pub trait CachePolicy<L>: Debug + Send + 'static {
+    /// called on every lookup, with whether the link was in the cache
+    fn on_access(&mut self, link: &L, hit: bool);
+
+    /// called after a branch of the given level has been added to the cache
+    fn on_insert(&mut self, link: &L, level: u32);
+
+    /// called after an entry has been removed from the cache
+    fn on_remove(&mut self, link: &L);
+
+    /// the entries that can be evicted, in the order in which they should be evicted. Must only
+    /// contain entries that have been inserted and not removed.
+    ///
+    /// If there are not enough entries to make room for a new one, it is not cached.
+    fn victims(&self) -> Box<dyn Iterator<Item = L> + '_>;
+
+    /// the entry to evict next, if any
+    fn victim(&self) -> Option<L> {
+        self.victims().next()
+    }
+
+    /// whether `candidate` is worth evicting `victim` for. If not, the candidate is not cached.
+    fn admit(&self, _candidate: &L, _victim: &L) -> bool {
+        true
+    }
+
+    /// forget all entries
+    fn clear(&mut self);
+}
+
+/// Evicts the least recently used entry. This is the default.
+#[derive(Debug, Clone)]
+pub struct Lru<L> {
+    tick: u64,
+    last_used: HashMap<L, u64>,
+    by_time: BTreeMap<u64, L>,
+}
+
+impl<L> Default for Lru<L> {
+    fn default() -> Self {
+        Self {
+            tick: 0,
+            last_used: HashMap::new(),
+            by_time: BTreeMap::new(),
+        }
+    }
+}
+
+impl<L: Hash + Eq + Copy> Lru<L> {
+    fn touch(&mut self, link: &L) {
+        self.tick += 1;
+        if let Some(t) = self.last_used.insert(*link, self.tick) {
+            self.by_time.remove(&t);
+        }
+        self.by_time.insert(self.tick, *link);
+    }
+}
+
+impl<L: Debug + Hash + Eq + Copy + Send + 'static> CachePolicy<L> for Lru<L> {
+    fn on_access(&mut self, link: &L, hit: bool) {
+        if hit {
+            self.touch(link);
+        }
+    }
+
+    fn on_insert(&mut self, link: &L, _: u32) {
+        self.touch(link);
+    }
+
+    fn on_remove(&mut self, link: &L) {
+        if let Some(t) = self.last_used.remove(link) {
+            self.by_time.remove(&t);
+        }
+    }
+
+    fn victims(&self) -> Box<dyn Iterator<Item = L> + '_> {
+        Box::new(self.by_time.values().copied())
+    }
+
+    fn clear(&mut self) {
+        self.last_used.clear();
+        self.by_time.clear();
+    }
+}
+
+/// Evicts the least frequently used entry, and among those the least recently used one.
+///
+/// Entries that are used over and over stay in the cache even during long scans.
+#[derive(Debug, Clone)]
+pub struct Lfu<L> {
+    tick: u64,
+    /// use count and time of last use
+    usage: HashMap<L, (u64, u64)>,
+    by_usage: BTreeMap<(u64, u64), L>,
+}
+
+impl<L> Default for Lfu<L> {
+    fn default() -> Self {
+        Self {
+            tick: 0,
+            usage: HashMap::new(),
+            by_usage: BTreeMap::new(),
+        }
+    }
+}
+
+impl<L: Hash + Eq + Copy> Lfu<L> {
+    fn touch(&mut self, link: &L) {
+        self.tick += 1;
+        let count = match self.usage.get(link) {
+            Some(usage) => {
+                self.by_usage.remove(usage);
+                usage.0 + 1
+            }
+            None => 1,
+        };
+        self.usage.insert(*link, (count, self.tick));
+        self.by_usage.insert((count, self.tick), *link);
+    }
+}
+
+impl<L: Debug + Hash + Eq + Copy + Send + 'static> CachePolicy<L> for Lfu<L> {
+    fn on_access(&mut self, link: &L, hit: bool) {
+        if hit {
+            self.touch(link);
+        }
+    }
+
+    fn on_insert(&mut self, link: &L, _: u32) {
+        self.touch(link);
+    }
+
+    fn on_remove(&mut self, link: &L) {
+        if let Some(usage) = self.usage.remove(link) {
+            self.by_usage.remove(&usage);
+        }
+    }
+
+    fn victims(&self) -> Box<dyn Iterator<Item = L> + '_> {
+        Box::new(self.by_usage.values().copied())
+    }
+
+    fn clear(&mut self) {
+        self.usage.clear();
+        self.by_usage.clear();
+    }
+}
+
+/// Number of counters per row of the frequency sketch
+const SKETCH_WIDTH: usize = 4096;
+/// Number of rows of the frequency sketch
+const SKETCH_DEPTH: usize = 4;
+
+/// LRU eviction, but a new entry is only admitted if it has been asked for more often than
+/// the entry it would replace, see https://arxiv.org/abs/1512.00727
+///
+/// Access frequencies are approximated with a count-min sketch, including accesses of entries
+/// that are not in the cache. So a bulk scan that touches every branch once can not push out
+/// branches that are used repeatedly.
+#[derive(Debug, Clone)]
+pub struct TinyLfu<L> {
+    lru: Lru<L>,
+    sketch: Vec<u8>,
+    /// number of increments since the counters were last halved
+    increments: usize,
+}
+
+impl<L> Default for TinyLfu<L> {
+    fn default() -> Self {
+        Self {
+            lru: Lru::default(),
+            sketch: vec![0; SKETCH_WIDTH * SKETCH_DEPTH],
+            increments: 0,
+        }
+    }
+}
+
+impl<L: Hash> TinyLfu<L> {
+    fn slots(link: &L) -> impl Iterator<Item = usize> {
+        let mut hasher = FnvHasher::default();
+        link.hash(&mut hasher);
+        let hash = hasher.finish();
+        (0..SKETCH_DEPTH).map(move |row| {
+            let h = hash.rotate_left(row as u32 * 16) as usize;
+            row * SKETCH_WIDTH + h % SKETCH_WIDTH
+        })
+    }
+
+    fn frequency(&self, link: &L) -> u8 {
+        Self::slots(link)
+            .map(|i| self.sketch[i])
+            .min()
+            .unwrap_or_default()
+    }
+
+    fn increment(&mut self, link: &L) {
+        for i in Self::slots(link) {
+            self.sketch[i] = self.sketch[i].saturating_add(1);
+        }
+        self.increments += 1;
+        // age the counters, so the sketch adapts to changing workloads
+        if self.increments >= SKETCH_WIDTH * 8 {
+            self.sketch.iter_mut().for_each(|x| *x /= 2);
+            self.increments = 0;
+        }
+    }
+}
+
+impl<L: Debug + Hash + Eq + Copy + Send + 'static> CachePolicy<L> for TinyLfu<L> {
+    fn on_access(&mut self, link: &L, hit: bool) {
+        self.increment(link);
+        self.lru.on_access(link, hit);
+    }
+
+    fn on_insert(&mut self, link: &L, level: u32) {
+        self.lru.on_insert(link, level);
+    }
+
+    fn on_remove(&mut self, link: &L) {
+        self.lru.on_remove(link);
+    }
+
+    fn victims(&self) -> Box<dyn Iterator<Item = L> + '_> {
+        self.lru.victims()
+    }
+
+    fn admit(&self, candidate: &L, victim: &L) -> bool {
+        self.frequency(candidate) > self.frequency(victim)
+    }
+
+    fn clear(&mut self) {
+        self.lru.clear();
+        self.sketch.iter_mut().for_each(|x| *x = 0);
+        self.increments = 0;
+    }
+}
+
+/// Never evicts branches of level `min_level` or above, and uses policy `P` for all others.
+///
+/// The upper levels of a tree are needed for every traversal but are small, so pinning them
+/// is usually a good idea. Pinned branches still count towards the capacity, so at most
+/// `max_pinned` branches are pinned, 1024 by default. Further branches of high levels are
+/// handed to `P` like all others.
+#[derive(Debug, Clone)]
+pub struct PinSpine<L, P> {
+    min_level: u32,
+    max_pinned: usize,
+    pinned: HashSet<L>,
+    inner: P,
+}
+
+impl<L, P> PinSpine<L, P> {
+    pub fn new(min_level: u32, inner: P) -> Self {
+        Self {
+            min_level,
+            max_pinned: 1024,
+            pinned: HashSet::new(),
+            inner,
+        }
+    }
+
+    /// pins at most `max_pinned` branches
+    pub fn with_max_pinned(mut self, max_pinned: usize) -> Self {
+        self.max_pinned = max_pinned;
+        self
+    }
+}
+
+impl<L, P> CachePolicy<L> for PinSpine<L, P>
+where
+    L: Debug + Hash + Eq + Copy + Send + 'static,
+    P: CachePolicy<L>,
+{
+    fn on_access(&mut self, link: &L, hit: bool) {
+        if !self.pinned.contains(link) {
+            self.inner.on_access(link, hit);
+        }
+    }
+
+    fn on_insert(&mut self, link: &L, level: u32) {
+        if level >= self.min_level && self.pinned.len() < self.max_pinned {
+            self.pinned.insert(*link);
+        } else {
+            self.inner.on_insert(link, level);
+        }
+    }
+
+    fn on_remove(&mut self, link: &L) {
+        if !self.pinned.remove(link) {
+            self.inner.on_remove(link);
+        }
+    }
+
+    fn victims(&self) -> Box<dyn Iterator<Item = L> + '_> {
+        self.inner.victims()
+    }
+
+    fn admit(&self, candidate: &L, victim: &L) -> bool {
+        self.inner.admit(candidate, victim)
+    }
+
+    fn clear(&mut self) {
+        self.pinned.clear();
+        self.inner.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_victim() {
+        let mut p = Lru::default();
+        for i in 0..3u64 {
+            p.on_insert(&i, 1);
+        }
+        p.on_access(&0, true);
+        assert_eq!(p.victim(), Some(1));
+        p.on_remove(&1);
+        assert_eq!(p.victim(), Some(2));
+    }
+
+    #[test]
+    fn lfu_victim() {
+        let mut p = Lfu::default();
+        for i in 0..3u64 {
+            p.on_insert(&i, 1);
+        }
+        p.on_access(&0, true);
+        p.on_access(&1, true);
+        assert_eq!(p.victim(), Some(2));
+        p.on_access(&2, true);
+        // all have been used twice, so the least recently used one goes
+        assert_eq!(p.victim(), Some(0));
+    }
+
+    #[test]
+    fn tiny_lfu_admission() {
+        let mut p = TinyLfu::default();
+        p.on_access(&0u64, false);
+        p.on_insert(&0, 1);
+        p.on_access(&0, true);
+        // a link that was asked for once is not worth evicting a popular one
+        p.on_access(&1, false);
+        assert!(!p.admit(&1, &0));
+        for _ in 0..3 {
+            p.on_access(&1, false);
+        }
+        assert!(p.admit(&1, &0));
+    }
+
+    #[test]
+    fn pin_spine() {
+        let mut p = PinSpine::new(2, Lru::default());
+        p.on_insert(&0u64, 3);
+        p.on_insert(&1, 1);
+        assert_eq!(p.victim(), Some(1));
+        p.on_remove(&1);
+        assert_eq!(p.victim(), None);
+
+        // once the limit is reached, high levels are no longer pinned
+        let mut p = PinSpine::new(2, Lru::default()).with_max_pinned(1);
+        p.on_insert(&0u64, 3);
+        p.on_insert(&1, 2);
+        p.on_insert(&2, 1);
+        assert_eq!(p.victims().collect::<Vec<_>>(), vec![1, 2]);
+    }
+}
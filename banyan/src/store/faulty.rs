@@ -0,0 +1,276 @@
+//! store wrapper that injects faults, for testing error handling
+use super::{BlockWriter, ReadOnlyStore};
+use fnv::FnvHashMap;
+use parking_lot::Mutex;
+use std::{hash::Hash, io, sync::Arc, time::Duration};
+
+/// A fault that a [FaultyStore] applies to an operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// fail with an [io::Error] of the given kind, without touching the inner store
+    Fail(io::ErrorKind),
+    /// wait before performing the operation
+    Delay(Duration),
+    /// flip the bits of one byte of the block, after reading or before writing it
+    Corrupt,
+}
+
+/// splitmix64, good enough to decide which operations fail
+#[derive(Debug)]
+struct Random {
+    state: u64,
+    probability: f64,
+    fault: Fault,
+}
+
+impl Random {
+    fn next(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Debug)]
+struct Faults<L> {
+    links: FnvHashMap<L, Fault>,
+    after: Option<(u64, Fault)>,
+    random: Option<Random>,
+    operations: u64,
+}
+
+impl<L: Hash + Eq> Faults<L> {
+    /// counts an operation and returns the fault to apply to it, if any
+    fn next(&mut self, link: Option<&L>) -> Option<Fault> {
+        self.operations += 1;
+        if let Some(fault) = link.and_then(|link| self.links.get(link)) {
+            return Some(*fault);
+        }
+        if let Some((n, fault)) = self.after {
+            if self.operations > n {
+                return Some(fault);
+            }
+        }
+        let random = self.random.as_mut()?;
+        if random.next() < random.probability {
+            Some(random.fault)
+        } else {
+            None
+        }
+    }
+}
+
+fn corrupt(data: &mut [u8]) {
+    if !data.is_empty() {
+        let i = data.len() / 2;
+        data[i] ^= 0xff;
+    }
+}
+
+/// A store that fails, delays or corrupts operations of an inner store, as programmed
+///
+/// Faults can be set for reads of specific links, for all operations after a number of
+/// operations, or for a seeded random fraction of operations. If several apply, link faults take
+/// precedence over faults after a number of operations, which take precedence over random faults.
+///
+/// Clones share the faults, so the store can be reprogrammed while a forest is using it.
+#[derive(Debug)]
+pub struct FaultyStore<S, L> {
+    inner: S,
+    faults: Arc<Mutex<Faults<L>>>,
+}
+
+// derive would require L: Clone
+impl<S: Clone, L> Clone for FaultyStore<S, L> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            faults: self.faults.clone(),
+        }
+    }
+}
+
+impl<S, L: Hash + Eq> FaultyStore<S, L> {
+    pub fn new(inner: S) -> Self {
+        let faults = Faults {
+            links: FnvHashMap::default(),
+            after: None,
+            random: None,
+            operations: 0,
+        };
+        Self {
+            inner,
+            faults: Arc::new(Mutex::new(faults)),
+        }
+    }
+
+    /// the wrapped store
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// applies `fault` to all reads of `link`
+    pub fn fault_link(&self, link: L, fault: Fault) {
+        self.faults.lock().links.insert(link, fault);
+    }
+
+    /// applies `fault` to all operations after the next `n` operations
+    pub fn fault_after(&self, n: u64, fault: Fault) {
+        let mut faults = self.faults.lock();
+        faults.after = Some((faults.operations + n, fault));
+    }
+
+    /// applies `fault` to each operation with the given probability, using a random number
+    /// generator with the given seed, so runs are reproducible
+    pub fn fault_randomly(&self, seed: u64, probability: f64, fault: Fault) {
+        self.faults.lock().random = Some(Random {
+            state: seed,
+            probability,
+            fault,
+        });
+    }
+
+    /// removes all faults
+    pub fn clear(&self) {
+        let mut faults = self.faults.lock();
+        faults.links.clear();
+        faults.after = None;
+        faults.random = None;
+    }
+
+    /// number of operations so far, with each block of a batch counting as one operation
+    pub fn operations(&self) -> u64 {
+        self.faults.lock().operations
+    }
+}
+
+/// applies failures and delays for a batch of operations, and returns the indices of the blocks
+/// to corrupt
+fn apply(faults: &[Option<Fault>]) -> io::Result<Vec<usize>> {
+    let mut delay = Duration::default();
+    let mut corrupted = Vec::new();
+    for (i, fault) in faults.iter().enumerate() {
+        match fault {
+            Some(Fault::Fail(kind)) => {
+                return Err(io::Error::new(*kind, "injected fault"));
+            }
+            Some(Fault::Delay(duration)) => delay += *duration,
+            Some(Fault::Corrupt) => corrupted.push(i),
+            None => {}
+        }
+    }
+    std::thread::sleep(delay);
+    Ok(corrupted)
+}
+
+impl<L, S> ReadOnlyStore<L> for FaultyStore<S, L>
+where
+    L: Hash + Eq + Send + 'static,
+    S: ReadOnlyStore<L>,
+{
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        let fault = self.faults.lock().next(Some(link));
+        let corrupted = apply(&[fault])?;
+        let mut block = self.inner.get(link)?;
+        if !corrupted.is_empty() {
+            corrupt(&mut block);
+        }
+        Ok(block)
+    }
+
+    fn get_many(&self, links: &[L]) -> anyhow::Result<Vec<Box<[u8]>>> {
+        let faults = {
+            let mut faults = self.faults.lock();
+            links
+                .iter()
+                .map(|link| faults.next(Some(link)))
+                .collect::<Vec<_>>()
+        };
+        let corrupted = apply(&faults)?;
+        let mut blocks = self.inner.get_many(links)?;
+        for i in corrupted {
+            corrupt(&mut blocks[i]);
+        }
+        Ok(blocks)
+    }
+
+    fn will_need(&self, links: &[L]) {
+        self.inner.will_need(links)
+    }
+}
+
+impl<L, S> BlockWriter<L> for FaultyStore<S, L>
+where
+    L: Hash + Eq + Send + 'static,
+    S: BlockWriter<L>,
+{
+    fn put(&mut self, mut data: Vec<u8>) -> anyhow::Result<L> {
+        let fault = self.faults.lock().next(None);
+        if !apply(&[fault])?.is_empty() {
+            corrupt(&mut data);
+        }
+        self.inner.put(data)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+
+    fn compresses_at_rest(&self) -> bool {
+        self.inner.compresses_at_rest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{is_transient_io_error, MemStore};
+
+    fn mem_store() -> MemStore<u64> {
+        MemStore::new(usize::max_value(), |x: &[u8]| x.len() as u64)
+    }
+
+    #[test]
+    fn programmed_faults() -> anyhow::Result<()> {
+        let mut store = FaultyStore::new(mem_store());
+        let a = store.put(vec![1; 4])?;
+        let b = store.put(vec![2; 5])?;
+
+        store.fault_link(a, Fault::Corrupt);
+        assert_eq!(&store.get(&a)?[..], &[1, 1, 0xfe, 1]);
+        assert_eq!(&store.get(&b)?[..], &[2; 5]);
+
+        store.fault_after(1, Fault::Fail(io::ErrorKind::TimedOut));
+        store.get(&b)?;
+        let error = store.get_many(&[b]).unwrap_err();
+        assert!(is_transient_io_error(&error));
+        assert!(store.put(vec![3; 6]).is_err());
+        // failed writes do not reach the inner store
+        assert!(store.inner().get(&6).is_err());
+
+        store.clear();
+        assert_eq!(&store.get(&a)?[..], &[1; 4]);
+        assert_eq!(store.operations(), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn random_faults() -> anyhow::Result<()> {
+        let mut store = FaultyStore::new(mem_store());
+        let a = store.put(vec![1; 4])?;
+        let failures = |seed: u64| {
+            store.fault_randomly(seed, 0.5, Fault::Fail(io::ErrorKind::Other));
+            (0..100).map(|_| store.get(&a).is_err()).collect::<Vec<_>>()
+        };
+        let first = failures(42);
+        let count = first.iter().filter(|x| **x).count();
+        assert!(count > 25 && count < 75);
+        // the same seed gives the same faults
+        assert_eq!(failures(42), first);
+        assert_ne!(failures(43), first);
+        Ok(())
+    }
+}
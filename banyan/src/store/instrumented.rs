@@ -0,0 +1,284 @@
+//! store wrapper that counts operations and measures their latency
+use super::{BlockWriter, ReadOnlyStore};
+use crate::util::Instant;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Number of latency buckets. Bucket i counts operations that took less than 2^i microseconds,
+/// the last bucket counts all slower operations.
+const LATENCY_BUCKETS: usize = 24;
+
+/// Latency distribution of store operations, with power of two buckets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    /// number of recorded operations
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// counts per bucket, together with the upper bound of the bucket
+    ///
+    /// The upper bound of the last bucket is `None`, since it contains all slower operations.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (Self::upper_bound(i), *n))
+    }
+
+    /// upper bound for the latency of the given fraction of operations, e.g. 0.99 for p99
+    ///
+    /// Returns `None` if there are no operations, or if the quantile falls into the last bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let target = ((count as f64) * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return Self::upper_bound(i);
+            }
+        }
+        None
+    }
+
+    fn upper_bound(bucket: usize) -> Option<Duration> {
+        if bucket + 1 < LATENCY_BUCKETS {
+            Some(Duration::from_micros(1 << bucket))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram([AtomicU64; LATENCY_BUCKETS]);
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros();
+        // index of the first bucket whose upper bound is above the latency
+        let bucket = (128 - micros.leading_zeros()) as usize;
+        self.0[bucket.min(LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        let mut res = LatencyHistogram::default();
+        for (to, from) in res.buckets.iter_mut().zip(self.0.iter()) {
+            *to = from.load(Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    read_errors: AtomicU64,
+    write_errors: AtomicU64,
+    read_latency: Histogram,
+    write_latency: Histogram,
+}
+
+/// Snapshot of the counters of an [InstrumentedStore]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// number of blocks read successfully
+    pub reads: u64,
+    /// number of blocks written successfully
+    pub writes: u64,
+    /// total size of the blocks read
+    pub bytes_read: u64,
+    /// total size of the blocks written
+    pub bytes_written: u64,
+    /// number of failed reads
+    pub read_errors: u64,
+    /// number of failed writes
+    pub write_errors: u64,
+    /// latency of read calls. A call to get_many counts as a single call.
+    pub read_latency: LatencyHistogram,
+    /// latency of write calls
+    pub write_latency: LatencyHistogram,
+}
+
+/// A store wrapper that counts reads and writes and measures their latency
+///
+/// Clones share the counters, so the store can be given to a forest and a transaction, and the
+/// counters can still be read using [InstrumentedStore::stats].
+#[derive(Debug, Clone)]
+pub struct InstrumentedStore<S> {
+    inner: S,
+    counters: Arc<Counters>,
+}
+
+impl<S> InstrumentedStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            counters: Default::default(),
+        }
+    }
+
+    /// the wrapped store
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// number of blocks read so far
+    pub fn reads(&self) -> u64 {
+        self.counters.reads.load(Ordering::Relaxed)
+    }
+
+    /// number of blocks written so far
+    pub fn writes(&self) -> u64 {
+        self.counters.writes.load(Ordering::Relaxed)
+    }
+
+    /// snapshot of all counters
+    pub fn stats(&self) -> StoreStats {
+        let c = &self.counters;
+        let get = |x: &AtomicU64| x.load(Ordering::Relaxed);
+        StoreStats {
+            reads: get(&c.reads),
+            writes: get(&c.writes),
+            bytes_read: get(&c.bytes_read),
+            bytes_written: get(&c.bytes_written),
+            read_errors: get(&c.read_errors),
+            write_errors: get(&c.write_errors),
+            read_latency: c.read_latency.snapshot(),
+            write_latency: c.write_latency.snapshot(),
+        }
+    }
+
+    fn record_reads(&self, t0: Instant, blocks: Option<&[Box<[u8]>]>) {
+        let c = &self.counters;
+        c.read_latency.observe(t0.elapsed());
+        match blocks {
+            Some(blocks) => {
+                let bytes = blocks.iter().map(|x| x.len() as u64).sum();
+                c.reads.fetch_add(blocks.len() as u64, Ordering::Relaxed);
+                c.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+            }
+            None => {
+                c.read_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for InstrumentedStore<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        let t0 = Instant::now();
+        let res = self.inner.get(link);
+        self.record_reads(t0, res.as_ref().ok().map(std::slice::from_ref));
+        res
+    }
+
+    fn get_many(&self, links: &[L]) -> anyhow::Result<Vec<Box<[u8]>>> {
+        let t0 = Instant::now();
+        let res = self.inner.get_many(links);
+        self.record_reads(t0, res.as_ref().ok().map(|x| x.as_slice()));
+        res
+    }
+
+    fn will_need(&self, links: &[L]) {
+        self.inner.will_need(links)
+    }
+}
+
+impl<L, S: BlockWriter<L>> BlockWriter<L> for InstrumentedStore<S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        let len = data.len() as u64;
+        let t0 = Instant::now();
+        let res = self.inner.put(data);
+        let c = &self.counters;
+        c.write_latency.observe(t0.elapsed());
+        if res.is_ok() {
+            c.writes.fetch_add(1, Ordering::Relaxed);
+            c.bytes_written.fetch_add(len, Ordering::Relaxed);
+        } else {
+            c.write_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// counts every block as a write, but records the latency of the batch only once
+    fn put_many(&mut self, blocks: Vec<Vec<u8>>) -> anyhow::Result<Vec<L>> {
+        let n = blocks.len() as u64;
+        let len = blocks.iter().map(|data| data.len() as u64).sum();
+        let t0 = Instant::now();
+        let res = self.inner.put_many(blocks);
+        let c = &self.counters;
+        c.write_latency.observe(t0.elapsed());
+        if res.is_ok() {
+            c.writes.fetch_add(n, Ordering::Relaxed);
+            c.bytes_written.fetch_add(len, Ordering::Relaxed);
+        } else {
+            c.write_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+
+    fn compresses_at_rest(&self) -> bool {
+        self.inner.compresses_at_rest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemStore;
+
+    #[test]
+    fn instrumented_store_counts() -> anyhow::Result<()> {
+        let store = MemStore::new(usize::max_value(), |x: &[u8]| x.len() as u64);
+        let mut store = InstrumentedStore::new(store);
+        let a = store.put(vec![1, 2, 3])?;
+        let b = store.clone().put(vec![4])?;
+        store.get(&a)?;
+        store.get_many(&[a, b])?;
+        assert!(store.get(&17).is_err());
+        let stats = store.stats();
+        assert_eq!(stats.writes, 2);
+        assert_eq!(stats.bytes_written, 4);
+        assert_eq!(stats.reads, 3);
+        assert_eq!(stats.bytes_read, 7);
+        assert_eq!(stats.read_errors, 1);
+        assert_eq!(stats.read_latency.count(), 3);
+        assert_eq!(stats.write_latency.count(), 2);
+        assert!(stats.read_latency.quantile(0.5).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn latency_buckets() {
+        let h = Histogram::default();
+        h.observe(Duration::from_nanos(10));
+        h.observe(Duration::from_micros(3));
+        h.observe(Duration::from_secs(3600));
+        let h = h.snapshot();
+        assert_eq!(h.count(), 3);
+        assert_eq!(h.quantile(0.3), Some(Duration::from_micros(1)));
+        assert_eq!(h.quantile(0.6), Some(Duration::from_micros(4)));
+        assert_eq!(h.quantile(1.0), None);
+    }
+}
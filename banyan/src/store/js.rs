@@ -0,0 +1,89 @@
+//! bridge to block stores implemented in javascript
+//!
+//! This allows reading trees in browsers, from any block source that javascript can access, e.g.
+//! IndexedDB or an HTTP gateway. The store interface is synchronous, so the javascript side must
+//! be able to answer without awaiting, e.g. by filling a cache before running a query, or by
+//! using synchronous requests from a web worker.
+use super::{BlockWriter, ReadOnlyStore};
+use crate::error::BlockNotFound;
+use anyhow::{anyhow, Result};
+use js_sys::Uint8Array;
+use std::{fmt, sync::Arc};
+use wasm_bindgen::{prelude::*, JsCast};
+
+#[wasm_bindgen]
+extern "C" {
+    /// A javascript object with the methods `get(key)` and `put(key, data)`
+    ///
+    /// Keys are the links formatted as strings, e.g. CIDs, and blocks are `Uint8Array`s. `get`
+    /// returns `undefined` for missing blocks. Both methods may throw to signal an error.
+    pub type JsBlockStore;
+
+    #[wasm_bindgen(method, catch)]
+    fn get(this: &JsBlockStore, key: &str) -> std::result::Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn put(this: &JsBlockStore, key: &str, data: &[u8]) -> std::result::Result<(), JsValue>;
+}
+
+/// A [ReadOnlyStore] and [BlockWriter] that delegates to a [JsBlockStore]
+///
+/// Links for new blocks are computed with the given digest function, like for
+/// [MemStore](super::MemStore).
+pub struct JsStore<L> {
+    inner: Arc<JsBlockStore>,
+    digest: fn(&[u8]) -> L,
+}
+
+impl<L> JsStore<L> {
+    pub fn new(inner: JsBlockStore, digest: fn(&[u8]) -> L) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            digest,
+        }
+    }
+}
+
+impl<L> Clone for JsStore<L> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            digest: self.digest,
+        }
+    }
+}
+
+// javascript objects can only be used from the thread that created them. Without the atomics
+// target feature wasm32 is single threaded, so the store is never actually shared between threads.
+#[cfg(not(target_feature = "atomics"))]
+unsafe impl<L> Send for JsStore<L> {}
+#[cfg(not(target_feature = "atomics"))]
+unsafe impl<L> Sync for JsStore<L> {}
+
+fn js_error(value: JsValue) -> anyhow::Error {
+    match value.as_string() {
+        Some(text) => anyhow!("javascript store error: {}", text),
+        None => anyhow!("javascript store error: {:?}", value),
+    }
+}
+
+impl<L: fmt::Display + 'static> ReadOnlyStore<L> for JsStore<L> {
+    fn get(&self, link: &L) -> Result<Box<[u8]>> {
+        let value = self.inner.get(&link.to_string()).map_err(js_error)?;
+        if value.is_undefined() || value.is_null() {
+            return Err(BlockNotFound.into());
+        }
+        let data = value
+            .dyn_into::<Uint8Array>()
+            .map_err(|_| anyhow!("block {} is not a Uint8Array", link))?;
+        Ok(data.to_vec().into())
+    }
+}
+
+impl<L: fmt::Display + 'static> BlockWriter<L> for JsStore<L> {
+    fn put(&mut self, data: Vec<u8>) -> Result<L> {
+        let link = (self.digest)(&data);
+        self.inner.put(&link.to_string(), &data).map_err(js_error)?;
+        Ok(link)
+    }
+}
@@ -0,0 +1,77 @@
+use crate::{index::Leaf, TreeTypes};
+use parking_lot::Mutex;
+use std::{num::NonZeroUsize, sync::Arc};
+use weight_cache::{Weighable, WeightCache};
+
+impl Weighable for Leaf {
+    fn measure(value: &Self) -> usize {
+        std::mem::size_of::<Leaf>() + value.items.compressed().len()
+    }
+}
+
+/// Cache for decrypted leaves, bounded by their size in bytes
+///
+/// Repeated queries over the same data, typically the most recent part of a stream, otherwise
+/// pay for getting, decrypting and possibly decompressing the leaves every time. The cache is
+/// disabled by default, see [Forest::with_leaf_cache](../struct.Forest.html#method.with_leaf_cache).
+#[derive(Debug, Clone)]
+pub struct LeafCache<T: TreeTypes> {
+    cache: Option<Arc<Mutex<WeightCache<T::Link, Leaf>>>>,
+    decompress: bool,
+}
+
+impl<T: TreeTypes> Default for LeafCache<T> {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl<T: TreeTypes> LeafCache<T> {
+    /// Creates a cache holding leaves with a total size of up to `capacity` bytes.
+    ///
+    /// Passing a capacity of 0 disables the cache.
+    pub fn new(capacity: usize) -> Self {
+        let cache = NonZeroUsize::new(capacity)
+            .map(WeightCache::new)
+            .map(Mutex::new)
+            .map(Arc::new);
+        Self {
+            cache,
+            decompress: false,
+        }
+    }
+
+    /// Store leaves decompressed, so reading them later skips zstd decompression as well.
+    ///
+    /// This trades memory for speed, since decompressed leaves are typically several times
+    /// larger.
+    pub fn decompressed(mut self) -> Self {
+        self.decompress = true;
+        self
+    }
+
+    pub fn get(&self, link: &T::Link) -> Option<Leaf> {
+        self.cache
+            .as_ref()
+            .and_then(|x| x.lock().get(link).cloned())
+    }
+
+    pub fn put(&self, link: T::Link, leaf: &Leaf) {
+        if let Some(cache) = self.cache.as_ref() {
+            let leaf = if self.decompress {
+                match leaf.items.to_uncompressed() {
+                    Ok(items) => Leaf::new(items, leaf.byte_range.clone()),
+                    Err(e) => {
+                        tracing::warn!("Decompressing {} for the cache failed: {}", link, e);
+                        return;
+                    }
+                }
+            } else {
+                leaf.clone()
+            };
+            if let Err(e) = cache.lock().put(link, leaf) {
+                tracing::warn!("Adding {} to cache failed: {}", link, e);
+            }
+        }
+    }
+}
@@ -120,4 +120,8 @@ impl<L: Eq + Hash + Send + Sync + Copy + 'static, I: BlockWriter<L> + Send + Syn
         }
         self.inner.put(data)
     }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
 }
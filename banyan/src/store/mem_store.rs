@@ -1,4 +1,5 @@
-use super::{BlockWriter, ReadOnlyStore};
+use super::{BlockWriter, ReadOnlyStore, RootStore};
+use crate::error::BlockNotFound;
 use anyhow::anyhow;
 use fnv::FnvHashMap;
 use parking_lot::Mutex;
@@ -10,6 +11,7 @@ pub struct MemStore<L>(Arc<Inner<L>>);
 
 struct Inner<L> {
     blocks: Mutex<Blocks<L>>,
+    roots: Mutex<FnvHashMap<String, L>>,
     digest: Arc<dyn Fn(&[u8]) -> L + Send + Sync>,
     max_size: usize,
 }
@@ -28,6 +30,7 @@ impl<L: Eq + Hash + Copy> MemStore<L> {
                 map: FnvHashMap::default(),
                 current_size: 0,
             }),
+            roots: Mutex::new(FnvHashMap::default()),
             max_size,
         }))
     }
@@ -64,7 +67,7 @@ impl<L: Eq + Hash + Copy + Send + Sync + 'static> ReadOnlyStore<L> for MemStore<
         if let Some(value) = self.get0(link) {
             Ok(value)
         } else {
-            Err(anyhow!("not there"))
+            Err(BlockNotFound.into())
         }
     }
 }
@@ -74,3 +77,21 @@ impl<L: Eq + Hash + Send + Sync + Copy + 'static> BlockWriter<L> for MemStore<L>
         self.put0(data)
     }
 }
+
+impl<L: Eq + Hash + Send + Sync + Copy + 'static> RootStore<L> for MemStore<L> {
+    fn get_root(&self, name: &str) -> anyhow::Result<Option<L>> {
+        Ok(self.0.roots.lock().get(name).copied())
+    }
+
+    fn cas_root(&self, name: &str, old: Option<L>, new: Option<L>) -> anyhow::Result<bool> {
+        let mut roots = self.0.roots.lock();
+        if roots.get(name).copied() != old {
+            return Ok(false);
+        }
+        match new {
+            Some(root) => roots.insert(name.to_owned(), root),
+            None => roots.remove(name),
+        };
+        Ok(true)
+    }
+}
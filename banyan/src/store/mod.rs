@@ -1,16 +1,54 @@
 //! interface to a content-addressed store
 
 mod branch_cache;
+mod cache_policy;
+#[cfg(not(target_arch = "wasm32"))]
+mod faulty;
+mod instrumented;
+#[cfg(all(feature = "js", target_arch = "wasm32"))]
+mod js;
+mod leaf_cache;
 mod mem_cache;
 mod mem_store;
+#[cfg(not(target_arch = "wasm32"))]
+mod parallel;
+#[cfg(not(target_arch = "wasm32"))]
+mod read_ahead;
+#[cfg(not(target_arch = "wasm32"))]
+mod retrying;
+mod selection_cache;
+mod swappable;
 mod thread_local_zstd;
+#[cfg(not(target_arch = "wasm32"))]
+mod throttled;
+mod tiered;
 mod zstd_dag_cbor_seq;
 
 pub use branch_cache::BranchCache;
+pub use cache_policy::{CachePolicy, Lfu, Lru, PinSpine, TinyLfu};
+#[cfg(not(target_arch = "wasm32"))]
+pub use faulty::{Fault, FaultyStore};
+pub use instrumented::{InstrumentedStore, LatencyHistogram, StoreStats};
+#[cfg(all(feature = "js", target_arch = "wasm32"))]
+pub use js::{JsBlockStore, JsStore};
+pub use leaf_cache::LeafCache;
 pub use mem_cache::{MemCache, MemWriter};
 pub use mem_store::MemStore;
-pub(crate) use thread_local_zstd::decompress_and_transform;
-pub use zstd_dag_cbor_seq::ZstdDagCborSeq;
+#[cfg(not(target_arch = "wasm32"))]
+pub use parallel::ParallelWriter;
+#[cfg(not(target_arch = "wasm32"))]
+pub use read_ahead::ReadAheadStore;
+#[cfg(not(target_arch = "wasm32"))]
+pub use retrying::{is_transient_io_error, Backoff, Classifier, RetryingStore};
+pub use selection_cache::SelectionCache;
+pub use swappable::SwappableStore;
+pub(crate) use thread_local_zstd::{
+    count_decompressed, decompress_and_transform, record_decompressed,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use throttled::{Limits, ThrottledStore};
+pub use tiered::{TieredStore, WritePolicy};
+pub use zstd_dag_cbor_seq::{ItemIter, ZstdDagCborSeq, NO_COMPRESSION};
 
 use cbor_data::codec::ReadCbor;
 use cbor_data::codec::WriteCbor;
@@ -24,8 +62,85 @@ pub trait BlockWriter<L>: Send + Sync + 'static {
     ///
     /// We might have to do this async at some point, but let's keep it sync for now.
     fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L>;
+
+    /// adds multiple blocks to the staging area, returning their links in the same order
+    ///
+    /// Stores that can write several blocks in one round trip or database transaction should
+    /// override this.
+    fn put_many(&mut self, blocks: Vec<Vec<u8>>) -> anyhow::Result<Vec<L>> {
+        blocks.into_iter().map(|data| self.put(data)).collect()
+    }
+
+    /// makes all blocks in the staging area durable
+    ///
+    /// Since links are derived from the content, a store can return the link from [put] right
+    /// away and defer the actual write until the next flush. Transactions flush at the end of
+    /// each operation that writes blocks, e.g. [Transaction::extend]. The default does nothing.
+    ///
+    /// [put]: #tymethod.put
+    /// [Transaction::extend]: ../struct.Transaction.html#method.extend
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// true if the store compresses blocks at rest, so compressing them before is redundant
+    ///
+    /// Note that blocks are encrypted before they are handed to the store, so this only helps if
+    /// the store compresses before it encrypts, or if the data is not encrypted.
+    fn compresses_at_rest(&self) -> bool {
+        false
+    }
 }
 
 pub trait ReadOnlyStore<L>: Clone + Send + Sync + 'static {
     fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>>;
+
+    /// gets multiple blocks, returning them in the same order as the links
+    ///
+    /// Stores that can fetch several blocks in one round trip should override this.
+    fn get_many(&self, links: &[L]) -> anyhow::Result<Vec<Box<[u8]>>> {
+        links.iter().map(|link| self.get(link)).collect()
+    }
+
+    /// hint that the blocks for `links` will probably be requested soon, in this order
+    ///
+    /// Tree traversals call this with the children of a branch they are about to visit. Stores
+    /// can use it to fetch the blocks in the background, see [ReadAheadStore]. The default
+    /// ignores the hint.
+    fn will_need(&self, _links: &[L]) {}
+}
+
+/// A store for named roots that can be updated atomically
+///
+/// Blocks are immutable, so the only mutable state of a stream is the link to its current root.
+/// Keeping it in a store that supports compare-and-swap means that two writers on the same
+/// stream notice when they diverge, instead of one silently overwriting the other. See
+/// [Forest::load_root] and [Transaction::publish_root].
+///
+/// [Forest::load_root]: ../struct.Forest.html#method.load_root
+/// [Transaction::publish_root]: ../struct.Transaction.html#method.publish_root
+pub trait RootStore<L>: Send + Sync + 'static {
+    /// the current root for `name`, or `None` if there is none
+    fn get_root(&self, name: &str) -> anyhow::Result<Option<L>>;
+
+    /// sets the root for `name` to `new` if it is currently `old`, `None` meaning no root
+    ///
+    /// Returns false without changing anything if the current root is not `old`.
+    fn cas_root(&self, name: &str, old: Option<L>, new: Option<L>) -> anyhow::Result<bool>;
 }
+
+/// Error when publishing a root that has been changed by someone else in the meantime
+///
+/// The stream has to be loaded again with the new root and the changes redone on top of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootConflict {
+    pub name: String,
+}
+
+impl std::fmt::Display for RootConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "root {} has been changed concurrently", self.name)
+    }
+}
+
+impl std::error::Error for RootConflict {}
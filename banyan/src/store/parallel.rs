@@ -0,0 +1,121 @@
+//! store wrapper that writes batches of blocks in parallel
+use super::{BlockWriter, ReadOnlyStore};
+use futures::{channel::oneshot, executor::ThreadPool};
+use std::fmt;
+
+/// A writer that splits [BlockWriter::put_many] batches over a thread pool
+///
+/// Transactions hand all new leaves of a branch to the writer in one batch, so with a writer that
+/// does expensive work per block, e.g. hashing or a network round trip, extending a tree with
+/// many values is no longer bound by a single core. The links are returned in order.
+///
+/// Every chunk of a batch is written by a clone of the inner writer, so clones have to share
+/// their blocks, like [MemStore](struct.MemStore.html). Writing blocks the threads of the pool,
+/// so it should not be shared with async tasks.
+#[derive(Clone)]
+pub struct ParallelWriter<S> {
+    inner: S,
+    pool: ThreadPool,
+    parallelism: usize,
+}
+
+impl<S> ParallelWriter<S> {
+    /// writes batches in up to `parallelism` chunks on `pool`
+    pub fn new(inner: S, pool: ThreadPool, parallelism: usize) -> Self {
+        Self {
+            inner,
+            pool,
+            parallelism: parallelism.max(1),
+        }
+    }
+
+    /// the wrapped store
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> fmt::Debug for ParallelWriter<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParallelWriter")
+            .field("parallelism", &self.parallelism)
+            .finish()
+    }
+}
+
+impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for ParallelWriter<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        self.inner.get(link)
+    }
+
+    fn get_many(&self, links: &[L]) -> anyhow::Result<Vec<Box<[u8]>>> {
+        self.inner.get_many(links)
+    }
+
+    fn will_need(&self, links: &[L]) {
+        self.inner.will_need(links)
+    }
+}
+
+impl<L, S> BlockWriter<L> for ParallelWriter<S>
+where
+    L: Send + 'static,
+    S: BlockWriter<L> + Clone,
+{
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        self.inner.put(data)
+    }
+
+    fn put_many(&mut self, blocks: Vec<Vec<u8>>) -> anyhow::Result<Vec<L>> {
+        if blocks.len() < 2 || self.parallelism == 1 {
+            return self.inner.put_many(blocks);
+        }
+        let chunk_size = blocks.len().div_ceil(self.parallelism);
+        let mut blocks = blocks.into_iter();
+        let mut results = Vec::new();
+        loop {
+            let chunk = blocks.by_ref().take(chunk_size).collect::<Vec<_>>();
+            if chunk.is_empty() {
+                break;
+            }
+            let mut inner = self.inner.clone();
+            let (tx, rx) = oneshot::channel();
+            self.pool.spawn_ok(async move {
+                let _ = tx.send(inner.put_many(chunk));
+            });
+            results.push(rx);
+        }
+        let mut links = Vec::new();
+        for rx in results {
+            links.extend(futures::executor::block_on(rx)??);
+        }
+        Ok(links)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+
+    fn compresses_at_rest(&self) -> bool {
+        self.inner.compresses_at_rest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemStore;
+
+    #[test]
+    fn parallel_put_many() -> anyhow::Result<()> {
+        let inner = MemStore::new(usize::max_value(), |x: &[u8]| x.len() as u64);
+        let mut store = ParallelWriter::new(inner.clone(), ThreadPool::new()?, 3);
+        let blocks = (1..=10).map(|n| vec![0u8; n]).collect::<Vec<_>>();
+        let links = store.put_many(blocks)?;
+        assert_eq!(links, (1..=10).collect::<Vec<u64>>());
+        for link in links {
+            assert_eq!(inner.get(&link)?.len() as u64, link);
+        }
+        Ok(())
+    }
+}
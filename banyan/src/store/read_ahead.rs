@@ -0,0 +1,192 @@
+//! store wrapper that fetches blocks in the background before they are needed
+use super::ReadOnlyStore;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::executor::ThreadPool;
+use parking_lot::{Condvar, Mutex};
+use std::{collections::VecDeque, fmt, hash::Hash, sync::Arc};
+
+struct Shared<L> {
+    state: Mutex<State<L>>,
+    /// notified whenever a background fetch completes
+    done: Condvar,
+}
+
+struct State<L> {
+    /// fetched blocks that have not been requested yet
+    ready: FnvHashMap<L, Box<[u8]>>,
+    /// order in which the ready blocks arrived, oldest first
+    order: VecDeque<L>,
+    /// blocks that are being fetched
+    pending: FnvHashSet<L>,
+    hits: u64,
+}
+
+/// A store that acts on [ReadOnlyStore::will_need] hints by fetching the blocks on a thread pool
+///
+/// Tree traversals announce the children of a branch they are going to visit, so with a slow
+/// store the next blocks are already on their way while the current one is processed. At most
+/// `capacity` blocks are fetched or kept ahead of time; when that is reached, the oldest blocks
+/// that were never requested are dropped.
+///
+/// A request for a block that is still being fetched waits for the fetch instead of fetching it
+/// twice. Fetching blocks the threads of the pool, so it should not be shared with async tasks.
+#[derive(Clone)]
+pub struct ReadAheadStore<L, S> {
+    inner: S,
+    pool: ThreadPool,
+    capacity: usize,
+    shared: Arc<Shared<L>>,
+}
+
+impl<L: Eq + Hash + Copy, S> ReadAheadStore<L, S> {
+    pub fn new(inner: S, pool: ThreadPool, capacity: usize) -> Self {
+        Self {
+            inner,
+            pool,
+            capacity,
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    ready: Default::default(),
+                    order: Default::default(),
+                    pending: Default::default(),
+                    hits: 0,
+                }),
+                done: Condvar::new(),
+            }),
+        }
+    }
+
+    /// the wrapped store
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// number of requests that were answered by a block fetched ahead of time
+    pub fn hits(&self) -> u64 {
+        self.shared.state.lock().hits
+    }
+
+    /// takes a block that was fetched ahead of time, waiting for it if it is being fetched
+    fn take(&self, link: &L) -> Option<Box<[u8]>> {
+        let mut state = self.shared.state.lock();
+        while state.pending.contains(link) {
+            self.shared.done.wait(&mut state);
+        }
+        let block = state.ready.remove(link)?;
+        state.order.retain(|x| x != link);
+        state.hits += 1;
+        Some(block)
+    }
+}
+
+impl<L, S> fmt::Debug for ReadAheadStore<L, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadAheadStore")
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+impl<L, S> ReadOnlyStore<L> for ReadAheadStore<L, S>
+where
+    L: Eq + Hash + Copy + Send + Sync + 'static,
+    S: ReadOnlyStore<L>,
+{
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        match self.take(link) {
+            Some(block) => Ok(block),
+            None => self.inner.get(link),
+        }
+    }
+
+    fn get_many(&self, links: &[L]) -> anyhow::Result<Vec<Box<[u8]>>> {
+        let mut blocks = links.iter().map(|link| self.take(link)).collect::<Vec<_>>();
+        let missing = links
+            .iter()
+            .zip(&blocks)
+            .filter(|(_, block)| block.is_none())
+            .map(|(link, _)| *link)
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            let mut fetched = self.inner.get_many(&missing)?.into_iter();
+            for block in blocks.iter_mut().filter(|block| block.is_none()) {
+                *block = fetched.next();
+            }
+        }
+        blocks
+            .into_iter()
+            .map(|block| block.ok_or_else(|| anyhow::anyhow!("store returned too few blocks")))
+            .collect()
+    }
+
+    fn will_need(&self, links: &[L]) {
+        let mut todo = Vec::new();
+        {
+            let mut state = self.shared.state.lock();
+            for link in links {
+                if state.ready.contains_key(link) || state.pending.contains(link) {
+                    continue;
+                }
+                while state.ready.len() + state.pending.len() >= self.capacity {
+                    match state.order.pop_front() {
+                        Some(oldest) => {
+                            state.ready.remove(&oldest);
+                        }
+                        None => break,
+                    }
+                }
+                if state.ready.len() + state.pending.len() >= self.capacity {
+                    break;
+                }
+                state.pending.insert(*link);
+                todo.push(*link);
+            }
+        }
+        for link in todo {
+            let inner = self.inner.clone();
+            let shared = self.shared.clone();
+            self.pool.spawn_ok(async move {
+                let result = inner.get(&link);
+                let mut state = shared.state.lock();
+                state.pending.remove(&link);
+                match result {
+                    Ok(block) => {
+                        state.ready.insert(link, block);
+                        state.order.push_back(link);
+                    }
+                    // the block will be requested again when it is needed
+                    Err(cause) => tracing::debug!("read ahead failed: {}", cause),
+                }
+                drop(state);
+                shared.done.notify_all();
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{BlockWriter, MemStore};
+
+    #[test]
+    fn read_ahead() -> anyhow::Result<()> {
+        let mut inner = MemStore::new(usize::max_value(), |x: &[u8]| x.len() as u64);
+        let links = (1..=10)
+            .map(|n| inner.put(vec![0; n]))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let store = ReadAheadStore::new(inner, ThreadPool::new()?, 4);
+        store.will_need(&links);
+        // only the first 4 are fetched ahead of time
+        for link in &links[..4] {
+            assert_eq!(store.get(link)?.len() as u64, *link);
+        }
+        assert_eq!(store.hits(), 4);
+        assert_eq!(store.get_many(&links[4..])?.len(), 6);
+        assert_eq!(store.hits(), 4);
+        // missing blocks are an error when they are needed, not when they are announced
+        store.will_need(&[42]);
+        assert!(store.get(&42).is_err());
+        Ok(())
+    }
+}
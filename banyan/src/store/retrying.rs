@@ -0,0 +1,331 @@
+//! store wrapper that retries transient failures
+use super::{BlockWriter, ReadOnlyStore};
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// How often and how long a [RetryingStore] waits before giving up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    /// number of retries after the first attempt
+    pub max_retries: u32,
+    /// delay before the first retry
+    pub initial: Duration,
+    /// upper bound for the delay between retries
+    pub max: Duration,
+    /// factor by which the delay grows after each retry
+    pub multiplier: f64,
+    /// fraction of the delay that is randomized, between 0 and 1, so clients that failed
+    /// together do not retry together
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial: Duration::from_millis(50),
+            max: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Backoff {
+    /// no retries at all
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// checks that the delays are well defined
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.multiplier.is_finite() && self.multiplier >= 1.0,
+            "multiplier must be at least 1, but is {}",
+            self.multiplier
+        );
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&self.jitter),
+            "jitter must be between 0 and 1, but is {}",
+            self.jitter
+        );
+        Ok(())
+    }
+
+    /// delay before the given retry, starting at 0, including jitter
+    pub fn delay(&self, retry: u32) -> Duration {
+        let base = self.initial.as_secs_f64() * self.multiplier.powi(retry as i32);
+        let base = base.min(self.max.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        // uniform in [1 - jitter, 1]
+        let factor = 1.0 - jitter * random_fraction();
+        Duration::from_secs_f64(base * factor)
+    }
+}
+
+/// random number in [0, 1), good enough for jitter without pulling in a rng
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// decides whether an error is worth retrying
+pub type Classifier = Arc<dyn Fn(&anyhow::Error) -> bool + Send + Sync>;
+
+/// default classification: io errors that typically go away on their own are transient,
+/// everything else, such as a missing block, is permanent
+pub fn is_transient_io_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            )
+        })
+    })
+}
+
+/// A store that retries failed operations of an inner store, such as a network backed store
+///
+/// Only errors that the classifier considers transient are retried, with exponential backoff.
+/// By default these are io errors like timeouts and connection resets, see
+/// [is_transient_io_error]. Retrying blocks the calling thread.
+///
+/// Writes are retried as well, which is safe since blocks are content-addressed.
+#[derive(Clone)]
+pub struct RetryingStore<S> {
+    inner: S,
+    retry: Retry,
+}
+
+/// retry state, separate from the inner store so writes can borrow both
+#[derive(Clone)]
+struct Retry {
+    backoff: Backoff,
+    classifier: Classifier,
+    retries: Arc<AtomicU64>,
+}
+
+impl Retry {
+    fn run<T>(&self, mut f: impl FnMut() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let mut retry = 0;
+        loop {
+            match f() {
+                Err(cause) if retry < self.backoff.max_retries && (self.classifier)(&cause) => {
+                    let delay = self.backoff.delay(retry);
+                    tracing::debug!("retrying in {:?} after {}", delay, cause);
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    std::thread::sleep(delay);
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<S> RetryingStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            retry: Retry {
+                backoff: Backoff::default(),
+                classifier: Arc::new(is_transient_io_error),
+                retries: Default::default(),
+            },
+        }
+    }
+
+    /// uses `backoff` for the delays between retries, failing if it is not [valid](Backoff::validate)
+    pub fn with_backoff(mut self, backoff: Backoff) -> anyhow::Result<Self> {
+        backoff.validate()?;
+        self.retry.backoff = backoff;
+        Ok(self)
+    }
+
+    /// uses `classifier` to decide which errors are retried
+    pub fn with_classifier(
+        mut self,
+        classifier: impl Fn(&anyhow::Error) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.retry.classifier = Arc::new(classifier);
+        self
+    }
+
+    /// the wrapped store
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// number of retries so far, shared between clones
+    pub fn retries(&self) -> u64 {
+        self.retry.retries.load(Ordering::Relaxed)
+    }
+}
+
+impl<S> fmt::Debug for RetryingStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryingStore")
+            .field("backoff", &self.retry.backoff)
+            .field("retries", &self.retries())
+            .finish()
+    }
+}
+
+impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for RetryingStore<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        self.retry.run(|| self.inner.get(link))
+    }
+
+    fn get_many(&self, links: &[L]) -> anyhow::Result<Vec<Box<[u8]>>> {
+        self.retry.run(|| self.inner.get_many(links))
+    }
+
+    fn will_need(&self, links: &[L]) {
+        self.inner.will_need(links)
+    }
+}
+
+impl<L, S: BlockWriter<L>> BlockWriter<L> for RetryingStore<S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        let inner = &mut self.inner;
+        self.retry.run(|| inner.put(data.clone()))
+    }
+
+    fn put_many(&mut self, blocks: Vec<Vec<u8>>) -> anyhow::Result<Vec<L>> {
+        let inner = &mut self.inner;
+        self.retry.run(|| inner.put_many(blocks.clone()))
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        let inner = &mut self.inner;
+        self.retry.run(|| inner.flush())
+    }
+
+    fn compresses_at_rest(&self) -> bool {
+        self.inner.compresses_at_rest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemStore;
+    use std::sync::atomic::AtomicU32;
+
+    /// a store that fails the first `failures` operations with a timeout
+    #[derive(Clone)]
+    struct Flaky {
+        inner: MemStore<u64>,
+        failures: Arc<AtomicU32>,
+    }
+
+    impl Flaky {
+        fn check(&self) -> anyhow::Result<()> {
+            let left = self.failures.load(Ordering::SeqCst);
+            if left > 0 {
+                self.failures.store(left - 1, Ordering::SeqCst);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "timeout").into());
+            }
+            Ok(())
+        }
+    }
+
+    impl ReadOnlyStore<u64> for Flaky {
+        fn get(&self, link: &u64) -> anyhow::Result<Box<[u8]>> {
+            self.check()?;
+            self.inner.get(link)
+        }
+    }
+
+    impl BlockWriter<u64> for Flaky {
+        fn put(&mut self, data: Vec<u8>) -> anyhow::Result<u64> {
+            self.check()?;
+            self.inner.put(data)
+        }
+    }
+
+    fn backoff() -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn retry_transient_errors() -> anyhow::Result<()> {
+        let flaky = Flaky {
+            inner: MemStore::new(usize::max_value(), |x: &[u8]| x.len() as u64),
+            failures: Arc::new(AtomicU32::new(2)),
+        };
+        let mut store = RetryingStore::new(flaky.clone()).with_backoff(backoff())?;
+        let link = store.put(vec![1, 2, 3])?;
+        assert_eq!(store.retries(), 2);
+        flaky.failures.store(2, Ordering::SeqCst);
+        assert_eq!(store.get(&link)?.len(), 3);
+        assert_eq!(store.retries(), 4);
+        // more failures than retries
+        flaky.failures.store(4, Ordering::SeqCst);
+        assert!(store.get(&link).is_err());
+        assert_eq!(store.retries(), 7);
+        // permanent errors are not retried
+        assert!(store.get(&42).is_err());
+        assert_eq!(store.retries(), 7);
+        // unless the classifier says so
+        let store = store.with_classifier(|_| true);
+        assert!(store.get(&42).is_err());
+        assert_eq!(store.retries(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_backoff() {
+        let store =
+            || RetryingStore::new(MemStore::new(usize::max_value(), |x: &[u8]| x.len() as u64));
+        for multiplier in [-2.0, 0.5, f64::NAN, f64::INFINITY].iter() {
+            let backoff = Backoff {
+                multiplier: *multiplier,
+                ..Default::default()
+            };
+            assert!(store().with_backoff(backoff).is_err());
+        }
+        let backoff = Backoff {
+            jitter: f64::NAN,
+            ..Default::default()
+        };
+        assert!(store().with_backoff(backoff).is_err());
+        assert!(store().with_backoff(Backoff::none()).is_ok());
+    }
+
+    #[test]
+    fn delay_is_bounded() {
+        let backoff = Backoff::default();
+        for retry in 0..20 {
+            let delay = backoff.delay(retry);
+            assert!(delay <= backoff.max);
+            let base = backoff.initial.as_secs_f64() * backoff.multiplier.powi(retry as i32);
+            let min = base.min(backoff.max.as_secs_f64()) * (1.0 - backoff.jitter);
+            assert!(delay.as_secs_f64() >= min * 0.999);
+        }
+    }
+}
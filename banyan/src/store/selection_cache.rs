@@ -0,0 +1,107 @@
+use crate::{util::Instant, TreeTypes};
+use parking_lot::Mutex;
+use std::{num::NonZeroUsize, sync::Arc, time::Duration};
+use weight_cache::{Weighable, WeightCache};
+
+/// the selected items of a leaf, as a concatenation of their cbor encodings
+#[derive(Debug)]
+struct Selected {
+    items: Arc<[u8]>,
+    weight: usize,
+    created: Instant,
+}
+
+impl Weighable for Selected {
+    fn measure(value: &Self) -> usize {
+        value.weight
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SelectionKey<L> {
+    link: L,
+    len: usize,
+    bits: Box<[u64]>,
+}
+
+impl<L> SelectionKey<L> {
+    fn new(link: L, selection: &[bool]) -> Self {
+        // ignore trailing misses, they do not change which items are selected
+        let len = selection.iter().rposition(|x| *x).map_or(0, |i| i + 1);
+        let mut bits = vec![0u64; len.div_ceil(64)].into_boxed_slice();
+        for (i, _) in selection[..len].iter().enumerate().filter(|(_, x)| **x) {
+            bits[i / 64] |= 1 << (i % 64);
+        }
+        Self { link, len, bits }
+    }
+}
+
+/// Cache for the items selected from a leaf by a query, bounded by their size in bytes
+///
+/// Similar queries that run at about the same time, such as the panels of a dashboard, often
+/// select the same items from the same leaves. With this cache only the first one pays for
+/// getting, decrypting and decompressing the leaf and finding the items. Entries are keyed by
+/// leaf link and selection, so a query that selects different items from a leaf misses.
+///
+/// The cache stores the encoded items, so values are still decoded on every hit.
+#[derive(Debug, Clone)]
+pub struct SelectionCache<T: TreeTypes> {
+    cache: Option<Arc<Mutex<WeightCache<SelectionKey<T::Link>, Selected>>>>,
+    ttl: Option<Duration>,
+}
+
+impl<T: TreeTypes> Default for SelectionCache<T> {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl<T: TreeTypes> SelectionCache<T> {
+    /// Creates a cache holding selections with a total size of up to `capacity` bytes.
+    ///
+    /// Passing a capacity of 0 disables the cache.
+    pub fn new(capacity: usize) -> Self {
+        let cache = NonZeroUsize::new(capacity)
+            .map(WeightCache::new)
+            .map(Mutex::new)
+            .map(Arc::new);
+        Self { cache, ttl: None }
+    }
+
+    /// Only use entries that are younger than `ttl`.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.cache.is_some()
+    }
+
+    /// the encoded items selected from the leaf with the given link
+    pub(crate) fn get(&self, link: &T::Link, selection: &[bool]) -> Option<Arc<[u8]>> {
+        let cache = self.cache.as_ref()?;
+        let key = SelectionKey::new(*link, selection);
+        let mut cache = cache.lock();
+        let entry = cache.get(&key)?;
+        match self.ttl {
+            Some(ttl) if entry.created.elapsed() > ttl => None,
+            _ => Some(entry.items.clone()),
+        }
+    }
+
+    pub(crate) fn put(&self, link: T::Link, selection: &[bool], items: Arc<[u8]>) {
+        if let Some(cache) = self.cache.as_ref() {
+            let key = SelectionKey::new(link, selection);
+            let weight = std::mem::size_of::<Selected>() + items.len() + key.bits.len() * 8;
+            let entry = Selected {
+                items,
+                weight,
+                created: Instant::now(),
+            };
+            if let Err(e) = cache.lock().put(key, entry) {
+                tracing::warn!("Adding {} to cache failed: {}", link, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! store wrapper that allows replacing the underlying store at runtime
+use super::{BlockWriter, ReadOnlyStore};
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+/// A store that forwards to an inner store which can be replaced while it is in use
+///
+/// Clones share the inner store, so swapping it affects all forests, transactions and caches
+/// using any clone. This allows failing over to a replica without recreating them. Blocks are
+/// content-addressed, so caches stay valid as long as the new store contains the same data.
+///
+/// Every operation works on a clone of the inner store taken at the start of the operation, so
+/// operations in flight while the store is swapped complete on the old store.
+#[derive(Debug, Clone)]
+pub struct SwappableStore<S> {
+    inner: Arc<RwLock<S>>,
+}
+
+impl<S: Clone> SwappableStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(inner)),
+        }
+    }
+
+    /// the store that is currently used
+    pub fn current(&self) -> S {
+        self.inner.read().clone()
+    }
+
+    /// replaces the inner store, returning the previous one
+    pub fn swap(&self, store: S) -> S {
+        std::mem::replace(&mut *self.inner.write(), store)
+    }
+}
+
+impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for SwappableStore<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        self.current().get(link)
+    }
+
+    fn get_many(&self, links: &[L]) -> anyhow::Result<Vec<Box<[u8]>>> {
+        self.current().get_many(links)
+    }
+
+    fn will_need(&self, links: &[L]) {
+        self.current().will_need(links)
+    }
+}
+
+impl<L, S: BlockWriter<L> + Clone> BlockWriter<L> for SwappableStore<S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        self.current().put(data)
+    }
+
+    fn put_many(&mut self, blocks: Vec<Vec<u8>>) -> anyhow::Result<Vec<L>> {
+        self.current().put_many(blocks)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.current().flush()
+    }
+
+    fn compresses_at_rest(&self) -> bool {
+        self.inner.read().compresses_at_rest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemStore;
+
+    #[test]
+    fn swappable_store() -> anyhow::Result<()> {
+        let a = MemStore::new(usize::max_value(), |x: &[u8]| x.len() as u64);
+        let b = MemStore::new(usize::max_value(), |x: &[u8]| x.len() as u64);
+        let mut store = SwappableStore::new(a.clone());
+        let link = store.put(vec![1, 2, 3])?;
+        let clone = store.clone();
+        assert!(clone.get(&link).is_ok());
+        store.swap(b.clone());
+        // clones share the inner store, so the clone now reads from b
+        assert!(clone.get(&link).is_err());
+        let link2 = store.put(vec![4, 5, 6, 7])?;
+        assert!(b.get(&link2).is_ok());
+        assert!(a.get(&link2).is_err());
+        assert_eq!(store.swap(a).get(&link2)?.len(), 4);
+        assert!(clone.get(&link).is_ok());
+        Ok(())
+    }
+}
@@ -1,5 +1,8 @@
 //! # ZStd decompressor that uses thread local buffers to prevent allocations
-use std::cell::RefCell;
+//!
+//! On wasm32, where the zstd C library is not available, frames are decompressed with ruzstd.
+use std::cell::{Cell, RefCell};
+#[cfg(not(target_arch = "wasm32"))]
 use zstd::block::Decompressor;
 
 /// The size of the thread local buffer
@@ -11,11 +14,13 @@ const MAX_CAPACITY: usize = 1024 * 1024 * 16;
 /// thread-local decompression state
 pub(crate) struct DecompressionState {
     /// reused zstd decompressor
+    #[cfg(not(target_arch = "wasm32"))]
     decompressor: Decompressor,
     buffer: Vec<u8>,
 }
 
 impl DecompressionState {
+    #[cfg(not(target_arch = "wasm32"))]
     fn new() -> Self {
         Self {
             decompressor: Decompressor::new(),
@@ -23,9 +28,17 @@ impl DecompressionState {
         }
     }
 
+    #[cfg(target_arch = "wasm32")]
+    fn new() -> Self {
+        Self {
+            buffer: Vec::with_capacity(MIN_CAPACITY),
+        }
+    }
+
     /// Decompress some data and apply a transform to it, e.g. deserialization.
     ///
     /// Returns the result of the transform and the uncompressed size.
+    #[cfg(not(target_arch = "wasm32"))]
     fn decompress_and_transform<F, R>(
         &mut self,
         compressed: &[u8],
@@ -51,10 +64,80 @@ impl DecompressionState {
         let result = f(&buffer[0..len]);
         Ok((len, result))
     }
+
+    #[cfg(target_arch = "wasm32")]
+    fn decompress_and_transform<F, R>(
+        &mut self,
+        compressed: &[u8],
+        f: &mut F,
+    ) -> std::io::Result<(usize, R)>
+    where
+        F: FnMut(&[u8]) -> R,
+    {
+        use std::io::{Error, ErrorKind, Read};
+        let span = tracing::trace_span!("decompress_and_transform");
+        let _entered = span.enter();
+        let decoder = ruzstd::StreamingDecoder::new(compressed)
+            .map_err(|cause| Error::new(ErrorKind::InvalidData, cause.to_string()))?;
+        self.buffer.clear();
+        // read one byte more than allowed, to detect frames that are too large
+        decoder
+            .take(MAX_CAPACITY as u64 + 1)
+            .read_to_end(&mut self.buffer)?;
+        if self.buffer.len() > MAX_CAPACITY {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "decompressed data exceeds the maximum size",
+            ));
+        }
+        let len = self.buffer.len();
+        let result = f(&self.buffer);
+        if self.buffer.capacity() > MIN_CAPACITY {
+            self.buffer = Vec::with_capacity(MIN_CAPACITY);
+        }
+        Ok((len, result))
+    }
 }
 
 thread_local!(static DECOMPRESSOR: RefCell<DecompressionState> = RefCell::new(DecompressionState::new()));
 
+thread_local!(static DECOMPRESSED_BYTES: Cell<u64> = const { Cell::new(0) });
+
+/// record bytes that were decompressed on this thread
+pub(crate) fn record_decompressed(n: usize) {
+    DECOMPRESSED_BYTES.with(|x| x.set(x.get() + n as u64));
+}
+
+/// total number of bytes decompressed on this thread. Only differences are meaningful.
+fn decompressed_bytes() -> u64 {
+    DECOMPRESSED_BYTES.with(|x| x.get())
+}
+
+thread_local!(static COUNTING: Cell<bool> = const { Cell::new(false) });
+
+/// resets [COUNTING] even if the counted function panics
+struct CountingGuard;
+
+impl Drop for CountingGuard {
+    fn drop(&mut self) {
+        COUNTING.with(|x| x.set(false));
+    }
+}
+
+/// runs `f` and returns the number of bytes it decompressed on this thread
+///
+/// Returns None for calls nested in another call, since the outermost call already counts
+/// their bytes.
+pub(crate) fn count_decompressed<X>(f: impl FnOnce() -> X) -> (X, Option<u64>) {
+    if COUNTING.with(|x| x.replace(true)) {
+        return (f(), None);
+    }
+    let _guard = CountingGuard;
+    let before = decompressed_bytes();
+    let res = f();
+    (res, Some(decompressed_bytes() - before))
+}
+
 /// decompress some data into an internal thread-local buffer, and, on success, applies a transform to the buffer
 ///
 /// returns the result of the function call and the size of the
@@ -62,7 +145,9 @@ pub fn decompress_and_transform<F, R>(compressed: &[u8], f: &mut F) -> std::io::
 where
     F: FnMut(&[u8]) -> R,
 {
-    DECOMPRESSOR.with(|d| d.borrow_mut().decompress_and_transform(compressed, f))
+    let res = DECOMPRESSOR.with(|d| d.borrow_mut().decompress_and_transform(compressed, f))?;
+    record_decompressed(res.0);
+    Ok(res)
 }
 
 #[cfg(test)]
@@ -79,4 +164,18 @@ mod tests {
         let (size, decompressed) = decompress_and_transform(&compressed, &mut |x| x.to_vec())?;
         Ok(size == decompressed.len() && data == decompressed)
     }
+
+    #[test]
+    fn nested_counting() {
+        let (inner, outer) = count_decompressed(|| {
+            record_decompressed(3);
+            let (_, inner) = count_decompressed(|| record_decompressed(4));
+            inner
+        });
+        assert_eq!(inner, None);
+        assert_eq!(outer, Some(7));
+        // the outermost call is over, so counting starts again
+        let (_, n) = count_decompressed(|| record_decompressed(5));
+        assert_eq!(n, Some(5));
+    }
 }
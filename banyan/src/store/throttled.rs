@@ -0,0 +1,325 @@
+//! store wrapper that limits the rate of operations
+use super::{BlockWriter, ReadOnlyStore};
+use crate::util::Instant;
+use parking_lot::Mutex;
+use std::{sync::Arc, time::Duration};
+
+/// Limits of a [ThrottledStore]. Unset limits are not enforced.
+///
+/// Rates allow bursts of up to one second worth of operations or bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// number of blocks read per second
+    pub reads_per_second: Option<f64>,
+    /// number of blocks written per second
+    pub writes_per_second: Option<f64>,
+    /// number of bytes read per second
+    pub read_bytes_per_second: Option<f64>,
+    /// number of bytes written per second
+    pub write_bytes_per_second: Option<f64>,
+    /// total number of bytes that may be read, after which reads fail
+    pub read_quota: Option<u64>,
+    /// total number of bytes that may be written, after which writes fail
+    pub write_quota: Option<u64>,
+}
+
+impl Limits {
+    /// fails if a rate is not a positive number
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let rates = [
+            ("reads_per_second", self.reads_per_second),
+            ("writes_per_second", self.writes_per_second),
+            ("read_bytes_per_second", self.read_bytes_per_second),
+            ("write_bytes_per_second", self.write_bytes_per_second),
+        ];
+        for (name, rate) in rates.iter() {
+            if let Some(rate) = rate {
+                anyhow::ensure!(
+                    rate.is_finite() && *rate > 0.0,
+                    "{} must be positive, but is {}",
+                    name,
+                    rate
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// token bucket that can go into debt, so a single large block does not block forever
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    /// takes `n` tokens, and returns how long to wait until the bucket is out of debt
+    fn take(&mut self, n: f64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate) - n;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::default()
+        }
+    }
+}
+
+fn take(bucket: &mut Option<Bucket>, n: f64) -> Duration {
+    bucket.as_mut().map(|b| b.take(n)).unwrap_or_default()
+}
+
+#[derive(Debug)]
+struct State {
+    reads: Option<Bucket>,
+    writes: Option<Bucket>,
+    read_bytes: Option<Bucket>,
+    write_bytes: Option<Bucket>,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+/// A store that limits the rate of reads and writes of an inner store, and the total number of
+/// bytes read and written
+///
+/// Operations that exceed a rate block the calling thread until they are allowed. Operations that
+/// exceed a quota fail. Clones share the limits, so a store can be throttled for all forests and
+/// transactions using it, e.g. to keep background packing from saturating a shared store.
+///
+/// The size of a block is only known after reading it, so a large read can exceed the byte rate,
+/// and is paid for by waiting afterwards. A read that would exceed the read quota fails after
+/// the block has been read, and is not counted.
+#[derive(Debug, Clone)]
+pub struct ThrottledStore<S> {
+    inner: S,
+    limits: Limits,
+    state: Arc<Mutex<State>>,
+}
+
+impl<S> ThrottledStore<S> {
+    /// fails if the limits are invalid, see [Limits::validate]
+    pub fn new(inner: S, limits: Limits) -> anyhow::Result<Self> {
+        limits.validate()?;
+        let state = State {
+            reads: limits.reads_per_second.map(Bucket::new),
+            writes: limits.writes_per_second.map(Bucket::new),
+            read_bytes: limits.read_bytes_per_second.map(Bucket::new),
+            write_bytes: limits.write_bytes_per_second.map(Bucket::new),
+            bytes_read: 0,
+            bytes_written: 0,
+        };
+        Ok(Self {
+            inner,
+            limits,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// the wrapped store
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// total number of bytes read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.state.lock().bytes_read
+    }
+
+    /// total number of bytes written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.state.lock().bytes_written
+    }
+
+    fn before_read(&self, blocks: usize) -> anyhow::Result<()> {
+        let wait = {
+            let mut state = self.state.lock();
+            if let Some(quota) = self.limits.read_quota {
+                anyhow::ensure!(
+                    state.bytes_read < quota,
+                    "read quota of {} bytes exceeded",
+                    quota
+                );
+            }
+            take(&mut state.reads, blocks as f64)
+        };
+        std::thread::sleep(wait);
+        Ok(())
+    }
+
+    fn after_read(&self, bytes: usize) -> anyhow::Result<()> {
+        let wait = {
+            let mut state = self.state.lock();
+            if let Some(quota) = self.limits.read_quota {
+                anyhow::ensure!(
+                    state.bytes_read + bytes as u64 <= quota,
+                    "read quota of {} bytes exceeded",
+                    quota
+                );
+            }
+            state.bytes_read += bytes as u64;
+            take(&mut state.read_bytes, bytes as f64)
+        };
+        std::thread::sleep(wait);
+        Ok(())
+    }
+
+    /// reserves `bytes` of the write quota, see [refund](#method.refund)
+    fn before_write(&self, blocks: usize, bytes: usize) -> anyhow::Result<()> {
+        let wait = {
+            let mut state = self.state.lock();
+            if let Some(quota) = self.limits.write_quota {
+                anyhow::ensure!(
+                    state.bytes_written + bytes as u64 <= quota,
+                    "write quota of {} bytes exceeded",
+                    quota
+                );
+            }
+            state.bytes_written += bytes as u64;
+            take(&mut state.writes, blocks as f64).max(take(&mut state.write_bytes, bytes as f64))
+        };
+        std::thread::sleep(wait);
+        Ok(())
+    }
+
+    /// gives back the quota reserved for a write that failed
+    fn refund(&self, bytes: usize) {
+        self.state.lock().bytes_written -= bytes as u64;
+    }
+}
+
+impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for ThrottledStore<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        self.before_read(1)?;
+        let block = self.inner.get(link)?;
+        self.after_read(block.len())?;
+        Ok(block)
+    }
+
+    fn get_many(&self, links: &[L]) -> anyhow::Result<Vec<Box<[u8]>>> {
+        self.before_read(links.len())?;
+        let blocks = self.inner.get_many(links)?;
+        self.after_read(blocks.iter().map(|block| block.len()).sum())?;
+        Ok(blocks)
+    }
+
+    fn will_need(&self, links: &[L]) {
+        self.inner.will_need(links)
+    }
+}
+
+impl<L, S: BlockWriter<L>> BlockWriter<L> for ThrottledStore<S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        let bytes = data.len();
+        self.before_write(1, bytes)?;
+        let res = self.inner.put(data);
+        if res.is_err() {
+            self.refund(bytes);
+        }
+        res
+    }
+
+    fn put_many(&mut self, blocks: Vec<Vec<u8>>) -> anyhow::Result<Vec<L>> {
+        let bytes = blocks.iter().map(|data| data.len()).sum();
+        self.before_write(blocks.len(), bytes)?;
+        let res = self.inner.put_many(blocks);
+        if res.is_err() {
+            self.refund(bytes);
+        }
+        res
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+
+    fn compresses_at_rest(&self) -> bool {
+        self.inner.compresses_at_rest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemStore;
+
+    fn mem_store() -> MemStore<u64> {
+        MemStore::new(usize::max_value(), |x: &[u8]| x.len() as u64)
+    }
+
+    #[test]
+    fn rate_limit() -> anyhow::Result<()> {
+        let limits = Limits {
+            writes_per_second: Some(100.0),
+            ..Default::default()
+        };
+        let mut store = ThrottledStore::new(mem_store(), limits)?;
+        let t0 = Instant::now();
+        // the first 100 writes are a burst, the next 10 have to wait
+        for i in 0..110u8 {
+            store.put(vec![i])?;
+        }
+        assert!(t0.elapsed() >= Duration::from_millis(90));
+        Ok(())
+    }
+
+    #[test]
+    fn quotas() -> anyhow::Result<()> {
+        let limits = Limits {
+            read_quota: Some(5),
+            write_quota: Some(10),
+            ..Default::default()
+        };
+        let mut store = ThrottledStore::new(mem_store(), limits)?;
+        let a = store.put(vec![0; 4])?;
+        let b = store.put(vec![0; 6])?;
+        assert!(store.put(vec![0]).is_err());
+        assert_eq!(store.bytes_written(), 10);
+        store.get(&a)?;
+        // the size is only known after reading, but the data is not returned
+        assert!(store.get(&b).is_err());
+        assert_eq!(store.bytes_read(), 4);
+        assert!(store.put_many(vec![vec![0]]).is_err());
+        assert_eq!(store.bytes_written(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_limits() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY].iter() {
+            let limits = Limits {
+                read_bytes_per_second: Some(*rate),
+                ..Default::default()
+            };
+            assert!(ThrottledStore::new(mem_store(), limits).is_err());
+        }
+    }
+
+    #[test]
+    fn failed_writes_are_refunded() {
+        let limits = Limits {
+            write_quota: Some(10),
+            ..Default::default()
+        };
+        // a memory store that is full
+        let mut store =
+            ThrottledStore::new(MemStore::new(0, |x: &[u8]| x.len() as u64), limits).unwrap();
+        assert!(store.put(vec![0; 4]).is_err());
+        assert_eq!(store.bytes_written(), 0);
+    }
+}
@@ -0,0 +1,207 @@
+//! store wrapper that caches a slow store in a fast one
+use super::{BlockWriter, ReadOnlyStore};
+use parking_lot::Mutex;
+use std::{collections::VecDeque, sync::Arc};
+
+/// When blocks written to a [TieredStore] reach the slow store
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum WritePolicy {
+    /// every block is written to both stores before `put` returns
+    Through,
+    /// blocks are only written to the fast store, and kept in memory until
+    /// [TieredStore::flush_pending] or [BlockWriter::flush] writes them to the slow store
+    Back,
+}
+
+/// A store that reads through a fast store, such as a local disk, and falls back to a slow
+/// store, such as remote object storage
+///
+/// Blocks that are read from the slow store are added to the fast store, so the fast store has
+/// to share its blocks between clones, like [MemStore](struct.MemStore.html). Failures of the
+/// fast store are not errors, since it only acts as a cache.
+///
+/// Clones share the blocks that are waiting to be flushed.
+#[derive(Debug, Clone)]
+pub struct TieredStore<F, S> {
+    fast: F,
+    slow: S,
+    policy: WritePolicy,
+    /// blocks that have not been written to the slow store yet
+    pending: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+impl<F, S> TieredStore<F, S> {
+    pub fn new(fast: F, slow: S, policy: WritePolicy) -> Self {
+        Self {
+            fast,
+            slow,
+            policy,
+            pending: Default::default(),
+        }
+    }
+
+    /// the fast store
+    pub fn fast(&self) -> &F {
+        &self.fast
+    }
+
+    /// the slow store
+    pub fn slow(&self) -> &S {
+        &self.slow
+    }
+
+    /// number of blocks that have not been written to the slow store yet
+    pub fn pending(&self) -> usize {
+        self.pending.lock().len()
+    }
+
+    /// writes all pending blocks to the slow store, in the order they were written
+    ///
+    /// If writing fails, all blocks stay pending, so flush can be retried.
+    pub fn flush_pending<L>(&mut self) -> anyhow::Result<()>
+    where
+        S: BlockWriter<L>,
+    {
+        let mut pending = self.pending.lock();
+        let blocks = pending.iter().cloned().collect::<Vec<_>>();
+        if !blocks.is_empty() {
+            self.slow.put_many(blocks)?;
+            pending.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<L, F, S> ReadOnlyStore<L> for TieredStore<F, S>
+where
+    L: Clone,
+    F: ReadOnlyStore<L> + BlockWriter<L>,
+    S: ReadOnlyStore<L>,
+{
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        if let Ok(block) = self.fast.get(link) {
+            return Ok(block);
+        }
+        let block = self.slow.get(link)?;
+        if let Err(cause) = self.fast.clone().put(block.to_vec()) {
+            tracing::debug!("caching block failed: {}", cause);
+        }
+        Ok(block)
+    }
+
+    fn get_many(&self, links: &[L]) -> anyhow::Result<Vec<Box<[u8]>>> {
+        let mut blocks = links
+            .iter()
+            .map(|link| self.fast.get(link).ok())
+            .collect::<Vec<_>>();
+        let missing = links
+            .iter()
+            .zip(&blocks)
+            .filter(|(_, block)| block.is_none())
+            .map(|(link, _)| link.clone())
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(blocks.into_iter().flatten().collect());
+        }
+        let mut fast = self.fast.clone();
+        let mut fetched = self.slow.get_many(&missing)?.into_iter();
+        for block in blocks.iter_mut().filter(|block| block.is_none()) {
+            let data = fetched.next().expect("one block per missing link");
+            if let Err(cause) = fast.put(data.to_vec()) {
+                tracing::debug!("caching block failed: {}", cause);
+            }
+            *block = Some(data);
+        }
+        Ok(blocks.into_iter().flatten().collect())
+    }
+
+    /// passes the hint on to the slow store, which is where prefetching pays off
+    fn will_need(&self, links: &[L]) {
+        self.slow.will_need(links)
+    }
+}
+
+impl<L, F, S> BlockWriter<L> for TieredStore<F, S>
+where
+    F: BlockWriter<L>,
+    S: BlockWriter<L>,
+{
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        match self.policy {
+            WritePolicy::Through => {
+                let link = self.slow.put(data.clone())?;
+                if let Err(cause) = self.fast.put(data) {
+                    tracing::debug!("caching block failed: {}", cause);
+                }
+                Ok(link)
+            }
+            WritePolicy::Back => {
+                let link = self.fast.put(data.clone())?;
+                self.pending.lock().push_back(data);
+                Ok(link)
+            }
+        }
+    }
+
+    /// writes the pending blocks to the slow store and flushes both stores
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.fast.flush()?;
+        self.flush_pending::<L>()?;
+        self.slow.flush()
+    }
+
+    fn compresses_at_rest(&self) -> bool {
+        self.slow.compresses_at_rest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemStore;
+
+    fn mem_store() -> MemStore<u64> {
+        MemStore::new(usize::max_value(), |x: &[u8]| x.len() as u64)
+    }
+
+    #[test]
+    fn read_through() -> anyhow::Result<()> {
+        let (fast, mut slow) = (mem_store(), mem_store());
+        let a = slow.put(vec![1])?;
+        let b = slow.put(vec![1, 2])?;
+        let store = TieredStore::new(fast.clone(), slow, WritePolicy::Through);
+        assert!(fast.get(&a).is_err());
+        assert_eq!(store.get(&a)?.len(), 1);
+        assert!(fast.get(&a).is_ok());
+        assert_eq!(store.get_many(&[b, a])?.len(), 2);
+        assert!(fast.get(&b).is_ok());
+        assert!(store.get(&3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn write_back() -> anyhow::Result<()> {
+        let (fast, slow) = (mem_store(), mem_store());
+        let mut store = TieredStore::new(fast.clone(), slow.clone(), WritePolicy::Back);
+        let a = store.put(vec![1, 2, 3])?;
+        assert!(fast.get(&a).is_ok());
+        assert!(slow.get(&a).is_err());
+        assert_eq!(store.pending(), 1);
+        store.flush_pending()?;
+        assert!(slow.get(&a).is_ok());
+        assert_eq!(store.pending(), 0);
+
+        // flushing the store also writes the pending blocks
+        let b = store.put(vec![4, 5])?;
+        assert_eq!(store.pending(), 1);
+        BlockWriter::<u64>::flush(&mut store)?;
+        assert!(slow.get(&b).is_ok());
+        assert_eq!(store.pending(), 0);
+
+        let mut store = TieredStore::new(fast, slow.clone(), WritePolicy::Through);
+        let c = store.put(vec![6])?;
+        assert!(slow.get(&c).is_ok());
+        assert_eq!(store.pending(), 0);
+        Ok(())
+    }
+}
@@ -1,8 +1,8 @@
 //! Compressed and encrypted cbor seq with links.
 //!
 //! This is the block format for both branches and leaves. Basically it is a dag-cbor
-//! 2-tuple, containing an encrypted and zstd compressed blob which again is a sequence
-//! of dag-cbor items, and a number of links.
+//! list, containing a format version, the cipher offset, a number of links, and an encrypted
+//! and zstd compressed blob which again is a sequence of dag-cbor items.
 //!
 //! The links are unencrypted so they are visible for tools that do not have the encryption
 //! keys.
@@ -19,12 +19,21 @@
 //! The blob is encrypted with the chacha20 symmetric cipher, with a 24 byte nonce that is
 //! appended to the blob.
 //!
+//! Without the `crypto` feature, or with [Secrets::none], the blob is not encrypted, and the cipher
+//! offset is null.
+//!
+//! [Secrets::none]: ../struct.Secrets.html#method.none
+//!
+//! Blocks written before the format version was introduced are lists of offset, links and blob,
+//! or of links and blob for plain blocks. They can still be read.
+//!
 //! https://github.com/ipld/specs/blob/master/block-layer/codecs/dag-cbor.md
 //! https://tools.ietf.org/html/rfc8742
 use cbor_data::{
     codec::{ReadCbor, WriteCbor},
     Cbor, CborBuilder, ItemKind, Visitor,
 };
+#[cfg(feature = "crypto")]
 use chacha20::{
     cipher::{NewCipher, StreamCipher, StreamCipherSeek},
     XChaCha20,
@@ -33,19 +42,114 @@ use libipld::{
     cbor::DagCborCodec,
     codec::Codec,
     prelude::{Decode, Encode},
-    Cid, DagCbor, Ipld,
+    Cid, Ipld,
 };
 use std::{
     collections::BTreeSet,
     convert::TryFrom,
     fmt,
-    io::{Cursor, ErrorKind, Write},
+    io::{Cursor, ErrorKind, Read},
     iter,
+    marker::PhantomData,
     ops::Range,
-    time::Instant,
 };
 
-use crate::{store::decompress_and_transform, stream_builder::CipherOffset};
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::Write;
+
+use crate::{
+    crypto::{Key, XNonce},
+    store::{decompress_and_transform, record_decompressed},
+    stream_builder::CipherOffset,
+    util::Instant,
+};
+
+/// Pseudo zstd level to store data without compression.
+///
+/// Data written with this level is still a valid zstd frame, but consists only of raw blocks.
+/// So the choice is recorded in each block, and readers do not have to know about it.
+pub const NO_COMPRESSION: i32 = i32::MIN;
+
+/// Maximum size of a raw zstd block
+const MAX_RAW_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Writes either a compressed zstd frame or a zstd frame consisting only of raw blocks
+///
+/// There is no zstd encoder on wasm32, so all frames are raw there.
+enum FrameEncoder {
+    #[cfg(not(target_arch = "wasm32"))]
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+    Raw(Vec<u8>),
+}
+
+impl FrameEncoder {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn new(zstd_level: i32) -> std::io::Result<Self> {
+        Ok(if zstd_level == NO_COMPRESSION {
+            Self::Raw(Vec::new())
+        } else {
+            Self::Zstd(zstd::Encoder::new(Vec::new(), zstd_level)?)
+        })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn new(_zstd_level: i32) -> std::io::Result<Self> {
+        Ok(Self::Raw(Vec::new()))
+    }
+
+    /// bytes written to the output so far
+    fn compressed_len(&self) -> usize {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Zstd(encoder) => encoder.get_ref().len(),
+            Self::Raw(data) => data.len(),
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Zstd(encoder) => encoder.write_all(data),
+            Self::Raw(buffer) => {
+                buffer.extend_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Zstd(encoder) => encoder.finish(),
+            Self::Raw(data) => Ok(raw_frame(&data)),
+        }
+    }
+}
+
+/// Wrap data in a zstd frame without compressing it, see
+/// https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md
+fn raw_frame(data: &[u8]) -> Vec<u8> {
+    let blocks = (data.len() / MAX_RAW_BLOCK_SIZE).max(1);
+    let mut res = Vec::with_capacity(data.len() + 13 + blocks * 3);
+    // magic number
+    res.extend_from_slice(&0xFD2FB528u32.to_le_bytes());
+    // frame header descriptor: 8 byte content size, single segment, no checksum, no dictionary
+    res.push(0b1110_0000);
+    res.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    let mut chunks = data.chunks(MAX_RAW_BLOCK_SIZE).peekable();
+    if chunks.peek().is_none() {
+        // a frame must contain at least one block, so write an empty last block
+        res.extend_from_slice(&[1, 0, 0]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none() as u32;
+        // block header: last block flag, block type 0 (raw), block size
+        let header = last | ((chunk.len() as u32) << 3);
+        res.extend_from_slice(&header.to_le_bytes()[..3]);
+        res.extend_from_slice(chunk);
+    }
+    res
+}
 
 #[derive(Clone, PartialEq, Eq)]
 pub struct ZstdDagCborSeq {
@@ -67,7 +171,7 @@ impl ZstdDagCborSeq {
         T: WriteCbor + 'a,
     {
         let t0 = Instant::now();
-        let mut encoder = zstd::Encoder::new(Vec::new(), zstd_level)?;
+        let mut encoder = FrameEncoder::new(zstd_level)?;
         let mut links = BTreeSet::new();
         let mut size: usize = 0;
         let mut encoded = Vec::new();
@@ -97,7 +201,7 @@ impl ZstdDagCborSeq {
         T: Encode<DagCborCodec> + 'a,
     {
         let t0 = Instant::now();
-        let mut encoder = zstd::Encoder::new(Vec::new(), zstd_level)?;
+        let mut encoder = FrameEncoder::new(zstd_level)?;
         let mut links = BTreeSet::new();
         let mut size: usize = 0;
         for item in iter.into_iter() {
@@ -154,9 +258,53 @@ impl ZstdDagCborSeq {
         uncompressed_size: usize,
         max_keys: usize,
     ) -> anyhow::Result<(Self, bool)> {
+        let (data, full, _) = Self::fill_inspect(
+            compressed,
+            from,
+            keys,
+            &mut |_| {},
+            zstd_level,
+            compressed_size,
+            uncompressed_size,
+            None,
+            max_keys,
+            false,
+        )?;
+        Ok((data, full))
+    }
+
+    /// like [fill](#method.fill), but calls `inspect` with the CBOR encoding of every value that
+    /// is added
+    ///
+    /// If `uncompressed_target` is given, the result is also full once it has that many
+    /// uncompressed bytes. The zstd encoder only reports its compressed size when it flushes a
+    /// block, so for small leaves this is a better predictor of the compressed size.
+    ///
+    /// With `pack_values`, the values are stored packed if they are byte strings of the same
+    /// size, see [Config::pack_values]. Other values are an error. If `compressed` is a sequence
+    /// that is not packed, the values are appended as CBOR items.
+    ///
+    /// Also returns the uncompressed size of the result.
+    ///
+    /// [Config::pack_values]: ../struct.Config.html#structfield.pack_values
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn fill_inspect<K, V: WriteCbor>(
+        compressed: &[u8],
+        from: &mut iter::Peekable<impl Iterator<Item = (K, V)>>,
+        keys: &mut Vec<K>,
+        inspect: &mut impl FnMut(&[u8]),
+        zstd_level: i32,
+        compressed_size: usize,
+        uncompressed_size: usize,
+        uncompressed_target: Option<usize>,
+        max_keys: usize,
+        pack_values: bool,
+    ) -> anyhow::Result<(Self, bool, usize)> {
         let mut links = BTreeSet::new();
         let t0 = Instant::now();
-        let mut encoder = zstd::Encoder::new(Vec::new(), zstd_level)?;
+        let mut encoder = FrameEncoder::new(zstd_level)?;
+        // size of the packed values of the prefix, if it is packed
+        let mut packed = None;
         // decompress into the encoder, if necessary
         //
         // also init decompressed size
@@ -164,7 +312,11 @@ impl ZstdDagCborSeq {
             // the first ? is to handle the io error from decompress_and_transform, the second to handle the inner io error from write_all
             let (size, data) =
                 decompress_and_transform(compressed, &mut |decompressed| -> anyhow::Result<()> {
-                    scrape_links(decompressed, &mut links)?;
+                    packed = packed_size(decompressed)?;
+                    // packed values are byte strings, so they do not contain links
+                    if packed.is_none() {
+                        scrape_links(decompressed, &mut links)?;
+                    }
                     encoder.write_all(decompressed)?;
                     Ok(())
                 })?;
@@ -173,6 +325,7 @@ impl ZstdDagCborSeq {
         } else {
             0
         };
+        let pack_values = pack_values && (size == 0 || packed.is_some());
         let mut full = false;
         let mut bytes = Vec::new();
         // fill until rough size goal exceeded
@@ -183,24 +336,54 @@ impl ZstdDagCborSeq {
             }
             bytes.clear();
             value.write_cbor(CborBuilder::append_to(&mut bytes));
+            // the header of a packed sequence, if this is its first value
+            let mut header = None;
+            let item = if pack_values {
+                let payload = byte_string_payload(&bytes)
+                    .ok_or_else(|| anyhow::anyhow!("only byte strings can be packed"))?;
+                match packed {
+                    Some(len) => anyhow::ensure!(
+                        payload.len() == len,
+                        "packed values must have the same size, expected {} bytes but got {}",
+                        len,
+                        payload.len()
+                    ),
+                    None => header = Some(packed_header(payload.len())?),
+                }
+                payload
+            } else {
+                &bytes[..]
+            };
+            let item_size = item.len() + header.map_or(0, |header| header.len());
             // if a single item is too big, bail out
-            anyhow::ensure!(bytes.len() <= uncompressed_size, "single item too large!");
+            anyhow::ensure!(item_size <= uncompressed_size, "single item too large!");
             // check that we don't exceed the uncompressed_size goal before adding
-            if size + bytes.len() > uncompressed_size {
+            if size + item_size > uncompressed_size {
                 // we know that the next item does not fit, so we are full even if
                 // there is some space left.
                 full = true;
                 break;
             }
             // scrape links from the new item
-            scrape_links(bytes.as_ref(), &mut links)?;
+            if !pack_values {
+                scrape_links(item, &mut links)?;
+            }
+            if let Some(header) = header {
+                encoder.write_all(&header)?;
+                packed = Some(item.len());
+            }
+            encoder.write_all(item)?;
             // this is guaranteed to work because of the peek above.
             // Now we are committed to add the item.
             let (key, _) = from.next().unwrap();
-            size += bytes.len();
-            encoder.write_all(&bytes)?;
+            inspect(&bytes);
+            size += item_size;
             keys.push(key);
-            if encoder.get_ref().len() >= compressed_size {
+            if encoder.compressed_len() >= compressed_size {
+                break;
+            }
+            if uncompressed_target.is_some_and(|target| size >= target) {
+                full = true;
                 break;
             }
         }
@@ -216,7 +399,7 @@ impl ZstdDagCborSeq {
         full |= data.len() >= compressed_size;
         full |= keys.len() >= max_keys;
         full |= size >= uncompressed_size;
-        Ok((Self::new(data, links.into_iter().collect()), full))
+        Ok((Self::new(data, links.into_iter().collect()), full, size))
     }
 
     /// create a ZStdArray by filling from an iterator
@@ -244,7 +427,7 @@ impl ZstdDagCborSeq {
     ) -> anyhow::Result<(Self, bool)> {
         let mut links = BTreeSet::new();
         let t0 = Instant::now();
-        let mut encoder = zstd::Encoder::new(Vec::new(), zstd_level)?;
+        let mut encoder = FrameEncoder::new(zstd_level)?;
         // decompress into the encoder, if necessary
         //
         // also init decompressed size
@@ -286,7 +469,7 @@ impl ZstdDagCborSeq {
             size += bytes.len();
             encoder.write_all(&bytes)?;
             keys.push(key);
-            if encoder.get_ref().len() >= compressed_size {
+            if encoder.compressed_len() >= compressed_size {
                 break;
             }
         }
@@ -310,60 +493,66 @@ impl ZstdDagCborSeq {
         &self.data
     }
 
+    /// the same sequence, stored without compression
+    pub(crate) fn to_uncompressed(&self) -> anyhow::Result<Self> {
+        let (_, data) = decompress_and_transform(self.compressed(), &mut raw_frame)?;
+        Ok(Self::new(data, self.links.clone()))
+    }
+
     /// Computes the number of cbor items in the cbor seq
     pub fn count(&self) -> anyhow::Result<u64> {
         decompress_and_transform(self.compressed(), &mut |uncompressed| {
-            count_cbor_items(uncompressed)
+            let packed = packed_size(uncompressed)?;
+            match packed {
+                Some(len) => Ok(((uncompressed.len() - PACKED_HEADER_LEN) / len) as u64),
+                None => count_cbor_items(uncompressed),
+            }
         })?
         .1
     }
 
+    /// returns an iterator that decodes the items one at a time
+    ///
+    /// Decompression happens incrementally, so the full decompressed sequence is never held in
+    /// memory. Peak memory is bounded by the size of the largest item. Packed sequences are the
+    /// exception, they are decompressed in one go.
+    pub fn iter<T: ReadCbor>(&self) -> anyhow::Result<ItemIter<'_, T>> {
+        ItemIter::new(self.compressed())
+    }
+
     /// returns all items as a vec
     pub fn items<T: ReadCbor>(&self) -> anyhow::Result<Vec<T>> {
-        let (_, data) = decompress_and_transform(self.compressed(), &mut |mut uncompressed| {
-            let mut result = Vec::new();
-            while !uncompressed.is_empty() {
-                let (cbor, rest) = Cbor::checked_prefix(uncompressed)?;
-                result.push(T::read_cbor(cbor)?);
-                uncompressed = rest;
-            }
-            Ok(result)
-        })?;
-        data
+        self.iter()?.collect()
     }
 
     /// returns all items as a vec
     pub fn items_ipld<T: Decode<DagCborCodec>>(&self) -> anyhow::Result<Vec<T>> {
         let (_, data) = decompress_and_transform(self.compressed(), &mut |uncompressed| {
-            let mut result = Vec::new();
-            let mut r = Cursor::new(&uncompressed);
-            let len = u64::try_from(uncompressed.len())?;
-            while r.position() < len {
-                result.push(T::decode(DagCborCodec, &mut r)?);
-            }
-            Ok(result)
+            with_cbor_seq(uncompressed, |uncompressed| {
+                let mut result = Vec::new();
+                let mut r = Cursor::new(&uncompressed);
+                let len = u64::try_from(uncompressed.len())?;
+                while r.position() < len {
+                    result.push(T::decode(DagCborCodec, &mut r)?);
+                }
+                Ok(result)
+            })
         })?;
         data
     }
 
     /// Decompress and decode a single item
+    ///
+    /// Items before `index` are skipped without being decoded.
     pub fn get<T: ReadCbor>(&self, index: u64) -> anyhow::Result<Option<T>> {
-        let (_, data) = decompress_and_transform(self.compressed(), &mut |uncompressed| {
-            let mut remaining = index;
-            let mut bytes = uncompressed;
-            while !bytes.is_empty() {
-                // we cannot use ipld since the contained data may be arbitrary CBOR, not just IPLD-CBOR
-                let (cbor, rest) = Cbor::checked_prefix(bytes)?;
-                if remaining > 0 {
-                    remaining -= 1;
-                    bytes = rest;
-                } else {
-                    return Ok(Some(T::read_cbor(cbor)?));
-                }
+        let mut items = self.iter::<T>()?;
+        for _ in 0..index {
+            match items.skip_item() {
+                Some(res) => res?,
+                None => return Ok(None),
             }
-            Ok(None)
-        })?;
-        data
+        }
+        items.next().transpose()
     }
 
     /// select the items marked by the bool slice and deserialize them into a vec.
@@ -378,25 +567,61 @@ impl ZstdDagCborSeq {
             return Ok(Vec::new());
         }
         let (_, data) = decompress_and_transform(self.compressed(), &mut |uncompressed| {
-            let mut result: Vec<T> = Vec::new();
-            let mut bytes = uncompressed;
-            for take in take.iter().cloned() {
-                if bytes.is_empty() {
-                    break;
+            with_cbor_seq(uncompressed, |uncompressed| {
+                let mut result: Vec<T> = Vec::new();
+                let mut bytes = uncompressed;
+                for take in take.iter().cloned() {
+                    if bytes.is_empty() {
+                        break;
+                    }
+                    // we cannot use ipld since the contained data may be arbitrary CBOR, not just IPLD-CBOR
+                    let (cbor, rest) = Cbor::checked_prefix(bytes)?;
+                    bytes = rest;
+                    if take {
+                        result.push(T::read_cbor(cbor)?);
+                    }
                 }
-                // we cannot use ipld since the contained data may be arbitrary CBOR, not just IPLD-CBOR
-                let (cbor, rest) = Cbor::checked_prefix(bytes)?;
-                bytes = rest;
-                if take {
-                    result.push(T::read_cbor(cbor)?);
+                Ok(result)
+            })
+        })?;
+        data
+    }
+
+    /// calls `f` with the position and the undecoded cbor of each item marked by the bool slice
+    ///
+    /// The cbor borrows from an internal decompression buffer, so large values such as byte
+    /// strings can be looked at without copying them. Other items are skipped.
+    pub fn visit_selected(
+        &self,
+        take: &[bool],
+        f: &mut impl FnMut(usize, &Cbor) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let take = shrink_to_fit(take);
+        if take.is_empty() {
+            return Ok(());
+        }
+        let (_, data) = decompress_and_transform(self.compressed(), &mut |uncompressed| {
+            with_cbor_seq(uncompressed, |uncompressed| {
+                let mut bytes = uncompressed;
+                for (i, take) in take.iter().enumerate() {
+                    if bytes.is_empty() {
+                        break;
+                    }
+                    // we cannot use ipld since the contained data may be arbitrary CBOR, not just IPLD-CBOR
+                    let (cbor, rest) = Cbor::checked_prefix(bytes)?;
+                    bytes = rest;
+                    if *take {
+                        f(i, cbor)?;
+                    }
                 }
-            }
-            Ok(result)
+                Ok(())
+            })
         })?;
         data
     }
 
     /// encrypt using the given key and nonce
+    #[cfg(feature = "crypto")]
     pub fn encrypt(
         &self,
         key: &chacha20::Key,
@@ -408,30 +633,70 @@ impl ZstdDagCborSeq {
     }
 
     /// convert into an encrypted blob, using the given key and nonce
+    #[cfg(feature = "crypto")]
     pub(crate) fn into_encrypted(
         self,
-        key: &chacha20::Key,
-        nonce: &chacha20::XNonce,
+        key: &Key,
+        nonce: &XNonce,
         state: &mut CipherOffset,
     ) -> anyhow::Result<Vec<u8>> {
         let Self { mut data, links } = self;
         // encrypt in place with the key and nonce
         let mut chacha20 = XChaCha20::new(key, nonce);
-        let offset = state.reserve(data.len());
+        let offset = state.reserve(data.len())?;
         chacha20.seek(offset);
         chacha20.apply_keystream(&mut data);
-        // encode via IpldNode
-        let result = DagCborCodec.encode(&IpldNode::new(links, data, offset))?;
-        Ok(result)
+        Envelope {
+            offset: Some(offset),
+            links,
+            data,
+        }
+        .encode()
+    }
+
+    /// convert into a blob in the plain envelope, since there is no encryption without the
+    /// `crypto` feature
+    #[cfg(not(feature = "crypto"))]
+    pub(crate) fn into_encrypted(
+        self,
+        _: &Key,
+        _: &XNonce,
+        state: &mut CipherOffset,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.into_plain(state)
+    }
+
+    /// convert into a blob in the plain envelope, without encryption
+    pub(crate) fn into_plain(self, state: &mut CipherOffset) -> anyhow::Result<Vec<u8>> {
+        let Self { data, links } = self;
+        // not needed without a cipher, but keeps the offsets of the stream the same
+        state.reserve(data.len())?;
+        Envelope {
+            offset: None,
+            links,
+            data,
+        }
+        .encode()
     }
 
     /// decrypt using the given key
+    ///
+    /// Blocks in the plain envelope, written without the `crypto` feature, are also accepted.
+    #[cfg(feature = "crypto")]
     pub fn decrypt(
         data: &[u8],
         key: &chacha20::Key,
         nonce: &chacha20::XNonce,
     ) -> anyhow::Result<(Self, Range<u64>)> {
-        let (offset, links, mut encrypted) = DagCborCodec.decode::<IpldNode>(data)?.into_data()?;
+        let Envelope {
+            offset,
+            links,
+            data: mut encrypted,
+        } = Envelope::decode(data)?;
+        let offset = match offset {
+            Some(offset) => offset,
+            None => return Ok(Self::from_plain(encrypted, links)),
+        };
         let mut cipher = XChaCha20::new(key, nonce);
         let end_offset = offset
             .checked_add(encrypted.len() as u64)
@@ -441,23 +706,122 @@ impl ZstdDagCborSeq {
         let decrypted = encrypted;
         Ok((Self::new(decrypted, links), offset..end_offset))
     }
+
+    /// decode a block, which must be in the plain envelope without the `crypto` feature
+    #[cfg(not(feature = "crypto"))]
+    pub(crate) fn decrypt(data: &[u8], _: &Key, _: &XNonce) -> anyhow::Result<(Self, Range<u64>)> {
+        Self::decode_plain(data)
+    }
+
+    /// decode a block, which must be in the plain envelope
+    pub(crate) fn decode_plain(data: &[u8]) -> anyhow::Result<(Self, Range<u64>)> {
+        let Envelope {
+            offset,
+            links,
+            data,
+        } = Envelope::decode(data)?;
+        anyhow::ensure!(
+            offset.is_none(),
+            "not a plain block, encrypted blocks need the crypto feature and keys"
+        );
+        Ok(Self::from_plain(data, links))
+    }
+
+    /// Plain blocks have no cipher offsets, so the byte range just covers the data.
+    fn from_plain(data: Vec<u8>, links: Vec<Cid>) -> (Self, Range<u64>) {
+        let len = data.len() as u64;
+        (Self::new(data, links), 0..len)
+    }
+}
+
+/// version of the block envelope that is written, see [Envelope]
+const ENVELOPE_VERSION: u64 = 1;
+
+/// The dag-cbor list around the compressed data of a block
+///
+/// The current envelope is a list of 4 items: the version, the cipher offset or null for plain
+/// blocks, the links and the data. Lists of other lengths, except for the legacy envelope of
+/// length 3, are dispatched on their first item, so later versions can change everything after
+/// the version while old blocks stay readable.
+///
+/// The legacy envelope, written before the version was introduced, is read as well: the cipher
+/// offset, the links and the data.
+struct Envelope {
+    offset: Option<u64>,
+    links: Vec<Cid>,
+    data: Vec<u8>,
+}
+
+impl Envelope {
+    fn encode(self) -> anyhow::Result<Vec<u8>> {
+        let offset = self.offset.map_or(Ipld::Null, |x| Ipld::Integer(x.into()));
+        let links = self.links.into_iter().map(Ipld::Link).collect();
+        let envelope = Ipld::List(vec![
+            Ipld::Integer(ENVELOPE_VERSION.into()),
+            offset,
+            Ipld::List(links),
+            Ipld::Bytes(self.data),
+        ]);
+        DagCborCodec.encode(&envelope)
+    }
+
+    fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let items = match DagCborCodec.decode::<Ipld>(data)? {
+            Ipld::List(items) => items,
+            _ => anyhow::bail!("block envelope must be a list"),
+        };
+        let len = items.len();
+        let mut items = items.into_iter();
+        let mut next = || {
+            items
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("block envelope too short"))
+        };
+        Ok(match len {
+            3 => Self {
+                offset: Some(unsigned(next()?)?),
+                links: links(next()?)?,
+                data: bytes(next()?)?,
+            },
+            _ => match unsigned(next()?)? {
+                1 => Self {
+                    offset: match next()? {
+                        Ipld::Null => None,
+                        offset => Some(unsigned(offset)?),
+                    },
+                    links: links(next()?)?,
+                    data: bytes(next()?)?,
+                },
+                version => anyhow::bail!("unsupported block envelope version {}", version),
+            },
+        })
+    }
 }
 
-/// utility struct for encoding and decoding
-#[derive(DagCbor)]
-struct IpldNode(u64, Vec<Cid>, Ipld);
+fn unsigned(ipld: Ipld) -> anyhow::Result<u64> {
+    match ipld {
+        Ipld::Integer(x) => Ok(u64::try_from(x)?),
+        _ => anyhow::bail!("expected unsigned integer"),
+    }
+}
 
-impl IpldNode {
-    fn new(links: Vec<Cid>, data: impl Into<Vec<u8>>, offset: u64) -> Self {
-        Self(offset, links, Ipld::Bytes(data.into()))
+fn links(ipld: Ipld) -> anyhow::Result<Vec<Cid>> {
+    match ipld {
+        Ipld::List(items) => items
+            .into_iter()
+            .map(|item| match item {
+                Ipld::Link(cid) => Ok(cid),
+                _ => anyhow::bail!("expected ipld link"),
+            })
+            .collect(),
+        _ => anyhow::bail!("expected list of links"),
     }
+}
 
-    fn into_data(self) -> anyhow::Result<(u64, Vec<Cid>, Vec<u8>)> {
-        if let Ipld::Bytes(data) = self.2 {
-            Ok((self.0, self.1, data))
-        } else {
-            Err(anyhow::anyhow!("expected ipld bytes"))
-        }
+fn bytes(ipld: Ipld) -> anyhow::Result<Vec<u8>> {
+    match ipld {
+        Ipld::Bytes(data) => Ok(data),
+        _ => anyhow::bail!("expected ipld bytes"),
     }
 }
 
@@ -477,6 +841,243 @@ impl fmt::Debug for ZstdDagCborSeq {
     }
 }
 
+/// Initial number of bytes to decompress at a time when streaming items
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+#[cfg(not(target_arch = "wasm32"))]
+type FrameDecoder<'a> = zstd::stream::read::Decoder<'static, &'a [u8]>;
+
+#[cfg(target_arch = "wasm32")]
+type FrameDecoder<'a> = ruzstd::StreamingDecoder<&'a [u8], ruzstd::FrameDecoder>;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn frame_decoder(compressed: &[u8]) -> std::io::Result<FrameDecoder<'_>> {
+    zstd::stream::read::Decoder::with_buffer(compressed)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn frame_decoder(compressed: &[u8]) -> std::io::Result<FrameDecoder<'_>> {
+    ruzstd::StreamingDecoder::new(compressed)
+        .map_err(|cause| std::io::Error::new(ErrorKind::InvalidData, cause.to_string()))
+}
+
+/// Iterator over the items of a [ZstdDagCborSeq], see [ZstdDagCborSeq::iter]
+pub struct ItemIter<'a, T> {
+    /// the decompression stream, None once it is exhausted
+    decoder: Option<FrameDecoder<'a>>,
+    /// decompressed bytes that have not been fully consumed yet
+    buffer: Vec<u8>,
+    /// start of the unconsumed part of the buffer
+    pos: usize,
+    /// whether the first byte was checked for the packed format
+    checked_format: bool,
+    _t: PhantomData<T>,
+}
+
+impl<'a, T> ItemIter<'a, T> {
+    fn new(compressed: &'a [u8]) -> anyhow::Result<Self> {
+        Ok(Self {
+            decoder: Some(frame_decoder(compressed)?),
+            buffer: Vec::new(),
+            pos: 0,
+            checked_format: false,
+            _t: PhantomData,
+        })
+    }
+
+    /// skips the next item without decoding it
+    pub fn skip_item(&mut self) -> Option<anyhow::Result<()>> {
+        self.next_with(|_| Ok(()))
+    }
+
+    /// applies `f` to the next complete cbor item, decompressing more data as needed
+    fn next_with<R>(
+        &mut self,
+        f: impl FnOnce(&Cbor) -> anyhow::Result<R>,
+    ) -> Option<anyhow::Result<R>> {
+        loop {
+            if !self.checked_format && self.pos < self.buffer.len() {
+                self.checked_format = true;
+                if self.buffer[self.pos] == PACKED {
+                    if let Err(cause) = self.unpack() {
+                        self.buffer.clear();
+                        self.pos = 0;
+                        self.decoder = None;
+                        return Some(Err(cause));
+                    }
+                }
+            }
+            let pending = &self.buffer[self.pos..];
+            if !pending.is_empty() {
+                // we cannot use ipld since the contained data may be arbitrary CBOR, not just IPLD-CBOR
+                match Cbor::checked_prefix(pending) {
+                    Ok((cbor, rest)) => {
+                        let consumed = pending.len() - rest.len();
+                        let res = f(cbor);
+                        self.pos += consumed;
+                        return Some(res);
+                    }
+                    // no more data coming, so the item is invalid
+                    Err(cause) if self.decoder.is_none() => {
+                        self.pos = self.buffer.len();
+                        return Some(Err(cause.into()));
+                    }
+                    // the item is incomplete, decompress more
+                    Err(_) => {}
+                }
+            }
+            let decoder = self.decoder.as_mut()?;
+            self.buffer.drain(..self.pos);
+            self.pos = 0;
+            // grow the chunk size with the buffer, so parsing large items is not quadratic
+            let len = self.buffer.len();
+            self.buffer.resize(len + STREAM_CHUNK_SIZE.max(len), 0);
+            match decoder.read(&mut self.buffer[len..]) {
+                Ok(0) => {
+                    self.buffer.truncate(len);
+                    self.decoder = None;
+                }
+                Ok(n) => {
+                    record_decompressed(n);
+                    self.buffer.truncate(len + n)
+                }
+                Err(cause) => {
+                    self.buffer.clear();
+                    self.decoder = None;
+                    return Some(Err(cause.into()));
+                }
+            }
+        }
+    }
+}
+
+impl<'a, T> ItemIter<'a, T> {
+    /// decompresses the rest of a packed sequence and converts it to a CBOR sequence
+    fn unpack(&mut self) -> anyhow::Result<()> {
+        let mut data = self.buffer.split_off(self.pos);
+        if let Some(mut decoder) = self.decoder.take() {
+            let len = data.len();
+            decoder.read_to_end(&mut data)?;
+            record_decompressed(data.len() - len);
+        }
+        self.buffer = with_cbor_seq(&data, |cbor| Ok(cbor.to_vec()))?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<'a, T: ReadCbor> Iterator for ItemIter<'a, T> {
+    type Item = anyhow::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with(|cbor| Ok(T::read_cbor(cbor)?))
+    }
+}
+
+/// First byte of a sequence of packed values, see [Config::pack_values]
+///
+/// It is followed by the size of each value as a little endian u32 and the concatenated values.
+/// 0xfc is a reserved initial byte in CBOR, so a CBOR sequence can not start with it.
+///
+/// [Config::pack_values]: ../struct.Config.html#structfield.pack_values
+const PACKED: u8 = 0xfc;
+
+/// size of the header of a packed sequence
+const PACKED_HEADER_LEN: usize = 5;
+
+/// the header of a packed sequence of values with `len` bytes each
+fn packed_header(len: usize) -> anyhow::Result<[u8; PACKED_HEADER_LEN]> {
+    anyhow::ensure!(len > 0, "empty values can not be packed");
+    let len = u32::try_from(len)?.to_le_bytes();
+    Ok([PACKED, len[0], len[1], len[2], len[3]])
+}
+
+/// the size of the values if `data` is a packed sequence, or None if it is a CBOR sequence
+fn packed_size(data: &[u8]) -> anyhow::Result<Option<usize>> {
+    if data.first() != Some(&PACKED) {
+        return Ok(None);
+    }
+    anyhow::ensure!(data.len() >= PACKED_HEADER_LEN, "truncated packed header");
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&data[1..PACKED_HEADER_LEN]);
+    let len = u32::from_le_bytes(len) as usize;
+    anyhow::ensure!(len > 0, "invalid packed value size 0");
+    anyhow::ensure!(
+        (data.len() - PACKED_HEADER_LEN).is_multiple_of(len),
+        "truncated packed value"
+    );
+    Ok(Some(len))
+}
+
+/// the content of `item` if it is an untagged byte string of definite length
+fn byte_string_payload(item: &[u8]) -> Option<&[u8]> {
+    let (&initial, rest) = item.split_first()?;
+    if initial >> 5 != 2 {
+        return None;
+    }
+    let (len, width) = match initial & 0x1f {
+        info @ 0..=23 => (u64::from(info), 0),
+        24 => (u64::from(*rest.first()?), 1),
+        25 => {
+            let mut len = [0u8; 2];
+            len.copy_from_slice(rest.get(..2)?);
+            (u64::from(u16::from_be_bytes(len)), 2)
+        }
+        26 => {
+            let mut len = [0u8; 4];
+            len.copy_from_slice(rest.get(..4)?);
+            (u64::from(u32::from_be_bytes(len)), 4)
+        }
+        27 => {
+            let mut len = [0u8; 8];
+            len.copy_from_slice(rest.get(..8)?);
+            (u64::from_be_bytes(len), 8)
+        }
+        _ => return None,
+    };
+    let payload = &rest[width..];
+    if payload.len() as u64 == len {
+        Some(payload)
+    } else {
+        None
+    }
+}
+
+/// appends the shortest CBOR header of a byte string with `len` bytes
+fn write_byte_string_header(len: usize, out: &mut Vec<u8>) {
+    let len = len as u64;
+    if len < 24 {
+        out.push(0x40 | len as u8);
+    } else if len <= u64::from(u8::MAX) {
+        out.extend_from_slice(&[0x58, len as u8]);
+    } else if len <= u64::from(u16::MAX) {
+        out.push(0x59);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else if len <= u64::from(u32::MAX) {
+        out.push(0x5a);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x5b);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+/// calls `f` with `data` as a CBOR sequence, converting packed values to byte strings first
+fn with_cbor_seq<R>(data: &[u8], f: impl FnOnce(&[u8]) -> anyhow::Result<R>) -> anyhow::Result<R> {
+    match packed_size(data)? {
+        Some(len) => {
+            let values = &data[PACKED_HEADER_LEN..];
+            let mut cbor = Vec::with_capacity(values.len() + values.len() / len * 9);
+            for value in values.chunks(len) {
+                write_byte_string_header(len, &mut cbor);
+                cbor.extend_from_slice(value);
+            }
+            f(&cbor)
+        }
+        None => f(data),
+    }
+}
+
 /// count the number of items in a dag cbor seq
 fn count_cbor_items(data: &[u8]) -> anyhow::Result<u64> {
     let mut count = 0;
@@ -565,6 +1166,47 @@ mod tests {
         Ok(())
     }
 
+    #[quickcheck]
+    fn zstd_array_no_compression_roundtrip(data: Vec<Vec<u8>>) -> anyhow::Result<bool> {
+        let za = ZstdDagCborSeq::from_iter(&data, NO_COMPRESSION)?;
+        Ok(za.items::<Vec<u8>>()? == data)
+    }
+
+    #[test]
+    fn zstd_array_no_compression_large() -> anyhow::Result<()> {
+        // spans multiple raw blocks
+        let data = vec![vec![7u8; 100000]; 5];
+        let za = ZstdDagCborSeq::from_iter(&data, NO_COMPRESSION)?;
+        assert!(za.compressed().len() > 500000);
+        assert_eq!(za.items::<Vec<u8>>()?, data);
+        Ok(())
+    }
+
+    #[quickcheck]
+    fn zstd_array_get_roundtrip(data: Vec<Vec<u8>>) -> anyhow::Result<bool> {
+        let za = ZstdDagCborSeq::from_iter(&data, 10)?;
+        for (i, item) in data.iter().enumerate() {
+            if za.get::<Vec<u8>>(i as u64)?.as_ref() != Some(item) {
+                return Ok(false);
+            }
+        }
+        Ok(za.get::<Vec<u8>>(data.len() as u64)?.is_none())
+    }
+
+    #[test]
+    fn zstd_array_streaming_large_items() -> anyhow::Result<()> {
+        // items larger than the stream chunk size, and many small items in between
+        let mut data = vec![vec![1u8; 3 * STREAM_CHUNK_SIZE + 17]];
+        data.extend((0..10000).map(|i| vec![i as u8; i % 7]));
+        data.push(vec![2u8; STREAM_CHUNK_SIZE]);
+        for level in [NO_COMPRESSION, 10] {
+            let za = ZstdDagCborSeq::from_iter(&data, level)?;
+            assert_eq!(za.items::<Vec<u8>>()?, data);
+            assert_eq!(za.get::<Vec<u8>>(10001)?, data.last().cloned());
+        }
+        Ok(())
+    }
+
     #[test]
     fn zstd_array_fill_keys() -> anyhow::Result<()> {
         let mut items = vec![
@@ -672,6 +1314,7 @@ mod tests {
     }
 
     /// basic test to ensure that the decompress works and properly clears the thread local buffer
+    #[cfg(feature = "crypto")]
     fn do_zstd_array_fill_roundtrip(
         first: Vec<u8>,
         data: Vec<Vec<u8>>,
@@ -724,6 +1367,7 @@ mod tests {
         }
         Ok(true)
     }
+    #[cfg(feature = "crypto")]
     #[quickcheck]
     fn zstd_array_fill_roundtrip(
         first: Vec<u8>,
@@ -733,11 +1377,13 @@ mod tests {
         do_zstd_array_fill_roundtrip(first, data, seed)
     }
 
+    #[cfg(feature = "crypto")]
     #[test]
     fn zstd_array_fill_roundtrip_1() {
         assert!(do_zstd_array_fill_roundtrip(vec![], vec![], 0).unwrap());
     }
 
+    #[cfg(feature = "crypto")]
     #[test]
     fn test_disk_format() -> anyhow::Result<()> {
         let data = vec![1u64, 2, 3, 4];
@@ -751,17 +1397,18 @@ mod tests {
         // do not exactly check the compressed and encrypted part, since the exact
         // bytes depend on zstd details and might be fragile.
         assert_eq!(
-            bytes[0..3],
+            bytes[0..4],
             vec![
-                0x83, // list 0x80 of length 3
+                0x84, // list 0x80 of length 4
+                0x01, // version, unsigned(1)
                 0x07, // offset, unsigned(7)
                 0x80, // array of links, size 0 (no links)
             ]
         );
         let items: Vec<Ipld> = DagCborCodec.decode(&bytes)?;
-        assert_eq!(items.len(), 3);
-        assert_eq!(items[1], Ipld::List(vec![]));
-        if let (Ipld::Integer(offset1), Ipld::Bytes(encrypted)) = (&items[0], &items[2]) {
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[2], Ipld::List(vec![]));
+        if let (Ipld::Integer(offset1), Ipld::Bytes(encrypted)) = (&items[1], &items[3]) {
             let offset1 = u64::try_from(*offset1)?;
             assert_eq!(offset1, offset);
             // once decrypted, must be valid zstd
@@ -778,4 +1425,69 @@ mod tests {
         }
         Ok(())
     }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn test_legacy_disk_format() -> anyhow::Result<()> {
+        let data = vec![1u64, 2, 3, 4];
+        let key: chacha20::Key = [0u8; 32].into();
+        let nonce: chacha20::XNonce = [0u8; 24].into();
+        let offset = 7u64;
+        let res = ZstdDagCborSeq::single(&data, 10)?;
+        let mut encrypted = res.compressed().to_vec();
+        let mut chacha = XChaCha20::new(&key, &nonce);
+        chacha.seek(offset);
+        chacha.apply_keystream(&mut encrypted);
+        // offset, links and data, without a version
+        let bytes = DagCborCodec.encode(&Ipld::List(vec![
+            Ipld::Integer(offset.into()),
+            Ipld::List(vec![]),
+            Ipld::Bytes(encrypted),
+        ]))?;
+        assert_eq!(bytes[0], 0x83);
+        let (res1, byte_range) = ZstdDagCborSeq::decrypt(&bytes, &key, &nonce)?;
+        assert_eq!(res1, res);
+        assert_eq!(byte_range, offset..offset + res.compressed().len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plain_disk_format() -> anyhow::Result<()> {
+        let data = vec![1u64, 2, 3, 4];
+        let res = ZstdDagCborSeq::single(&data, 10)?;
+        let bytes = Envelope {
+            offset: None,
+            links: vec![],
+            data: res.compressed().to_vec(),
+        }
+        .encode()?;
+        assert_eq!(
+            bytes[0..4],
+            vec![
+                0x84, // list 0x80 of length 4
+                0x01, // version, unsigned(1)
+                0xf6, // no offset, null
+                0x80, // array of links, size 0 (no links)
+            ]
+        );
+        // plain blocks can be read with and without the crypto feature
+        let (res1, byte_range) =
+            ZstdDagCborSeq::decrypt(&bytes, &Key::default(), &XNonce::default())?;
+        assert_eq!(res1, res);
+        assert_eq!(byte_range, 0..res.compressed().len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_envelope_version() -> anyhow::Result<()> {
+        let bytes = DagCborCodec.encode(&Ipld::List(vec![
+            Ipld::Integer(2),
+            Ipld::Null,
+            Ipld::List(vec![]),
+            Ipld::Bytes(vec![]),
+        ]))?;
+        let err = ZstdDagCborSeq::decrypt(&bytes, &Key::default(), &XNonce::default());
+        assert!(err.unwrap_err().to_string().contains("version 2"));
+        Ok(())
+    }
 }
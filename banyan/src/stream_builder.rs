@@ -1,13 +1,23 @@
+use anyhow::{anyhow, ensure};
+use cbor_data::codec::ReadCbor;
 use core::fmt;
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Codec, Decode, Encode},
+    DagCbor,
+};
 use std::{
+    convert::TryInto,
     marker::PhantomData,
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, Range},
+    sync::Arc,
 };
 
 use crate::{
     forest::{Config, Secrets, TreeTypes},
-    index::Index,
+    index::{Index, TypedValueSummarizer, ValueSummarizable, ValueSummarizer},
     tree::Tree,
+    util::tuple_version,
 };
 
 /// A thing that hands out unique offsets. Parts of StreamBuilderState
@@ -17,25 +27,46 @@ use crate::{
 #[derive(Debug, Clone)]
 pub(crate) struct CipherOffset {
     value: u64,
+    /// offsets from here on must not be handed out
+    end: u64,
 }
 
 impl CipherOffset {
     pub fn new(value: u64) -> Self {
-        Self { value }
+        Self {
+            value,
+            end: u64::MAX,
+        }
+    }
+
+    /// an offset source that will only hand out offsets in the given range
+    pub fn limited(range: Range<u64>) -> Self {
+        Self {
+            value: range.start,
+            end: range.end,
+        }
     }
 
-    pub fn reserve(&mut self, n: usize) -> u64 {
+    pub fn reserve(&mut self, n: usize) -> anyhow::Result<u64> {
         let result = self.value;
         self.value = self
             .value
             .checked_add(n as u64)
-            .expect("ran out of offsets");
-        result
+            .filter(|end| *end <= self.end)
+            .ok_or_else(|| anyhow::anyhow!("ran out of offsets"))?;
+        Ok(result)
     }
 
     pub fn current(&self) -> u64 {
         self.value
     }
+
+    /// skips the offsets before `value`, e.g. because they have been used elsewhere
+    pub fn advance_to(&mut self, value: u64) -> anyhow::Result<()> {
+        anyhow::ensure!(value <= self.end, "ran out of offsets");
+        self.value = self.value.max(value);
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -49,6 +80,11 @@ pub(crate) struct StreamBuilderState {
     /// this is the first free offset, or the total number of bytes ever written
     /// on this stream.
     pub(crate) offset: CipherOffset,
+    /// moving average of compressed / uncompressed size of the sealed leaves, see
+    /// [Config::adaptive_leaf_size]
+    compression_ratio: Option<f64>,
+    /// computes the value summaries of new leaves, see [StreamBuilder::with_value_summarizer]
+    value_summarizer: Option<Arc<dyn ValueSummarizer>>,
 }
 
 impl StreamBuilderState {
@@ -57,7 +93,40 @@ impl StreamBuilderState {
             offset: CipherOffset::new(offset),
             secrets,
             config,
+            compression_ratio: None,
+            value_summarizer: None,
+        }
+    }
+
+    /// a state for new nodes of the same stream, handing out cipher offsets from `offset`
+    pub(crate) fn fork(&self, offset: u64) -> Self {
+        Self {
+            value_summarizer: self.value_summarizer.clone(),
+            ..Self::new(offset, self.secrets.clone(), self.config.clone())
+        }
+    }
+
+    /// uncompressed size at which a leaf is considered full, if adaptive leaf sizing is enabled
+    /// and a leaf has been sealed before
+    pub(crate) fn leaf_fill_target(&self) -> Option<usize> {
+        if !self.config.adaptive_leaf_size {
+            return None;
         }
+        let ratio = self.compression_ratio?;
+        let target = self.config.target_leaf_size as f64 / ratio.max(f64::EPSILON);
+        Some((target as usize).min(self.config.max_uncompressed_leaf_size))
+    }
+
+    /// records the sizes of a sealed leaf
+    pub(crate) fn record_leaf(&mut self, compressed: usize, uncompressed: usize) {
+        if uncompressed == 0 {
+            return;
+        }
+        let ratio = compressed as f64 / uncompressed as f64;
+        self.compression_ratio = Some(match self.compression_ratio {
+            Some(previous) => previous * 0.75 + ratio * 0.25,
+            None => ratio,
+        });
     }
 
     pub fn config(&self) -> &Config {
@@ -68,12 +137,8 @@ impl StreamBuilderState {
         &self.secrets
     }
 
-    pub fn value_key(&self) -> &chacha20::Key {
-        self.secrets.value_key()
-    }
-
-    pub fn index_key(&self) -> &chacha20::Key {
-        self.secrets.index_key()
+    pub fn value_summarizer(&self) -> Option<&Arc<dyn ValueSummarizer>> {
+        self.value_summarizer.as_ref()
     }
 }
 
@@ -83,9 +148,115 @@ impl StreamBuilderState {
 pub struct StreamBuilder<T: TreeTypes, V> {
     root: Option<Index<T>>,
     state: StreamBuilderState,
+    /// number of operations that did more than just append to the tree
+    rewrites: u64,
+    /// root as of the last load from or publish to a [RootStore](store/trait.RootStore.html)
+    published: Option<T::Link>,
     _p: PhantomData<V>,
 }
 
+/// A snapshot of a stream builder that can be packed while the builder is being extended
+///
+/// Created with [StreamBuilder::begin_pack]. See [Transaction::pack_snapshot] for details.
+///
+/// [StreamBuilder::begin_pack]: struct.StreamBuilder.html#method.begin_pack
+/// [Transaction::pack_snapshot]: struct.Transaction.html#method.pack_snapshot
+pub struct PackJob<T: TreeTypes, V> {
+    pub(crate) tree: Tree<T, V>,
+    /// state with a reserved range of cipher offsets for the pack
+    pub(crate) state: StreamBuilderState,
+    pub(crate) rewrites: u64,
+}
+
+/// The packed sealed prefix of a snapshot, to be applied with [Transaction::apply_pack]
+///
+/// [Transaction::apply_pack]: struct.Transaction.html#method.apply_pack
+pub struct PackedSnapshot<T: TreeTypes, V> {
+    pub(crate) index: Option<Index<T>>,
+    pub(crate) rewrites: u64,
+    pub(crate) count: u64,
+    pub(crate) _p: PhantomData<V>,
+}
+
+/// Current version of the serialization format of [StreamBuilder::to_bytes]
+const CHECKPOINT_VERSION: u64 = 1;
+
+/// Number of cipher offsets a builder resumed with [StreamBuilder::from_bytes] skips
+///
+/// The builder that wrote a checkpoint may have written more blocks before it stopped, using the
+/// offsets after the checkpointed one. Skipping ahead makes sure the resumed builder does not
+/// encrypt with any of them again, as long as less than this many bytes were written after the
+/// checkpoint. So checkpoints have to be taken at least that often. Each resume uses up this much
+/// of the about 256 GiB of offsets a stream has.
+pub const CHECKPOINT_HEADROOM: u64 = 1 << 30;
+
+/// `[version, root, offset, compression ratio, rewrites, published, config]`
+#[derive(DagCbor)]
+struct CheckpointIpld<T: TreeTypes>(
+    u64,
+    Option<Index<T>>,
+    u64,
+    Option<f64>,
+    u64,
+    Option<T::Link>,
+    ConfigIpld,
+);
+
+/// the fields of a [Config], in declaration order
+#[derive(DagCbor)]
+struct ConfigIpld(
+    u64,
+    u64,
+    u64,
+    u64,
+    u64,
+    i64,
+    bool,
+    Option<u64>,
+    bool,
+    bool,
+    bool,
+    u64,
+);
+
+impl From<&Config> for ConfigIpld {
+    fn from(config: &Config) -> Self {
+        Self(
+            config.max_summary_branches as u64,
+            config.max_key_branches as u64,
+            config.max_leaf_count as u64,
+            config.target_leaf_size as u64,
+            config.max_uncompressed_leaf_size as u64,
+            config.zstd_level.into(),
+            config.skip_redundant_compression,
+            config.summary_truncation_level.map(u64::from),
+            config.adaptive_leaf_size,
+            config.ordered_keys,
+            config.pack_values,
+            config.dedup_window as u64,
+        )
+    }
+}
+
+impl ConfigIpld {
+    fn into_config(self) -> anyhow::Result<Config> {
+        Ok(Config {
+            max_summary_branches: self.0.try_into()?,
+            max_key_branches: self.1.try_into()?,
+            max_leaf_count: self.2.try_into()?,
+            target_leaf_size: self.3.try_into()?,
+            max_uncompressed_leaf_size: self.4.try_into()?,
+            zstd_level: self.5.try_into()?,
+            skip_redundant_compression: self.6,
+            summary_truncation_level: self.7.map(|x| x.try_into()).transpose()?,
+            adaptive_leaf_size: self.8,
+            ordered_keys: self.9,
+            pack_values: self.10,
+            dedup_window: self.11.try_into()?,
+        })
+    }
+}
+
 impl<T: TreeTypes, V> fmt::Debug for StreamBuilder<T, V> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.root {
@@ -132,6 +303,27 @@ impl<T: TreeTypes, V> StreamBuilder<T, V> {
         Self::new_from_index(None, state)
     }
 
+    /// Computes a summary of the values of new leaves, see [LeafIndex::value_summary]
+    ///
+    /// The summarizer is not part of the stream, so it has to be set again when the builder is
+    /// loaded or restored. Leaves that are extended without it lose their summary.
+    ///
+    /// [LeafIndex::value_summary]: index/struct.LeafIndex.html#structfield.value_summary
+    pub fn with_value_summarizer(mut self, summarizer: Arc<dyn ValueSummarizer>) -> Self {
+        self.state.value_summarizer = Some(summarizer);
+        self
+    }
+
+    /// Computes a summary of type `S` of the values of new leaves, see
+    /// [with_value_summarizer](#method.with_value_summarizer)
+    pub fn with_value_summary<S>(self) -> Self
+    where
+        S: ValueSummarizable<V> + Default + Encode<DagCborCodec> + Decode<DagCborCodec> + 'static,
+        V: ReadCbor + 'static,
+    {
+        self.with_value_summarizer(Arc::new(TypedValueSummarizer::<S, V>::new()))
+    }
+
     pub fn snapshot(&self) -> Tree<T, V> {
         self.root
             .as_ref()
@@ -177,6 +369,55 @@ impl<T: TreeTypes, V> StreamBuilder<T, V> {
         self.root.as_ref()
     }
 
+    /// Serializes the builder, so appending can be resumed with [from_bytes](#method.from_bytes),
+    /// e.g. after a restart.
+    ///
+    /// This includes the root index with its unsealed nodes, the config and the next cipher
+    /// offset, so the resumed builder continues exactly where this one stopped, without having to
+    /// pack or reload the tree. The blocks of the tree are not included and have to be in the
+    /// store. The secrets are not included either, they have to be passed when resuming.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let ipld = CheckpointIpld::<T>(
+            CHECKPOINT_VERSION,
+            self.root.clone(),
+            self.state.offset.current(),
+            self.state.compression_ratio,
+            self.rewrites,
+            self.published,
+            self.state.config().into(),
+        );
+        DagCborCodec.encode(&ipld)
+    }
+
+    /// Resumes a builder serialized with [to_bytes](#method.to_bytes), failing for unknown
+    /// versions.
+    ///
+    /// The secrets have to be the ones the builder was created with. The resumed builder skips
+    /// [CHECKPOINT_HEADROOM] cipher offsets, since the original builder may have used some of
+    /// them after the checkpoint was taken.
+    pub fn from_bytes(data: &[u8], secrets: Secrets) -> anyhow::Result<Self> {
+        let version = tuple_version(data)
+            .map_err(|cause| anyhow!("invalid stream builder checkpoint: {}", cause))?;
+        ensure!(
+            version == CHECKPOINT_VERSION,
+            "unsupported stream builder checkpoint version {}",
+            version
+        );
+        let CheckpointIpld::<T>(_, root, offset, compression_ratio, rewrites, published, config) =
+            DagCborCodec
+                .decode(data)
+                .map_err(|cause| anyhow!("invalid stream builder checkpoint: {}", cause))?;
+        let offset = offset
+            .checked_add(CHECKPOINT_HEADROOM)
+            .ok_or_else(|| anyhow!("ran out of offsets"))?;
+        let mut state = StreamBuilderState::new(offset, secrets, config.into_config()?);
+        state.compression_ratio = compression_ratio;
+        let mut builder = Self::new_from_index(root, state);
+        builder.rewrites = rewrites;
+        builder.published = published;
+        Ok(builder)
+    }
+
     /// Modify a StreamBuilder and roll back the changes if the operation was not successful
     ///
     /// Note that consumed offets are *not* rolled back to make sure we don't reuse offsets.
@@ -192,10 +433,54 @@ impl<T: TreeTypes, V> StreamBuilder<T, V> {
         &mut self.state
     }
 
+    /// Take a snapshot of the builder, for packing it while the builder continues to be extended.
+    ///
+    /// This reserves a range of cipher offsets for the pack, so the pack and concurrent appends
+    /// never use the same offsets.
+    pub fn begin_pack(&mut self) -> PackJob<T, V> {
+        let tree = self.snapshot();
+        // packing the sealed prefix only writes branches containing indexes that are already
+        // part of the tree, so this is a generous upper bound.
+        let budget = self
+            .root
+            .as_ref()
+            .map(|root| root.key_bytes())
+            .unwrap_or_default()
+            .saturating_mul(2)
+            .saturating_add(1 << 20);
+        let start = self
+            .state
+            .offset
+            .reserve(budget as usize)
+            .unwrap_or_else(|_| {
+                // out of offsets. The pack will fail, but we must not fail here.
+                self.state.offset.current()
+            });
+        let end = self.state.offset.current();
+        let mut state = self.state.fork(0);
+        state.offset = CipherOffset::limited(start..end);
+        PackJob {
+            tree,
+            state,
+            rewrites: self.rewrites,
+        }
+    }
+
+    pub(crate) fn rewrites(&self) -> u64 {
+        self.rewrites
+    }
+
+    /// mark the builder as modified in a way other than appending
+    pub(crate) fn mark_rewritten(&mut self) {
+        self.rewrites += 1;
+    }
+
     pub(crate) fn new_from_index(root: Option<Index<T>>, state: StreamBuilderState) -> Self {
         Self {
             root,
             state,
+            rewrites: 0,
+            published: None,
             _p: PhantomData,
         }
     }
@@ -203,6 +488,17 @@ impl<T: TreeTypes, V> StreamBuilder<T, V> {
     pub(crate) fn set_index(&mut self, index: Option<Index<T>>) {
         self.root = index
     }
+
+    /// the root that was last loaded from or published to a root store
+    ///
+    /// Publishing fails if the root in the store is no longer this one.
+    pub fn published(&self) -> Option<&T::Link> {
+        self.published.as_ref()
+    }
+
+    pub(crate) fn set_published(&mut self, link: Option<T::Link>) {
+        self.published = link
+    }
 }
 
 pub struct StreamTransaction<'a, T: TreeTypes, V> {
@@ -0,0 +1,309 @@
+//! helpers for testing code that works with banyan trees
+//!
+//! Many bugs only show up for unusual tree shapes, which are tedious to construct by hand.
+//! [TreeGen] builds trees of a given [TreeShape] deterministically from a seed, so failures found
+//! by fuzzing or property tests can be reproduced.
+//!
+//! [SimpleTT] is a minimal [TreeTypes] implementation with `u64` keys, for examples, benchmarks
+//! and tests. It is also a good starting point for your own tree types.
+//!
+//! The [model] module checks trees against a reference model for sequences of operations. With
+//! the `arbitrary` feature, [TreeGen], [TreeShape] and [model::Op] implement quickcheck's
+//! `Arbitrary`, so property tests of your own tree types can generate them.
+#[cfg(feature = "arbitrary")]
+mod arbitrary;
+pub mod model;
+
+use crate::{
+    index::{Summarizable, VecSeq},
+    query::{OrderedSummary, SamplingQuery},
+    store::{BanyanValue, BlockWriter, ReadOnlyStore},
+    Config, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use anyhow::{ensure, Result};
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Decode, Encode},
+    multihash::Multihash,
+    Cid, DagCbor,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    cmp,
+    convert::{TryFrom, TryInto},
+    fmt,
+    io::{Read, Seek, Write},
+    ops::Range,
+};
+
+/// Tree types with `u64` keys, summarized by their minimum and maximum, and sha2-256 links
+///
+/// Range queries work out of the box with [KeyRangeQuery](../query/struct.KeyRangeQuery.html).
+#[derive(Debug, Clone)]
+pub struct SimpleTT;
+
+impl TreeTypes for SimpleTT {
+    type Key = u64;
+    type KeySeq = VecSeq<u64>;
+    type Summary = MinMax;
+    type SummarySeq = VecSeq<MinMax>;
+    type Link = Sha256Digest;
+
+    fn compare_keys(a: &u64, b: &u64) -> Option<cmp::Ordering> {
+        Some(a.cmp(b))
+    }
+}
+
+/// Smallest and largest key of a subtree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DagCbor)]
+pub struct MinMax {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl MinMax {
+    fn combine(self, other: MinMax) -> MinMax {
+        MinMax {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+}
+
+impl OrderedSummary<u64> for MinMax {
+    fn min_key(&self) -> u64 {
+        self.min
+    }
+
+    fn max_key(&self) -> u64 {
+        self.max
+    }
+}
+
+impl Summarizable<MinMax> for VecSeq<u64> {
+    fn summarize(&self) -> MinMax {
+        self.as_ref()
+            .iter()
+            .map(|k| MinMax { min: *k, max: *k })
+            .reduce(MinMax::combine)
+            .expect("summarize of empty sequence")
+    }
+}
+
+impl Summarizable<MinMax> for VecSeq<MinMax> {
+    fn summarize(&self) -> MinMax {
+        self.as_ref()
+            .iter()
+            .copied()
+            .reduce(MinMax::combine)
+            .expect("summarize of empty sequence")
+    }
+}
+
+/// A sha2-256 digest, encoded as a dag-cbor [Cid]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Sha256Digest([u8; 32]);
+
+impl Sha256Digest {
+    pub fn digest(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+}
+
+impl From<Sha256Digest> for Cid {
+    fn from(value: Sha256Digest) -> Self {
+        // 0x12 is sha2-256 and 0x71 is dag-cbor, see
+        // https://github.com/multiformats/multicodec/blob/master/table.csv
+        let mh = Multihash::wrap(0x12, &value.0).unwrap();
+        Cid::new_v1(0x71, mh)
+    }
+}
+
+impl TryFrom<Cid> for Sha256Digest {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Cid) -> Result<Self> {
+        ensure!(value.codec() == 0x71, "Unexpected codec");
+        ensure!(value.hash().code() == 0x12, "Unexpected hash algorithm");
+        Ok(Self(value.hash().digest().try_into()?))
+    }
+}
+
+impl Encode<DagCborCodec> for Sha256Digest {
+    fn encode<W: Write>(&self, c: DagCborCodec, w: &mut W) -> Result<()> {
+        Cid::from(*self).encode(c, w)
+    }
+}
+
+impl Decode<DagCborCodec> for Sha256Digest {
+    fn decode<R: Read + Seek>(c: DagCborCodec, r: &mut R) -> Result<Self> {
+        Self::try_from(Cid::decode(c, r)?)
+    }
+}
+
+impl fmt::Display for Sha256Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Cid::from(*self))
+    }
+}
+
+impl fmt::Debug for Sha256Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Cid::from(*self))
+    }
+}
+
+/// The kind of tree to generate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TreeShape {
+    /// single element leaves and binary branches, so the tree is as deep as possible
+    Deep,
+    /// many children per branch and few elements per leaf
+    Wide,
+    /// built in many small unpacked batches, so there are lots of unsealed nodes
+    Fragmented,
+    /// most leaves are purged, leaving holes all over the tree
+    Purged,
+    /// very large leaves with many elements each
+    HugeLeaves,
+}
+
+impl TreeShape {
+    /// all shapes, for exhaustive tests
+    pub const ALL: [TreeShape; 5] = [
+        TreeShape::Deep,
+        TreeShape::Wide,
+        TreeShape::Fragmented,
+        TreeShape::Purged,
+        TreeShape::HugeLeaves,
+    ];
+}
+
+/// Deterministic generator for trees of a given shape
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeGen {
+    /// the shape of the generated tree
+    pub shape: TreeShape,
+    /// seed for all random decisions, such as batch sizes
+    pub seed: u64,
+    /// number of elements to add
+    pub count: u64,
+}
+
+impl TreeGen {
+    pub fn new(shape: TreeShape, seed: u64, count: u64) -> Self {
+        Self { shape, seed, count }
+    }
+
+    /// The config used to build the tree
+    pub fn config(&self) -> Config {
+        let debug = Config::debug();
+        match self.shape {
+            TreeShape::Deep => Config {
+                max_leaf_count: 1,
+                max_key_branches: 2,
+                max_summary_branches: 2,
+                ..debug
+            },
+            TreeShape::Wide => Config {
+                max_leaf_count: 4,
+                max_key_branches: 64,
+                max_summary_branches: 64,
+                ..debug
+            },
+            TreeShape::Fragmented | TreeShape::Purged => debug,
+            TreeShape::HugeLeaves => Config {
+                max_leaf_count: 1 << 16,
+                target_leaf_size: 1 << 20,
+                ..debug
+            },
+        }
+    }
+
+    /// Builds a tree, using `elem` to create the key and value for each offset
+    ///
+    /// For [TreeShape::Purged], all leaves that contain an offset that is a multiple of 50 are
+    /// kept. Most other leaves are purged.
+    pub fn build<T, R, W, V, F>(
+        &self,
+        txn: &mut Transaction<T, R, W>,
+        secrets: Secrets,
+        mut elem: F,
+    ) -> Result<StreamBuilder<T, V>>
+    where
+        T: TreeTypes,
+        R: ReadOnlyStore<T::Link>,
+        W: BlockWriter<T::Link>,
+        V: BanyanValue,
+        F: FnMut(u64) -> (T::Key, V),
+    {
+        let mut builder = StreamBuilder::new(self.config(), secrets);
+        let mut rng = SplitMix64(self.seed);
+        match self.shape {
+            TreeShape::Fragmented => {
+                let mut offset = 0;
+                while offset < self.count {
+                    let n = (rng.next_u64() % 8 + 1).min(self.count - offset);
+                    txn.extend_unpacked(&mut builder, batch(&mut elem, offset..offset + n))?;
+                    offset += n;
+                }
+            }
+            TreeShape::Purged => {
+                // vary the batches, so the leaves end up at different offsets
+                let mut offset = 0;
+                while offset < self.count {
+                    let n = (rng.next_u64() % 32 + 1).min(self.count - offset);
+                    txn.extend(&mut builder, batch(&mut elem, offset..offset + n))?;
+                    offset += n;
+                }
+                // retain only drops sealed nodes
+                txn.pack(&mut builder)?;
+                txn.retain(&mut builder, &SamplingQuery::new(50, 0))?;
+            }
+            _ => txn.extend(&mut builder, batch(&mut elem, 0..self.count))?,
+        }
+        Ok(builder)
+    }
+}
+
+fn batch<K, V>(elem: &mut impl FnMut(u64) -> (K, V), offsets: Range<u64>) -> Vec<(K, V)> {
+    offsets.map(elem).collect()
+}
+
+/// Tiny deterministic random number generator, see http://xoshiro.di.unimi.it/splitmix64.c
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        query::KeyRangeQuery,
+        store::{BranchCache, MemStore},
+        Forest,
+    };
+
+    #[test]
+    fn simple_tt_range_query() -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+        let mut builder = StreamBuilder::<SimpleTT, u64>::debug();
+        txn.extend(&mut builder, (0..1000u64).map(|i| (i * 2, i)))?;
+        let tree = txn.load_tree::<u64>(Secrets::default(), builder.link().unwrap())?;
+        let values = txn
+            .iter_filtered(&tree, KeyRangeQuery::from(100u64..200))
+            .map(|res| res.map(|(_, _, v)| v))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(values, (50..100).collect::<Vec<_>>());
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+//! quickcheck [Arbitrary] implementations, for property tests of code that uses banyan trees
+use super::{model::Op, TreeGen, TreeShape};
+use quickcheck::{empty_shrinker, Arbitrary, Gen};
+use std::ops::Range;
+
+/// a random number below `max`, or 0 if `max` is 0
+fn below(g: &mut Gen, max: u64) -> u64 {
+    u64::arbitrary(g).checked_rem(max).unwrap_or_default()
+}
+
+/// an offset range that mostly overlaps with the streams generated by [Op::Extend] for the size
+fn offset_range(g: &mut Gen) -> Range<u64> {
+    let max = g.size() as u64 * 4 + 1;
+    let a = below(g, max);
+    let b = below(g, max);
+    a.min(b)..a.max(b)
+}
+
+impl Arbitrary for TreeShape {
+    fn arbitrary(g: &mut Gen) -> Self {
+        *g.choose(&TreeShape::ALL).unwrap()
+    }
+}
+
+impl Arbitrary for TreeGen {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let count = below(g, g.size() as u64 * 10 + 1);
+        Self::new(TreeShape::arbitrary(g), u64::arbitrary(g), count)
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let this = self.clone();
+        Box::new(
+            self.count
+                .shrink()
+                .map(move |count| Self::new(this.shape, this.seed, count)),
+        )
+    }
+}
+
+impl<K: Arbitrary, V: Arbitrary> Arbitrary for Op<K, V> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        // appending is more common than anything else, so the streams do not stay tiny
+        match g.choose(&[0, 0, 1, 1, 2, 3, 4]).unwrap() {
+            0 => Op::Extend(Arbitrary::arbitrary(g)),
+            1 => Op::ExtendUnpacked(Arbitrary::arbitrary(g)),
+            2 => Op::Pack,
+            3 => Op::Retain(offset_range(g)),
+            _ => Op::Slice(offset_range(g)),
+        }
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        match self {
+            Op::Extend(xs) => Box::new(xs.shrink().map(Op::Extend)),
+            Op::ExtendUnpacked(xs) => Box::new(xs.shrink().map(Op::ExtendUnpacked)),
+            _ => empty_shrinker(),
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! reference model of a stream, for property testing trees against simple vector semantics
+//!
+//! A [Model] is just the elements of a stream by offset. [Model::apply] performs an [Op] on both
+//! a [StreamBuilder] and the model, and checks that the tree still has the same elements. This
+//! works for any [TreeTypes], so property tests for custom tree types only need to generate
+//! keys and values. With the `arbitrary` feature, [Op] implements quickcheck's `Arbitrary`.
+use crate::{
+    error::PurgedSplit,
+    query::OffsetRangeQuery,
+    store::{BanyanValue, BlockWriter, ReadOnlyStore},
+    Config, StreamBuilder, Transaction, TreeTypes,
+};
+use anyhow::{ensure, Result};
+use std::{fmt::Debug, ops::Range};
+
+/// An operation on a stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op<K, V> {
+    /// append the elements with [Transaction::extend]
+    Extend(Vec<(K, V)>),
+    /// append the elements with [Transaction::extend_unpacked]
+    ExtendUnpacked(Vec<(K, V)>),
+    /// [Transaction::pack] the tree
+    Pack,
+    /// [Transaction::retain] the elements in the offset range
+    Retain(Range<u64>),
+    /// keep only the elements in the offset range, using [Transaction::split_at]
+    ///
+    /// The offsets of the remaining elements start at 0 again. Splitting within a purged node
+    /// fails, so if an element next to a split point is purged, the tree may stay as it is.
+    Slice(Range<u64>),
+}
+
+/// The expected elements of a stream by offset, with `None` for purged elements
+#[derive(Debug, Clone, PartialEq)]
+pub struct Model<K, V> {
+    elements: Vec<Option<(K, V)>>,
+}
+
+impl<K, V> Default for Model<K, V> {
+    fn default() -> Self {
+        Self {
+            elements: Vec::new(),
+        }
+    }
+}
+
+impl<K, V> Model<K, V>
+where
+    K: Debug + PartialEq + Clone,
+    V: Debug + PartialEq + Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the expected elements, in the same form as [Forest::collect](crate::Forest::collect)
+    pub fn elements(&self) -> &[Option<(K, V)>] {
+        &self.elements
+    }
+
+    /// Performs `op` on `builder` and on the model, and checks that they still match
+    ///
+    /// [Op::Retain] only drops sealed nodes, so the model can not predict which elements are
+    /// gone. It only checks that all elements in the range are kept, and then takes over the
+    /// elements of the tree. After [Op::Pack], the tree must also be packed and satisfy the
+    /// invariants of the config of the builder.
+    pub fn apply<T, R, W>(
+        &mut self,
+        txn: &mut Transaction<T, R, W>,
+        builder: &mut StreamBuilder<T, V>,
+        op: &Op<K, V>,
+    ) -> Result<()>
+    where
+        T: TreeTypes<Key = K>,
+        R: ReadOnlyStore<T::Link>,
+        W: BlockWriter<T::Link>,
+        K: Send,
+        V: BanyanValue,
+    {
+        match op {
+            Op::Extend(xs) => {
+                txn.extend(builder, xs.clone())?;
+                self.elements.extend(xs.iter().cloned().map(Some));
+            }
+            Op::ExtendUnpacked(xs) => {
+                txn.extend_unpacked(builder, xs.clone())?;
+                self.elements.extend(xs.iter().cloned().map(Some));
+            }
+            Op::Pack => {
+                txn.pack(builder)?;
+                ensure!(txn.is_packed(&builder.snapshot())?, "tree is not packed");
+                let violations = txn.check_invariants(builder)?;
+                ensure!(
+                    violations.is_empty(),
+                    "invariants violated: {:?}",
+                    violations
+                );
+            }
+            Op::Retain(range) => {
+                txn.retain(builder, &OffsetRangeQuery::from(range.clone()))?;
+                let actual = txn.collect(&builder.snapshot())?;
+                ensure!(
+                    actual.len() == self.elements.len(),
+                    "retain changed the number of elements from {} to {}",
+                    self.elements.len(),
+                    actual.len()
+                );
+                for (offset, (expected, actual)) in self.elements.iter().zip(&actual).enumerate() {
+                    let retained = range.contains(&(offset as u64));
+                    ensure!(
+                        actual == expected || (actual.is_none() && !retained),
+                        "element {} is {:?} instead of {:?}",
+                        offset,
+                        actual,
+                        expected
+                    );
+                }
+                self.elements = actual;
+            }
+            Op::Slice(range) => {
+                let len = self.elements.len();
+                let end = (range.end as usize).min(len);
+                let start = (range.start as usize).min(end);
+                match self.slice(txn, builder, start as u64, end as u64) {
+                    Ok(sliced) => {
+                        *builder = sliced;
+                        self.elements.truncate(end);
+                        self.elements.drain(..start);
+                    }
+                    Err(cause)
+                        if cause.downcast_ref::<PurgedSplit>().is_some()
+                            && (self.is_purged_at(start) || self.is_purged_at(end)) => {}
+                    Err(cause) => return Err(cause),
+                }
+            }
+        }
+        self.check(txn, builder)
+    }
+
+    /// Checks that `builder` contains exactly the elements of the model
+    pub fn check<T, R, W>(
+        &self,
+        txn: &Transaction<T, R, W>,
+        builder: &StreamBuilder<T, V>,
+    ) -> Result<()>
+    where
+        T: TreeTypes<Key = K>,
+        R: ReadOnlyStore<T::Link>,
+        W: BlockWriter<T::Link>,
+        V: BanyanValue,
+    {
+        ensure!(
+            builder.count() == self.elements.len() as u64,
+            "tree has {} elements instead of {}",
+            builder.count(),
+            self.elements.len()
+        );
+        let actual = txn.collect(&builder.snapshot())?;
+        for (offset, (expected, actual)) in self.elements.iter().zip(&actual).enumerate() {
+            ensure!(
+                actual == expected,
+                "element {} is {:?} instead of {:?}",
+                offset,
+                actual,
+                expected
+            );
+        }
+        Ok(())
+    }
+
+    /// whether splitting at `offset` might hit a purged node
+    fn is_purged_at(&self, offset: usize) -> bool {
+        let purged = |i: usize| self.elements.get(i).is_some_and(Option::is_none);
+        offset > 0 && offset < self.elements.len() && (purged(offset - 1) || purged(offset))
+    }
+
+    fn slice<T, R, W>(
+        &self,
+        txn: &mut Transaction<T, R, W>,
+        builder: &StreamBuilder<T, V>,
+        start: u64,
+        end: u64,
+    ) -> Result<StreamBuilder<T, V>>
+    where
+        T: TreeTypes<Key = K>,
+        R: ReadOnlyStore<T::Link>,
+        W: BlockWriter<T::Link>,
+        V: BanyanValue,
+    {
+        let (left, _) = txn.split_at(builder, end)?;
+        let left = self.reload(txn, builder, left.link())?;
+        let (_, right) = txn.split_at(&left, start)?;
+        self.reload(txn, builder, right.link())
+    }
+
+    /// a builder for the tree with the given root, with the config and secrets of `builder`
+    fn reload<T, R, W>(
+        &self,
+        txn: &Transaction<T, R, W>,
+        builder: &StreamBuilder<T, V>,
+        link: Option<T::Link>,
+    ) -> Result<StreamBuilder<T, V>>
+    where
+        T: TreeTypes<Key = K>,
+        R: ReadOnlyStore<T::Link>,
+        W: BlockWriter<T::Link>,
+    {
+        let config = builder.state().config().clone();
+        let secrets = builder.state().secrets().clone();
+        Ok(match link {
+            Some(link) => txn.load_stream_builder(secrets, config, link)?,
+            None => StreamBuilder::new(config, secrets),
+        })
+    }
+}
+
+/// Performs `ops` on a new stream with the given config and on a [Model], checking after each
+/// operation that the tree matches the model
+pub fn check_ops<T, R, W, V>(
+    txn: &mut Transaction<T, R, W>,
+    config: Config,
+    ops: &[Op<T::Key, V>],
+) -> Result<()>
+where
+    T: TreeTypes,
+    T::Key: Clone,
+    R: ReadOnlyStore<T::Link>,
+    W: BlockWriter<T::Link>,
+    V: BanyanValue + Debug + PartialEq + Clone,
+{
+    let mut builder = StreamBuilder::new(config, Default::default());
+    let mut model = Model::new();
+    for (i, op) in ops.iter().enumerate() {
+        model
+            .apply(txn, &mut builder, op)
+            .map_err(|cause| cause.context(format!("operation {} failed: {:?}", i, op)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        store::{BranchCache, MemStore},
+        testing::{Sha256Digest, SimpleTT},
+        Forest,
+    };
+
+    #[test]
+    fn model_ops() -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+        let xs = |range: Range<u64>| range.map(|i| (i, i * 10)).collect::<Vec<_>>();
+        let ops = vec![
+            Op::Extend(xs(0..100)),
+            Op::ExtendUnpacked(xs(100..110)),
+            Op::ExtendUnpacked(xs(110..113)),
+            Op::Pack,
+            Op::Retain(50..80),
+            Op::Slice(40..90),
+            Op::Extend(xs(200..300)),
+            Op::Pack,
+            Op::Slice(0..1000),
+            Op::Slice(5..5),
+        ];
+        check_ops::<SimpleTT, _, _, u64>(&mut txn, Config::debug(), &ops)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[quickcheck]
+    fn model_arbitrary_ops(ops: Vec<Op<u64, u64>>) -> Result<()> {
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+        check_ops::<SimpleTT, _, _, u64>(&mut txn, Config::debug(), &ops)
+    }
+}
@@ -1,20 +1,49 @@
 //! creation and traversal of banyan trees
 use super::index::*;
+use crate::{
+    cursor::Cursor,
+    pipeline::Pipeline,
+    query::{AllQuery, AndQuery, MatchEstimate, OffsetRangeQuery, OrderedSummary, Query},
+    store::{ReadOnlyStore, RootConflict, RootStore},
+    util::{EitherIter, IterExt},
+    PackJob, PackedSnapshot, StreamBuilder, StreamBuilderState,
+};
 use crate::{
     forest::{
-        ChunkVisitor, Config, FilteredChunk, Forest, IndexIter, Secrets, Transaction, TreeIter,
-        TreeTypes,
+        ChunkVisitor, Config, FilteredChunk, Forest, IndexIter, MissingValuePolicy, PackProgress,
+        Secrets, Transaction, TreeIter, TreeStats, TreeTypes, Validation, Violation,
     },
     store::{BanyanValue, BlockWriter},
 };
-use crate::{query::Query, store::ReadOnlyStore, util::IterExt, StreamBuilder, StreamBuilderState};
 use anyhow::Result;
-use core::fmt;
+use cbor_data::Cbor;
+use core::{cmp, fmt};
 use futures::prelude::*;
-use std::{collections::BTreeMap, iter, marker::PhantomData, usize};
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    iter,
+    marker::PhantomData,
+    ops::{AddAssign, Range},
+    sync::{atomic::Ordering, Arc},
+};
 
-#[derive(Clone)]
-pub struct Tree<T: TreeTypes, V>(Option<(Index<T>, Secrets, u64)>, PhantomData<V>);
+/// number of elements that are added at a time when extending from a source that is not
+/// collected up front, such as in [Transaction::repack_as]
+pub(crate) const EXTEND_CHUNK_SIZE: usize = 4096;
+
+/// A snapshot of a tree
+///
+/// Trees are immutable and cheap to clone, and they are `Send + Sync` regardless of the value
+/// type, since they do not contain any values. So a snapshot can be shared between threads
+/// without any locking, e.g. using a [TreeReader].
+pub struct Tree<T: TreeTypes, V>(Option<(Index<T>, Secrets, u64)>, PhantomData<fn() -> V>);
+
+impl<T: TreeTypes, V> Clone for Tree<T, V> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), PhantomData)
+    }
+}
 
 impl<T: TreeTypes, V> Tree<T, V> {
     pub(crate) fn new(root: Index<T>, secrets: Secrets, offset: u64) -> Self {
@@ -66,6 +95,17 @@ impl<T: TreeTypes, V> Tree<T, V> {
     pub fn secrets(&self) -> Option<&Secrets> {
         self.0.as_ref().map(|(_, secrets, _)| secrets)
     }
+
+    /// A view of this tree that yields values converted by `f`, see [MappedTree]
+    pub fn map_values<V2>(
+        &self,
+        f: impl Fn(V) -> V2 + Send + Sync + 'static,
+    ) -> MappedTree<T, V, V2> {
+        MappedTree {
+            tree: self.clone(),
+            f: Arc::new(f),
+        }
+    }
 }
 
 impl<T: TreeTypes, V> Default for Tree<T, V> {
@@ -101,32 +141,201 @@ impl<T: TreeTypes, V> fmt::Display for Tree<T, V> {
     }
 }
 
+/// A tree whose values are converted when they are read
+///
+/// Nothing is rewritten, the conversion is applied by [Forest::iter_mapped] and friends to every
+/// value that is yielded, so exposing a derived representation of a tree is cheap. Queries still
+/// work on the keys and summaries of the underlying tree.
+pub struct MappedTree<T: TreeTypes, V, V2> {
+    tree: Tree<T, V>,
+    f: Arc<dyn Fn(V) -> V2 + Send + Sync>,
+}
+
+impl<T: TreeTypes, V: 'static, V2: 'static> MappedTree<T, V, V2> {
+    /// the underlying tree
+    pub fn tree(&self) -> &Tree<T, V> {
+        &self.tree
+    }
+
+    /// number of elements in the tree
+    pub fn count(&self) -> u64 {
+        self.tree.count()
+    }
+
+    /// converts the values of this view again
+    pub fn map_values<V3: 'static>(
+        &self,
+        f: impl Fn(V2) -> V3 + Send + Sync + 'static,
+    ) -> MappedTree<T, V, V3> {
+        let g = self.f.clone();
+        MappedTree {
+            tree: self.tree.clone(),
+            f: Arc::new(move |value| f(g(value))),
+        }
+    }
+}
+
+impl<T: TreeTypes, V, V2> Clone for MappedTree<T, V, V2> {
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+            f: self.f.clone(),
+        }
+    }
+}
+
+impl<T: TreeTypes, V, V2> fmt::Debug for MappedTree<T, V, V2> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MappedTree").field(&self.tree).finish()
+    }
+}
+
+/// A cheap, cloneable handle for reading a snapshot of a tree, see [Forest::reader]
+///
+/// All clones share the forest, including its caches, and the snapshot. Both are immutable
+/// apart from the caches, which are synchronized internally, so a server can give a clone to
+/// every request and serve many concurrent queries over the same snapshot without locking.
+pub struct TreeReader<T: TreeTypes, R, V> {
+    forest: Forest<T, R>,
+    tree: Tree<T, V>,
+}
+
+impl<T: TreeTypes, R: ReadOnlyStore<T::Link>, V: BanyanValue> TreeReader<T, R, V> {
+    /// the snapshot that is read
+    pub fn tree(&self) -> &Tree<T, V> {
+        &self.tree
+    }
+
+    /// the forest that is used for reading
+    pub fn forest(&self) -> &Forest<T, R> {
+        &self.forest
+    }
+
+    /// number of elements in the snapshot
+    pub fn count(&self) -> u64 {
+        self.tree.count()
+    }
+
+    /// see [Forest::get]
+    pub fn get(&self, offset: u64) -> Result<Option<(T::Key, V)>> {
+        self.forest.get(&self.tree, offset)
+    }
+
+    /// see [Forest::get_many]
+    #[allow(clippy::type_complexity)]
+    pub fn get_many(&self, offsets: &[u64]) -> Result<Vec<Option<(T::Key, V)>>> {
+        self.forest.get_many(&self.tree, offsets)
+    }
+
+    /// see [Forest::iter_filtered]
+    pub fn iter_filtered(
+        &self,
+        query: impl Query<T> + Clone + 'static,
+    ) -> impl Iterator<Item = Result<(u64, T::Key, V)>> + 'static {
+        self.forest.iter_filtered(&self.tree, query)
+    }
+
+    /// see [Forest::stream_filtered]
+    pub fn stream_filtered(
+        &self,
+        query: impl Query<T> + Clone + 'static,
+    ) -> impl Stream<Item = Result<(u64, T::Key, V)>> + 'static {
+        self.forest.stream_filtered(&self.tree, query)
+    }
+}
+
+impl<T: TreeTypes, R, V> Clone for TreeReader<T, R, V> {
+    fn clone(&self) -> Self {
+        Self {
+            forest: self.forest.clone(),
+            tree: self.tree.clone(),
+        }
+    }
+}
+
+impl<T: TreeTypes, R, V> fmt::Debug for TreeReader<T, R, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TreeReader").field(&self.tree).finish()
+    }
+}
+
 pub type GraphEdges = Vec<(usize, usize)>;
 pub type GraphNodes<S> = BTreeMap<usize, S>;
 
 impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
+    /// A handle for reading a snapshot, which can be cloned cheaply and shared between threads
+    pub fn reader<V>(&self, tree: &Tree<T, V>) -> TreeReader<T, R, V> {
+        TreeReader {
+            forest: self.clone(),
+            tree: tree.clone(),
+        }
+    }
+
     pub fn load_stream_builder<V>(
         &self,
         secrets: Secrets,
         config: Config,
         link: T::Link,
     ) -> Result<StreamBuilder<T, V>> {
-        let (index, byte_range) = self.create_index_from_link(
-            &secrets,
-            |items, level| config.branch_sealed(items, level),
-            link,
-        )?;
+        let (index, byte_range) = self.create_index_from_link(&secrets, Some(&config), link)?;
         let state = StreamBuilderState::new(byte_range.end, secrets, config);
         Ok(StreamBuilder::new_from_index(Some(index), state))
     }
 
+    /// Loads the stream with the given name from a root store, or creates an empty builder if
+    /// there is no root for `name` yet.
+    ///
+    /// The builder remembers the root it was loaded from, so publishing it with
+    /// [Transaction::publish_root] fails if someone else has published in the meantime.
+    pub fn load_root<V>(
+        &self,
+        roots: &impl RootStore<T::Link>,
+        name: &str,
+        secrets: Secrets,
+        config: Config,
+    ) -> Result<StreamBuilder<T, V>> {
+        let root = roots.get_root(name)?;
+        let mut builder = match root {
+            Some(link) => self.load_stream_builder(secrets, config, link)?,
+            None => StreamBuilder::new(config, secrets),
+        };
+        builder.set_published(root);
+        Ok(builder)
+    }
+
     pub fn load_tree<V>(&self, secrets: Secrets, link: T::Link) -> Result<Tree<T, V>> {
-        // we pass in a predicate that makes the nodes sealed, since we don't care
-        let (index, byte_range) = self.create_index_from_link(&secrets, |_, _| true, link)?;
+        // without a config, the root is considered sealed, since we don't care
+        let (index, byte_range) = self.create_index_from_link(&secrets, None, link)?;
         // store the offset with the snapshot. Snapshots are immutable, so this won't change.
         Ok(Tree::new(index, secrets, byte_range.end))
     }
 
+    /// Like [load_tree](#method.load_tree), but checks the tree first, so a broken or
+    /// truncated tree is noticed right away instead of during some later query.
+    ///
+    /// Fails with a list of the failed checks if the tree is not valid.
+    pub fn load_tree_with<V>(
+        &self,
+        secrets: Secrets,
+        link: T::Link,
+        validation: Validation,
+    ) -> Result<Tree<T, V>> {
+        let tree = self.load_tree(secrets, link)?;
+        if validation != Validation::None {
+            let mut msgs = Vec::new();
+            if let Some((index, secrets, _)) = &tree.0 {
+                self.validate0(secrets, index, validation == Validation::Full, &mut msgs)?;
+            }
+            anyhow::ensure!(
+                msgs.is_empty(),
+                "tree {} failed validation: {}",
+                link,
+                msgs.join(",")
+            );
+        }
+        Ok(tree)
+    }
+
     /// dumps the tree structure
     pub fn dump<V>(&self, tree: &Tree<T, V>) -> Result<()> {
         match &tree.0 {
@@ -147,6 +356,19 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
         }
     }
 
+    /// writes the tree structure as a [DOT](https://graphviz.org/doc/info/lang.html) graph
+    ///
+    /// Nodes are annotated with their level, count, sealed flag and byte sizes. Unsealed nodes
+    /// are drawn dashed and purged nodes dotted. Render with e.g. `dot -Tsvg`.
+    pub fn dump_dot<V>(&self, tree: &Tree<T, V>, mut w: impl Write) -> Result<()> {
+        writeln!(w, "digraph tree {{")?;
+        if let Some((index, secrets, _)) = &tree.0 {
+            self.dump_dot0(secrets, index, &mut 0, &mut w)?;
+        }
+        writeln!(w, "}}")?;
+        Ok(())
+    }
+
     pub(crate) fn traverse0<
         Q: Query<T>,
         V: BanyanValue,
@@ -194,7 +416,7 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
         secrets: Secrets,
         query: Q,
         index: Index<T>,
-    ) -> impl Iterator<Item = Result<Index<T>>> {
+    ) -> impl Iterator<Item = Result<(Index<T>, Range<u64>)>> {
         IndexIter::new(self.clone(), secrets, query, index)
     }
 
@@ -203,7 +425,7 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
         secrets: Secrets,
         query: Q,
         index: Index<T>,
-    ) -> impl Iterator<Item = Result<Index<T>>> {
+    ) -> impl Iterator<Item = Result<(Index<T>, Range<u64>)>> {
         IndexIter::new_rev(self.clone(), secrets, query, index)
     }
 
@@ -274,19 +496,35 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
         Ok(result)
     }
 
-    pub fn check_invariants<V>(&self, tree: &StreamBuilder<T, V>) -> Result<Vec<String>> {
-        let mut msgs = Vec::new();
+    /// Checks the structural invariants of a tree against the config of the builder, and returns
+    /// all violations. This reads the entire tree.
+    ///
+    /// An empty result means that the tree is consistent. Errors are only returned if the tree
+    /// can not be read at all.
+    pub fn check_invariants<V>(&self, tree: &StreamBuilder<T, V>) -> Result<Vec<Violation>> {
+        let mut violations = Vec::new();
         if let Some(root) = tree.index() {
             let mut level = i32::max_value();
             self.check_invariants0(
                 tree.state().secrets(),
-                tree.state().config(),
+                Some(tree.state().config()),
                 root,
                 &mut level,
-                &mut msgs,
+                &mut Vec::new(),
+                &mut violations,
             )?;
         }
-        Ok(msgs)
+        Ok(violations)
+    }
+
+    /// Structural statistics of a tree, such as the number of nodes per level and the fill of
+    /// the leaves. This only reads the branches, not the leaves.
+    pub fn tree_stats<V>(&self, tree: &Tree<T, V>) -> Result<TreeStats> {
+        let mut stats = TreeStats::default();
+        for index in self.iter_index(tree, AllQuery) {
+            stats.add(&index?);
+        }
+        Ok(stats)
     }
 
     pub fn is_packed<V>(&self, tree: &Tree<T, V>) -> Result<bool> {
@@ -298,7 +536,11 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
     }
 
     pub fn assert_invariants<V>(&self, tree: &StreamBuilder<T, V>) -> Result<()> {
-        let msgs = self.check_invariants(tree)?;
+        let msgs = self
+            .check_invariants(tree)?
+            .iter()
+            .map(|violation| violation.to_string())
+            .collect::<Vec<_>>();
         if !msgs.is_empty() {
             let invariants = msgs.join(",");
             for msg in msgs {
@@ -322,6 +564,42 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
         }
     }
 
+    /// Like [iter_filtered](#method.iter_filtered), but for a [MappedTree], yielding the
+    /// converted values
+    pub fn iter_mapped<V: BanyanValue, V2: 'static>(
+        &self,
+        tree: &MappedTree<T, V, V2>,
+        query: impl Query<T> + Clone + 'static,
+    ) -> impl Iterator<Item = Result<(u64, T::Key, V2)>> + 'static {
+        let f = tree.f.clone();
+        self.iter_filtered(&tree.tree, query)
+            .map(move |item| item.map(|(offset, key, value)| (offset, key, f(value))))
+    }
+
+    /// Like [iter_filtered_reverse](#method.iter_filtered_reverse), but for a [MappedTree],
+    /// yielding the converted values
+    pub fn iter_mapped_reverse<V: BanyanValue, V2: 'static>(
+        &self,
+        tree: &MappedTree<T, V, V2>,
+        query: impl Query<T> + Clone + 'static,
+    ) -> impl Iterator<Item = Result<(u64, T::Key, V2)>> + 'static {
+        let f = tree.f.clone();
+        self.iter_filtered_reverse(&tree.tree, query)
+            .map(move |item| item.map(|(offset, key, value)| (offset, key, f(value))))
+    }
+
+    /// Like [stream_filtered](#method.stream_filtered), but for a [MappedTree], yielding the
+    /// converted values
+    pub fn stream_mapped<V: BanyanValue, V2: 'static>(
+        &self,
+        tree: &MappedTree<T, V, V2>,
+        query: impl Query<T> + Clone + 'static,
+    ) -> impl Stream<Item = Result<(u64, T::Key, V2)>> + 'static {
+        let f = tree.f.clone();
+        self.stream_filtered(&tree.tree, query)
+            .map_ok(move |(offset, key, value)| (offset, key, f(value)))
+    }
+
     /// Returns an iterator yielding all indexes that have values matching the
     /// provided query.
     pub fn iter_index<V>(
@@ -329,9 +607,29 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
         tree: &Tree<T, V>,
         query: impl Query<T> + Clone + 'static,
     ) -> impl Iterator<Item = Result<Index<T>>> + 'static {
+        self.iter_index_with_offsets(tree, query)
+            .map(|item| item.map(|(index, _, _)| index))
+    }
+
+    /// Like [iter_index](#method.iter_index), but also yields the level of each index and the
+    /// range of offsets of its elements, e.g. to see how much of a tree a query visits.
+    ///
+    /// Branches are yielded before their children. No leaves are loaded.
+    #[allow(clippy::type_complexity)]
+    pub fn iter_index_with_offsets<V>(
+        &self,
+        tree: &Tree<T, V>,
+        query: impl Query<T> + Clone + 'static,
+    ) -> impl Iterator<Item = Result<(Index<T>, u32, Range<u64>)>> + 'static {
         match &tree.0 {
             Some((index, secrets, _)) => self
                 .index_iter0(secrets.clone(), query, index.clone())
+                .map(|item| {
+                    item.map(|(index, offsets)| {
+                        let level = index.level();
+                        (index, level, offsets)
+                    })
+                })
                 .left_iter(),
             None => iter::empty().right_iter(),
         }
@@ -347,6 +645,7 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
         match &tree.0 {
             Some((index, secrets, _)) => self
                 .index_iter_rev0(secrets.clone(), query, index.clone())
+                .map(|item| item.map(|(index, _)| index))
                 .left_iter(),
             None => iter::empty().right_iter(),
         }
@@ -365,6 +664,125 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
         }
     }
 
+    /// Like [iter_filtered](#method.iter_filtered), but with a policy for leaves whose values
+    /// can not be decrypted or decoded, e.g. because the secrets only contain the index key.
+    ///
+    /// Values are `None` only for [MissingValuePolicy::KeysOnly].
+    #[allow(clippy::type_complexity)]
+    pub fn iter_filtered_with_policy<V: BanyanValue>(
+        &self,
+        tree: &Tree<T, V>,
+        query: impl Query<T> + Clone + 'static,
+        policy: MissingValuePolicy,
+    ) -> impl Iterator<Item = Result<(u64, T::Key, Option<V>)>> + 'static {
+        match &tree.0 {
+            Some((index, secrets, _)) => self
+                .iter_filtered_with_policy0(secrets.clone(), query, index.clone(), policy)
+                .left_iter(),
+            None => iter::empty().right_iter(),
+        }
+    }
+
+    /// Like [iter_filtered](#method.iter_filtered), but gets the blocks of up to `batch_size`
+    /// leaves of the same branch from the store at once, using [ReadOnlyStore::get_many].
+    ///
+    /// Stores with a high latency per request benefit from large batches, while for local stores
+    /// the default of fetching one leaf at a time is usually best.
+    ///
+    /// [ReadOnlyStore::get_many]: store/trait.ReadOnlyStore.html#method.get_many
+    pub fn iter_filtered_batched<V: BanyanValue>(
+        &self,
+        tree: &Tree<T, V>,
+        query: impl Query<T> + Clone + 'static,
+        batch_size: usize,
+    ) -> impl Iterator<Item = Result<(u64, T::Key, V)>> + 'static {
+        match &tree.0 {
+            Some((index, secrets, _)) => self
+                .iter_filtered_batched0(secrets.clone(), query, index.clone(), batch_size)
+                .left_iter(),
+            None => iter::empty().right_iter(),
+        }
+    }
+
+    /// Calls `f` for all elements matching `query`, with the value as undecoded cbor.
+    ///
+    /// The values of each leaf are copied out of the decompression buffer before `f` is called,
+    /// so `f` may read from this forest itself. To get the contents of a byte string without
+    /// decoding it, use `Cbor::tagged_item`. Returning an error from `f` stops the traversal.
+    pub fn for_each_filtered_cbor<V>(
+        &self,
+        tree: &Tree<T, V>,
+        query: impl Query<T>,
+        mut f: impl FnMut(u64, T::Key, &Cbor) -> Result<()>,
+    ) -> Result<()> {
+        match &tree.0 {
+            Some((index, secrets, _)) => {
+                self.for_each_filtered_cbor0(secrets.clone(), query, index.clone(), &mut f)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the stages of `pipeline` on all elements matching `query` while their values are
+    /// decoded, and collects the results.
+    ///
+    /// Once the limit of the pipeline is reached, the traversal stops without decoding the rest
+    /// of the current leaf. See the [pipeline module](pipeline/index.html).
+    pub fn run_pipeline<V: BanyanValue, O: 'static>(
+        &self,
+        tree: &Tree<T, V>,
+        query: impl Query<T>,
+        mut pipeline: Pipeline<(u64, T::Key, V), O>,
+    ) -> Result<Vec<O>> {
+        match &tree.0 {
+            Some((index, secrets, _)) => {
+                self.run_pipeline0(secrets.clone(), query, index.clone(), &mut pipeline)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Gets up to `limit` elements matching `query`, starting where `cursor` stopped, or at the
+    /// start of the tree if there is no cursor.
+    ///
+    /// Returns the elements and a cursor for the next page, which is None once the end of the
+    /// tree has been reached. Fails if the cursor was created for a different query. A cursor
+    /// can be used with a later version of the same tree, see [Cursor].
+    ///
+    /// [Cursor]: cursor/struct.Cursor.html
+    #[allow(clippy::type_complexity)]
+    pub fn page<V: BanyanValue, Q: Query<T> + Clone + 'static>(
+        &self,
+        tree: &Tree<T, V>,
+        query: Q,
+        cursor: Option<&Cursor<T::Link>>,
+        limit: usize,
+    ) -> Result<(Vec<(u64, T::Key, V)>, Option<Cursor<T::Link>>)> {
+        let offset = match cursor {
+            Some(cursor) => {
+                cursor.validate(&query)?;
+                cursor.offset()
+            }
+            None => 0,
+        };
+        let mut items = self
+            .iter_filtered(
+                tree,
+                AndQuery(OffsetRangeQuery::from(offset..), query.clone()),
+            )
+            .take(limit.saturating_add(1))
+            .collect::<Result<Vec<_>>>()?;
+        // we got one more element than requested, so the next page starts there
+        let next = if items.len() > limit {
+            items
+                .pop()
+                .map(|(offset, _, _)| Cursor::new(tree.link(), offset, &query))
+        } else {
+            None
+        };
+        Ok((items, next))
+    }
+
     pub fn iter_filtered_reverse<V: BanyanValue>(
         &self,
         tree: &Tree<T, V>,
@@ -384,7 +802,7 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
     ) -> impl Iterator<Item = Result<(u64, T::Key, V)>> + 'static {
         match &tree.0 {
             Some((index, secrets, _)) => self
-                .iter_filtered0(secrets.clone(), crate::query::AllQuery, index.clone())
+                .iter_filtered0(secrets.clone(), AllQuery, index.clone())
                 .left_iter(),
             None => iter::empty().right_iter(),
         }
@@ -472,6 +890,10 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
 
     /// element at index
     ///
+    /// This descends directly to the leaf containing the offset using the counts of the
+    /// children, so it reads the branches on the path and exactly one leaf. Use this instead of
+    /// a query for `offset..offset + 1`.
+    ///
     /// returns Ok(None) when offset is larger than count, or when hitting a purged
     /// part of the tree. Returns an error when part of the tree should be there, but could
     /// not be read.
@@ -486,6 +908,109 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
         })
     }
 
+    /// elements at the given offsets, in the same order as the offsets
+    ///
+    /// The offsets are sorted and grouped by leaf, so every branch and leaf that is needed is
+    /// read and decompressed exactly once, no matter how many of the offsets it contains. This
+    /// is much cheaper than calling [get](#method.get) for each offset.
+    ///
+    /// Like for [get](#method.get), elements are None when the offset is larger than count, or
+    /// when hitting a purged part of the tree.
+    #[allow(clippy::type_complexity)]
+    pub fn get_many<V: BanyanValue>(
+        &self,
+        tree: &Tree<T, V>,
+        offsets: &[u64],
+    ) -> Result<Vec<Option<(T::Key, V)>>> {
+        let mut result = iter::repeat_with(|| None)
+            .take(offsets.len())
+            .collect::<Vec<_>>();
+        if let Some((index, secrets, _)) = &tree.0 {
+            let mut requests = offsets
+                .iter()
+                .copied()
+                .enumerate()
+                .map(|(slot, offset)| (offset, slot))
+                .filter(|(offset, _)| *offset < index.count())
+                .collect::<Vec<_>>();
+            requests.sort_unstable();
+            self.get_many0(secrets, index, 0, &requests, &mut result)?;
+        }
+        Ok(result)
+    }
+
+    /// Finds the first element with a key that is not smaller than `key`, e.g. the first event
+    /// at or after a timestamp, and returns it together with its offset.
+    ///
+    /// This does a binary search using the summaries of the branches, so the tree must have been
+    /// built with [Config::ordered_keys]. For other trees, the result is meaningless.
+    pub fn find_by_key<K: Ord, V: BanyanValue>(
+        &self,
+        tree: &Tree<T, V>,
+        key: &K,
+    ) -> Result<Option<(u64, T::Key, V)>>
+    where
+        T::Key: OrderedSummary<K>,
+        T::Summary: OrderedSummary<K>,
+    {
+        Ok(match &tree.0 {
+            Some((index, secrets, _)) => self.find_by_key0(secrets, index, key, 0)?,
+            None => None,
+        })
+    }
+
+    /// Aggregate all elements matching a query, such as summing up a numeric component of the keys.
+    ///
+    /// This never loads any values. The leaves of subtrees that are completely covered by the
+    /// query, as determined by [Query::covers], are answered from their summaries without looking
+    /// at the individual keys. The branches of such subtrees are still loaded, to skip purged
+    /// leaves. Purged elements are not aggregated.
+    ///
+    /// [Query::covers]: query/trait.Query.html#method.covers
+    pub fn aggregate<S, Q, V>(&self, tree: &Tree<T, V>, query: Q) -> Result<Aggregate<S>>
+    where
+        S: AddAssign + Default,
+        Q: Query<T>,
+        T::Key: Aggregatable<S>,
+        T::Summary: Aggregatable<S>,
+    {
+        let mut result = Aggregate::default();
+        if let Some((index, secrets, _)) = &tree.0 {
+            self.aggregate0(secrets, &query, 0, index, &mut result)?;
+        }
+        Ok(result)
+    }
+
+    /// Number of elements matching a query.
+    ///
+    /// Like [aggregate](#method.aggregate), this only looks at keys and summaries, so value
+    /// blocks are never loaded and decompressed. Purged elements are not counted.
+    pub fn count_matching<V>(&self, tree: &Tree<T, V>, query: impl Query<T>) -> Result<u64> {
+        Ok(match &tree.0 {
+            Some((index, secrets, _)) => self.count_matching0(secrets, &query, 0, index)?,
+            None => 0,
+        })
+    }
+
+    /// Lower and upper bounds for the number of elements matching a query.
+    ///
+    /// This is meant for quick result size hints. Only the upper levels of the tree are
+    /// loaded, never the branches directly above the leaves, so it is much cheaper than
+    /// [count_matching](#method.count_matching). Since the index of a branch does not tell
+    /// whether some of its leaves are purged, the lower bound only counts elements of leaves
+    /// whose parent branch was loaded.
+    pub fn estimate_matching<V>(
+        &self,
+        tree: &Tree<T, V>,
+        query: impl Query<T>,
+    ) -> Result<MatchEstimate> {
+        let mut result = MatchEstimate::default();
+        if let Some((index, secrets, _)) = &tree.0 {
+            self.estimate_matching0(secrets, &query, 0, index, &mut result)?;
+        }
+        Ok(result)
+    }
+
     /// Collects all elements from a stream. Might produce an OOM for large streams.
     #[allow(clippy::type_complexity)]
     pub fn collect<V: BanyanValue>(&self, tree: &Tree<T, V>) -> Result<Vec<Option<(T::Key, V)>>> {
@@ -508,15 +1033,19 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>> Forest<T, R> {
 }
 
 impl<T: TreeTypes, R: ReadOnlyStore<T::Link>, W: BlockWriter<T::Link>> Transaction<T, R, W> {
+    /// joins the roots into one tree, calling `progress` with the number of remaining roots
     pub(crate) fn tree_from_roots<V>(
         &mut self,
         mut roots: Vec<Index<T>>,
         stream: &mut StreamBuilder<T, V>,
+        progress: &mut dyn FnMut(&Self, usize),
     ) -> Result<()> {
         assert!(roots.iter().all(|x| x.sealed()));
         assert!(is_sorted(roots.iter().map(|x| x.level()).rev()));
+        progress(self, roots.len());
         while roots.len() > 1 {
             self.simplify_roots(&mut roots, 0, stream.state_mut())?;
+            progress(self, roots.len());
         }
         stream.set_index(roots.pop());
         Ok(())
@@ -530,15 +1059,358 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>, W: BlockWriter<T::Link>> Transacti
     ///
     /// ![packing illustration](https://ipfs.io/ipfs/QmaEDTjHSdCKyGQ3cFMCf73kE67NvffLA5agquLW5qSEVn/packing.jpg)
     pub fn pack<V: BanyanValue>(&mut self, tree: &mut StreamBuilder<T, V>) -> Result<()> {
+        self.pack_with_progress(tree, |_| {})?;
+        Ok(())
+    }
+
+    /// Like [pack](#method.pack), but calls `progress` while packing, and returns the final
+    /// progress, which includes the number of blocks that were written.
+    ///
+    /// Packing first joins the packed subtrees of the tree, reporting the number of subtrees
+    /// left to join. Then it adds the elements after the packed subtrees again.
+    pub fn pack_with_progress<V: BanyanValue>(
+        &mut self,
+        tree: &mut StreamBuilder<T, V>,
+        mut progress: impl FnMut(&PackProgress),
+    ) -> Result<PackProgress> {
+        let blocks_written = self.blocks_written();
+        let bytes_written = self.bytes_written();
+        let mut report = PackProgress::default();
+        let update = |txn: &Self, report: &mut PackProgress| {
+            report.blocks_written = txn.blocks_written() - blocks_written;
+            report.bytes_written = txn.bytes_written() - bytes_written;
+        };
         let initial = tree.snapshot();
         let roots = self.roots(tree)?;
-        self.tree_from_roots(roots, tree)?;
+        self.tree_from_roots(roots, tree, &mut |txn, roots| {
+            report.roots = roots;
+            update(txn, &mut report);
+            progress(&report);
+        })?;
         let remainder: Vec<_> = self
             .collect_from(&initial, tree.count())?
             .into_iter()
             .collect::<Option<Vec<_>>>()
             .ok_or_else(|| anyhow::anyhow!("found purged data"))?;
+        report.reinserted = remainder.len() as u64;
         self.extend(tree, remainder)?;
+        update(self, &mut report);
+        progress(&report);
+        Ok(report)
+    }
+
+    /// Rewrites a tree with a different config and secrets, e.g. to change the branch and leaf
+    /// sizes of an existing stream or to rotate its keys.
+    ///
+    /// If the value key stays the same, the longest prefix of leaves that are full under the new
+    /// config is reused without reading or writing them; only the branches above them are
+    /// rebuilt. Reused leaves keep their compression level. All other elements are read and
+    /// added again. Fails if the tree contains purged data, or if the new config has
+    /// [Config::ordered_keys] and the keys of the tree are not ordered.
+    pub fn repack_as<V: BanyanValue>(
+        &mut self,
+        tree: &Tree<T, V>,
+        config: Config,
+        secrets: Secrets,
+    ) -> Result<StreamBuilder<T, V>> {
+        let (same_value_key, offset) = match &tree.0 {
+            Some((_, old, offset)) if old.same_value_key(&secrets)? => (true, *offset),
+            Some(_) => (false, 0),
+            None => return Ok(StreamBuilder::new(config, secrets)),
+        };
+        let mut reused = Vec::new();
+        if same_value_key {
+            for index in self.iter_index(tree, AllQuery) {
+                match index? {
+                    Index::Leaf(leaf) if leaf.link.is_some() && config.leaf_sealed(&leaf) => {
+                        reused.push(Index::Leaf(leaf))
+                    }
+                    Index::Leaf(_) => break,
+                    Index::Branch(_) => {}
+                }
+            }
+        }
+        if config.ordered_keys {
+            // the remaining elements are checked when they are added again
+            let mut prev = None;
+            for leaf in &reused {
+                if let Index::Leaf(leaf) = leaf {
+                    check_keys_ordered::<T>(&mut prev, leaf.keys.to_vec())?;
+                }
+            }
+        }
+        let count: u64 = reused.iter().map(|x| x.count()).sum();
+        // leaves that are added again must not reuse the nonces of the reused leaves
+        let state = StreamBuilderState::new(offset, secrets, config);
+        let mut builder = StreamBuilder::new_from_index(None, state);
+        if !reused.is_empty() {
+            self.tree_from_roots(reused, &mut builder, &mut |_, _| {})?;
+        }
+        let remainder = self.iter_filtered(tree, OffsetRangeQuery::from(count..));
+        self.extend_chunked(&mut builder, remainder)?;
+        anyhow::ensure!(builder.count() == tree.count(), "found purged data");
+        self.writer_mut().flush()?;
+        Ok(builder)
+    }
+
+    /// Builds a new packed tree that contains only the elements matching a query, e.g. to
+    /// extract the data of a single tenant from a shared stream.
+    ///
+    /// Keys and values are preserved, but the elements get new, consecutive offsets. Sealed
+    /// subtrees at the start of the tree whose elements all match are reused without rewriting
+    /// them, skipping subtrees without any matches. Everything after the first partially matching
+    /// or unsealed node is added again. Purged elements are skipped.
+    ///
+    /// Returns a builder with the same config and secrets.
+    pub fn materialize<Q, V>(
+        &mut self,
+        tree: &StreamBuilder<T, V>,
+        query: Q,
+    ) -> Result<StreamBuilder<T, V>>
+    where
+        Q: Query<T> + Clone + 'static,
+        V: BanyanValue,
+    {
+        let state = tree.state();
+        let mut roots = Vec::new();
+        let mut resume = 0;
+        if let Some(index) = tree.index() {
+            self.materialize_prefix0(state.secrets(), &query, 0, index, &mut roots, &mut resume)?;
+        }
+        // leaves that are added again must not reuse the nonces of the reused leaves
+        let state = state.fork(state.offset.current());
+        let mut builder = StreamBuilder::new_from_index(None, state);
+        if !roots.is_empty() {
+            self.tree_from_roots(roots, &mut builder, &mut |_, _| {})?;
+        }
+        let query = AndQuery(OffsetRangeQuery::from(resume..), query);
+        let remainder = self.iter_filtered(&tree.snapshot(), query);
+        self.extend_chunked(&mut builder, remainder)?;
+        self.writer_mut().flush()?;
+        Ok(builder)
+    }
+
+    /// extends a builder with elements from an iterator, without collecting them up front
+    fn extend_chunked<V: BanyanValue>(
+        &mut self,
+        tree: &mut StreamBuilder<T, V>,
+        mut from: impl Iterator<Item = Result<(u64, T::Key, V)>>,
+    ) -> Result<()> {
+        loop {
+            let chunk = from
+                .by_ref()
+                .take(EXTEND_CHUNK_SIZE)
+                .map(|x| x.map(|(_, k, v)| (k, v)))
+                .collect::<Result<Vec<_>>>()?;
+            if chunk.is_empty() {
+                break;
+            }
+            self.extend(tree, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Converts the values of a stream from `V1` to `V2`, e.g. after a change of the data model
+    ///
+    /// Every leaf is rewritten with the converted values, and so is every branch, since the
+    /// links to the leaves change. Keys, offsets and the shape of the tree stay the same, so leaves
+    /// stay sealed even if the converted values are much larger or smaller than the old ones.
+    /// Purged parts of the tree stay purged. Value summaries are dropped, since they were computed
+    /// for the old values.
+    ///
+    /// Returns a builder for the converted stream with the same config and secrets.
+    pub fn migrate_values<V1, V2>(
+        &mut self,
+        tree: &StreamBuilder<T, V1>,
+        f: impl Fn(V1) -> V2,
+    ) -> Result<StreamBuilder<T, V2>>
+    where
+        V1: BanyanValue,
+        V2: BanyanValue,
+    {
+        let state = tree.state();
+        // the new leaves must not reuse the cipher offsets of the old ones
+        let mut state = StreamBuilderState::new(
+            state.offset.current(),
+            state.secrets().clone(),
+            state.config().clone(),
+        );
+        let index = match tree.index() {
+            Some(index) => Some(self.migrate0(index, &f, &mut state)?),
+            None => None,
+        };
+        self.writer_mut().flush()?;
+        Ok(StreamBuilder::new_from_index(index, state))
+    }
+
+    /// Appends all elements of another tree to a builder, e.g. to stitch together log segments.
+    ///
+    /// The other tree is linked as it is instead of adding its elements again, so this only
+    /// writes a single branch. Like with [extend_unpacked](#method.extend_unpacked), the result
+    /// is not packed. [pack](#method.pack) reuses the sealed subtrees of both parts.
+    ///
+    /// The other tree must have been built with the same secrets. The builder will not reuse the
+    /// cipher offsets of the other tree afterwards. With [Config::ordered_keys], the keys of the
+    /// other tree must be ordered and not smaller than the last key of the builder. Checking
+    /// this reads all branches of the other tree, but no leaves.
+    pub fn concat<V>(&mut self, tree: &mut StreamBuilder<T, V>, other: &Tree<T, V>) -> Result<()> {
+        let other_tree = other;
+        let (other, secrets, offset) = match &other.0 {
+            Some(other) => other,
+            None => return Ok(()),
+        };
+        let own = tree.state().secrets();
+        anyhow::ensure!(
+            own == secrets,
+            "can only concatenate trees with the same secrets"
+        );
+        if tree.state().config().ordered_keys {
+            let mut prev = match tree.index() {
+                Some(index) => Some(self.last_key0(own, index)?),
+                None => None,
+            };
+            for index in self.iter_index(other_tree, AllQuery) {
+                if let Index::Leaf(leaf) = index? {
+                    check_keys_ordered::<T>(&mut prev, leaf.keys.to_vec())?;
+                }
+            }
+        }
+        tree.state_mut().offset.advance_to(*offset)?;
+        let index = tree.index().cloned();
+        let index = self.concat0(index.as_ref(), other, tree.state_mut())?;
+        self.writer_mut().flush()?;
+        tree.set_index(Some(index));
+        Ok(())
+    }
+
+    /// Splits a tree into the elements before `offset` and the elements from `offset` on, e.g.
+    /// to move the older part of a long history to an archive.
+    ///
+    /// Only the nodes on the path to the split point are written again, all other blocks are
+    /// shared with the original tree. Both trees use the secrets of the builder. They are
+    /// generally not packed, see [pack](#method.pack).
+    pub fn split_at<V: BanyanValue>(
+        &mut self,
+        tree: &StreamBuilder<T, V>,
+        offset: u64,
+    ) -> Result<(Tree<T, V>, Tree<T, V>)> {
+        let state = tree.state();
+        let secrets = state.secrets().clone();
+        // the new nodes must not reuse the cipher offsets of the builder
+        let mut state = state.fork(state.offset.current());
+        let (left, right) = match tree.index() {
+            Some(index) => self.split0::<V>(index, offset, &mut state)?,
+            None => (None, None),
+        };
+        self.writer_mut().flush()?;
+        let offset = state.offset.current();
+        let tree = |index: Option<Index<T>>| match index {
+            Some(index) => Tree::new(index, secrets.clone(), offset),
+            None => Tree::default(),
+        };
+        Ok((tree(left), tree(right)))
+    }
+
+    /// Packs just the right part of the tree.
+    ///
+    /// This follows the rightmost path from the root for `depth` levels, and packs the subtree
+    /// found there. The nodes on the path are recreated, but everything to the left of the path
+    /// is left alone. Since fragmentation from unpacked appends accumulates on the right, this is
+    /// usually sufficient for regular maintenance and much cheaper than a full pack.
+    ///
+    /// A `depth` of 0 is equivalent to [pack](#method.pack).
+    pub fn pack_tail<V: BanyanValue>(
+        &mut self,
+        tree: &mut StreamBuilder<T, V>,
+        depth: usize,
+    ) -> Result<()> {
+        let index = tree.index().cloned();
+        if let Some(index) = index {
+            let index = self.pack_tail0::<V>(&index, depth, tree.state_mut())?;
+            self.writer_mut().flush()?;
+            tree.set_index(Some(index));
+        }
+        Ok(())
+    }
+
+    /// Packs the sealed prefix of a snapshot taken with [StreamBuilder::begin_pack].
+    ///
+    /// This does not need access to the builder, so it can run while the builder is being
+    /// extended. The result can then be applied to the builder using [apply_pack], which only
+    /// has to re-add the elements after the sealed prefix.
+    ///
+    /// [StreamBuilder::begin_pack]: struct.StreamBuilder.html#method.begin_pack
+    /// [apply_pack]: #method.apply_pack
+    pub fn pack_snapshot<V>(&mut self, job: PackJob<T, V>) -> Result<PackedSnapshot<T, V>> {
+        let PackJob {
+            tree,
+            state,
+            rewrites,
+        } = job;
+        let roots = match tree.index() {
+            Some(index) => self.roots_impl(state.secrets(), index)?,
+            None => Vec::new(),
+        };
+        let count = roots.iter().map(|x| x.count()).sum();
+        let mut builder = StreamBuilder::<T, V>::new_from_index(None, state);
+        self.tree_from_roots(roots, &mut builder, &mut |_, _| {})?;
+        self.writer_mut().flush()?;
+        Ok(PackedSnapshot {
+            index: builder.index().cloned(),
+            rewrites,
+            count,
+            _p: PhantomData,
+        })
+    }
+
+    /// Applies a packed snapshot to a builder.
+    ///
+    /// This will only succeed if the builder has just been appended to since the snapshot was
+    /// taken. Otherwise, it will leave the builder alone and return false.
+    pub fn apply_pack<V: BanyanValue>(
+        &mut self,
+        tree: &mut StreamBuilder<T, V>,
+        packed: PackedSnapshot<T, V>,
+    ) -> Result<bool> {
+        if tree.rewrites() != packed.rewrites || tree.count() < packed.count {
+            return Ok(false);
+        }
+        let current = tree.snapshot();
+        let remainder: Vec<_> = self
+            .collect_from(&current, packed.count)?
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow::anyhow!("found purged data"))?;
+        let mut txn = tree.transaction();
+        txn.set_index(packed.index);
+        self.extend(&mut txn, remainder)?;
+        txn.commit();
+        Ok(true)
+    }
+
+    /// Makes the current root of the builder the root for `name` in a root store.
+    ///
+    /// The root is only replaced if it is still the one the builder was loaded from or last
+    /// published, see [Forest::load_root]. Otherwise this fails with a [RootConflict] and the
+    /// store is left alone. The builder is not changed either way, so after a conflict the
+    /// stream has to be loaded again and the changes redone.
+    pub fn publish_root<V>(
+        &mut self,
+        roots: &impl RootStore<T::Link>,
+        name: &str,
+        tree: &mut StreamBuilder<T, V>,
+    ) -> Result<()> {
+        // the blocks have to be durable before anyone can see the new root
+        self.writer_mut().flush()?;
+        let old = tree.published().copied();
+        let new = tree.link();
+        if !roots.cas_root(name, old, new)? {
+            return Err(RootConflict {
+                name: name.to_owned(),
+            }
+            .into());
+        }
+        tree.set_published(new);
         Ok(())
     }
 
@@ -561,10 +1433,12 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>, W: BlockWriter<T::Link>> Transacti
         I::IntoIter: Send,
         V: BanyanValue,
     {
-        let mut from = from.into_iter().peekable();
+        let mut unordered = None;
+        let mut from = self.check_order(tree, from, &mut unordered)?.peekable();
         if from.peek().is_none() {
-            // nothing to do
-            return Ok(());
+            drop(from);
+            // nothing to do, unless the first element is out of order
+            return unordered.map_or(Ok(()), Err);
         }
         let index = tree.as_index_ref().cloned();
         let index = self.extend_above(
@@ -573,10 +1447,132 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>, W: BlockWriter<T::Link>> Transacti
             from.by_ref(),
             tree.state_mut(),
         )?;
+        drop(from);
+        if let Some(err) = unordered {
+            // leave the builder as it was
+            return Err(err);
+        }
+        self.writer_mut().flush()?;
         tree.set_index(Some(index));
         Ok(())
     }
 
+    /// checks the order of the keys that are added to a builder with [Config::ordered_keys]
+    ///
+    /// The iterator stops before the first element that is out of order, and sets `error`.
+    fn check_order<'a, I, V>(
+        &self,
+        tree: &StreamBuilder<T, V>,
+        from: I,
+        error: &'a mut Option<anyhow::Error>,
+    ) -> Result<EitherIter<KeyOrderCheck<'a, T, I::IntoIter, V>, I::IntoIter>>
+    where
+        I: IntoIterator<Item = (T::Key, V)>,
+    {
+        let from = from.into_iter();
+        Ok(if tree.state().config().ordered_keys {
+            let last = match tree.index() {
+                Some(index) => Some(self.last_key0(tree.state().secrets(), index)?),
+                None => None,
+            };
+            EitherIter::Left(KeyOrderCheck::new(from, last, error))
+        } else {
+            EitherIter::Right(from)
+        })
+    }
+
+    /// fills an empty builder with the elements of a large iterator
+    ///
+    /// This builds a packed tree like [extend](#method.extend), but bottom-up: every node is
+    /// written once, as soon as it is full, and leaves are written in batches of one branch. Use
+    /// this for initial loads of many elements, instead of extending in chunks, which rewrites
+    /// the unsealed right edge of the tree for every chunk.
+    ///
+    /// Fails if the builder is not empty.
+    pub fn bulk_import<I, V>(&mut self, tree: &mut StreamBuilder<T, V>, from: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (T::Key, V)>,
+        V: BanyanValue,
+    {
+        anyhow::ensure!(tree.index().is_none(), "bulk import needs an empty builder");
+        let mut unordered = None;
+        let mut from = self.check_order(tree, from, &mut unordered)?.peekable();
+        let index = self.bulk_import0(&mut from, tree.state_mut())?;
+        drop(from);
+        if let Some(err) = unordered {
+            return Err(err);
+        }
+        self.writer_mut().flush()?;
+        tree.set_index(index);
+        Ok(())
+    }
+
+    /// extend the node with elements from the given iterator until about `max_bytes` of blocks
+    /// have been written, and return the number of elements that were taken
+    ///
+    /// This allows interleaving ingestion with other work, or slowing it down when the store
+    /// falls behind. Pass the iterator by reference to continue with the remaining elements in
+    /// the next call.
+    ///
+    /// Elements are only taken while less than `max_bytes` have been written, so the last leaf
+    /// that is started is completed. Leaves are written in batches, and a batch ends as soon as
+    /// its leaves use up the budget, so the budget is exceeded by at most one leaf and the
+    /// branches above it. The element following a full leaf is sometimes taken before the leaf
+    /// is written, and then ends up in a small unsealed leaf.
+    pub fn extend_with_budget<I, V>(
+        &mut self,
+        tree: &mut StreamBuilder<T, V>,
+        mut from: I,
+        max_bytes: u64,
+    ) -> Result<u64>
+    where
+        I: Iterator<Item = (T::Key, V)> + Send,
+        V: BanyanValue,
+    {
+        let written = self.bytes_written_counter();
+        let limit = self.bytes_written().saturating_add(max_bytes);
+        let count = tree.count();
+        // check before taking an element, so no element is lost once the budget is used up
+        let budgeted = iter::from_fn(move || {
+            if written.load(Ordering::Relaxed) < limit {
+                from.next()
+            } else {
+                None
+            }
+        });
+        self.set_write_limit(Some(limit));
+        let res = self.extend(tree, budgeted);
+        self.set_write_limit(None);
+        res?;
+        Ok(tree.count() - count)
+    }
+
+    /// extend the node with the elements of a stream, as they arrive
+    ///
+    /// Elements that are ready are added in chunks of up to a few thousand, so at most one chunk
+    /// is buffered, and sealed leaves and branches are written while the stream is still running.
+    /// If adding a chunk fails, the builder contains the elements of all previous chunks.
+    ///
+    /// Every chunk rewrites the unsealed part of the tree, so this works best for streams that
+    /// produce elements in bursts. Compressing and writing happens on the polling task, which
+    /// blocks the executor thread for the duration of a chunk.
+    pub async fn extend_from_stream<S, V>(
+        &mut self,
+        tree: &mut StreamBuilder<T, V>,
+        from: S,
+    ) -> Result<()>
+    where
+        S: Stream<Item = (T::Key, V)>,
+        V: BanyanValue,
+    {
+        let chunks = from.ready_chunks(EXTEND_CHUNK_SIZE);
+        futures::pin_mut!(chunks);
+        while let Some(chunk) = chunks.next().await {
+            self.extend(tree, chunk)?;
+        }
+        Ok(())
+    }
+
     /// extend the node with the given iterator of key/value pairs
     ///
     /// This variant will not pack the tree, but just create a new tree from the new values and join it
@@ -592,8 +1588,14 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>, W: BlockWriter<T::Link>> Transacti
         I::IntoIter: Send,
         V: BanyanValue,
     {
+        let mut unordered = None;
+        let from = self.check_order(tree, from, &mut unordered)?;
         let index = tree.as_index_ref().cloned();
         let index = self.extend_unpacked0(index.as_ref(), from, tree.state_mut())?;
+        if let Some(err) = unordered {
+            return Err(err);
+        }
+        self.writer_mut().flush()?;
         tree.set_index(index);
         Ok(())
     }
@@ -617,7 +1619,9 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>, W: BlockWriter<T::Link>> Transacti
         if let Some(index) = index {
             let mut level: i32 = i32::max_value();
             let index = self.retain0(0, query, &index, &mut level, tree.state_mut())?;
+            self.writer_mut().flush()?;
             tree.set_index(Some(index));
+            tree.mark_rewritten();
         }
         Ok(())
     }
@@ -635,12 +1639,85 @@ impl<T: TreeTypes, R: ReadOnlyStore<T::Link>, W: BlockWriter<T::Link>> Transacti
         if let Some(index) = index {
             let mut level: i32 = i32::max_value();
             let repaired = self.repair0(&index, &mut report, &mut level, tree.state_mut())?;
+            self.writer_mut().flush()?;
             tree.set_index(Some(repaired));
+            tree.mark_rewritten();
         }
         Ok(report)
     }
 }
 
+/// iterator that stops before the first key that is smaller than the previous one
+struct KeyOrderCheck<'a, T: TreeTypes, I, V> {
+    inner: I,
+    next: Option<(T::Key, V)>,
+    error: &'a mut Option<anyhow::Error>,
+}
+
+impl<'a, T: TreeTypes, I: Iterator<Item = (T::Key, V)>, V> KeyOrderCheck<'a, T, I, V> {
+    fn new(mut inner: I, last: Option<T::Key>, error: &'a mut Option<anyhow::Error>) -> Self {
+        let next = inner.next();
+        let mut result = Self {
+            inner,
+            next: None,
+            error,
+        };
+        match (last, next) {
+            (Some(last), Some(next)) => result.check(&last, next),
+            (None, next) => result.next = next,
+            (_, None) => {}
+        }
+        result
+    }
+
+    fn check(&mut self, prev: &T::Key, next: (T::Key, V)) {
+        match check_key_order::<T>(prev, &next.0) {
+            Ok(()) => self.next = Some(next),
+            Err(cause) => *self.error = Some(cause),
+        }
+    }
+}
+
+/// checks that `keys` are ordered and follow `prev`, and sets `prev` to the last of them
+fn check_keys_ordered<T: TreeTypes>(prev: &mut Option<T::Key>, keys: Vec<T::Key>) -> Result<()> {
+    for key in keys {
+        if let Some(prev) = prev.as_ref() {
+            check_key_order::<T>(prev, &key)?;
+        }
+        *prev = Some(key);
+    }
+    Ok(())
+}
+
+/// fails if `next` may not follow `prev` in a stream with [Config::ordered_keys]
+fn check_key_order<T: TreeTypes>(prev: &T::Key, next: &T::Key) -> Result<()> {
+    match T::compare_keys(prev, next) {
+        Some(cmp::Ordering::Less) | Some(cmp::Ordering::Equal) => Ok(()),
+        Some(cmp::Ordering::Greater) => Err(anyhow::anyhow!(
+            "key {:?} is smaller than the previous key {:?}",
+            next,
+            prev
+        )),
+        None => Err(anyhow::anyhow!(
+            "key {:?} can not be compared to the previous key {:?}",
+            next,
+            prev
+        )),
+    }
+}
+
+impl<'a, T: TreeTypes, I: Iterator<Item = (T::Key, V)>, V> Iterator for KeyOrderCheck<'a, T, I, V> {
+    type Item = (T::Key, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.next.take()?;
+        if let Some(next) = self.inner.next() {
+            self.check(&item.0, next);
+        }
+        Some(item)
+    }
+}
+
 fn is_sorted<T: Ord>(iter: impl Iterator<Item = T>) -> bool {
     iter.collect::<Vec<_>>().windows(2).all(|x| x[0] <= x[1])
 }
@@ -1,13 +1,38 @@
-use crate::TreeTypes;
-use futures::{channel::mpsc, executor::ThreadPool, prelude::*, SinkExt};
+#[cfg(feature = "crypto")]
+use crate::{crypto::XNonce, TreeTypes};
+use futures::prelude::*;
+#[cfg(not(target_arch = "wasm32"))]
+use futures::{channel::mpsc, executor::ThreadPool, SinkExt};
+use libipld::{cbor::DagCborCodec, codec::Codec, Ipld};
 use smallvec::{smallvec, SmallVec};
 use std::{
-    convert::TryFrom,
+    convert::TryInto,
     ops::{Bound, RangeBounds},
 };
 
-pub(crate) fn nonce<T: TreeTypes>() -> &'static chacha20::XNonce {
-    <&chacha20::XNonce>::try_from(T::NONCE).unwrap()
+/// std::time::Instant panics on wasm32, so use one that is based on the javascript clock there
+#[cfg(target_arch = "wasm32")]
+pub(crate) use instant::Instant;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use std::time::Instant;
+
+/// The version in the first field of a versioned dag-cbor tuple, without decoding the other fields
+///
+/// Checking the version first gives a proper error for data written by a newer version, instead
+/// of whatever error decoding the changed fields happens to produce.
+pub(crate) fn tuple_version(data: &[u8]) -> anyhow::Result<u64> {
+    match DagCborCodec.decode::<Ipld>(data)? {
+        Ipld::List(fields) => match fields.first() {
+            Some(Ipld::Integer(version)) => Ok((*version).try_into()?),
+            _ => Err(anyhow::anyhow!("missing version")),
+        },
+        _ => Err(anyhow::anyhow!("expected a list")),
+    }
+}
+
+#[cfg(feature = "crypto")]
+pub(crate) fn nonce<T: TreeTypes>() -> &'static XNonce {
+    <&XNonce>::from(T::NONCE)
 }
 
 fn lt<T: Ord>(end: Bound<T>, start: Bound<T>) -> bool {
@@ -88,6 +113,7 @@ where
 impl<'a, T: Iterator + Sized + Send + 'a> IterExt<'a> for T {}
 
 /// same approach as https://crates.io/crates/iterstream
+#[cfg(not(target_arch = "wasm32"))]
 pub(crate) trait ToStreamExt: Iterator
 where
     Self: 'static + Sized + Send,
@@ -111,6 +137,7 @@ where
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl<I> ToStreamExt for I
 where
     I: Iterator + Send + 'static,
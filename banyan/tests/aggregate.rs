@@ -0,0 +1,153 @@
+//! tests for aggregate queries that are answered from summaries
+use banyan::{
+    index::{Aggregatable, Summarizable, VecSeq},
+    query::{AllQuery, AndQuery, KeyRangeQuery, OffsetRangeQuery, OrderedSummary},
+    store::{BranchCache, MemStore},
+    Forest, StreamBuilder, Transaction, TreeTypes,
+};
+use common::Sha256Digest;
+use libipld::DagCbor;
+use quickcheck_macros::quickcheck;
+use std::ops::Range;
+
+mod common;
+
+#[derive(Debug, Clone)]
+struct TT;
+
+/// a key that has a numeric component
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DagCbor)]
+struct Value(u64);
+
+/// summary that contains the sum and the range of the values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DagCbor)]
+struct Sum(u64, u64, u64);
+
+impl TreeTypes for TT {
+    type Key = Value;
+    type KeySeq = VecSeq<Value>;
+    type Summary = Sum;
+    type SummarySeq = VecSeq<Sum>;
+    type Link = Sha256Digest;
+}
+
+impl Summarizable<Sum> for VecSeq<Value> {
+    fn summarize(&self) -> Sum {
+        let values = self.as_ref().iter().map(|x| x.0);
+        Sum(
+            values.clone().sum(),
+            values.clone().min().unwrap(),
+            values.max().unwrap(),
+        )
+    }
+}
+
+impl Summarizable<Sum> for VecSeq<Sum> {
+    fn summarize(&self) -> Sum {
+        let sums = self.as_ref();
+        Sum(
+            sums.iter().map(|x| x.0).sum(),
+            sums.iter().map(|x| x.1).min().unwrap(),
+            sums.iter().map(|x| x.2).max().unwrap(),
+        )
+    }
+}
+
+impl Aggregatable<u64> for Value {
+    fn aggregate_value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Aggregatable<u64> for Sum {
+    fn aggregate_value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl OrderedSummary<u64> for Value {
+    fn min_key(&self) -> u64 {
+        self.0
+    }
+
+    fn max_key(&self) -> u64 {
+        self.0
+    }
+}
+
+impl OrderedSummary<u64> for Sum {
+    fn min_key(&self) -> u64 {
+        self.1
+    }
+
+    fn max_key(&self) -> u64 {
+        self.2
+    }
+}
+
+type Txn = Transaction<TT, MemStore<Sha256Digest>, MemStore<Sha256Digest>>;
+
+fn build(values: &[u32]) -> anyhow::Result<(Txn, banyan::Tree<TT, u64>)> {
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::new(1 << 20)), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    txn.extend(
+        &mut builder,
+        values.iter().map(|x| (Value(*x as u64), *x as u64)),
+    )?;
+    Ok((txn, builder.snapshot()))
+}
+
+#[quickcheck]
+fn aggregate_offset_range(values: Vec<u32>, range: Range<u8>) -> anyhow::Result<bool> {
+    let (txn, tree) = build(&values)?;
+    let range = range.start as u64..range.end as u64;
+    let actual = txn.aggregate::<u64, _, _>(&tree, OffsetRangeQuery::from(range.clone()))?;
+    let matching = values
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| range.contains(&(*i as u64)))
+        .map(|(_, x)| *x as u64)
+        .collect::<Vec<_>>();
+    Ok(actual.count == matching.len() as u64 && actual.value == matching.iter().sum::<u64>())
+}
+
+#[quickcheck]
+fn aggregate_key_range(values: Vec<u32>, range: Range<u32>) -> anyhow::Result<bool> {
+    let (txn, tree) = build(&values)?;
+    let range = range.start as u64..range.end as u64;
+    let query = AndQuery(AllQuery, KeyRangeQuery::from(range.clone()));
+    let actual = txn.aggregate::<u64, _, _>(&tree, query)?;
+    let matching = values
+        .iter()
+        .map(|x| *x as u64)
+        .filter(|x| range.contains(x))
+        .collect::<Vec<_>>();
+    Ok(actual.count == matching.len() as u64 && actual.value == matching.iter().sum::<u64>())
+}
+
+/// purged elements are neither counted nor aggregated, even in subtrees covered by the query
+#[test]
+fn aggregate_after_retain() -> anyhow::Result<()> {
+    let values = (0..1000u32).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::new(1 << 20)), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    txn.extend(
+        &mut builder,
+        values.iter().map(|x| (Value(*x as u64), *x as u64)),
+    )?;
+    txn.retain(&mut builder, &OffsetRangeQuery::from(500..))?;
+    let tree = builder.snapshot();
+    let live = txn
+        .collect(&tree)?
+        .into_iter()
+        .flatten()
+        .map(|(key, _)| key.0)
+        .collect::<Vec<_>>();
+    assert!(live.len() < values.len());
+    let actual = txn.aggregate::<u64, _, _>(&tree, OffsetRangeQuery::from(0..1000))?;
+    assert_eq!(actual.count, live.len() as u64);
+    assert_eq!(actual.value, live.iter().sum::<u64>());
+    Ok(())
+}
@@ -1,12 +1,21 @@
 use banyan::{
-    index::{BranchIndex, Index, LeafIndex, VecSeq},
-    query::{AllQuery, EmptyQuery, OffsetRangeQuery},
-    store::{BranchCache, MemStore},
-    Config, Forest, Secrets, StreamBuilder, Tree,
+    cursor::Cursor,
+    error::{BlockContext, BlockKind},
+    index::{Branch, BranchIndex, Index, LeafIndex, VecSeq},
+    pipeline::Pipeline,
+    query::{AllQuery, EmptyQuery, KeyRangeQuery, NotQuery, OffsetRangeQuery},
+    store::{
+        BlockWriter, BranchCache, LeafCache, MemStore, ReadAheadStore, ReadOnlyStore, RootConflict,
+        RootStore, SelectionCache,
+    },
+    testing::{TreeGen, TreeShape},
+    BackgroundBuilder, Config, Forest, ForestStats, KeyProvider, MissingValuePolicy, Secrets,
+    StreamBuilder, Transaction, Tree, TreeReader, Validation, ViolationKind, CHECKPOINT_HEADROOM,
 };
+use cbor_data::codec::ReadCbor;
 use common::{txn, IterExt, Key, KeyRange, KeySeq, Sha256Digest, TestFilter, TestTree, TT};
-use futures::prelude::*;
-use libipld::{cbor::DagCborCodec, codec::Codec, Cid};
+use futures::{executor::ThreadPool, prelude::*};
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld};
 use quickcheck::TestResult;
 use quickcheck_macros::quickcheck;
 use std::{convert::TryInto, iter, str::FromStr};
@@ -26,6 +35,794 @@ fn build_stream(t: TestTree) -> anyhow::Result<bool> {
     Ok(actual == xs)
 }
 
+/// checks that prefetching siblings does not change the result of a traversal
+#[quickcheck]
+fn build_stream_prefetch(t: TestTree, filter: TestFilter) -> anyhow::Result<bool> {
+    let (tree, txn, _) = t.tree()?;
+    let forest = Forest::new(
+        txn.store().clone(),
+        BranchCache::new(1 << 20).with_prefetch(2),
+    );
+    let actual = forest
+        .iter_filtered(&tree, filter.query())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let expected = txn
+        .iter_filtered(&tree, filter.query())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(actual == expected)
+}
+
+#[test]
+fn prefetch_siblings_during_scan() -> anyhow::Result<()> {
+    let xs = (0..10000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (tree, txn, _) = TestTree::packed(xs).tree()?;
+    assert!(tree.level() >= 3);
+    let forest = Forest::new(
+        txn.store().clone(),
+        BranchCache::new(1 << 24).with_prefetch(2),
+    );
+    forest
+        .iter_filtered(&tree, AllQuery)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert!(forest.stats().branches_prefetched > 0);
+    // without prefetching, nothing is loaded ahead of time
+    let forest = Forest::new(txn.store().clone(), BranchCache::new(1 << 24));
+    forest
+        .iter_filtered(&tree, AllQuery)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert_eq!(forest.stats().branches_prefetched, 0);
+    Ok(())
+}
+
+/// checks that leaves and branches are served from the caches once they have been read
+#[quickcheck]
+fn build_stream_leaf_cache(t: TestTree, decompress: bool) -> anyhow::Result<bool> {
+    let (tree, txn, xs) = t.tree()?;
+    let branch_cache = BranchCache::new(1 << 24);
+    let leaf_cache = LeafCache::new(1 << 24);
+    let leaf_cache = if decompress {
+        leaf_cache.decompressed()
+    } else {
+        leaf_cache
+    };
+    let forest =
+        Forest::new(txn.store().clone(), branch_cache.clone()).with_leaf_cache(leaf_cache.clone());
+    let first = forest.collect(&tree)?;
+    // a forest without any blocks can only answer from the caches
+    let empty = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let forest = Forest::new(empty, branch_cache).with_leaf_cache(leaf_cache);
+    let second = forest.collect(&tree)?;
+    let expected = xs.into_iter().map(Some).collect::<Vec<_>>();
+    Ok(first == expected && second == expected)
+}
+
+/// checks that repeating a query gives the same result when the selections come from the cache
+#[quickcheck]
+fn build_stream_selection_cache(t: TestTree, filter: TestFilter) -> anyhow::Result<bool> {
+    let (tree, txn, _) = t.tree()?;
+    let expected = txn
+        .iter_filtered(&tree, filter.query())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let branch_cache = BranchCache::new(1 << 24);
+    let selection_cache = SelectionCache::new(1 << 24);
+    let forest = Forest::new(txn.store().clone(), branch_cache.clone())
+        .with_selection_cache(selection_cache.clone());
+    let first = forest
+        .iter_filtered(&tree, filter.query())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // a forest without any blocks can only answer from the caches
+    let empty = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let forest = Forest::new(empty, branch_cache).with_selection_cache(selection_cache);
+    let second = forest
+        .iter_filtered(&tree, filter.query())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(first == expected && second == expected)
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn missing_value_policy() -> anyhow::Result<()> {
+    let xs = (0..100u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (builder, txn, _) = TestTree::packed(xs.clone()).builder()?;
+    let link = builder.link().unwrap();
+    // right index key, wrong value key
+    let secrets = Secrets::new(Default::default(), [1u8; 32].into());
+    let tree = txn.load_tree::<u64>(secrets, link)?;
+    let query = OffsetRangeQuery::from(10..20);
+    let iter = |policy| {
+        txn.iter_filtered_with_policy(&tree, query.clone(), policy)
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    assert!(txn.iter_filtered(&tree, query.clone()).any(|x| x.is_err()));
+    assert!(iter(MissingValuePolicy::Error).is_err());
+    assert!(iter(MissingValuePolicy::SkipWithGap)?.is_empty());
+    let expected = xs[10..20]
+        .iter()
+        .enumerate()
+        .map(|(i, (k, _))| (i as u64 + 10, *k, None))
+        .collect::<Vec<_>>();
+    assert_eq!(iter(MissingValuePolicy::KeysOnly)?, expected);
+    // with the right value key, all policies give the same result
+    let tree = txn.load_tree::<u64>(Secrets::default(), link)?;
+    let values = txn
+        .iter_filtered_with_policy(&tree, query, MissingValuePolicy::KeysOnly)
+        .map(|x| x.map(|(_, _, v)| v))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert_eq!(values, (10..20).map(Some).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn error_block_context() -> anyhow::Result<()> {
+    let xs = (0..100u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (builder, txn, _) = TestTree::packed(xs).builder()?;
+    let link = builder.link().unwrap();
+    // right index key, wrong value key
+    let secrets = Secrets::new(Default::default(), [1u8; 32].into());
+    let tree = txn.load_tree::<u64>(secrets, link)?;
+    let error = txn
+        .iter_filtered(&tree, OffsetRangeQuery::from(25..30))
+        .find_map(|x| x.err())
+        .unwrap();
+    let ctx = error
+        .downcast_ref::<BlockContext<Sha256Digest>>()
+        .expect("block context");
+    assert_eq!(ctx.kind, BlockKind::Leaf);
+    assert_eq!(ctx.root, Some(link));
+    assert_eq!(ctx.level, Some(0));
+    assert_eq!(ctx.offsets, Some(20..30));
+    assert!(error.to_string().contains(&ctx.link.to_string()));
+    Ok(())
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn load_tree_validation() -> anyhow::Result<()> {
+    let xs = (0..100u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (builder, txn, _) = TestTree::packed(xs).builder()?;
+    let link = builder.link().unwrap();
+    for validation in [Validation::None, Validation::Quick, Validation::Full] {
+        let tree = txn.load_tree_with::<u64>(Secrets::default(), link, validation)?;
+        assert_eq!(tree.count(), 100);
+    }
+    // the quick check only reads the root, so it does not need the value key
+    let secrets = Secrets::new(Default::default(), [1u8; 32].into());
+    txn.load_tree_with::<u64>(secrets.clone(), link, Validation::Quick)?;
+    assert!(txn
+        .load_tree_with::<u64>(secrets, link, Validation::Full)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn tree_stats() -> anyhow::Result<()> {
+    // 10 elements per leaf and 4 children per branch, so this fills 4 levels completely
+    let xs = (0..640u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (builder, txn, _) = TestTree::packed(xs).builder()?;
+    let stats = txn.tree_stats(&builder.snapshot())?;
+    assert_eq!(stats.depth() as i32, builder.level() + 1);
+    assert_eq!(stats.levels[0].children, 640);
+    assert_eq!(stats.levels[0].nodes, 64);
+    assert_eq!(stats.sealed_ratio(), 1.0);
+    assert_eq!(stats.leaf_fill(&Config::debug()), 1.0);
+    assert_eq!(stats.branch_fanout(), 4.0);
+    // every node except the root is the child of a branch
+    let children: u64 = stats.levels.iter().skip(1).map(|x| x.children).sum();
+    assert_eq!(children + 1, stats.nodes());
+    Ok(())
+}
+
+#[test]
+fn dump_dot() -> anyhow::Result<()> {
+    let xs = (0..100u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (tree, txn, _) = TestTree::packed(xs).tree()?;
+    let mut out = Vec::new();
+    txn.dump_dot(&tree, &mut out)?;
+    let dot = String::from_utf8(out)?;
+    assert!(dot.starts_with("digraph tree {"));
+    assert_eq!(dot.matches("label=\"Leaf").count(), 10);
+    // one edge for every node except the root
+    let nodes = dot.matches("[shape=").count();
+    assert_eq!(dot.matches(" -> ").count() + 1, nodes);
+
+    let mut out = Vec::new();
+    txn.dump_dot(&Tree::<TT, u64>::default(), &mut out)?;
+    assert_eq!(String::from_utf8(out)?, "digraph tree {\n}\n");
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "dedup")]
+fn write_dedup() -> anyhow::Result<()> {
+    use banyan::store::InstrumentedStore;
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = InstrumentedStore::new(MemStore::new(usize::max_value(), Sha256Digest::digest));
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let config = Config {
+        dedup_window: 1024,
+        ..Config::debug()
+    };
+    let mut a = StreamBuilder::<TT, u64>::new(config.clone(), Secrets::default());
+    let mut b = StreamBuilder::<TT, u64>::new(config, Secrets::default());
+    txn.extend(&mut a, xs.clone())?;
+    let writes = txn.writer().writes();
+    // identical trees consist of identical blocks, so none of them is written again
+    txn.extend(&mut b, xs.clone())?;
+    assert_eq!(a.link(), b.link());
+    assert_eq!(txn.writer().writes(), writes);
+    assert_eq!(txn.deduplicated_puts(), writes);
+    // off by default
+    let mut c = StreamBuilder::<TT, u64>::debug();
+    txn.extend(&mut c, xs)?;
+    assert_eq!(txn.writer().writes(), 2 * writes);
+    Ok(())
+}
+
+/// a writer that only writes blocks to the store when it is flushed
+struct StagingWriter {
+    store: MemStore<Sha256Digest>,
+    staged: Vec<Vec<u8>>,
+}
+
+impl BlockWriter<Sha256Digest> for StagingWriter {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<Sha256Digest> {
+        let link = Sha256Digest::digest(&data);
+        self.staged.push(data);
+        Ok(link)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.store.put_many(std::mem::take(&mut self.staged))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn pack_progress() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    for chunk in xs.chunks(100) {
+        txn.extend_unpacked(&mut builder, chunk.to_vec())?;
+    }
+    let mut updates = Vec::new();
+    let report = txn.pack_with_progress(&mut builder, |progress| updates.push(*progress))?;
+    assert_eq!(updates.last(), Some(&report));
+    assert!(updates.first().unwrap().roots > 1);
+    assert!(report.roots <= 1);
+    assert!(report.blocks_written > 0);
+    assert!(updates
+        .windows(2)
+        .all(|w| w[0].blocks_written <= w[1].blocks_written));
+    let actual = txn
+        .collect(&builder.snapshot())?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    assert_eq!(actual, xs);
+    Ok(())
+}
+
+#[test]
+fn bulk_import() -> anyhow::Result<()> {
+    for n in [0u64, 1, 7, 10, 160, 10000] {
+        let xs = (0..n).map(|i| (Key(i), i)).collect::<Vec<_>>();
+        let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+        let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+        let mut builder = StreamBuilder::<TT, u64>::debug();
+        txn.bulk_import(&mut builder, xs.clone())?;
+        txn.assert_invariants(&builder)?;
+        assert!(txn.is_packed(&builder.snapshot())?);
+        let actual = txn
+            .collect(&builder.snapshot())?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        assert_eq!(actual, xs);
+        // the builder can be extended as usual
+        txn.extend(&mut builder, vec![(Key(n), n)])?;
+        txn.assert_invariants(&builder)?;
+        assert_eq!(builder.count(), n + 1);
+        assert!(txn.bulk_import(&mut builder, xs).is_err());
+    }
+    Ok(())
+}
+
+#[test]
+fn extend_with_budget() -> anyhow::Result<()> {
+    let xs = (0..10000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    let mut from = xs.clone().into_iter();
+    let mut rounds = 0;
+    loop {
+        let consumed = txn.extend_with_budget(&mut builder, &mut from, 1000)?;
+        if consumed == 0 {
+            break;
+        }
+        rounds += 1;
+    }
+    assert!(rounds > 1);
+    assert_eq!(builder.count(), xs.len() as u64);
+    // nothing is taken without a budget
+    let mut rest = iter::once((Key(0), 0));
+    assert_eq!(txn.extend_with_budget(&mut builder, &mut rest, 0)?, 0);
+    assert!(rest.next().is_some());
+    let actual = txn
+        .collect(&builder.snapshot())?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    assert_eq!(actual, xs);
+
+    // with wide branches, the batch of leaves ends once the budget is used up
+    let config = Config {
+        max_key_branches: 64,
+        ..Config::debug()
+    };
+    let mut builder = StreamBuilder::<TT, u64>::new(config, Secrets::default());
+    let mut from = xs.clone().into_iter();
+    let consumed = txn.extend_with_budget(&mut builder, &mut from, 1)?;
+    assert!(consumed > 0 && consumed <= 20);
+    Ok(())
+}
+
+#[test]
+fn map_values() -> anyhow::Result<()> {
+    let xs = (0..100u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    txn.extend(&mut builder, xs.clone())?;
+    let blocks = txn.blocks_written();
+    let view = builder
+        .snapshot()
+        .map_values(|v| v.to_string())
+        .map_values(|s| format!("#{}", s));
+    assert_eq!(view.count(), 100);
+    let actual = txn
+        .iter_mapped(&view, OffsetRangeQuery::from(10..20))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let expected = (10..20u64)
+        .map(|i| (i, Key(i), format!("#{}", i)))
+        .collect::<Vec<_>>();
+    assert_eq!(actual, expected);
+    let reversed = txn
+        .iter_mapped_reverse(&view, OffsetRangeQuery::from(10..20))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let unmapped = txn
+        .iter_filtered_reverse(&builder.snapshot(), OffsetRangeQuery::from(10..20))
+        .map(|item| item.map(|(offset, key, value)| (offset, key, format!("#{}", value))))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert_eq!(reversed.len(), 10);
+    assert_eq!(reversed, unmapped);
+    // nothing is written
+    assert_eq!(txn.blocks_written(), blocks);
+    Ok(())
+}
+
+#[test]
+fn migrate_values() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    for chunk in xs.chunks(300) {
+        txn.extend_unpacked(&mut builder, chunk.to_vec())?;
+    }
+    let mut migrated = txn.migrate_values(&builder, |v: u64| v * 1000)?;
+    txn.assert_invariants(&migrated)?;
+    let actual = txn
+        .iter_filtered(&migrated.snapshot(), AllQuery)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let expected = xs
+        .iter()
+        .enumerate()
+        .map(|(i, (k, v))| (i as u64, *k, v * 1000))
+        .collect::<Vec<_>>();
+    assert_eq!(actual, expected);
+    // same shape
+    let shape = |tree: &Tree<TT, u64>| -> anyhow::Result<Vec<(u32, u64, bool)>> {
+        txn.iter_index(tree, AllQuery)
+            .map(|index| index.map(|index| (index.level(), index.count(), index.sealed())))
+            .collect()
+    };
+    assert_eq!(shape(&migrated.snapshot())?, shape(&builder.snapshot())?);
+    // the migrated stream can be extended
+    txn.extend(&mut migrated, vec![(Key(1000), 1)])?;
+    assert_eq!(migrated.count(), 1001);
+    Ok(())
+}
+
+#[test]
+fn repack_as() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    txn.extend(&mut builder, xs.clone())?;
+    let tree = builder.snapshot();
+    let elements = |txn: &Transaction<TT, _, _>, tree: &Tree<TT, u64>| {
+        txn.iter_filtered(tree, AllQuery)
+            .map(|res| res.map(|(_, k, v)| (k, v)))
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+
+    // wider branches with the same secrets reuse all sealed leaves
+    let wide = Config {
+        max_key_branches: 16,
+        max_summary_branches: 16,
+        ..Config::debug()
+    };
+    let blocks = txn.blocks_written();
+    let repacked = txn.repack_as(&tree, wide, Secrets::default())?;
+    let reused_leaves = (xs.len() / 10) as u64;
+    assert!(txn.blocks_written() - blocks < reused_leaves);
+    assert!(repacked.level() < builder.level());
+    assert_eq!(elements(&txn, &repacked.snapshot())?, xs);
+
+    // smaller leaves and new keys rewrite everything
+    let small = Config {
+        max_leaf_count: 5,
+        ..Config::debug()
+    };
+    let secrets = Secrets::new([1u8; 32].into(), [2u8; 32].into());
+    let repacked = txn.repack_as(&tree, small, secrets.clone())?;
+    let new_secrets = repacked.snapshot().secrets().cloned().unwrap();
+    assert_eq!(new_secrets.value_key(), secrets.value_key());
+    assert_eq!(elements(&txn, &repacked.snapshot())?, xs);
+    let leaves = txn
+        .iter_index(&repacked.snapshot(), AllQuery)
+        .filter(|index| {
+            index
+                .as_ref()
+                .map(|index| index.level() == 0)
+                .unwrap_or(true)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert!(leaves.iter().all(|leaf| leaf.count() <= 5));
+    Ok(())
+}
+
+#[test]
+fn repack_as_larger_leaves() -> anyhow::Result<()> {
+    let xs = (0..100000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let leaves = |txn: &Transaction<TT, _, _>, tree: &Tree<TT, u64>| {
+        txn.iter_index(tree, AllQuery)
+            .filter(|index| {
+                index
+                    .as_ref()
+                    .map(|index| index.level() == 0)
+                    .unwrap_or(true)
+            })
+            .count()
+    };
+    // leaves that are sealed because of their compressed size
+    let small = Config {
+        target_leaf_size: 1000,
+        max_leaf_count: 1 << 20,
+        ..Config::debug()
+    };
+    let mut builder = StreamBuilder::<TT, u64>::new(small, Secrets::default());
+    txn.extend(&mut builder, xs.clone())?;
+    let tree = builder.snapshot();
+    assert!(leaves(&txn, &tree) > 1);
+
+    // are not full with a larger target size, so they are not reused
+    let large = Config {
+        target_leaf_size: 1 << 20,
+        max_leaf_count: 1 << 20,
+        ..Config::debug()
+    };
+    let repacked = txn.repack_as(&tree, large, Secrets::default())?;
+    let repacked = repacked.snapshot();
+    assert_eq!(leaves(&txn, &repacked), 1);
+    let elements = txn
+        .iter_filtered(&repacked, AllQuery)
+        .map(|res| res.map(|(_, k, v)| (k, v)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert_eq!(elements, xs);
+    Ok(())
+}
+
+#[test]
+fn materialize() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    txn.extend(&mut builder, xs.clone())?;
+    let elements = |txn: &Transaction<TT, _, _>, tree: &StreamBuilder<TT, u64>| {
+        txn.iter_filtered(&tree.snapshot(), AllQuery)
+            .map(|res| res.map(|(_, k, v)| (k, v)))
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+
+    // a matching prefix is reused, only the branches above it are written
+    let blocks = txn.blocks_written();
+    let extract = txn.materialize(&builder, OffsetRangeQuery::from(0..500))?;
+    assert!(txn.blocks_written() - blocks < 50);
+    assert_eq!(elements(&txn, &extract)?, xs[..500]);
+    assert!(txn.is_packed(&extract.snapshot())?);
+    txn.assert_invariants(&extract)?;
+
+    let extract = txn.materialize(&builder, KeyRangeQuery::from(250..750))?;
+    assert_eq!(elements(&txn, &extract)?, xs[250..750]);
+    assert!(txn.is_packed(&extract.snapshot())?);
+    txn.assert_invariants(&extract)?;
+
+    let extract = txn.materialize(&builder, NotQuery(OffsetRangeQuery::from(100..200)))?;
+    let expected = xs[..100]
+        .iter()
+        .chain(&xs[200..])
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(elements(&txn, &extract)?, expected);
+    assert!(txn.is_packed(&extract.snapshot())?);
+    txn.assert_invariants(&extract)?;
+
+    let extract = txn.materialize(&builder, EmptyQuery)?;
+    assert_eq!(extract.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn concat() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    txn.extend(&mut builder, xs[..500].iter().cloned())?;
+    let mut segment = StreamBuilder::<TT, u64>::debug();
+    txn.extend(&mut segment, xs[500..].iter().cloned())?;
+    let elements = |txn: &Transaction<TT, _, _>, tree: &StreamBuilder<TT, u64>| {
+        txn.iter_filtered(&tree.snapshot(), AllQuery)
+            .map(|res| res.map(|(_, k, v)| (k, v)))
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+
+    // only the branch joining the two trees is written
+    let blocks = txn.blocks_written();
+    txn.concat(&mut builder, &segment.snapshot())?;
+    assert_eq!(txn.blocks_written() - blocks, 1);
+    assert_eq!(elements(&txn, &builder)?, xs);
+    txn.assert_invariants(&builder)?;
+    txn.pack(&mut builder)?;
+    assert!(txn.is_packed(&builder.snapshot())?);
+    assert_eq!(elements(&txn, &builder)?, xs);
+
+    // concatenating to an empty builder just links the tree
+    let mut empty = StreamBuilder::<TT, u64>::debug();
+    let blocks = txn.blocks_written();
+    txn.concat(&mut empty, &segment.snapshot())?;
+    assert_eq!(txn.blocks_written(), blocks);
+    assert_eq!(empty.link(), segment.link());
+
+    let secrets = Secrets::new([1u8; 32].into(), [2u8; 32].into());
+    let mut other = StreamBuilder::<TT, u64>::new(Config::debug(), secrets);
+    assert!(txn.concat(&mut other, &segment.snapshot()).is_err());
+    Ok(())
+}
+
+#[test]
+fn split_at() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    txn.extend(&mut builder, xs.clone())?;
+    let elements = |txn: &Transaction<TT, _, _>, tree: &Tree<TT, u64>| {
+        txn.iter_filtered(tree, AllQuery)
+            .map(|res| res.map(|(_, k, v)| (k, v)))
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+
+    for at in [0, 1, 10, 123, 500, 999, 1000, 2000] {
+        let blocks = txn.blocks_written();
+        let (left, right) = txn.split_at(&builder, at)?;
+        // at most two leaves and the branches above them are written
+        assert!(txn.blocks_written() - blocks <= 2 * (builder.level() as u64 + 1));
+        let at = (at as usize).min(xs.len());
+        assert_eq!(elements(&txn, &left)?, xs[..at]);
+        assert_eq!(elements(&txn, &right)?, xs[at..]);
+    }
+    Ok(())
+}
+
+#[test]
+fn ordered_keys() -> anyhow::Result<()> {
+    let config = Config {
+        ordered_keys: true,
+        ..Config::debug()
+    };
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let mut builder = StreamBuilder::<TT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, (0..1000u64).map(|i| (Key(i / 2 * 2), i)))?;
+    txn.push(&mut builder, Key(998), 1000)?;
+
+    // elements that are out of order are rejected, and the builder stays as it was
+    let link = builder.link();
+    assert!(txn.push(&mut builder, Key(997), 0).is_err());
+    assert!(txn
+        .extend(&mut builder, vec![(Key(1000), 0), (Key(999), 0)])
+        .is_err());
+    assert!(txn
+        .extend_unpacked(&mut builder, vec![(Key(1), 0)])
+        .is_err());
+    assert_eq!(builder.link(), link);
+    assert_eq!(builder.count(), 1001);
+
+    // concatenated trees must continue the order, and be ordered themselves
+    let segment =
+        |txn: &mut Transaction<TT, _, _>, keys: Vec<u64>| -> anyhow::Result<Tree<TT, u64>> {
+            let mut segment = StreamBuilder::<TT, u64>::new(Config::debug(), Secrets::default());
+            txn.extend(&mut segment, keys.into_iter().map(|key| (Key(key), key)))?;
+            Ok(segment.snapshot())
+        };
+    let before = segment(&mut txn, vec![997, 1000])?;
+    let unordered = segment(&mut txn, (1000..1100).chain(1050..1060).collect())?;
+    let after = segment(&mut txn, vec![998, 1000])?;
+    assert!(txn.concat(&mut builder, &before).is_err());
+    assert!(txn.concat(&mut builder, &unordered).is_err());
+    assert_eq!(builder.link(), link);
+    // the same goes for the leaves that repacking reuses
+    let unordered = segment(
+        &mut txn,
+        (1050..1060).chain(1000..1010).chain(1100..1200).collect(),
+    )?;
+    assert!(txn
+        .repack_as(&unordered, config.clone(), Secrets::default())
+        .is_err());
+
+    let tree = builder.snapshot();
+    let found =
+        |key: u64| -> anyhow::Result<Option<(u64, Key, u64)>> { txn.find_by_key(&tree, &key) };
+    assert_eq!(found(0)?, Some((0, Key(0), 0)));
+    assert_eq!(found(123)?, Some((124, Key(124), 124)));
+    assert_eq!(found(500)?, Some((500, Key(500), 500)));
+    assert_eq!(found(998)?, Some((998, Key(998), 998)));
+    assert_eq!(found(999)?, None);
+
+    txn.concat(&mut builder, &after)?;
+    assert_eq!(builder.count(), 1003);
+    Ok(())
+}
+
+#[test]
+fn adaptive_leaf_size() -> anyhow::Result<()> {
+    let target = 1000;
+    let config = Config {
+        target_leaf_size: target,
+        adaptive_leaf_size: true,
+        ..Config::debug_fast()
+    };
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let mut builder = StreamBuilder::<TT, u64>::new(config, Secrets::default());
+    txn.extend(&mut builder, (0..100000u64).map(|i| (Key(i), i)))?;
+    let leaves = txn
+        .iter_index(&builder.snapshot(), AllQuery)
+        .filter(|index| {
+            index
+                .as_ref()
+                .map(|index| index.level() == 0)
+                .unwrap_or(true)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert!(leaves.len() > 2);
+    // the first leaf is filled before the compression ratio is known, the last one is not full
+    for leaf in &leaves[1..leaves.len() - 1] {
+        assert!(leaf.value_bytes() < 2 * target as u64);
+    }
+    Ok(())
+}
+
+#[test]
+fn background_builder() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let builder = BackgroundBuilder::new(txn, StreamBuilder::<TT, u64>::debug(), 4);
+    for (k, v) in xs.iter().take(500) {
+        builder.push(*k, *v)?;
+    }
+    builder.extend(xs[500..].iter().cloned())?;
+    assert!(builder.snapshot().count() <= 1000);
+    let (txn, builder) = builder.commit()?;
+    let actual = txn
+        .collect(&builder.snapshot())?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    assert_eq!(actual, xs);
+    Ok(())
+}
+
+#[test]
+fn extend_flushes_writer() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let writer = StagingWriter {
+        store: store.clone(),
+        staged: Vec::new(),
+    };
+    let mut txn = Transaction::new(Forest::new(store, BranchCache::default()), writer);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    txn.extend(&mut builder, xs.clone())?;
+    assert!(txn.writer().staged.is_empty());
+    txn.extend_unpacked(&mut builder, xs.clone())?;
+    assert!(txn.writer().staged.is_empty());
+    let actual = txn
+        .collect(&builder.snapshot())?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    assert_eq!(actual, [xs.clone(), xs].concat());
+    Ok(())
+}
+
+#[test]
+fn forest_stats() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (tree, txn, _) = TestTree::packed(xs).tree()?;
+    let forest = Forest::new(txn.store().clone(), BranchCache::new(1 << 24))
+        .with_leaf_cache(LeafCache::new(1 << 24));
+    assert_eq!(forest.stats(), ForestStats::default());
+    forest.collect(&tree)?;
+    let first = forest.stats();
+    assert!(first.branch_cache_misses > 0);
+    assert!(first.leaf_cache_misses > 0);
+    assert_eq!(
+        first.block_reads,
+        first.branch_cache_misses + first.leaf_cache_misses
+    );
+    assert!(first.bytes_read > 0);
+    assert!(first.bytes_decompressed > 0);
+    // the second time everything comes from the caches
+    forest.collect(&tree)?;
+    let second = forest.stats();
+    assert_eq!(second.block_reads, first.block_reads);
+    assert_eq!(second.branch_cache_hits, first.branch_cache_misses);
+    assert_eq!(second.leaf_cache_hits, first.leaf_cache_misses);
+    Ok(())
+}
+
+#[test]
+fn get_reads_one_leaf() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (tree, txn, _) = TestTree::packed(xs).tree()?;
+    let forest = Forest::new(txn.store().clone(), BranchCache::new(1 << 24));
+    assert_eq!(forest.get(&tree, 500)?, Some((Key(500), 500)));
+    let stats = forest.stats();
+    assert_eq!(stats.leaf_cache_misses, 1);
+    assert_eq!(stats.block_reads, stats.branch_cache_misses + 1);
+    assert_eq!(stats.branch_cache_misses as i32, tree.level());
+    assert_eq!(forest.get(&tree, 1000)?, None);
+    Ok(())
+}
+
+#[test]
+fn read_ahead_hints() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (tree, txn, _) = TestTree::packed(xs.clone()).tree()?;
+    let store = ReadAheadStore::new(txn.store().clone(), ThreadPool::new()?, 16);
+    let forest = Forest::new(store.clone(), BranchCache::default());
+    let actual = forest
+        .iter_filtered(&tree, AllQuery)
+        .map(|res| res.map(|(_, k, v)| (k, v)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert_eq!(actual, xs);
+    // the traversal announced the children of each branch before visiting them
+    assert!(store.hits() > 0);
+    Ok(())
+}
+
 /// checks that stream_filtered returns the same elements as filtering each element manually
 fn compare_filtered(t: TestTree, filter: TestFilter) -> anyhow::Result<bool> {
     let (tree, txn, xs) = t.tree()?;
@@ -45,6 +842,179 @@ fn compare_filtered(t: TestTree, filter: TestFilter) -> anyhow::Result<bool> {
     Ok(actual == expected)
 }
 
+/// checks that count_matching agrees with the number of elements returned by iter_filtered
+#[quickcheck]
+fn compare_count_matching(t: TestTree, filter: TestFilter) -> anyhow::Result<bool> {
+    let (tree, txn, _) = t.tree()?;
+    let expected = txn.iter_filtered(&tree, filter.query()).count() as u64;
+    let actual = txn.count_matching(&tree, filter.query())?;
+    Ok(actual == expected)
+}
+
+/// checks that purged elements are not counted, even in subtrees covered by the query
+#[test]
+fn count_matching_after_retain() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (mut builder, mut txn, _) = TestTree::packed(xs).builder()?;
+    txn.retain(&mut builder, &OffsetRangeQuery::from(500..))?;
+    let tree = builder.snapshot();
+    let live = txn.iter_filtered(&tree, AllQuery).count() as u64;
+    assert!(live < 1000);
+    let query = OffsetRangeQuery::from(0..1000);
+    assert_eq!(txn.count_matching(&tree, query.clone())?, live);
+    let estimate = txn.estimate_matching(&tree, query)?;
+    assert!(estimate.lower <= live && live <= estimate.upper);
+    Ok(())
+}
+
+/// checks that the bounds of estimate_matching contain the actual count
+#[quickcheck]
+fn compare_estimate_matching(t: TestTree, filter: TestFilter) -> anyhow::Result<bool> {
+    let (tree, txn, _) = t.tree()?;
+    let count = txn.count_matching(&tree, filter.query())?;
+    let estimate = txn.estimate_matching(&tree, filter.query())?;
+    Ok(estimate.lower <= count && count <= estimate.upper)
+}
+
+/// checks that batched iteration returns the same elements as unbatched iteration
+#[quickcheck]
+fn compare_filtered_batched(
+    t: TestTree,
+    filter: TestFilter,
+    batch_size: u8,
+) -> anyhow::Result<bool> {
+    let (tree, txn, _) = t.tree()?;
+    let actual = txn
+        .iter_filtered_batched(&tree, filter.query(), batch_size as usize)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let expected = txn
+        .iter_filtered(&tree, filter.query())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(actual == expected)
+}
+
+/// checks that visiting the undecoded values returns the same elements as iteration
+#[quickcheck]
+fn compare_filtered_cbor(t: TestTree, filter: TestFilter) -> anyhow::Result<bool> {
+    let (tree, txn, _) = t.tree()?;
+    let mut actual = Vec::new();
+    txn.for_each_filtered_cbor(&tree, filter.query(), |offset, key, cbor| {
+        actual.push((offset, key, u64::read_cbor(cbor)?));
+        Ok(())
+    })?;
+    let expected = txn
+        .iter_filtered(&tree, filter.query())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(actual == expected)
+}
+
+/// checks that the callback of for_each_filtered_cbor can read from the same forest
+#[test]
+fn filtered_cbor_reentrant() -> anyhow::Result<()> {
+    let xs = (0..100u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (tree, txn, _) = TestTree::packed(xs).tree()?;
+    let mut count = 0;
+    txn.for_each_filtered_cbor(&tree, AllQuery, |offset, key, cbor| {
+        let value = u64::read_cbor(cbor)?;
+        assert_eq!(txn.get(&tree, offset)?, Some((key, value)));
+        count += 1;
+        Ok(())
+    })?;
+    assert_eq!(count, 100);
+    Ok(())
+}
+
+/// checks that a pipeline returns the same elements as filtering, mapping and taking afterwards
+#[quickcheck]
+fn compare_pipeline(t: TestTree, filter: TestFilter, limit: u8) -> anyhow::Result<bool> {
+    let (tree, txn, _) = t.tree()?;
+    let pipeline = Pipeline::new()
+        .filter(|(_, _, v): &(u64, Key, u64)| v % 2 == 0)
+        .map(|(offset, _, v)| (offset, v))
+        .take(limit as usize);
+    let actual = txn.run_pipeline(&tree, filter.query(), pipeline)?;
+    let expected = txn
+        .iter_filtered(&tree, filter.query())
+        .filter_map(|res| match res {
+            Ok((_, _, v)) if v % 2 != 0 => None,
+            res => Some(res.map(|(offset, _, v)| (offset, v))),
+        })
+        .take(limit as usize)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(actual == expected)
+}
+
+/// checks that paging through a tree with serialized cursors returns all matching elements
+#[quickcheck]
+fn compare_paged(t: TestTree, filter: TestFilter, limit: u8) -> anyhow::Result<TestResult> {
+    if limit == 0 {
+        return Ok(TestResult::discard());
+    }
+    let (tree, txn, _) = t.tree()?;
+    let mut actual = Vec::new();
+    let mut cursor = None;
+    loop {
+        let (page, next) = txn.page(&tree, filter.query(), cursor.as_ref(), limit as usize)?;
+        if page.len() > limit as usize {
+            return Ok(TestResult::failed());
+        }
+        actual.extend(page);
+        match next {
+            Some(next) => cursor = Some(Cursor::<Sha256Digest>::from_bytes(&next.to_bytes()?)?),
+            None => break,
+        }
+    }
+    let expected = txn
+        .iter_filtered(&tree, filter.query())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(TestResult::from_bool(actual == expected))
+}
+
+/// checks that a compiled query expression returns the same elements as the equivalent query
+#[quickcheck]
+fn compare_filtered_expr(t: TestTree, filter: TestFilter) -> anyhow::Result<bool> {
+    let (tree, txn, _) = t.tree()?;
+    let actual = txn
+        .iter_filtered(&tree, filter.expr().compile())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let expected = txn
+        .iter_filtered(&tree, filter.query())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(actual == expected)
+}
+
+/// checks that the complement of a query returns exactly the elements not matched by the query
+#[quickcheck]
+fn compare_filtered_not(t: TestTree, filter: TestFilter) -> anyhow::Result<bool> {
+    let (tree, txn, xs) = t.tree()?;
+    let actual = txn
+        .iter_filtered(&tree, NotQuery(filter.query()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let expected = xs
+        .into_iter()
+        .enumerate()
+        .map(|(i, (k, v))| (i as u64, k, v))
+        .filter(|triple| !filter.contains(triple))
+        .collect::<Vec<_>>();
+    Ok(actual == expected)
+}
+
+/// checks that a key range query returns the same elements as filtering each element manually
+#[quickcheck]
+fn compare_key_range(t: TestTree, start: u64, end: u64) -> anyhow::Result<bool> {
+    let (tree, txn, xs) = t.tree()?;
+    let actual = txn
+        .iter_filtered(&tree, KeyRangeQuery::from(start..end))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let expected = xs
+        .into_iter()
+        .enumerate()
+        .map(|(i, (k, v))| (i as u64, k, v))
+        .filter(|(_, k, _)| (start..end).contains(&k.0))
+        .collect::<Vec<_>>();
+    Ok(actual == expected)
+}
+
 /// checks that stream_filtered_chunked returns the same elements as filtering each element manually
 fn compare_filtered_chunked(t: TestTree, filter: TestFilter) -> anyhow::Result<bool> {
     let (tree, txn, xs) = t.tree()?;
@@ -172,6 +1142,50 @@ fn build_iter_index(t: TestTree) -> anyhow::Result<bool> {
     iter_index(t)
 }
 
+#[test]
+fn iter_index_with_offsets() -> anyhow::Result<()> {
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::default()), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    txn.extend(&mut builder, (0..1000u64).map(|i| (Key(i), i)))?;
+    let tree = builder.snapshot();
+
+    let nodes = txn
+        .iter_index_with_offsets(&tree, AllQuery)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert_eq!(nodes[0].1, builder.level() as u32);
+    assert_eq!(nodes[0].2, 0..1000);
+    // the leaves cover the tree without gaps, and every node covers its elements
+    let mut offset = 0;
+    for (index, level, offsets) in &nodes {
+        assert_eq!(*level, index.level());
+        assert_eq!(offsets.end - offsets.start, index.count());
+        if let Index::Leaf(_) = index {
+            assert_eq!(offsets.start, offset);
+            offset = offsets.end;
+        }
+    }
+    assert_eq!(offset, 1000);
+
+    // only the children of branches containing matching offsets are visited
+    let nodes = txn
+        .iter_index_with_offsets(&tree, OffsetRangeQuery::from(500..510))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert!(nodes
+        .iter()
+        .any(|(index, _, offsets)| index.level() == 0 && *offsets == (500..510)));
+    for (i, (_, level, _)) in nodes.iter().enumerate().skip(1) {
+        // in a packed tree, the parent is the closest preceding node one level up
+        let (_, _, parent) = nodes[..i]
+            .iter()
+            .rev()
+            .find(|(_, parent_level, _)| *parent_level == level + 1)
+            .unwrap();
+        assert!(parent.start < 510 && parent.end > 500);
+    }
+    Ok(())
+}
+
 #[quickcheck]
 fn build_stream_filtered(t: TestTree, filter: TestFilter) -> anyhow::Result<bool> {
     compare_filtered(t, filter)
@@ -218,6 +1232,43 @@ fn build_get(t: TestTree) -> anyhow::Result<bool> {
     Ok(actual == xs)
 }
 
+#[quickcheck]
+fn build_get_many(t: TestTree, offsets: Vec<u16>) -> anyhow::Result<bool> {
+    let (tree, txn, _) = t.tree()?;
+    let offsets = offsets.into_iter().map(u64::from).collect::<Vec<_>>();
+    let expected = offsets
+        .iter()
+        .map(|offset| txn.get(&tree, *offset))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(txn.get_many(&tree, &offsets)? == expected)
+}
+
+#[test]
+fn get_many_reads_each_leaf_once() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (tree, txn, _) = TestTree::packed(xs).tree()?;
+    let forest = Forest::new(txn.store().clone(), BranchCache::new(1 << 24));
+    let offsets = [999, 3, 500, 3, 2000, 0, 501];
+    let actual = forest.get_many(&tree, &offsets)?;
+    let expected = offsets
+        .iter()
+        .map(|i| Some((Key(*i), *i)).filter(|_| *i < 1000))
+        .collect::<Vec<_>>();
+    assert_eq!(actual, expected);
+    // reading the same leaves one by one reads every leaf every time
+    let stats = forest.stats();
+    let reader = Forest::new(txn.store().clone(), BranchCache::new(1 << 24));
+    for offset in offsets {
+        reader.get(&tree, offset)?;
+    }
+    assert!(stats.leaf_cache_misses < reader.stats().leaf_cache_misses);
+    assert_eq!(
+        stats.block_reads,
+        stats.branch_cache_misses + stats.leaf_cache_misses
+    );
+    Ok(())
+}
+
 fn do_build_pack(xss: Vec<Vec<(Key, u64)>>) -> anyhow::Result<bool> {
     let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
     let mut forest = txn(store, 1000);
@@ -256,6 +1307,88 @@ fn build_pack(xss: Vec<Vec<(Key, u64)>>) -> anyhow::Result<bool> {
     do_build_pack(xss)
 }
 
+/// pack a snapshot while extending the builder, then apply the pack
+#[quickcheck]
+fn concurrent_pack(xss: Vec<Vec<(Key, u64)>>, ys: Vec<(Key, u64)>) -> anyhow::Result<bool> {
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut forest = txn(store, 1000);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    let xs = xss
+        .iter()
+        .flatten()
+        .chain(ys.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+    for xs in xss {
+        forest.extend_unpacked(&mut builder, xs)?;
+    }
+    let job = builder.begin_pack();
+    // this would normally happen concurrently with packing the snapshot
+    forest.extend_unpacked(&mut builder, ys)?;
+    let packed = forest.pack_snapshot(job)?;
+    let unpacked_tree = builder.snapshot();
+    assert!(forest.apply_pack(&mut builder, packed)?);
+    let packed_tree = builder.snapshot();
+    assert!(forest.is_packed(&packed_tree)?);
+    let actual: Vec<_> = forest
+        .collect(&packed_tree)?
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .unwrap();
+    let offsets_ok = no_offset_overlap(&forest, &[unpacked_tree, packed_tree])?;
+    Ok(xs == actual && offsets_ok)
+}
+
+/// pack just the tail of an unbalanced tree, then the entire tree
+#[quickcheck]
+fn build_pack_tail(xss: Vec<Vec<(Key, u64)>>, depth: u8) -> anyhow::Result<bool> {
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut forest = txn(store, 1000);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    let xs = xss.iter().flatten().cloned().collect::<Vec<_>>();
+    for xs in xss {
+        forest.extend_unpacked(&mut builder, xs)?;
+    }
+    let unpacked_tree = builder.snapshot();
+    forest.pack_tail(&mut builder, (depth % 4) as usize)?;
+    let tail_packed_tree = builder.snapshot();
+    forest.assert_invariants(&builder)?;
+    let tail_packed: Vec<_> = forest
+        .collect(&tail_packed_tree)?
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .unwrap();
+    // depth 0 packs the entire tree
+    forest.pack_tail(&mut builder, 0)?;
+    let packed_tree = builder.snapshot();
+    assert!(forest.is_packed(&packed_tree)?);
+    let packed: Vec<_> = forest
+        .collect(&packed_tree)?
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .unwrap();
+    let offsets_ok = no_offset_overlap(&forest, &[unpacked_tree, tail_packed_tree, packed_tree])?;
+    Ok(xs == tail_packed && xs == packed && offsets_ok)
+}
+
+#[test]
+fn concurrent_pack_after_retain() -> anyhow::Result<()> {
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut forest = txn(store, 1000);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    for i in 0..100 {
+        forest.extend_unpacked(&mut builder, vec![(Key(i), i)])?;
+    }
+    let job = builder.begin_pack();
+    forest.retain(&mut builder, &OffsetRangeQuery::from(50..))?;
+    let packed = forest.pack_snapshot(job)?;
+    let before = builder.snapshot();
+    // the builder was modified by something other than appending, so the pack must not be applied
+    assert!(!forest.apply_pack(&mut builder, packed)?);
+    assert_eq!(builder.snapshot().link(), before.link());
+    Ok(())
+}
+
 #[test]
 fn build_pack_1() {
     let xss = vec![vec![(Key(8702892647260624503), 0)]];
@@ -271,6 +1404,23 @@ fn build_pack_2() {
     assert!(do_build_pack(xss).unwrap());
 }
 
+/// checks that leaves encrypted while the next one is compressed get disjoint cipher offsets
+#[test]
+fn encrypt_leaves_while_filling() -> anyhow::Result<()> {
+    let xs = (0..1000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (mut builder, mut txn, _) = TestTree::packed(xs[..500].to_vec()).builder()?;
+    let tree0 = builder.snapshot();
+    txn.extend(&mut builder, xs[500..].to_vec())?;
+    let tree1 = builder.snapshot();
+    assert!(no_offset_overlap(&txn, &[tree0, tree1.clone()])?);
+    let actual = txn
+        .iter_filtered(&tree1, AllQuery)
+        .map(|res| res.map(|(_, k, v)| (k, v)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert_eq!(actual, xs);
+    Ok(())
+}
+
 fn do_retain(t: TestTree) -> anyhow::Result<bool> {
     let (mut builder, mut txn, xs) = t.builder()?;
     let tree0 = builder.snapshot();
@@ -456,6 +1606,28 @@ async fn do_stream_trees_chunked_reverse_should_honour_an_inclusive_upper_bound(
     Ok(TestResult::from_bool(expected == actual))
 }
 
+#[tokio::test]
+async fn extend_from_stream() -> anyhow::Result<()> {
+    let xs = (0..10000u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut forest = txn(store, 1000);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    // elements arrive in bursts, with the stream pending in between
+    let bursts = stream::iter(xs.chunks(1000).map(|c| c.to_vec()))
+        .then(|burst| async move {
+            tokio::task::yield_now().await;
+            stream::iter(burst)
+        })
+        .flatten();
+    forest.extend_from_stream(&mut builder, bursts).await?;
+    let actual = forest
+        .iter_filtered(&builder.snapshot(), AllQuery)
+        .map(|res| res.map(|(_, k, v)| (k, v)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert_eq!(actual, xs);
+    Ok(())
+}
+
 #[tokio::test]
 async fn stream_trees_chunked_reverse_should_complete() {
     let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
@@ -541,12 +1713,81 @@ fn deep_tree_traversal_no_stack_overflow() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[quickcheck]
+fn tree_gen_shapes(seed: u64, count: u16) -> anyhow::Result<bool> {
+    let count = count as u64 % 2000;
+    for shape in TreeShape::ALL.iter() {
+        let gen = TreeGen::new(*shape, seed, count);
+        let mut t1 = txn(
+            MemStore::new(usize::max_value(), Sha256Digest::digest),
+            1000,
+        );
+        let builder = gen.build(&mut t1, Secrets::default(), |i| (Key(i), i))?;
+        t1.assert_invariants(&builder)?;
+        let actual = t1
+            .iter_filtered(&builder.snapshot(), AllQuery)
+            .map(|res| res.map(|(offset, _, value)| (offset, value)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let ok = if *shape == TreeShape::Purged {
+            actual.iter().all(|(o, v)| o == v)
+                && (0..count).step_by(50).all(|o| actual.contains(&(o, o)))
+        } else {
+            actual == (0..count).map(|i| (i, i)).collect::<Vec<_>>()
+        };
+        // the same seed must produce the same tree
+        let mut t2 = txn(
+            MemStore::new(usize::max_value(), Sha256Digest::digest),
+            1000,
+        );
+        let builder2 = gen.build(&mut t2, Secrets::default(), |i| (Key(i), i))?;
+        if !ok || builder.link() != builder2.link() || builder.count() != count {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[test]
+fn branch_cache_capacity_in_bytes() -> anyhow::Result<()> {
+    let branch = |n: u64| {
+        let children = (0..n)
+            .map(|i| {
+                LeafIndex {
+                    sealed: true,
+                    value_bytes: 0,
+                    keys: KeySeq((0..200).map(|j| Key(i * 200 + j)).collect()),
+                    value_summary: Ipld::Null,
+                    link: None,
+                }
+                .into()
+            })
+            .collect::<Vec<Index<TT>>>();
+        Branch::new(children, 0..0)
+    };
+    let link = |i: u8| Sha256Digest::digest(&[i]);
+    // a branch with a single leaf of 200 keys takes more than 1600 bytes, so it does not fit
+    let cache = BranchCache::<TT>::new(1000);
+    cache.put(link(0), branch(1));
+    assert!(cache.get(&link(0)).is_none());
+    // a large branch pushes out the small ones
+    let cache = BranchCache::<TT>::new(20_000);
+    for i in 0..10 {
+        cache.put(link(i), branch(1));
+    }
+    assert!((0..10).all(|i| cache.get(&link(i)).is_some()));
+    cache.put(link(10), branch(10));
+    assert!(cache.get(&link(10)).is_some());
+    assert!((0..10).any(|i| cache.get(&link(i)).is_none()));
+    Ok(())
+}
+
 #[test]
 fn leaf_index_wire_format() -> anyhow::Result<()> {
     let index: Index<TT> = LeafIndex {
         sealed: true,
         value_bytes: 1234,
         keys: KeySeq(vec![Key(1), Key(2)]),
+        value_summary: Ipld::Null,
         link: Some(
             Cid::from_str("bafyreihtx752fmf3zafbys5dtr4jxohb53yi3qtzfzf6wd5274jwtn5agu")?
                 .try_into()?,
@@ -698,6 +1939,12 @@ fn create_interesting_tree(n: usize) -> anyhow::Result<TreeFixture> {
         max_summary_branches: 4,
         zstd_level: 10,
         max_uncompressed_leaf_size: 16 * 1024 * 1024,
+        skip_redundant_compression: false,
+        summary_truncation_level: None,
+        adaptive_leaf_size: false,
+        ordered_keys: false,
+        pack_values: false,
+        dedup_window: 0,
     };
     let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
     let forest = Forest::new(store.clone(), BranchCache::new(1 << 20));
@@ -852,6 +2099,264 @@ fn build1() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn concurrent_readers() -> anyhow::Result<()> {
+    fn assert_send_sync<X: Send + Sync>() {}
+    assert_send_sync::<Tree<TT, std::rc::Rc<u64>>>();
+    assert_send_sync::<Forest<TT, MemStore<Sha256Digest>>>();
+    assert_send_sync::<BranchCache<TT>>();
+    assert_send_sync::<TreeReader<TT, MemStore<Sha256Digest>, u64>>();
+
+    let xs = (0..1000).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = txn(store, 1000);
+    let mut builder = StreamBuilder::debug();
+    txn.extend(&mut builder, xs.clone())?;
+    let reader = txn.reader(&builder.snapshot());
+    // the builder can be modified while the snapshot is read
+    txn.extend(&mut builder, vec![(Key(1000), 1000)])?;
+    let handles = (0..4)
+        .map(|_| {
+            let reader = reader.clone();
+            std::thread::spawn(move || {
+                reader
+                    .iter_filtered(AllQuery)
+                    .map(|res| res.map(|(_, k, v)| (k, v)))
+                    .collect::<anyhow::Result<Vec<_>>>()
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap()?, xs);
+    }
+    assert_eq!(reader.count(), 1000);
+    assert_eq!(reader.get(999)?, Some((Key(999), 999)));
+    Ok(())
+}
+
+#[test]
+fn publish_root_conflict() -> anyhow::Result<()> {
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = txn(store.clone(), 1000);
+    let load = |txn: &Transaction<TT, _, _>| {
+        txn.load_root::<u64>(&store, "stream", Secrets::default(), Config::debug())
+    };
+    let mut a = load(&txn)?;
+    let mut b = load(&txn)?;
+    assert!(a.is_empty());
+    txn.extend(&mut a, vec![(Key(0), 0)])?;
+    txn.publish_root(&store, "stream", &mut a)?;
+    assert_eq!(store.get_root("stream")?, a.link());
+
+    // b was loaded before a published, so it must not overwrite a's changes
+    txn.extend(&mut b, vec![(Key(1), 1)])?;
+    let err = txn.publish_root(&store, "stream", &mut b).unwrap_err();
+    assert!(err.downcast_ref::<RootConflict>().is_some());
+    assert_eq!(store.get_root("stream")?, a.link());
+
+    // after loading again, the changes can be redone and published
+    let mut b = load(&txn)?;
+    txn.extend(&mut b, vec![(Key(1), 1)])?;
+    txn.publish_root(&store, "stream", &mut b)?;
+    assert!(txn.publish_root(&store, "stream", &mut a).is_err());
+    let tree = load(&txn)?.snapshot();
+    assert_eq!(
+        txn.iter_filtered(&tree, AllQuery)
+            .map(|res| res.map(|(_, k, v)| (k, v)))
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        vec![(Key(0), 0), (Key(1), 1)]
+    );
+    Ok(())
+}
+
+#[test]
+fn stream_builder_checkpoint() -> anyhow::Result<()> {
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = txn(store, 1000);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    // unpacked, so there is an unsealed tail
+    for i in 0..25 {
+        txn.extend_unpacked(&mut builder, vec![(Key(i), i)])?;
+    }
+    let bytes = builder.to_bytes()?;
+    let mut resumed = StreamBuilder::<TT, u64>::from_bytes(&bytes, Secrets::default())?;
+    assert_eq!(resumed.link(), builder.link());
+    assert_eq!(resumed.level(), builder.level());
+
+    // the resumed builder does not reuse the cipher offsets after the checkpoint
+    let offset = |bytes: &[u8]| -> anyhow::Result<u64> {
+        match DagCborCodec.decode::<Ipld>(bytes)? {
+            Ipld::List(fields) => match fields[2] {
+                Ipld::Integer(offset) => Ok(offset.try_into()?),
+                _ => anyhow::bail!("not an offset"),
+            },
+            _ => anyhow::bail!("not a list"),
+        }
+    };
+    assert_eq!(
+        offset(&resumed.to_bytes()?)?,
+        offset(&bytes)? + CHECKPOINT_HEADROOM
+    );
+
+    // appending to the resumed builder produces a tree with the same elements
+    let xs = (25..50).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    txn.extend(&mut builder, xs.clone())?;
+    txn.extend(&mut resumed, xs)?;
+    assert_eq!(
+        txn.collect(&resumed.snapshot())?,
+        txn.collect(&builder.snapshot())?
+    );
+
+    // checkpoints of a newer version are rejected before looking at the other fields
+    let newer = DagCborCodec.encode(&Ipld::List(vec![Ipld::Integer(2), Ipld::Null]))?;
+    let err = StreamBuilder::<TT, u64>::from_bytes(&newer, Secrets::default()).unwrap_err();
+    assert!(err.to_string().contains("unsupported"));
+    assert!(StreamBuilder::<TT, u64>::from_bytes(&[1, 2, 3], Secrets::default()).is_err());
+    Ok(())
+}
+
+#[test]
+fn secrets_are_redacted() {
+    let key = banyan::chacha20::Key::from([7u8; 32]);
+    let secrets = Secrets::new(key, key);
+    let text = format!("{:?}", secrets);
+    assert!(text.contains("<redacted>"));
+    assert!(!text.contains('7'));
+    // clones share the keys
+    let clone = secrets.clone();
+    assert_eq!(clone, secrets);
+    assert_eq!(clone.index_key(), Some(&key));
+    assert_ne!(clone, Secrets::default());
+}
+
+#[test]
+fn key_provider() -> anyhow::Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    struct Provider(std::sync::Arc<AtomicUsize>);
+    impl KeyProvider for Provider {
+        fn index_key(&self) -> anyhow::Result<banyan::chacha20::Key> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok([1u8; 32].into())
+        }
+        fn value_key(&self) -> anyhow::Result<banyan::chacha20::Key> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok([2u8; 32].into())
+        }
+    }
+    let calls = std::sync::Arc::new(AtomicUsize::new(0));
+    let provided = Secrets::from_provider(Provider(calls.clone()));
+    assert_eq!(provided.index_key(), None);
+    let secrets = Secrets::new([1u8; 32].into(), [2u8; 32].into());
+
+    let xs = (0..100).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = txn(store, 1000);
+    let mut a = StreamBuilder::<TT, u64>::new(Config::debug(), provided);
+    let mut b = StreamBuilder::<TT, u64>::new(Config::debug(), secrets);
+    txn.extend(&mut a, xs.clone())?;
+    txn.extend(&mut b, xs.clone())?;
+    assert!(calls.load(Ordering::SeqCst) > 0);
+    // same keys, same blocks
+    assert_eq!(a.link(), b.link());
+    let actual = txn
+        .iter_filtered(&a.snapshot(), AllQuery)
+        .map(|res| res.map(|(_, k, v)| (k, v)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert_eq!(actual, xs);
+    Ok(())
+}
+
+#[test]
+fn stream_id_nonces() -> anyhow::Result<()> {
+    let xs = (0..100).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = txn(store, 1000);
+    let secrets = Secrets::new([1u8; 32].into(), [2u8; 32].into());
+    let build = |txn: &mut Transaction<TT, _, _>, secrets: Secrets| {
+        let mut builder = StreamBuilder::<TT, u64>::new(Config::debug(), secrets);
+        txn.extend(&mut builder, xs.clone())?;
+        Ok::<_, anyhow::Error>(builder)
+    };
+    let a1 = build(&mut txn, secrets.clone().with_stream_id("a"))?;
+    let a2 = build(&mut txn, secrets.clone().with_stream_id("a"))?;
+    let b = build(&mut txn, secrets.clone().with_stream_id("b"))?;
+    let plain = build(&mut txn, secrets.clone())?;
+    // deterministic for the same stream id, different for different ones
+    assert_eq!(a1.link(), a2.link());
+    assert_ne!(a1.link(), b.link());
+    assert_ne!(a1.link(), plain.link());
+
+    let link = a1.link().unwrap();
+    let tree = txn.load_tree::<u64>(secrets.clone().with_stream_id("a"), link)?;
+    assert_eq!(txn.collect(&tree)?.len(), 100);
+    // the stream id is needed for reading
+    assert!(txn.load_tree::<u64>(secrets, link).is_err());
+    Ok(())
+}
+
+#[test]
+fn plaintext_secrets() -> anyhow::Result<()> {
+    let xs = (0..100).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = txn(store.clone(), 1000);
+    let mut plain = StreamBuilder::<TT, u64>::new(Config::debug(), Secrets::none());
+    let mut encrypted = StreamBuilder::<TT, u64>::new(Config::debug(), Secrets::default());
+    txn.extend(&mut plain, xs.clone())?;
+    txn.extend(&mut encrypted, xs.clone())?;
+    assert!(!plain.snapshot().secrets().unwrap().encrypts());
+    assert_ne!(plain.link(), encrypted.link());
+    // the compressed data is visible as a zstd frame in the plain blocks
+    let zstd_magic = [0x28, 0xb5, 0x2f, 0xfd];
+    let has_magic = |link: &Sha256Digest| -> anyhow::Result<bool> {
+        let block = store.get(link)?;
+        Ok(block.windows(4).any(|w| w == zstd_magic))
+    };
+    assert!(has_magic(plain.link().as_ref().unwrap())?);
+    assert!(!has_magic(encrypted.link().as_ref().unwrap())?);
+
+    // plain blocks can be read with any secrets, encrypted ones not without keys
+    let link = plain.link().unwrap();
+    for secrets in [Secrets::none(), Secrets::default()] {
+        let tree = txn.load_tree::<u64>(secrets, link)?;
+        let actual = txn
+            .iter_filtered(&tree, AllQuery)
+            .map(|res| res.map(|(_, k, v)| (k, v)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(actual, xs);
+    }
+    assert!(txn
+        .load_tree::<u64>(Secrets::none(), encrypted.link().unwrap())
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn invariant_violations() -> anyhow::Result<()> {
+    // 10 elements per leaf and 4 children per branch, so this fills 4 levels completely
+    let xs = (0..640u64).map(|i| (Key(i), i)).collect::<Vec<_>>();
+    let (builder, txn, _) = TestTree::packed(xs).builder()?;
+    assert!(txn.check_invariants(&builder)?.is_empty());
+    // with 5 children per level 1 branch, none of the level 1 branches should be sealed
+    let config = Config {
+        max_key_branches: 5,
+        ..Config::debug()
+    };
+    let link = builder.link().unwrap();
+    let loaded = txn.load_stream_builder::<u64>(Secrets::default(), config, link)?;
+    let violations = txn.check_invariants(&loaded)?;
+    assert_eq!(violations.len(), 16);
+    for violation in &violations {
+        assert_eq!(
+            violation.kind,
+            ViolationKind::NonMonotoneSealing { sealed: true }
+        );
+        assert_eq!(violation.path.len(), 2);
+    }
+    assert_eq!(violations[5].path, vec![1, 1]);
+    assert_eq!(violations[5].to_string(), "branch is sealed at /1/1");
+    Ok(())
+}
+
 fn try_init_logging() {
     let builder = tracing_subscriber::fmt()
         .with_span_events(FmtSpan::CLOSE)
@@ -2,7 +2,7 @@
 //! helper methods for the tests
 use banyan::{
     index::{CompactSeq, Summarizable, VecSeq},
-    query::{AllQuery, AndQuery, OffsetRangeQuery, Query},
+    query::{AllQuery, AndQuery, OffsetRangeQuery, OrderedSummary, Query, QueryExpr},
     store::{BranchCache, MemStore, ReadOnlyStore},
     StreamBuilder, Transaction, Tree, TreeTypes,
 };
@@ -16,6 +16,7 @@ use quickcheck::{Arbitrary, Gen, TestResult};
 use range_collections::RangeSet;
 use sha2::{Digest, Sha256};
 use std::{
+    cmp,
     collections::HashSet,
     convert::{TryFrom, TryInto},
     fmt,
@@ -39,6 +40,26 @@ pub struct Key(pub u64);
 #[derive(Debug, Clone, Copy, PartialEq, Eq, DagCbor)]
 pub struct KeyRange(pub u64, pub u64);
 
+impl OrderedSummary<u64> for Key {
+    fn min_key(&self) -> u64 {
+        self.0
+    }
+
+    fn max_key(&self) -> u64 {
+        self.0
+    }
+}
+
+impl OrderedSummary<u64> for KeyRange {
+    fn min_key(&self) -> u64 {
+        self.0
+    }
+
+    fn max_key(&self) -> u64 {
+        self.1
+    }
+}
+
 impl KeyRange {
     fn as_range_set(&self) -> RangeSet<u64> {
         RangeSet::from(self.0..self.1.saturating_add(1))
@@ -106,6 +127,10 @@ impl TreeTypes for TT {
     type Summary = KeyRange;
     type SummarySeq = VecSeq<KeyRange>;
     type Link = Sha256Digest;
+
+    fn compare_keys(a: &Key, b: &Key) -> Option<cmp::Ordering> {
+        Some(a.0.cmp(&b.0))
+    }
 }
 
 impl Key {
@@ -158,6 +183,13 @@ impl TestFilter {
         )
     }
 
+    pub fn expr(&self) -> QueryExpr<KeyQuery> {
+        QueryExpr::And(vec![
+            QueryExpr::OffsetRange(self.offset_range.clone()),
+            QueryExpr::Atom(KeyQuery(self.keys.clone())),
+        ])
+    }
+
     pub fn contains(&self, triple: &(u64, Key, u64)) -> bool {
         let (offset, key, _) = triple;
         self.offset_range.contains(offset) && self.keys.contains(&key.0)
@@ -313,12 +345,16 @@ type OffsetSet = RangeSet<u64, [u64; 16]>;
 
 /// utility struct for encoding and decoding - copied for the tests
 #[derive(DagCbor)]
-struct IpldNode(u64, Vec<Cid>, Ipld);
+struct IpldNode(u64, Option<u64>, Vec<Cid>, Ipld);
 
 impl IpldNode {
     fn into_data(self) -> anyhow::Result<(u64, Vec<Cid>, Vec<u8>)> {
-        if let Ipld::Bytes(data) = self.2 {
-            Ok((self.0, self.1, data))
+        anyhow::ensure!(self.0 == 1, "unexpected envelope version {}", self.0);
+        let offset = self
+            .1
+            .ok_or_else(|| anyhow::anyhow!("expected encrypted block"))?;
+        if let Ipld::Bytes(data) = self.3 {
+            Ok((offset, self.2, data))
         } else {
             Err(anyhow::anyhow!("expected ipld bytes"))
         }
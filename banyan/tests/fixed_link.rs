@@ -0,0 +1,49 @@
+//! tests for trees with compact fixed size links
+use banyan::{
+    index::{UnitSeq, VecSeq},
+    link::FixedLink,
+    query::AllQuery,
+    store::{BranchCache, MemStore},
+    Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use common::Key;
+use quickcheck_macros::quickcheck;
+use sha2::{Digest, Sha256};
+
+mod common;
+
+#[derive(Debug, Clone)]
+struct TT;
+
+type Link = FixedLink<32>;
+
+impl TreeTypes for TT {
+    type Key = Key;
+    type KeySeq = VecSeq<Key>;
+    type Summary = ();
+    type SummarySeq = UnitSeq;
+    type Link = Link;
+}
+
+fn digest(data: &[u8]) -> Link {
+    Link::new(Sha256::digest(data).into())
+}
+
+#[quickcheck]
+fn fixed_link_roundtrip(xs: Vec<u64>) -> anyhow::Result<bool> {
+    let store = MemStore::new(usize::max_value(), digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::new(0)), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    txn.extend(&mut builder, xs.iter().map(|x| (Key(*x), *x)))?;
+    // load from the store, so the links go through serialization
+    let actual = match builder.link() {
+        Some(link) => {
+            let tree = txn.load_tree::<u64>(Secrets::default(), link)?;
+            txn.iter_filtered(&tree, AllQuery)
+                .map(|res| res.map(|(_, _, v)| v))
+                .collect::<anyhow::Result<Vec<_>>>()?
+        }
+        None => Vec::new(),
+    };
+    Ok(actual == xs)
+}
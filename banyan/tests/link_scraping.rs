@@ -110,8 +110,9 @@ fn links_get_properly_scraped(xs: Vec<(Key, Payload)>) -> anyhow::Result<bool> {
     // collect references from blocks
     for (_, v) in store {
         let t: Vec<Ipld> = DagCborCodec.decode(&v)?;
-        anyhow::ensure!(t.len() == 3);
-        t[1].references(&mut references_from_blocks);
+        // version, offset, links and data
+        anyhow::ensure!(t.len() == 4);
+        t[2].references(&mut references_from_blocks);
     }
     // collect references from keys and values
     for (k, v) in &xs {
@@ -151,3 +152,29 @@ fn tree_link_scraping_key() -> anyhow::Result<()> {
     assert!(links_get_properly_scraped(xs)?);
     Ok(())
 }
+
+/// checks that blocks are canonical dag-cbor, so generic ipld tooling can traverse them, and that
+/// the link section of every branch contains the links to its children
+#[test]
+fn blocks_are_canonical_dag_cbor() -> anyhow::Result<()> {
+    let xs = (0..1000u64)
+        .map(|i| (Key(ipld! { i }), Payload(ipld! { i })))
+        .collect::<Vec<_>>();
+    let (_, txn) = create_test_tree(xs)?;
+    let store = txn.into_writer().into_inner()?;
+    let links = store
+        .keys()
+        .map(|link| Cid::from(*link))
+        .collect::<FnvHashSet<_>>();
+    let mut referenced = FnvHashSet::default();
+    for (_, v) in store {
+        let ipld: Ipld = DagCborCodec.decode(&v)?;
+        // re-encoding gives the same bytes only for canonical dag-cbor
+        anyhow::ensure!(DagCborCodec.encode(&ipld)? == v.as_ref());
+        ipld.references(&mut referenced);
+    }
+    // the children of branches are referenced from the link section of the branch
+    anyhow::ensure!(!referenced.is_empty());
+    anyhow::ensure!(referenced.is_subset(&links));
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! tests for truncation of summaries in branches near the root
+use banyan::{
+    index::{Index, VecSeq},
+    query::KeyRangeQuery,
+    store::{BranchCache, MemStore},
+    testing::MinMax,
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use common::Sha256Digest;
+use quickcheck_macros::quickcheck;
+
+mod common;
+
+/// key ranges of summaries are rounded to multiples of this when truncated
+const GRANULARITY: u64 = 1 << 10;
+
+#[derive(Debug, Clone)]
+struct TT;
+
+impl TreeTypes for TT {
+    type Key = u64;
+    type KeySeq = VecSeq<u64>;
+    type Summary = MinMax;
+    type SummarySeq = VecSeq<MinMax>;
+    type Link = Sha256Digest;
+
+    fn truncate_summary(summary: MinMax, _: u32) -> MinMax {
+        MinMax {
+            min: summary.min / GRANULARITY * GRANULARITY,
+            max: summary.max | (GRANULARITY - 1),
+        }
+    }
+}
+
+type Txn = Transaction<TT, MemStore<Sha256Digest>, MemStore<Sha256Digest>>;
+
+fn build(n: u64, config: Config) -> anyhow::Result<(Txn, StreamBuilder<TT, u64>)> {
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::new(1 << 20)), store);
+    let mut builder = StreamBuilder::<TT, u64>::new(config, Secrets::default());
+    // extend in two batches, so the second one extends existing branches
+    txn.extend(&mut builder, (0..n / 2).map(|i| (i * 3, i)))?;
+    txn.extend(&mut builder, (n / 2..n).map(|i| (i * 3, i)))?;
+    Ok((txn, builder))
+}
+
+fn truncating() -> Config {
+    Config {
+        summary_truncation_level: Some(2),
+        adaptive_leaf_size: false,
+        ..Config::debug()
+    }
+}
+
+#[test]
+fn truncated_summaries_near_root() -> anyhow::Result<()> {
+    let (txn, builder) = build(1000, truncating())?;
+    txn.assert_invariants(&builder)?;
+    let root = match builder.index() {
+        Some(Index::Branch(root)) => root.clone(),
+        _ => anyhow::bail!("expected a branch"),
+    };
+    assert!(root.level >= 2);
+    for summary in root.summaries() {
+        assert_eq!(summary.min % GRANULARITY, 0);
+        assert_eq!(summary.max % GRANULARITY, GRANULARITY - 1);
+    }
+    // loading the builder truncates the summaries of the root in the same way
+    let link = builder.link().unwrap();
+    let loaded = txn.load_stream_builder::<u64>(Secrets::default(), truncating(), link)?;
+    txn.assert_invariants(&loaded)?;
+    match loaded.index() {
+        Some(Index::Branch(loaded)) => {
+            assert_eq!(
+                loaded.summaries().collect::<Vec<_>>(),
+                root.summaries().collect::<Vec<_>>()
+            );
+        }
+        _ => anyhow::bail!("expected a branch"),
+    }
+    Ok(())
+}
+
+#[quickcheck]
+fn truncation_keeps_query_results(n: u16, start: u16, len: u16) -> anyhow::Result<bool> {
+    let n = n as u64 % 2000;
+    let query = KeyRangeQuery::from(start as u64..start as u64 + len as u64);
+    let (txn, builder) = build(n, truncating())?;
+    let actual = txn
+        .iter_filtered(&builder.snapshot(), query.clone())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let (txn, builder) = build(n, Config::debug())?;
+    let expected = txn
+        .iter_filtered(&builder.snapshot(), query)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(actual == expected)
+}
@@ -0,0 +1,131 @@
+//! tests for value summaries stored in the leaf index
+use banyan::{
+    index::{BranchIndex, Index, LeafIndex, UnitSeq, ValueSummarizable, VecSeq},
+    query::{AllQuery, Query},
+    store::{BranchCache, MemStore},
+    Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use common::Sha256Digest;
+use libipld::DagCbor;
+use quickcheck_macros::quickcheck;
+
+mod common;
+
+#[derive(Debug, Clone)]
+struct TT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DagCbor)]
+struct Key(u64);
+
+/// maximum of all values in a leaf
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, DagCbor)]
+struct MaxValue(u64);
+
+impl TreeTypes for TT {
+    type Key = Key;
+    type KeySeq = VecSeq<Key>;
+    type Summary = ();
+    type SummarySeq = UnitSeq;
+    type Link = Sha256Digest;
+}
+
+impl ValueSummarizable<u64> for MaxValue {
+    fn add_value(&mut self, value: &u64) {
+        self.0 = self.0.max(*value);
+    }
+}
+
+/// all elements in leaves that contain a value of at least the given value
+#[derive(Debug, Clone)]
+struct MaxValueQuery(u64);
+
+impl Query<TT> for MaxValueQuery {
+    fn containing(&self, _: u64, index: &LeafIndex<TT>, res: &mut [bool]) {
+        let below = index
+            .value_summary_as::<MaxValue>()
+            .is_some_and(|max| max.0 < self.0);
+        if below {
+            res.iter_mut().for_each(|x| *x = false);
+        }
+    }
+
+    fn intersecting(&self, _: u64, _: &BranchIndex<TT>, _: &mut [bool]) {}
+}
+
+type Txn = Transaction<TT, MemStore<Sha256Digest>, MemStore<Sha256Digest>>;
+
+fn build(xss: &[Vec<u32>]) -> anyhow::Result<(Txn, StreamBuilder<TT, u64>)> {
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::new(1 << 20)), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug().with_value_summary::<MaxValue>();
+    let mut offset = 0;
+    // extend in batches, so existing leaves get extended
+    for xs in xss {
+        txn.extend(
+            &mut builder,
+            xs.iter().map(|x| {
+                offset += 1;
+                (Key(offset), *x as u64)
+            }),
+        )?;
+    }
+    Ok((txn, builder))
+}
+
+#[quickcheck]
+fn value_summary_is_max(xss: Vec<Vec<u32>>) -> anyhow::Result<bool> {
+    let (txn, builder) = build(&xss)?;
+    let values = xss.iter().flatten().map(|x| *x as u64).collect::<Vec<_>>();
+    // load from the store, so the value summaries go through serialization
+    let tree = match builder.link() {
+        Some(link) => txn.load_tree::<u64>(Secrets::default(), link)?,
+        None => return Ok(values.is_empty()),
+    };
+    let mut offset = 0usize;
+    for index in txn.iter_index(&tree, AllQuery) {
+        if let Index::Leaf(leaf) = index? {
+            let n = leaf.keys.as_ref().len();
+            let max = values[offset..offset + n].iter().max().cloned().unwrap();
+            if leaf.value_summary_as::<MaxValue>() != Some(MaxValue(max)) {
+                return Ok(false);
+            }
+            offset += n;
+        }
+    }
+    Ok(offset == values.len())
+}
+
+#[quickcheck]
+fn value_summary_query(xss: Vec<Vec<u32>>, min: u32) -> anyhow::Result<bool> {
+    let (txn, builder) = build(&xss)?;
+    let actual = txn
+        .iter_filtered(&builder.snapshot(), MaxValueQuery(min as u64))
+        .map(|res| res.map(|(_, _, v)| v))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let expected = xss
+        .iter()
+        .flatten()
+        .map(|x| *x as u64)
+        .filter(|x| *x >= min as u64)
+        .collect::<Vec<_>>();
+    // pruning is per leaf, so all matching values must be there, and every leaf must contain one
+    let all_found = expected.iter().all(|x| actual.contains(x));
+    Ok(all_found && (expected.is_empty() == actual.is_empty()))
+}
+
+#[test]
+fn value_summary_is_opt_in() -> anyhow::Result<()> {
+    let store = MemStore::new(usize::max_value(), Sha256Digest::digest);
+    let mut txn = Transaction::new(Forest::new(store.clone(), BranchCache::new(1 << 20)), store);
+    let mut builder = StreamBuilder::<TT, u64>::debug();
+    txn.extend(&mut builder, (0..100).map(|i| (Key(i), i)))?;
+    for index in txn.iter_index(&builder.snapshot(), AllQuery) {
+        if let Index::Leaf(leaf) = index? {
+            assert_eq!(leaf.value_summary_as::<MaxValue>(), None);
+        }
+    }
+    // leaves without a summary are never skipped
+    let found = txn.iter_filtered(&builder.snapshot(), MaxValueQuery(1000));
+    assert_eq!(found.count(), 100);
+    Ok(())
+}